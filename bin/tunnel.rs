@@ -1,42 +1,158 @@
 
 
 use anyhow::{Result};
-use clap::Arg;
-use futures::{FutureExt};
-use log::error;
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use log::{error, info};
 
 
-use tunnel::{
-    start,
-};
+use tunnel::{app::{bench, doctor}, ebpf, systemd, Instance, TunnelBuilder};
 
 fn load() -> Result<()> {
-    let app = clap::App::new("tunnel").arg(
-        Arg::with_name("config")
-            .short("-c")
-            .long("--config")
-            .required(true)
-            .value_name("FILE"),
-    );
+    let app = clap::App::new("tunnel")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(
+            Arg::with_name("config")
+                .short("-c")
+                .long("--config")
+                .required(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("--strict")
+                .help("treat unknown keys, unreachable rules and deprecated options as errors"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("--dry-run")
+                .help("log routing decisions but forward all traffic direct"),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("measure connect latency, ttfb and throughput through outbounds")
+                .arg(Arg::with_name("config").short("-c").long("--config").required(true).value_name("FILE"))
+                .arg(
+                    Arg::with_name("outbound")
+                        .long("--outbound")
+                        .value_name("TAG")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("outbound to measure, may be repeated, all outbounds if absent"),
+                )
+                .arg(Arg::with_name("url").long("--url").value_name("URL").help("http(s) url to download"))
+                .arg(Arg::with_name("rounds").long("--rounds").value_name("N").help("latency samples per outbound"))
+                .arg(Arg::with_name("duration").long("--duration").value_name("SECONDS").help("max seconds of the throughput download")),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("check tun permissions, forwarding, routes, rp_filter and dns through every outbound")
+                .arg(Arg::with_name("config").short("-c").long("--config").required(true).value_name("FILE")),
+        );
     let matchers = app.get_matches();
+    if let Some(matchers) = matchers.subcommand_matches("bench") {
+        return run_bench(matchers);
+    }
+    if let Some(matchers) = matchers.subcommand_matches("doctor") {
+        return run_doctor(matchers);
+    }
     let config_path = matchers
         .value_of("config")
         .expect("config file path required");
-    let config = match tunnel::load_from_file(config_path) {
+    let strict = matchers.is_present("strict");
+    let mut config = match tunnel::load_from_file_with_mode(config_path, strict) {
         Ok(x) => x,
         Err(err) => {
             eprintln!("failed to load config file {} {}", config_path, err);
             return Err(err);
         }
     };
-    let (shutdown_future, shutdown_handler) = futures::future::abortable(futures::future::pending::<bool>());
-    let handler = async {
-        shutdown_future.await.unwrap();
-    }.boxed();
-    start(config, handler).unwrap();
-    shutdown_handler.abort();
+    if matchers.is_present("dry-run") {
+        config.general.dry_run = true;
+    }
+    // 加载 ebpf 程序需要 root，在切换用户之前
+    let _bypass = ebpf::attach(&config)?;
+    let instance = TunnelBuilder::new(config).logger(true).start()?;
+    // tun 设备创建之后再切换用户
+    instance.drop_privileges()?;
+    systemd::ready();
+    let dry_run = matchers.is_present("dry-run");
+    if let Err(err) = instance.block_on(wait(&instance, config_path, strict, dry_run)) {
+        error!("wait for signals failed {}", err);
+    }
+    info!("shutting down");
+    systemd::stopping();
+    instance.shutdown();
     Ok(())
 }
+
+fn run_bench(matchers: &ArgMatches) -> Result<()> {
+    let config_path = matchers.value_of("config").expect("config file path required");
+    let config = tunnel::load_from_file(config_path)?;
+    let tags = matchers
+        .values_of("outbound")
+        .map(|x| x.map(|x| x.to_string()).collect())
+        .unwrap_or_default();
+    let rounds = match matchers.value_of("rounds") {
+        Some(x) => x.parse()?,
+        None => bench::DEFAULT_ROUNDS,
+    };
+    let duration = match matchers.value_of("duration") {
+        Some(x) => x.parse()?,
+        None => bench::DEFAULT_DURATION,
+    };
+    let url = matchers.value_of("url").unwrap_or(bench::DEFAULT_URL);
+    let table = tunnel::bench(&config, tags, url, rounds, std::time::Duration::from_secs(duration))?;
+    print!("{}", table);
+    Ok(())
+}
+
+// 有 FAIL 时退出码为 1，方便在脚本中使用
+fn run_doctor(matchers: &ArgMatches) -> Result<()> {
+    let config_path = matchers.value_of("config").expect("config file path required");
+    let config = tunnel::load_from_file(config_path)?;
+    let findings = tunnel::doctor(&config)?;
+    print!("{}", doctor::report(&findings));
+    if findings.iter().any(|x| x.status == doctor::Status::Fail) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// ctrl-c 与 SIGTERM 退出，SIGHUP 重新加载配置（systemctl reload）
+#[cfg(unix)]
+async fn wait(instance: &Instance, config_path: &str, strict: bool, dry_run: bool) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => return res,
+            _ = terminate.recv() => return Ok(()),
+            _ = hangup.recv() => {
+                systemd::reloading();
+                let res = match tunnel::load_from_file_with_mode(config_path, strict) {
+                    Ok(mut config) => {
+                        config.general.dry_run |= dry_run;
+                        instance.reload(config).await
+                    }
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = res {
+                    error!("reload {} failed {}, the previous config keeps running", config_path, err);
+                }
+                // 失败时也要通知，否则 systemd 一直等待
+                systemd::ready();
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait(_instance: &Instance, _config_path: &str, _strict: bool, _dry_run: bool) -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
 fn main() {
     if let Err(err) = load() {
         error!("{}", err);
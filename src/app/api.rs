@@ -0,0 +1,426 @@
+// 管理 api，简单的 http/1.1 + json
+// 目前提供
+// GET    /capture                           当前抓包状态
+// POST   /capture?host=<host>&duration=<s>  开始抓取 host 的完整流量
+// DELETE /capture                           停止抓包
+// GET    /dns/blocklist                     广告拦截的域名数量与拦截次数
+// GET    /stats/buffers                     buffer pool 各大小的分配与复用次数
+// GET    /stats/protocols?profile=<name>    按 outbound 与应用层协议 (tls/h2, http, quic, dns...) 统计的流量，默认为顶层配置
+// GET    /stats/circuits?profile=<name>     各 outbound 熔断、恢复以及改用 fallback 的次数
+// GET    /stats/crashes                     各 inbound 的 accept loop 与连接 task panic 的次数
+// GET    /sessions/trace?id=<sid>           进行中或最近结束的 session 的事件时间线，sid 见日志
+// GET    /inbounds?profile=<name>           全部 inbound、是否正在监听以及并发连接数
+// POST   /inbounds?profile=<name>           添加并启动 inbound，body 为与配置文件相同的 inbound json
+// DELETE /inbounds?profile=<name>&tag=<tag> 停止并删除 inbound
+// POST   /inbounds/start?tag=<tag>          启动已停止的 inbound，同样可以指定 profile
+// POST   /inbounds/stop?tag=<tag>           停止 inbound，已建立的连接不受影响
+// GET    /proxy.pac?token=<token>           system_proxy.pac 开启时的 pac 文件，token 见 ApiServer::pac_path
+//
+// 抓包会把明文写入磁盘，所以必须配置 secret，除了 pac 全部请求需携带 Authorization: Bearer <secret>
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use log::{debug, error, info, warn};
+use ring::constant_time::verify_slices_are_equal;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::config::{ApiConfig, Inbound};
+
+use super::{capture::Recorder, supervisor, trace, Blocklist, InboundManager, Stats};
+
+type TaskFuture = BoxFuture<'static, ()>;
+
+const MAX_REQUEST_HEADER: usize = 8192;
+const MAX_REQUEST_BODY: usize = 65536;
+const DEFAULT_CAPTURE_SECONDS: u64 = 60;
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+// application/x-www-form-urlencoded，+ 为空格，不是 utf8 时 None
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn parse_request(data: &[u8]) -> Option<Request> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut lines = text.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p, q),
+        None => (target, ""),
+    };
+    let query = query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .filter_map(|(k, v)| Some((percent_decode(k)?, percent_decode(v)?)))
+        .collect();
+    let headers = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+    Some(Request {
+        method,
+        path: path.to_string(),
+        query,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+fn pac_token(secret: &str) -> String {
+    Sha256::digest(format!("proxy.pac:{}", secret).as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub struct ApiServer {
+    secret: Option<String>,
+    recorder: Arc<Recorder>,
+    blocklist: Option<Arc<Blocklist>>,
+    // profile name => stats
+    stats: HashMap<String, Arc<Stats>>,
+    // profile name => inbounds
+    inbounds: HashMap<String, Arc<InboundManager>>,
+    pac: Option<String>,
+}
+
+impl ApiServer {
+    /// path of the pac file, system proxy settings can't send the bearer header so it carries a token
+    /// derived from the secret instead of the secret itself
+    pub fn pac_path(secret: Option<&str>) -> String {
+        match secret {
+            Some(secret) => format!("/proxy.pac?token={}", pac_token(secret)),
+            None => "/proxy.pac".to_string(),
+        }
+    }
+
+    pub fn listen(
+        config: ApiConfig,
+        recorder: Arc<Recorder>,
+        blocklist: Option<Arc<Blocklist>>,
+        stats: HashMap<String, Arc<Stats>>,
+        inbounds: HashMap<String, Arc<InboundManager>>,
+        pac: Option<String>,
+    ) -> TaskFuture {
+        let server = Arc::new(ApiServer {
+            secret: config.secret.clone(),
+            recorder,
+            blocklist,
+            stats,
+            inbounds,
+            pac,
+        });
+        async move {
+            let addr = format!(
+                "{}:{}",
+                config.listen.unwrap_or_else(|| "127.0.0.1".to_string()),
+                config.port
+            );
+            let addr = match addr.parse::<SocketAddr>() {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("invalid api listen address {} {}", addr, err);
+                    return;
+                }
+            };
+            let listener = match TcpListener::bind(addr).await {
+                Ok(l) => l,
+                Err(err) => {
+                    error!("api bind {} failed {}", addr, err);
+                    return;
+                }
+            };
+            if server.secret.is_none() {
+                warn!("api secret not configured, all api requests are rejected");
+            }
+            info!("Api listening at {}", addr);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let server = server.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = server.handle(stream).await {
+                                debug!("api request from {} failed {}", peer, err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        error!("api accept error {}", err);
+                        return;
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        let header_end = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 || buf.len() + n > MAX_REQUEST_HEADER {
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+        let request = parse_request(&buf[..header_end]);
+        // 系统代理设置读取 pac 时不会携带 Authorization，配置了 secret 时检查 url 中的 token
+        if let (Some(req), Some(pac)) = (&request, &self.pac) {
+            if req.method == "GET" && req.path == "/proxy.pac" && self.pac_authorized(req) {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    pac.len(),
+                    pac
+                );
+                stream.write_all(response.as_bytes()).await?;
+                return stream.shutdown().await;
+            }
+        }
+        let (code, body) = match request {
+            Some(mut req) => {
+                // 只有添加 inbound 需要 body
+                let len = match req.headers.get("content-length").map(|x| x.parse::<usize>()) {
+                    Some(Ok(x)) => x,
+                    Some(Err(_)) => return Ok(()),
+                    None => 0,
+                };
+                if len > MAX_REQUEST_BODY {
+                    return Ok(());
+                }
+                req.body = buf[header_end..].to_vec();
+                while req.body.len() < len {
+                    let n = stream.read(&mut chunk).await?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    req.body.extend_from_slice(&chunk[..n]);
+                }
+                req.body.truncate(len);
+                self.route(&req)
+            }
+            None => (400, json!({ "error": "bad request" })),
+        };
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            code,
+            reason(code),
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    fn authorized(&self, req: &Request) -> bool {
+        match (&self.secret, req.headers.get("authorization")) {
+            (Some(secret), Some(auth)) => match auth.strip_prefix("Bearer ") {
+                Some(token) => verify_slices_are_equal(token.as_bytes(), secret.as_bytes()).is_ok(),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn pac_authorized(&self, req: &Request) -> bool {
+        match (&self.secret, req.query.get("token")) {
+            (None, _) => true,
+            (Some(secret), Some(token)) => verify_slices_are_equal(pac_token(secret).as_bytes(), token.as_bytes()).is_ok(),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn route(&self, req: &Request) -> (u16, serde_json::Value) {
+        if !self.authorized(req) {
+            return (403, json!({ "error": "forbidden" }));
+        }
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/capture") => match self.recorder.status() {
+                Some((host, remaining)) => (
+                    200,
+                    json!({ "running": true, "host": host, "remaining": remaining.as_secs() }),
+                ),
+                None => (200, json!({ "running": false })),
+            },
+            ("POST", "/capture") => {
+                let host = match req.query.get("host") {
+                    Some(h) => h,
+                    None => return (400, json!({ "error": "missing host" })),
+                };
+                let duration = match req.query.get("duration").map(|d| d.parse::<u64>()) {
+                    Some(Ok(d)) => d,
+                    Some(Err(_)) => return (400, json!({ "error": "bad duration" })),
+                    None => DEFAULT_CAPTURE_SECONDS,
+                };
+                match self.recorder.start(host, Duration::from_secs(duration)) {
+                    Ok(_) => (200, json!({ "running": true, "host": host, "remaining": duration })),
+                    Err(err) => (400, json!({ "error": err.to_string() })),
+                }
+            }
+            ("DELETE", "/capture") => {
+                self.recorder.stop();
+                (200, json!({ "running": false }))
+            }
+            ("GET", "/dns/blocklist") => match &self.blocklist {
+                Some(blocklist) => (
+                    200,
+                    json!({ "domains": blocklist.len(), "blocked": blocklist.blocked() }),
+                ),
+                None => (404, json!({ "error": "blocklist not configured" })),
+            },
+            ("GET", "/stats/buffers") => (200, crate::common::buffer::snapshot()),
+            ("GET", "/stats/crashes") => (200, supervisor::snapshot()),
+            ("GET", "/sessions/trace") => {
+                let id = match req.query.get("id").map(|x| x.parse::<u64>()) {
+                    Some(Ok(x)) => x,
+                    Some(Err(_)) => return (400, json!({ "error": "bad id" })),
+                    None => return (400, json!({ "error": "missing id" })),
+                };
+                match trace::lookup(id) {
+                    Some(timeline) => (200, timeline),
+                    None => (404, json!({ "error": "unknown session" })),
+                }
+            }
+            ("GET", "/stats/protocols") => {
+                let profile = req.query.get("profile").map(|x| x.as_str()).unwrap_or(crate::DEFAULT_PROFILE);
+                match self.stats.get(profile) {
+                    Some(stats) => (200, stats.snapshot()),
+                    None => (404, json!({ "error": "unknown profile" })),
+                }
+            }
+            ("GET", "/stats/circuits") => {
+                let profile = req.query.get("profile").map(|x| x.as_str()).unwrap_or(crate::DEFAULT_PROFILE);
+                match self.stats.get(profile) {
+                    Some(stats) => (200, stats.circuit_snapshot()),
+                    None => (404, json!({ "error": "unknown profile" })),
+                }
+            }
+            ("GET", "/inbounds") => match self.inbound_manager(req) {
+                Some(manager) => (200, manager.snapshot()),
+                None => (404, json!({ "error": "unknown profile" })),
+            },
+            ("POST", "/inbounds") => {
+                let manager = match self.inbound_manager(req) {
+                    Some(x) => x,
+                    None => return (404, json!({ "error": "unknown profile" })),
+                };
+                let inbound = match serde_json::from_slice::<Inbound>(&req.body) {
+                    Ok(x) => x,
+                    Err(err) => return (400, json!({ "error": err.to_string() })),
+                };
+                let tag = inbound.tag.clone();
+                match manager.add(inbound).and_then(|_| manager.start(&tag)) {
+                    Ok(_) => (200, manager.snapshot()),
+                    Err(err) => (400, json!({ "error": err.to_string() })),
+                }
+            }
+            ("DELETE", "/inbounds") | ("POST", "/inbounds/start") | ("POST", "/inbounds/stop") => {
+                let manager = match self.inbound_manager(req) {
+                    Some(x) => x,
+                    None => return (404, json!({ "error": "unknown profile" })),
+                };
+                let tag = match req.query.get("tag") {
+                    Some(x) => x,
+                    None => return (400, json!({ "error": "missing tag" })),
+                };
+                let result = match req.path.as_str() {
+                    "/inbounds/start" => manager.start(tag),
+                    "/inbounds/stop" => manager.stop(tag),
+                    _ => manager.remove(tag),
+                };
+                match result {
+                    Ok(_) => (200, manager.snapshot()),
+                    Err(err) => (400, json!({ "error": err.to_string() })),
+                }
+            }
+            _ => (404, json!({ "error": "not found" })),
+        }
+    }
+
+    fn inbound_manager(&self, req: &Request) -> Option<&Arc<InboundManager>> {
+        let profile = req.query.get("profile").map(|x| x.as_str()).unwrap_or(crate::DEFAULT_PROFILE);
+        self.inbounds.get(profile)
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "",
+    }
+}
+
+#[test]
+fn test_parse_request() {
+    let req = parse_request(
+        b"POST /capture?host=example.com&duration=30 HTTP/1.1\r\nAuthorization: Bearer abc\r\n\r\n",
+    )
+    .unwrap();
+    assert_eq!(req.method, "POST");
+    assert_eq!(req.path, "/capture");
+    assert_eq!(req.query.get("host").unwrap(), "example.com");
+    assert_eq!(req.query.get("duration").unwrap(), "30");
+    assert_eq!(req.headers.get("authorization").unwrap(), "Bearer abc");
+
+    let req = parse_request(b"POST /capture?host=%5B%3A%3A1%5D%3A443&tag=my+socks HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(req.query.get("host").unwrap(), "[::1]:443");
+    assert_eq!(req.query.get("tag").unwrap(), "my socks");
+    assert_eq!(percent_decode("%E4%B8%AD").as_deref(), Some("中"));
+    assert_eq!(percent_decode("100%"), None);
+    assert_eq!(percent_decode("%zz"), None);
+}
+
+#[test]
+fn test_pac_path() {
+    assert_eq!(ApiServer::pac_path(None), "/proxy.pac");
+    let path = ApiServer::pac_path(Some("secret"));
+    let req = parse_request(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+    assert_eq!(req.path, "/proxy.pac");
+    let token = req.query.get("token").unwrap();
+    assert_eq!(token, &pac_token("secret"));
+    assert!(!token.contains("secret"));
+    assert_ne!(pac_token("secret"), pac_token("secret2"));
+}
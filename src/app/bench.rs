@@ -0,0 +1,221 @@
+// tunnel bench: 通过指定 outbound 测量连接延迟、TTFB 与吞吐量，输出对比表格
+// connect: outbound 建立到目标的连接（包括与代理 server 的握手），https 时包括 tls 握手
+// ttfb: 发出请求到收到第一个 byte
+// 延迟取多轮的中位数，吞吐量单独下载一次，最多持续 duration
+
+use std::{
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    time::timeout,
+};
+
+use crate::{
+    config::TlsSettings,
+    proxy::{Address, AnyStream},
+    transport::tls::TlsConnector,
+};
+
+use super::{
+    fetcher::{parse_url, Url},
+    Fetcher,
+};
+
+pub const DEFAULT_URL: &str = "https://speed.cloudflare.com/__down?bytes=100000000";
+pub const DEFAULT_ROUNDS: usize = 3;
+pub const DEFAULT_DURATION: u64 = 10;
+// 单次连接或者等待响应的超时
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct BenchResult {
+    pub tag: String,
+    pub connect: Option<Duration>,
+    pub ttfb: Option<Duration>,
+    // bytes per second
+    pub throughput: Option<f64>,
+    pub error: Option<String>,
+}
+
+pub struct Bench {
+    fetcher: Fetcher,
+    url: Url,
+    rounds: usize,
+    duration: Duration,
+}
+
+impl Bench {
+    pub fn new(fetcher: Fetcher, url: &str, rounds: usize, duration: Duration) -> Result<Bench> {
+        Ok(Bench {
+            fetcher,
+            url: parse_url(url)?,
+            rounds: rounds.max(1),
+            duration,
+        })
+    }
+
+    /// measure the outbound, errors are recorded in the result
+    pub async fn run(&self, tag: &str) -> BenchResult {
+        let fetcher = self.fetcher.with_outbound(tag);
+        let mut result = BenchResult {
+            tag: tag.to_string(),
+            connect: None,
+            ttfb: None,
+            throughput: None,
+            error: None,
+        };
+        let mut connects = Vec::new();
+        let mut ttfbs = Vec::new();
+        for _ in 0..self.rounds {
+            match self.latency(&fetcher).await {
+                Ok((connect, ttfb)) => {
+                    connects.push(connect);
+                    ttfbs.push(ttfb);
+                }
+                Err(err) => result.error = Some(err.to_string()),
+            }
+        }
+        result.connect = median(connects);
+        result.ttfb = median(ttfbs);
+        if result.connect.is_none() {
+            return result;
+        }
+        match self.throughput(&fetcher).await {
+            Ok(x) => result.throughput = Some(x),
+            Err(err) => result.error = Some(err.to_string()),
+        }
+        result
+    }
+
+    async fn request(&self, fetcher: &Fetcher) -> Result<(BufReader<AnyStream>, Duration)> {
+        let start = Instant::now();
+        let connect = async {
+            let destination = Address::try_from((self.url.host.clone(), self.url.port))?;
+            let mut stream = fetcher.connect(destination).await?;
+            if self.url.tls {
+                let connector = TlsConnector::new(&TlsSettings::default())?;
+                stream = Box::new(connector.connect(&self.url.host, stream).await?);
+            }
+            Ok::<_, anyhow::Error>(stream)
+        };
+        let mut stream = match timeout(STEP_TIMEOUT, connect).await {
+            Ok(x) => x?,
+            Err(_) => return Err(anyhow!("connect timeout after {:?}", STEP_TIMEOUT)),
+        };
+        let elapsed = start.elapsed();
+        stream.write_all(fetcher.build_request(&self.url).as_bytes()).await?;
+        Ok((BufReader::new(stream), elapsed))
+    }
+
+    async fn latency(&self, fetcher: &Fetcher) -> Result<(Duration, Duration)> {
+        let (mut stream, connect) = self.request(fetcher).await?;
+        let start = Instant::now();
+        let mut byte = [0u8; 1];
+        match timeout(STEP_TIMEOUT, stream.read(&mut byte)).await {
+            Ok(Ok(0)) => Err(anyhow!("connection closed without response")),
+            Ok(Ok(_)) => Ok((connect, start.elapsed())),
+            Ok(Err(err)) => Err(err.into()),
+            Err(_) => Err(anyhow!("no response after {:?}", STEP_TIMEOUT)),
+        }
+    }
+
+    // 响应头也计入，body 足够大时可以忽略
+    async fn throughput(&self, fetcher: &Fetcher) -> Result<f64> {
+        let (mut stream, _) = self.request(fetcher).await?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut total = 0u64;
+        let start = Instant::now();
+        let deadline = start + self.duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match timeout(deadline - now, stream.read(&mut buf)).await {
+                Ok(Ok(0)) | Err(_) => break,
+                Ok(Ok(n)) => total += n as u64,
+                // 部分 https server 不发送 close_notify 直接关闭连接
+                Ok(Err(err)) if total > 0 && err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Ok(Err(err)) => return Err(err.into()),
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        if total == 0 || elapsed == 0.0 {
+            return Err(anyhow!("nothing downloaded"));
+        }
+        Ok(total as f64 / elapsed)
+    }
+}
+
+fn median(mut values: Vec<Duration>) -> Option<Duration> {
+    values.sort();
+    values.get(values.len() / 2).copied()
+}
+
+/// comparison table, fastest throughput first
+pub fn table(results: &mut [BenchResult]) -> String {
+    results.sort_by(|a, b| {
+        let a = a.throughput.unwrap_or(-1.0);
+        let b = b.throughput.unwrap_or(-1.0);
+        b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let width = results.iter().map(|x| x.tag.len()).max().unwrap_or(0).max("outbound".len());
+    let ms = |x: Option<Duration>| match x {
+        Some(x) => format!("{:.0}ms", x.as_secs_f64() * 1000.0),
+        None => "-".to_string(),
+    };
+    let mut table = format!("{:<width$}  {:>9}  {:>9}  {:>12}  error\n", "outbound", "connect", "ttfb", "throughput", width = width);
+    for result in results.iter() {
+        let throughput = match result.throughput {
+            Some(x) => format!("{:.2}Mbps", x * 8.0 / 1_000_000.0),
+            None => "-".to_string(),
+        };
+        table.push_str(&format!(
+            "{:<width$}  {:>9}  {:>9}  {:>12}  {}\n",
+            result.tag,
+            ms(result.connect),
+            ms(result.ttfb),
+            throughput,
+            result.error.as_deref().unwrap_or(""),
+            width = width
+        ));
+    }
+    table
+}
+
+#[test]
+fn test_bench_table() {
+    let mut results = vec![
+        BenchResult {
+            tag: "direct".to_string(),
+            connect: Some(Duration::from_millis(12)),
+            ttfb: Some(Duration::from_millis(30)),
+            throughput: Some(1_250_000.0),
+            error: None,
+        },
+        BenchResult {
+            tag: "relay-hk".to_string(),
+            connect: None,
+            ttfb: None,
+            throughput: None,
+            error: Some("connection refused".to_string()),
+        },
+        BenchResult {
+            tag: "relay-jp".to_string(),
+            connect: Some(Duration::from_millis(80)),
+            ttfb: Some(Duration::from_millis(95)),
+            throughput: Some(5_000_000.0),
+            error: None,
+        },
+    ];
+    let table = table(&mut results);
+    let lines: Vec<&str> = table.lines().collect();
+    assert!(lines[1].starts_with("relay-jp"));
+    assert!(lines[1].contains("80ms") && lines[1].contains("40.00Mbps"));
+    assert!(lines[2].starts_with("direct  "));
+    assert!(lines[3].ends_with("connection refused"));
+    assert_eq!(median(vec![Duration::from_millis(3), Duration::from_millis(1), Duration::from_millis(2)]), Some(Duration::from_millis(2)));
+}
@@ -0,0 +1,197 @@
+// dns 广告拦截
+// 支持 hosts 格式 (0.0.0.0 ads.example.com) 与每行一个域名的格式，# 开头为注释
+// 列表中的域名同时拦截其全部子域名
+// 列表通常有几十万条，所以不复用 DomainSet 的 suffix 线性匹配，而是逐级查找父域名
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use futures_util::{future::BoxFuture, FutureExt};
+use log::{debug, info, trace, warn};
+
+use crate::config::BlocklistConfig;
+
+use super::Fetcher;
+
+// 默认每天刷新一次
+const DEFAULT_REFRESH: u64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockResponse {
+    NxDomain,
+    // A 返回 0.0.0.0，AAAA 返回 ::
+    Zero,
+}
+
+impl TryFrom<&str> for BlockResponse {
+    type Error = anyhow::Error;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            "zero" => Ok(BlockResponse::Zero),
+            _ => Err(anyhow!("unknown blocklist response {}", value)),
+        }
+    }
+}
+
+pub struct Blocklist {
+    pub response: BlockResponse,
+    domains: RwLock<HashSet<String>>,
+    // 本地文件只在启动时读取一次
+    file_domains: Vec<String>,
+    // 下载失败时继续使用上一次的结果
+    url_domains: RwLock<HashMap<String, Vec<String>>>,
+    urls: Vec<String>,
+    refresh: Duration,
+    blocked: AtomicU64,
+}
+
+fn parse_list(content: &str) -> Vec<String> {
+    let mut domains = Vec::new();
+    for line in content.lines() {
+        let line = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let names = match fields.len() {
+            0 => continue,
+            1 => &fields[..],
+            // hosts 格式，第一列是 ip
+            _ => &fields[1..],
+        };
+        for name in names {
+            let name = name.trim_end_matches('.').to_lowercase();
+            if name.is_empty() || name == "localhost" || name.parse::<std::net::IpAddr>().is_ok() {
+                continue;
+            }
+            domains.push(name);
+        }
+    }
+    domains
+}
+
+impl Blocklist {
+    pub fn new(config: &BlocklistConfig) -> Blocklist {
+        let response = match config.response.as_deref().map(BlockResponse::try_from) {
+            Some(Ok(x)) => x,
+            Some(Err(err)) => {
+                warn!("{}, use nxdomain", err);
+                BlockResponse::NxDomain
+            }
+            None => BlockResponse::NxDomain,
+        };
+        let mut file_domains = Vec::new();
+        for path in config.files.iter().flatten() {
+            match fs::read_to_string(path) {
+                Ok(content) => file_domains.append(&mut parse_list(&content)),
+                Err(err) => warn!("load blocklist {} failed {}", path, err),
+            }
+        }
+        let blocklist = Blocklist {
+            response,
+            domains: RwLock::new(HashSet::new()),
+            file_domains,
+            url_domains: RwLock::new(HashMap::new()),
+            urls: config.urls.clone().unwrap_or_default(),
+            refresh: Duration::from_secs(config.refresh.unwrap_or(DEFAULT_REFRESH)),
+            blocked: AtomicU64::new(0),
+        };
+        blocklist.rebuild();
+        blocklist
+    }
+
+    fn rebuild(&self) {
+        let mut domains: HashSet<String> = self.file_domains.iter().cloned().collect();
+        for list in self.url_domains.read().unwrap().values() {
+            domains.extend(list.iter().cloned());
+        }
+        info!("dns blocklist loaded {} domains", domains.len());
+        *self.domains.write().unwrap() = domains;
+    }
+
+    /// whether host or one of its parent domains is blocked, counts the hit
+    pub fn check(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let domains = self.domains.read().unwrap();
+        let mut name = host.as_str();
+        loop {
+            if domains.contains(name) {
+                let total = self.blocked.fetch_add(1, Ordering::Relaxed) + 1;
+                trace!("dns blocked {} by {}, total blocked {}", host, name, total);
+                return true;
+            }
+            match name.find('.') {
+                Some(idx) => name = &name[idx + 1..],
+                None => return false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of queries blocked since start
+    pub fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    async fn refresh(&self, fetcher: &Fetcher) {
+        for url in &self.urls {
+            match fetcher.get(url).await {
+                Ok(body) => {
+                    let list = parse_list(&String::from_utf8_lossy(&body));
+                    debug!("blocklist {} fetched {} domains", url, list.len());
+                    self.url_domains.write().unwrap().insert(url.clone(), list);
+                }
+                Err(err) => warn!("fetch blocklist {} failed {}", url, err),
+            }
+        }
+        self.rebuild();
+    }
+
+    /// fetch url lists now and then every refresh interval
+    pub fn watch(self: Arc<Self>, fetcher: Arc<Fetcher>) -> BoxFuture<'static, ()> {
+        async move {
+            // start 中任意 task 结束都会退出，没有 url 时保持 pending
+            if self.urls.is_empty() {
+                return futures_util::future::pending().await;
+            }
+            loop {
+                self.refresh(&fetcher).await;
+                tokio::time::sleep(self.refresh).await;
+            }
+        }
+        .boxed()
+    }
+}
+
+#[test]
+fn test_blocklist_parse_and_check() {
+    let content = "# hosts\n0.0.0.0 ads.example.com tracker.example.com\n127.0.0.1 localhost\nbanner.example.org. # trailing\n\n::1 ip6-localhost\n";
+    let list = parse_list(content);
+    assert_eq!(
+        list,
+        vec!["ads.example.com", "tracker.example.com", "banner.example.org", "ip6-localhost"]
+    );
+    let blocklist = Blocklist::new(&BlocklistConfig::default());
+    blocklist.domains.write().unwrap().extend(list);
+    assert!(blocklist.check("ads.example.com"));
+    assert!(blocklist.check("cdn.ads.example.com."));
+    assert!(!blocklist.check("example.com"));
+    assert_eq!(blocklist.blocked(), 2);
+}
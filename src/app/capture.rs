@@ -0,0 +1,316 @@
+// 抓取单个 host 的完整流量，用于排查应用经过 tunnel 后出现的问题
+// 记录的是 app <=> tunnel 之间的数据，也就是 proxy 协议解密之后、app 自身协议（例如 https）加密之前的内容
+// 只能通过 api 触发，并且一定有截止时间
+// 每次读写都检查截止时间与 stop，之后的数据不再记录并关闭文件，长连接也不会一直写下去
+// 写文件跟不上时丢弃抓取的数据，转发的数据不受影响
+//
+// 每条连接写两个文件
+// <dir>/<unix millis>-<seq>-<host>.up    app => remote
+// <dir>/<unix millis>-<seq>-<host>.down  remote => app
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use log::{debug, warn};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+};
+
+use crate::proxy::{Address, AnyStream, Session};
+
+const DEFAULT_CAPTURE_DIR: &str = "capture";
+// 避免忘记关闭后一直写磁盘
+pub const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(600);
+// 每个方向最多缓存的块数，写文件跟不上时丢弃
+const CAPTURE_BUFFER: usize = 256;
+
+struct Target {
+    host: String,
+    until: Instant,
+    // stop 或者被新的 start 替换
+    stopped: AtomicBool,
+}
+
+impl Target {
+    fn active(&self) -> bool {
+        !self.stopped.load(Ordering::Relaxed) && Instant::now() < self.until
+    }
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    target: Mutex<Option<Arc<Target>>>,
+    seq: AtomicU64,
+}
+
+/// api.capture_dir or the default, created before privileges are dropped
+pub fn capture_dir(dir: Option<&str>) -> PathBuf {
+    PathBuf::from(dir.unwrap_or(DEFAULT_CAPTURE_DIR))
+}
+
+impl Recorder {
+    pub fn new(dir: Option<String>) -> Recorder {
+        Recorder {
+            dir: capture_dir(dir.as_deref()),
+            target: Mutex::new(None),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn start(&self, host: &str, duration: Duration) -> Result<()> {
+        if host.is_empty() {
+            bail!("empty capture host");
+        }
+        if duration > MAX_CAPTURE_DURATION {
+            bail!("capture duration exceeds {:?}", MAX_CAPTURE_DURATION);
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        warn!(
+            "CAPTURE STARTED: full traffic of {} will be written to {} for {:?}",
+            host,
+            self.dir.display(),
+            duration
+        );
+        let previous = self.target.lock().unwrap().replace(Arc::new(Target {
+            host: host.to_string(),
+            until: Instant::now() + duration,
+            stopped: AtomicBool::new(false),
+        }));
+        if let Some(previous) = previous {
+            previous.stopped.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if let Some(target) = self.target.lock().unwrap().take() {
+            target.stopped.store(true, Ordering::Relaxed);
+            warn!("capture of {} stopped", target.host);
+        }
+    }
+
+    /// host and remaining duration of the running capture
+    pub fn status(&self) -> Option<(String, Duration)> {
+        let target = self.target.lock().unwrap();
+        target.as_ref().and_then(|t| {
+            t.until
+                .checked_duration_since(Instant::now())
+                .map(|remaining| (t.host.clone(), remaining))
+        })
+    }
+
+    pub fn matches(&self, destination: &Address) -> bool {
+        self.current(destination).is_some()
+    }
+
+    fn current(&self, destination: &Address) -> Option<Arc<Target>> {
+        let mut target = self.target.lock().unwrap();
+        let expired = match &*target {
+            Some(t) => Instant::now() >= t.until,
+            None => return None,
+        };
+        if expired {
+            if let Some(t) = target.take() {
+                warn!("capture of {} expired", t.host);
+            }
+            return None;
+        }
+        let t = target.as_ref().unwrap();
+        let matched = match destination {
+            Address::Domain(name, _) => *name == t.host || name.ends_with(&format!(".{}", t.host)),
+            Address::Ip(addr) => addr.ip().to_string() == t.host,
+        };
+        matched.then(|| t.clone())
+    }
+
+    /// wrap the inbound stream if the session matches the running capture
+    pub fn wrap(&self, sess: &Session, stream: AnyStream) -> AnyStream {
+        let target = match self.current(&sess.destination) {
+            Some(x) => x,
+            None => return stream,
+        };
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis())
+            .unwrap_or_default();
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let (up, down) = capture_paths(&self.dir, millis, seq, &sess.destination);
+        let (up_tx, up_rx) = mpsc::channel(CAPTURE_BUFFER);
+        let (down_tx, down_rx) = mpsc::channel(CAPTURE_BUFFER);
+        tokio::spawn(write_file(up, up_rx));
+        tokio::spawn(write_file(down, down_rx));
+        debug!("capture {} => {}", sess.peer_address, sess.destination);
+        Box::new(RecordStream {
+            inner: stream,
+            target,
+            up: Sink::new(up_tx),
+            down: Sink::new(down_tx),
+        })
+    }
+}
+
+// host 中的 . 不是扩展名，with_extension 会把 www.example.com_443 替换成 www.example.up
+fn capture_paths(dir: &Path, millis: u128, seq: u64, destination: &Address) -> (PathBuf, PathBuf) {
+    let name: String = destination
+        .to_string()
+        .chars()
+        .map(|c| if c == ':' || c == '/' || c == '[' || c == ']' { '_' } else { c })
+        .collect();
+    let prefix = dir.join(format!("{}-{}-{}", millis, seq, name));
+    (
+        PathBuf::from(format!("{}.up", prefix.display())),
+        PathBuf::from(format!("{}.down", prefix.display())),
+    )
+}
+
+async fn write_file(path: PathBuf, mut rx: mpsc::Receiver<Bytes>) {
+    let mut file = match File::create(&path).await {
+        Ok(f) => f,
+        Err(err) => {
+            warn!("create capture file {} failed {}", path.display(), err);
+            return;
+        }
+    };
+    while let Some(data) = rx.recv().await {
+        if let Err(err) = file.write_all(&data).await {
+            warn!("write capture file {} failed {}", path.display(), err);
+            return;
+        }
+    }
+    let _ = file.flush().await;
+}
+
+// 一个方向的文件，sender drop 之后 write_file 写完剩下的数据并关闭文件
+struct Sink {
+    tx: Option<mpsc::Sender<Bytes>>,
+    dropped: u64,
+}
+
+impl Sink {
+    fn new(tx: mpsc::Sender<Bytes>) -> Sink {
+        Sink { tx: Some(tx), dropped: 0 }
+    }
+
+    fn record(&mut self, target: &Target, data: &[u8]) {
+        if !target.active() {
+            self.close(target);
+            return;
+        }
+        let tx = match &self.tx {
+            Some(x) => x,
+            None => return,
+        };
+        match tx.try_send(Bytes::copy_from_slice(data)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("capture of {} falls behind, data dropped", target.host);
+                }
+                self.dropped += 1;
+            }
+            // 文件写入失败
+            Err(mpsc::error::TrySendError::Closed(_)) => self.tx = None,
+        }
+    }
+
+    fn close(&mut self, target: &Target) {
+        if self.tx.take().is_some() && self.dropped > 0 {
+            warn!("capture of {} dropped {} chunks", target.host, self.dropped);
+        }
+    }
+}
+
+// 读到的是 app 发出的数据，写入的是 remote 返回的数据
+struct RecordStream {
+    inner: AnyStream,
+    target: Arc<Target>,
+    up: Sink,
+    down: Sink,
+}
+
+impl AsyncRead for RecordStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let data = &buf.filled()[before..];
+            if !data.is_empty() {
+                let this = &mut *self;
+                this.up.record(&this.target, data);
+            }
+        }
+        res
+    }
+}
+
+impl AsyncWrite for RecordStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            let this = &mut *self;
+            this.down.record(&this.target, &buf[..n]);
+        }
+        res
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+
+#[test]
+fn test_capture_paths() {
+    let dir = Path::new("/tmp/capture");
+    let (up, down) = capture_paths(dir, 1700000000000, 3, &Address::Domain("www.example.com".to_string(), 443));
+    assert_eq!(up, Path::new("/tmp/capture/1700000000000-3-www.example.com_443.up"));
+    assert_eq!(down, Path::new("/tmp/capture/1700000000000-3-www.example.com_443.down"));
+    let (up, _) = capture_paths(dir, 1700000000000, 4, &"[::1]:8080".parse().unwrap());
+    assert_eq!(up, Path::new("/tmp/capture/1700000000000-4-___1__8080.up"));
+}
+
+#[tokio::test]
+async fn test_capture_stops_on_write() {
+    let (tx, mut rx) = mpsc::channel(1);
+    let target = Target {
+        host: "example.com".to_string(),
+        until: Instant::now() + Duration::from_secs(60),
+        stopped: AtomicBool::new(false),
+    };
+    let mut sink = Sink::new(tx);
+    sink.record(&target, b"a");
+    // 缓存已满，丢弃而不是等待
+    sink.record(&target, b"b");
+    assert_eq!(sink.dropped, 1);
+    assert_eq!(rx.recv().await.unwrap(), Bytes::from_static(b"a"));
+    target.stopped.store(true, Ordering::Relaxed);
+    sink.record(&target, b"c");
+    // sender 已经 drop，文件随之关闭
+    assert!(rx.recv().await.is_none());
+}
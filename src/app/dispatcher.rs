@@ -1,18 +1,40 @@
-use std::{convert::TryFrom, sync::Arc, net::SocketAddr};
+use std::{
+    convert::TryFrom,
+    future::{pending, Future},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
 use tokio::{
     net::{TcpStream, UdpSocket},
     sync::RwLock,
 };
 
 use crate::{
+    common::{
+        buffer::{self, Activity},
+        process::{lookup_process_name, lookup_socket_owner},
+        ratelimit::{Bandwidth, RateLimiter},
+    },
     config::Config,
-    proxy::{Address, Session, StreamWrapperTrait, TcpOutboundHandlerTrait},
+    net::udp::{self, RecvBatch},
+    proxy::{
+        direct, reject::BlockPage, Address, AnyStream, Dialer, Error, OutboundHandler, ResolveStrategy, Session, TcpOutboundHandlerTrait,
+        UdpFlow, UdpOutboundHandlerTrait, UdpOversizePolicy, UotReader, UotWriter,
+    },
     Context,
 };
 
-use super::{sniffer::Sniffer, DnsClient, OutboundManager, Router};
+use super::{
+    sniffer::{QuicSniff, QuicSniffer, Sniffer},
+    trace, CircuitBreaker, CircuitEvent, DnsClient, Limiter, OutboundManager, Recorder, Rewriter, Router, SessionStats, Stats,
+};
 
 // 负责将请求分发给不同的 代理协议 处理
 pub struct Dispatcher {
@@ -20,115 +42,785 @@ pub struct Dispatcher {
     router: Arc<Router>,
     dns_client: Arc<RwLock<DnsClient>>,
     outbound_manager: Arc<OutboundManager>,
+    rewriter: Rewriter,
+    recorder: Arc<Recorder>,
+    stats: Arc<Stats>,
+    // 本地解析失败时改用的 outbound
+    dns_fallback: Option<String>,
+    // dry run 时全部连接都通过这个 direct handler 转发
+    dry_run: Option<Arc<OutboundHandler>>,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    // 并发连接的全局限制
+    limiter: Arc<Limiter>,
+}
+
+// udp_handler 返回的 socket，或者 udp over tcp 的 stream，两个方向在同一个 task 中同时使用
+// socket 是 connect 过的，一次 sendmmsg / recvmmsg 处理一批 datagram
+enum DatagramSender {
+    Socket(Arc<UdpSocket>, SocketAddr),
+    Stream(UotWriter, Address),
+}
+
+impl DatagramSender {
+    async fn send(&mut self, datagrams: &[Vec<u8>]) -> io::Result<()> {
+        match self {
+            DatagramSender::Socket(socket, peer) => {
+                let batch: Vec<(&[u8], SocketAddr)> = datagrams.iter().map(|x| (x.as_slice(), *peer)).collect();
+                udp::send_batch(socket, &batch).await
+            }
+            DatagramSender::Stream(writer, destination) => {
+                for datagram in datagrams {
+                    writer
+                        .send_to(datagram, destination)
+                        .await
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
+
+enum DatagramReceiver {
+    Socket(Arc<UdpSocket>, RecvBatch),
+    Stream(UotReader),
+}
+
+impl DatagramReceiver {
+    // 截断的 datagram 被丢弃，out 可能为空
+    async fn recv(&mut self, out: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        match self {
+            DatagramReceiver::Socket(socket, batch) => {
+                batch.recv(socket).await?;
+                out.extend(batch.datagrams().map(|(datagram, _)| datagram.to_vec()));
+            }
+            DatagramReceiver::Stream(reader) => {
+                let (payload, _) = reader
+                    .recv_from()
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+                out.push(payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+// udp 没有关闭，flow 两个方向都没有 datagram 这么久之后结束
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// 每个 flow 接收 buffer 的总大小，超过 udp_limit 的 datagram 无论如何都会被丢弃，buffer 按 max_payload 分配
+const UDP_BATCH_BYTES: usize = 64 * 1024;
+// 等待 client 发送 http 请求
+const BLOCK_PAGE_WAIT: Duration = Duration::from_secs(1);
 impl Dispatcher {
     pub async fn dispatch_tcp(&self, stream: TcpStream, sess: &mut Session) {
+        // reject 时需要对 inbound socket 设置 SO_LINGER，stream 之后会被 box
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        let raw_fd = std::os::unix::io::AsRawFd::as_raw_fd(&stream);
         // https://github.com/iamwwc/v2ray-core/blob/8cdd680f5ca8d05c618752eb944a42a7b4d31f6c/app/dispatcher/default.go#L207
         // 由于需要提供 domain routing，所以如果 port == 443，首先尝试嗅探 TLS SNI
-        let mut local_stream: Box<dyn StreamWrapperTrait> = if sess.local_peer.port() == 443 {
+        // 其他端口 sniffer 不持有数据，只是透传
+        let mut sniffer = Sniffer::new(stream);
+        if sess.port() == 443 {
             // TLS，嗅探 SNI
-            let mut sniffer = Sniffer::new(stream);
             match sniffer.sniff().await {
                 Ok(s) => {
                     match s {
                         Some(name) => {
+                            trace::event(format_args!("sniffed server name {}", name));
                             sess.destination = match Address::try_from((name, sess.port())) {
                                 Ok(x) => x,
                                 Err(err) => {
-                                    debug!("try from failed {}", err);
+                                    debug!("sid={} try from failed {}", sess.id, err);
                                     return;
                                 }
                             };
                         }
                         None => {}
                     }
-                    Box::new(sniffer)
                 }
-                Err(_err) => return,
+                Err(err) => {
+                    trace::event(format_args!("sniff failed {}", err));
+                    return;
+                }
             }
-        } else {
-            Box::new(stream)
+            if !sniffer.sniffed().is_empty() {
+                sess.app_protocol = Some(super::stats::detect(sniffer.sniffed(), sess.port(), &sess.network));
+            }
+        }
+        let (outbound_handler, bandwidth) = match self.select_outbound(sess).await {
+            Some(x) => x,
+            None => return,
         };
+        #[cfg(all(target_os = "linux", feature = "splice"))]
+        let sniffer = match self.try_splice(&outbound_handler, &bandwidth, sniffer, sess).await {
+            Some(x) => x,
+            None => return,
+        };
+        let on_reject = move || {
+            // local_stream drop 时发送 RST
+            #[cfg(any(target_os = "macos", target_os = "linux"))]
+            crate::proxy::reject::reset_on_close(raw_fd);
+        };
+        self.relay(outbound_handler, bandwidth, Box::new(sniffer), sess, on_reject).await;
+    }
+
+    /// route and relay an inbound stream, on_reject is called before the stream is dropped for reject outbounds
+    pub async fn dispatch_stream<F>(&self, local_stream: AnyStream, sess: &mut Session, on_reject: F)
+    where
+        F: FnOnce() + Send,
+    {
+        if let Some((outbound_handler, bandwidth)) = self.select_outbound(sess).await {
+            self.relay(outbound_handler, bandwidth, local_stream, sess, on_reject).await;
+        }
+    }
+
+    /// route a udp flow by its first client datagram
+    /// quic to port 443 is routed by server_name, sniffed from its Initial packets like tls over tcp
+    pub async fn route_datagram(
+        &self,
+        datagram: &[u8],
+        server_name: Option<String>,
+        sess: &mut Session,
+    ) -> Option<(Arc<OutboundHandler>, Bandwidth)> {
+        if let Some(name) = server_name {
+            trace::event(format_args!("sniffed quic server name {}", name));
+            match Address::try_from((name, sess.port())) {
+                Ok(x) => sess.destination = x,
+                Err(err) => debug!("sid={} try from failed {}", sess.id, err),
+            }
+        }
+        if sess.port() == 443 {
+            sess.app_protocol = Some(super::stats::detect(datagram, sess.port(), &sess.network));
+        }
+        let (handler, bandwidth) = self.select_outbound(sess).await?;
+        if !handler.supports_udp() {
+            warn!("sid={} udp to {} dropped, outbound {} has no udp support, try udp_over_tcp", sess.id, sess.destination, handler.tag);
+            trace::event(format_args!("dropped, outbound {} has no udp support", handler.tag));
+            return None;
+        }
+        Some((handler, bandwidth))
+    }
+
+    /// relay a udp flow, its first client datagrams decide the route
+    pub async fn dispatch_udp(&self, mut flow: UdpFlow, mut sess: Session) {
+        // ClientHello 可能跨多个 Initial，同一个 sniffer 看完整之后再路由，之前的 datagram 先缓存
+        let mut sniffer = QuicSniffer::default();
+        let mut first = Vec::new();
+        let server_name = loop {
+            let datagram = match flow.rx.recv().await {
+                Some(x) => x,
+                None => return,
+            };
+            let sniff = if sess.port() == 443 { sniffer.push(&datagram) } else { QuicSniff::Done(None) };
+            first.push(datagram);
+            if let QuicSniff::Done(name) = sniff {
+                break name;
+            }
+        };
+        let (handler, bandwidth) = match self.route_datagram(&first[0], server_name, &mut sess).await {
+            Some(x) => x,
+            None => return,
+        };
+        let opened = match (&handler.udp_handler, &handler.udp_over_tcp) {
+            (Some(udp), _) => match UdpOutboundHandlerTrait::handle(udp.as_ref(), self.ctx.clone(), &sess).await {
+                Ok(socket) => socket.peer_addr().map_err(anyhow::Error::from).map(|peer| {
+                    let socket = Arc::new(socket);
+                    let size = handler.udp_limit.max_payload;
+                    let batch = RecvBatch::new((UDP_BATCH_BYTES / size.max(1)).clamp(1, udp::BATCH), size);
+                    (DatagramSender::Socket(socket.clone(), peer), DatagramReceiver::Socket(socket, batch))
+                }),
+                Err(err) => Err(err),
+            },
+            // 没有 udp handler 的 outbound 经由 tcp_handler 转发
+            (None, Some(uot)) => uot.connect(self.ctx.clone(), &sess).await.map(|stream| {
+                let (reader, writer) = stream.split();
+                (DatagramSender::Stream(writer, sess.destination.clone()), DatagramReceiver::Stream(reader))
+            }),
+            (None, None) => return,
+        };
+        let (sender, receiver) = match opened {
+            Ok(x) => x,
+            Err(err) => {
+                trace::event(format_args!("connect via {} failed {}", handler.tag, err));
+                debug!("sid={} udp to {} via {} failed {}", sess.id, sess.destination, handler.tag, err);
+                return;
+            }
+        };
+        trace::event(format_args!("connected via {}", handler.tag));
+        trace!("sid={} udp {} => {} via {}", sess.id, sess.peer_address, sess.destination, handler.tag);
+        let started = Instant::now();
+        self.ctx.events.session_start(&sess, &handler.tag);
+        let mut stats = SessionStats::default();
+        let relay = self.relay_datagrams(&handler, &bandwidth, first, flow, sender, receiver);
+        match self.with_timeouts(None, relay).await {
+            Ok((up, down)) => {
+                stats.up = up;
+                stats.down = down;
+            }
+            Err(err) => {
+                debug!("sid={} error when relaying udp {}, destination: {}", sess.id, err, sess.destination);
+                stats.error = Some(err.to_string());
+            }
+        }
+        stats.duration = started.elapsed();
+        trace::event(format_args!("closed, up {} down {} error {:?}", stats.up, stats.down, stats.error));
+        self.ctx.events.session_end(&sess, &handler.tag, &stats);
+    }
+
+    // inbound 结束 flow、socket 出错或者两个方向都空闲 UDP_IDLE_TIMEOUT 时结束
+    async fn relay_datagrams(
+        &self,
+        handler: &OutboundHandler,
+        bandwidth: &Bandwidth,
+        first: Vec<Vec<u8>>,
+        flow: UdpFlow,
+        mut sender: DatagramSender,
+        mut receiver: DatagramReceiver,
+    ) -> io::Result<(u64, u64)> {
+        let UdpFlow { mut rx, tx, too_big } = flow;
+        let limits = [bandwidth, &handler.bandwidth];
+        let up: Vec<&RateLimiter> = limits.iter().filter_map(|x| x.up.as_deref()).collect();
+        let down: Vec<&RateLimiter> = limits.iter().filter_map(|x| x.down.as_deref()).collect();
+        let activity = Activity::new();
+        let (up_bytes, down_bytes) = (AtomicU64::new(0), AtomicU64::new(0));
+        let uplink = async {
+            let mut pending = first;
+            let mut batch = Vec::with_capacity(udp::BATCH);
+            loop {
+                if pending.is_empty() {
+                    match rx.recv().await {
+                        Some(x) => pending.push(x),
+                        None => return Ok::<_, io::Error>(()),
+                    }
+                    // 已经排队的 datagram 一起发送
+                    while pending.len() < udp::BATCH {
+                        match rx.try_recv() {
+                            Ok(x) => pending.push(x),
+                            Err(_) => break,
+                        }
+                    }
+                }
+                batch.clear();
+                for datagram in pending.drain(..) {
+                    if !handler.udp_limit.admit(datagram.len()) {
+                        if let (UdpOversizePolicy::IcmpTooBig, Some(too_big)) = (handler.udp_limit.policy, &too_big) {
+                            let _ = too_big.send(handler.udp_limit.max_payload);
+                        }
+                        continue;
+                    }
+                    for limiter in &up {
+                        limiter.acquire(datagram.len()).await;
+                    }
+                    batch.push(datagram);
+                }
+                if batch.is_empty() {
+                    continue;
+                }
+                sender.send(&batch).await?;
+                activity.touch();
+                let n: usize = batch.iter().map(|x| x.len()).sum();
+                up_bytes.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        };
+        let downlink = async {
+            let mut datagrams = Vec::new();
+            loop {
+                receiver.recv(&mut datagrams).await?;
+                for datagram in datagrams.drain(..) {
+                    let n = datagram.len();
+                    if !handler.udp_limit.admit(n) {
+                        continue;
+                    }
+                    for limiter in &down {
+                        limiter.acquire(n).await;
+                    }
+                    if tx.send(datagram).await.is_err() {
+                        return Ok::<_, io::Error>(());
+                    }
+                    activity.touch();
+                    down_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                }
+            }
+        };
+        let timeout = self.idle_timeout.unwrap_or(UDP_IDLE_TIMEOUT);
+        let idle = async {
+            loop {
+                let idle = activity.idle();
+                if idle >= timeout {
+                    break;
+                }
+                tokio::time::sleep(timeout - idle).await;
+            }
+        };
+        let result: io::Result<()> = tokio::select! {
+            res = uplink => res,
+            res = downlink => res,
+            _ = idle => Ok(()),
+        };
+        result.map(|_| (up_bytes.load(Ordering::Relaxed), down_bytes.load(Ordering::Relaxed)))
+    }
+
+    // outbound 以及匹配到的 rule 的限速
+    async fn select_outbound(&self, sess: &mut Session) -> Option<(Arc<OutboundHandler>, Bandwidth)> {
+        // NAT loopback, public ip => internal ip
+        if let Some(destination) = self.rewriter.rewrite(&sess.destination) {
+            debug!("sid={} rewrite destination {} => {}", sess.id, sess.destination, destination);
+            trace::event(format_args!("rewrite destination {} => {}", sess.destination, destination));
+            sess.destination = destination;
+        }
+        if self.router.needs_process() && sess.process.is_none() {
+            sess.process = lookup_process_name(sess.network.clone(), sess.peer_address, sess.local_peer).await;
+        }
+        // socks 等 inbound 捕获的连接，对端是 local_peer
+        // tun/tproxy 捕获的连接，对端是真正的 destination
+        if self.router.needs_uid() && sess.uid.is_none() {
+            let mut remotes = vec![sess.local_peer];
+            if let Address::Ip(destination) = sess.destination {
+                remotes.push(destination);
+            }
+            sess.uid = lookup_socket_owner(sess.network.clone(), sess.peer_address, remotes)
+                .await
+                .map(|x| x.uid);
+        }
         // starting routing match
-        let outbound_handler = match self.router.route(&sess) {
-            Some(tag) => match self.outbound_manager.get_handler(&*tag) {
-                Some(h) => h,
-                None => {
-                    error!("no outbound tag found {}", tag);
-                    return;
+        let (outbound_handler, bandwidth) = match self.router.route_with_rule(&sess) {
+            Some((tag, bandwidth, rule)) => {
+                self.ctx.events.rule_match(sess, rule, &tag);
+                trace::event(format_args!("{} => {} matched {}, outbound {}", sess.peer_address, sess.destination, rule, tag));
+                match self.outbound_manager.get_handler(&*tag) {
+                    Some(h) => (h, bandwidth),
+                    None => {
+                        error!("sid={} no outbound tag found {}", sess.id, tag);
+                        return None;
+                    }
                 }
-            },
+            }
             None => {
-                error!("no outbound session {:?} found!", &sess);
-                return;
+                trace::event(format_args!("{} => {} matched no rule", sess.peer_address, sess.destination));
+                error!("sid={} no outbound session {:?} found!", sess.id, &sess);
+                return None;
+            }
+        };
+        match &self.dry_run {
+            Some(direct) => {
+                self.log_dry_run(sess, &outbound_handler.tag).await;
+                Some((direct.clone(), bandwidth))
+            }
+            None => Some((outbound_handler, bandwidth)),
+        }
+    }
+
+    // direct outbound 并且没有抓包时，在内核中用 splice 转发
+    // 返回 None 表示已经处理完毕，否则交回 sniffer 走普通转发
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    async fn try_splice(
+        &self,
+        outbound_handler: &Arc<OutboundHandler>,
+        bandwidth: &Bandwidth,
+        sniffer: Sniffer<TcpStream>,
+        sess: &Session,
+    ) -> Option<Sniffer<TcpStream>> {
+        use tokio::io::AsyncWriteExt;
+
+        let dialer = match outbound_handler.tcp_handler.as_ref().and_then(|x| x.dialer()) {
+            Some(d) => d,
+            None => return Some(sniffer),
+        };
+        // 限速需要在用户态拷贝
+        if self.recorder.matches(&sess.destination)
+            || !bandwidth.is_unlimited()
+            || !outbound_handler.bandwidth.is_unlimited()
+        {
+            return Some(sniffer);
+        }
+        let mut remote = match dialer.connect_tcp(self.ctx.dns_client.clone(), sess.destination.clone()).await {
+            Ok(x) => x,
+            // 交给普通路径处理 dns fail policy
+            Err(err) if matches!(err.downcast_ref::<Error>(), Some(Error::ResolveFailed(..))) => return Some(sniffer),
+            Err(err) => {
+                trace::event(format_args!("connect via {} failed {}", outbound_handler.tag, err));
+                debug!("sid={} Error {}, destination: {}", sess.id, err, sess.destination);
+                return None;
+            }
+        };
+        // 只用 sniff 时已经读到的数据统计协议，没有读到时不再等待
+        let (local, sniffed) = sniffer.into_inner();
+        if !sniffed.is_empty() {
+            if let Err(err) = remote.write_all(&sniffed).await {
+                debug!("sid={} write sniffed data to {} failed {}", sess.id, sess.destination, err);
+                return None;
+            }
+        }
+        trace::event(format_args!("connected via {} (splice)", outbound_handler.tag));
+        trace!(
+            "sid={} connection established. {} => {} => tunnel => {} (splice). Final destination: {}",
+            sess.id,
+            sess.peer_address,
+            sess.local_peer,
+            outbound_handler.tag,
+            sess.destination
+        );
+        let protocol = super::stats::detect(&sniffed, sess.port(), &sess.network);
+        let started = Instant::now();
+        self.ctx.events.session_start(sess, &outbound_handler.tag);
+        // splice 不经过用户态，无法统计 idle，只限制 max lifetime
+        let mut stats = SessionStats::default();
+        match self.with_timeouts(None, crate::net::splice::relay(&local, &remote)).await {
+            Ok((up, down)) => {
+                stats.up = up + sniffed.len() as u64;
+                stats.down = down;
+                self.stats.record(&outbound_handler.tag, protocol, stats.up, down)
+            }
+            Err(err) => {
+                debug!("sid={} error when in splice {}", sess.id, err);
+                stats.error = Some(err.to_string());
+            }
+        }
+        stats.duration = started.elapsed();
+        trace::event(format_args!("closed, up {} down {} error {:?}", stats.up, stats.down, stats.error));
+        self.ctx.events.session_end(sess, &outbound_handler.tag, &stats);
+        None
+    }
+
+    async fn relay<F>(
+        &self,
+        outbound_handler: Arc<OutboundHandler>,
+        bandwidth: Bandwidth,
+        local_stream: AnyStream,
+        sess: &mut Session,
+        on_reject: F,
+    )
+    where
+        F: FnOnce() + Send,
+    {
+        // 熔断中的 outbound 改用 fallback，fallback 的结果不计入原 outbound 的健康状态
+        let mut circuit = self.outbound_manager.get_circuit(&outbound_handler.tag);
+        let outbound_handler = match circuit.take() {
+            Some(c) if !c.allow() => {
+                self.stats.record_circuit(&outbound_handler.tag, "fallback");
+                match c.fallback.as_ref().and_then(|x| self.outbound_manager.get_handler(x)) {
+                    Some(fallback) => {
+                        info!(
+                            "sid={} circuit of {} open, {} => {} via {}",
+                            sess.id, outbound_handler.tag, sess.peer_address, sess.destination, fallback.tag
+                        );
+                        trace::event(format_args!("circuit of {} open, fallback to {}", outbound_handler.tag, fallback.tag));
+                        fallback
+                    }
+                    None => {
+                        info!(
+                            "sid={} circuit of {} open, {} => {} dropped",
+                            sess.id, outbound_handler.tag, sess.peer_address, sess.destination
+                        );
+                        trace::event(format_args!("circuit of {} open, dropped", outbound_handler.tag));
+                        return;
+                    }
+                }
+            }
+            c => {
+                circuit = c;
+                outbound_handler
             }
         };
         // connect to remote proxy server
         let tcp = if let Some(tcp) = &outbound_handler.tcp_handler {
             tcp
         } else {
-            error!("tag {} not have tcp handler !", outbound_handler.tag);
+            error!("sid={} tag {} not have tcp handler !", sess.id, outbound_handler.tag);
             return;
         };
-        let mut remote_stream =
-            match TcpOutboundHandlerTrait::handle(tcp.as_ref(), self.ctx.clone(), sess).await {
-                Ok(res) => res,
+        let res = match outbound_handler.resolve {
+            ResolveStrategy::Local => match self.resolve_locally(sess).await {
+                Ok(resolved) => TcpOutboundHandlerTrait::handle(tcp.as_ref(), self.ctx.clone(), &resolved).await,
+                Err(err) => Err(err),
+            },
+            _ => TcpOutboundHandlerTrait::handle(tcp.as_ref(), self.ctx.clone(), sess).await,
+        };
+        let res = match res {
+            Err(err) if outbound_handler.resolve == ResolveStrategy::PreferRemote
+                && matches!(sess.destination, Address::Domain(..))
+                && !matches!(err.downcast_ref::<Error>(), Some(Error::Rejected(..))) =>
+            {
+                debug!("sid={} {} via {} failed {}, retry with local resolution", sess.id, sess.destination, outbound_handler.tag, err);
+                trace::event(format_args!("connect via {} failed {}, retry with local resolution", outbound_handler.tag, err));
+                match self.resolve_locally(sess).await {
+                    Ok(resolved) => TcpOutboundHandlerTrait::handle(tcp.as_ref(), self.ctx.clone(), &resolved).await,
+                    Err(_) => Err(err),
+                }
+            }
+            res => res,
+        };
+        if let Some(circuit) = &circuit {
+            self.update_circuit(circuit, &outbound_handler.tag, &res);
+        }
+        let (outbound_handler, mut remote_stream) =
+            match res {
+                Ok(res) => (outbound_handler, res),
                 Err(err) => {
-                    debug!(
-                        "Error {}, destination: {}. connection {} => {} => tunnel",
-                        err,
-                        sess.destination,
-                        sess.peer_address,
-                        sess.local_peer,
-                    );
-                    return;
+                    trace::event(format_args!("connect via {} failed {}", outbound_handler.tag, err));
+                    if let Some(Error::Rejected(..)) = err.downcast_ref::<Error>() {
+                        if let Some(page) = &outbound_handler.block_page {
+                            let mut local_stream = local_stream;
+                            if self.send_block_page(page, &mut local_stream, sess).await {
+                                return;
+                            }
+                        }
+                        trace!("sid={} {}, reset {}", sess.id, err, sess.peer_address);
+                        on_reject();
+                        return;
+                    }
+                    if let Some(Error::ResolveFailed(..)) = err.downcast_ref::<Error>() {
+                        if let Some(x) = self.resolve_remotely(sess, &outbound_handler.tag).await {
+                            x
+                        } else {
+                            debug!("sid={} {}, destination: {}", sess.id, err, sess.destination);
+                            return;
+                        }
+                    } else {
+                        debug!(
+                            "sid={} Error {}, destination: {}. connection {} => {} => tunnel",
+                            sess.id,
+                            err,
+                            sess.destination,
+                            sess.peer_address,
+                            sess.local_peer,
+                        );
+                        return;
+                    }
                 }
             };
+        let local_stream = self.stats.wrap(&outbound_handler.tag, sess, local_stream);
+        let mut local_stream = self.recorder.wrap(sess, local_stream);
         // start pipe
-        let local_addr = match remote_stream.local_addr() {
-            Ok(res) => res.to_string(),
-            Err(err) => {
-                debug!("{}", err);
-                "unknown".to_string()
-            }
-        };
-        let peer_addr = match remote_stream.peer_addr() {
-            Ok(res) => res.to_string(),
-            Err(err) => {
-                debug!("{}", err);
-                "unknown".to_string()
-            }
-        };
+        trace::event(format_args!("connected via {}", outbound_handler.tag));
         trace!(
-            "connection established. {} => {} => tunnel => {} => {}. Final destination: {}",
+            "sid={} connection established. {} => {} => tunnel => {}. Final destination: {}",
+            sess.id,
             sess.peer_address,
             sess.local_peer,
-            local_addr,
-            peer_addr,
+            outbound_handler.tag,
             sess.destination
         );
-        match tokio::io::copy_bidirectional(&mut local_stream, &mut remote_stream).await {
+        // rule 与最终使用的 outbound 的限速同时生效
+        let limits = [&bandwidth, &outbound_handler.bandwidth];
+        let up: Vec<&RateLimiter> = limits.iter().filter_map(|x| x.up.as_deref()).collect();
+        let down: Vec<&RateLimiter> = limits.iter().filter_map(|x| x.down.as_deref()).collect();
+        let activity = Activity::new();
+        let started = Instant::now();
+        self.ctx.events.session_start(sess, &outbound_handler.tag);
+        let copy = buffer::copy_bidirectional_limited(&mut local_stream, &mut remote_stream, &up, &down, &activity);
+        let mut stats = SessionStats::default();
+        match self.with_timeouts(Some(&activity), copy).await {
             Err(err) => {
-                debug!("error when in copy bidirectional {}", err);
+                debug!("sid={} error when in copy bidirectional {}, destination: {}", sess.id, err, sess.destination);
+                stats.error = Some(err.to_string());
+            }
+            Ok((up, down)) => {
+                stats.up = up;
+                stats.down = down;
             }
-            _ => {}
         };
+        stats.duration = started.elapsed();
+        trace::event(format_args!("closed, up {} down {} error {:?}", stats.up, stats.down, stats.error));
+        self.ctx.events.session_end(sess, &outbound_handler.tag, &stats);
     }
 
-    pub async fn dispatch_udp(&self, _socket: UdpSocket, _sess: Session) {}
+    // idle timeout 或 max lifetime 到期时取消 relay，两端的 stream 随之 drop 关闭
+    // 一个方向 EOF 之后另一个方向继续转发，半关闭的连接同样受 idle timeout 约束
+    async fn with_timeouts<F>(&self, activity: Option<&Activity>, relay: F) -> io::Result<(u64, u64)>
+    where
+        F: Future<Output = io::Result<(u64, u64)>>,
+    {
+        let lifetime = async {
+            match self.max_lifetime {
+                Some(d) => tokio::time::sleep(d).await,
+                None => pending().await,
+            }
+        };
+        let idle = async {
+            match (self.idle_timeout, activity) {
+                (Some(timeout), Some(activity)) => loop {
+                    let idle = activity.idle();
+                    if idle >= timeout {
+                        break;
+                    }
+                    tokio::time::sleep(timeout - idle).await;
+                },
+                _ => pending().await,
+            }
+        };
+        tokio::select! {
+            res = relay => res,
+            _ = lifetime => Err(io::Error::new(io::ErrorKind::TimedOut, "max lifetime reached")),
+            _ = idle => Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout")),
+        }
+    }
+
+    // reject 与本地 dns 解析失败不代表 outbound 不可用
+    fn update_circuit<T>(&self, circuit: &CircuitBreaker, tag: &str, res: &anyhow::Result<T>) {
+        let event = match res {
+            Ok(_) => circuit.success(),
+            Err(err) => match err.downcast_ref::<Error>() {
+                Some(Error::Rejected(..)) | Some(Error::ResolveFailed(..)) => None,
+                _ => circuit.failure(),
+            },
+        };
+        match event {
+            Some(CircuitEvent::Opened) => {
+                warn!("outbound {} circuit opened after consecutive dial failures", tag);
+                self.stats.record_circuit(tag, "opened");
+            }
+            Some(CircuitEvent::Closed) => {
+                info!("outbound {} recovered, circuit closed", tag);
+                self.stats.record_circuit(tag, "closed");
+            }
+            None => {}
+        }
+    }
+
+    // outbound resolve local: 域名在这里解析，交给 outbound 的 session 目的地址是 ip
+    // 原来的 session 保留域名，日志与 events 里仍然是域名
+    async fn resolve_locally(&self, sess: &Session) -> anyhow::Result<Session> {
+        let (host, port) = match &sess.destination {
+            Address::Domain(host, port) => (host, *port),
+            Address::Ip(_) => return Ok(sess.clone()),
+        };
+        let ips = match self.dns_client.read().await.lookup(host).await {
+            Ok(x) => x,
+            // blocklist 拦截的域名不算解析失败
+            Err(err) if err.downcast_ref::<Error>().is_some() => return Err(err),
+            Err(err) => return Err(Error::ResolveFailed(format!("{} {}", host, err)).into()),
+        };
+        let ip = match ips.first() {
+            Some(x) => *x,
+            None => return Err(Error::ResolveFailed(format!("{} no ip found", host)).into()),
+        };
+        trace::event(format_args!("resolved {} locally => {}", host, ip));
+        let mut resolved = sess.clone();
+        resolved.destination = Address::Ip(SocketAddr::new(ip, port));
+        Ok(resolved)
+    }
+
+    // dns fail_policy proxy: 本地解析失败时把域名交给 fallback outbound，由远端解析
+    async fn resolve_remotely(&self, sess: &Session, failed_tag: &str) -> Option<(Arc<OutboundHandler>, AnyStream)> {
+        let tag = self.dns_fallback.as_ref()?;
+        if tag == failed_tag {
+            return None;
+        }
+        let handler = self.outbound_manager.get_handler(tag)?;
+        let tcp = handler.tcp_handler.as_ref()?;
+        debug!("sid={} resolve {} failed locally, fallback to {}", sess.id, sess.destination, tag);
+        trace::event(format_args!("resolve failed locally, fallback to {}", tag));
+        match TcpOutboundHandlerTrait::handle(tcp.as_ref(), self.ctx.clone(), sess).await {
+            Ok(stream) => Some((handler.clone(), stream)),
+            Err(err) => {
+                debug!("sid={} fallback {} to {} failed {}", sess.id, sess.destination, tag, err);
+                trace::event(format_args!("fallback to {} failed {}", tag, err));
+                None
+            }
+        }
+    }
+
+    // 明文 http 的连接回复 block page，返回 false 时由调用方 RST
+    // tls 等其他协议，或者 client 没有及时发送请求时不回复
+    async fn send_block_page(&self, page: &BlockPage, local_stream: &mut AnyStream, sess: &Session) -> bool {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut buf = buffer::get(buffer::SMALL);
+        let n = match tokio::time::timeout(BLOCK_PAGE_WAIT, local_stream.read(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            _ => return false,
+        };
+        if super::stats::detect(&buf[..n], sess.port(), &sess.network) != "http" {
+            return false;
+        }
+        let response = page.response(&sess.destination.host());
+        if let Err(err) = local_stream.write_all(&response).await {
+            debug!("sid={} send block page to {} failed {}", sess.id, sess.peer_address, err);
+            return false;
+        }
+        let _ = local_stream.shutdown().await;
+        trace!("sid={} block page sent to {}, destination {}", sess.id, sess.peer_address, sess.destination);
+        trace::event("block page sent");
+        true
+    }
+
+    // 记录本应使用的 outbound 与 dns 结果，方便在生产网关上先验证规则再启用
+    async fn log_dry_run(&self, sess: &Session, tag: &str) {
+        let resolved = match &sess.destination {
+            Address::Domain(host, _) => match self.dns_client.read().await.lookup(host).await {
+                Ok(ips) => format!("{:?}", ips),
+                Err(err) => format!("dns error {}", err),
+            },
+            Address::Ip(addr) => addr.ip().to_string(),
+        };
+        info!(
+            "sid={} dry run: {} => {} ({}) would use outbound {}, sent direct",
+            sess.id,
+            sess.peer_address, sess.destination, resolved, tag
+        );
+    }
+
+    pub fn dns_client(&self) -> Arc<RwLock<DnsClient>> {
+        self.dns_client.clone()
+    }
+
+    pub fn recorder(&self) -> Arc<Recorder> {
+        self.recorder.clone()
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    pub fn limiter(&self) -> Arc<Limiter> {
+        self.limiter.clone()
+    }
+
+    /// share the connection limit with another dispatcher, e.g. of the default profile
+    pub fn with_limiter(mut self, limiter: Arc<Limiter>) -> Dispatcher {
+        self.limiter = limiter;
+        self
+    }
 
     pub fn new(
         context: Arc<Context>,
         router: Arc<Router>,
         dns_client: Arc<RwLock<DnsClient>>,
         outbound_manager: Arc<OutboundManager>,
-        _config: Config,
+        config: Config,
     ) -> Dispatcher {
         Dispatcher {
             ctx: context,
             dns_client,
             outbound_manager: outbound_manager,
             router,
+            rewriter: Rewriter::new(&config.rewrite),
+            recorder: Arc::new(Recorder::new(
+                config.api.as_ref().and_then(|x| x.capture_dir.clone()),
+            )),
+            stats: Arc::new(Stats::new()),
+            dns_fallback: config
+                .dns
+                .as_ref()
+                .filter(|x| x.fail_policy.as_deref() == Some("proxy"))
+                .and_then(|x| x.fallback_outbound.clone()),
+            idle_timeout: match config.general.idle_timeout {
+                None | Some(0) => None,
+                Some(x) => Some(Duration::from_secs(x)),
+            },
+            max_lifetime: config.general.max_lifetime.map(Duration::from_secs),
+            limiter: Arc::new(Limiter::global(&config.general)),
+            dry_run: if config.general.dry_run {
+                let dialer = Arc::new(Dialer::default());
+                let tcp = Arc::new(direct::TcpOutboundHandler { dialer: dialer.clone() });
+                let udp = Arc::new(direct::UdpOutboundHandler { dialer });
+                Some(Arc::new(OutboundHandler::new("dry-run".to_string(), Some(tcp), Some(udp))))
+            } else {
+                None
+            },
         }
     }
 }
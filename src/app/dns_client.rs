@@ -3,11 +3,13 @@ use futures_util::{
     future::{self, BoxFuture},
     FutureExt,
 };
-use log::trace;
+use log::{debug, trace};
 use rand::{Rng, SeedableRng};
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, SocketAddr, SocketAddrV4},
     str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
     vec,
 };
 
@@ -18,10 +20,13 @@ use trust_dns_proto::{
 };
 
 use crate::{
-    config::{Config, GeneralSettings},
-    proxy::create_bounded_udp_socket,
+    common::{buffer, family::Family, monitor::RouteMonitor, network::NetworkState},
+    config::Config,
+    proxy::Dialer,
 };
 
+use super::{trace, Blocklist, DomainSet, Events, QuicUpstream};
+
 macro_rules! random_get {
     ($v:expr) => {{
         use rand::random;
@@ -30,10 +35,120 @@ macro_rules! random_get {
         $v.get(idx).expect("never reached!")
     }};
 }
+const DEFAULT_NETWORK_CHECK_INTERVAL: u64 = 10;
+const DNS_UDP_TIMEOUT: u64 = 5;
+
+// 发往 udp server 的查询，id 换成随机值，可选随机化 qname 的大小写 (0x20)
+// 响应必须带回相同的 id 与逐字节相同的 question，之后还原为客户端的 id 与大小写
+struct Outstanding {
+    request: Vec<u8>,
+    id: [u8; 2],
+    // question 在报文中的位置，响应中位置相同
+    question: std::ops::Range<usize>,
+    original: Vec<u8>,
+}
+
+impl Outstanding {
+    fn new(request: &[u8], randomize_case: bool) -> Result<Outstanding> {
+        if request.len() < 12 {
+            return Err(anyhow!("dns request too short"));
+        }
+        let mut query = request.to_vec();
+        let id = [request[0], request[1]];
+        query[..2].copy_from_slice(&rand::random::<u16>().to_be_bytes());
+        // 只有一个 question 时才检查与随机化
+        let qdcount = u16::from_be_bytes([request[2], request[3]]);
+        let question = match qdcount {
+            1 => Outstanding::question(request).ok_or_else(|| anyhow!("malformed dns question"))?,
+            _ => 12..12,
+        };
+        if randomize_case {
+            let mut pos = question.start;
+            while pos < question.end && query[pos] != 0 {
+                let len = query[pos] as usize;
+                for b in &mut query[pos + 1..pos + 1 + len] {
+                    if b.is_ascii_alphabetic() && rand::random::<bool>() {
+                        *b ^= 0x20;
+                    }
+                }
+                pos += len + 1;
+            }
+        }
+        Ok(Outstanding {
+            original: request[question.clone()].to_vec(),
+            request: query,
+            id,
+            question,
+        })
+    }
+
+    // qname 由 label 组成，查询中不会有压缩指针，之后是 qtype 与 qclass
+    fn question(data: &[u8]) -> Option<std::ops::Range<usize>> {
+        let mut pos = 12;
+        loop {
+            let len = *data.get(pos)? as usize;
+            if len == 0 {
+                break;
+            }
+            if len > 63 {
+                return None;
+            }
+            pos += len + 1;
+        }
+        let end = pos + 1 + 4;
+        if end > data.len() {
+            return None;
+        }
+        Some(12..end)
+    }
+
+    fn accept(&self, response: &[u8]) -> Option<Vec<u8>> {
+        // id，QR，question 数量与 question
+        if response.len() < self.question.end.max(12)
+            || response[..2] != self.request[..2]
+            || response[2] & 0x80 == 0
+            || response[4..6] != self.request[4..6]
+            || response[self.question.clone()] != self.request[self.question.clone()]
+        {
+            return None;
+        }
+        let mut response = response.to_vec();
+        response[..2].copy_from_slice(&self.id);
+        response[self.question.clone()].copy_from_slice(&self.original);
+        Some(response)
+    }
+}
+
+// split dns policy
+struct Policy {
+    domains: DomainSet,
+    // domains 中有 default，匹配全部域名
+    all: bool,
+    server: SocketAddr,
+    // 只在对应网络下生效，例如公司 Wi-Fi 才使用公司的 resolver
+    interface: Option<String>,
+    ssid: Option<String>,
+}
+
+impl Policy {
+    fn conditional(&self) -> bool {
+        self.interface.is_some() || self.ssid.is_some()
+    }
+}
+
 pub struct DnsClient {
     /// should be ipv4 addr
     pub remote_dns_servers: Vec<SocketAddr>,
     pub config: Config,
+    // split dns policies, first matched wins
+    policies: Vec<Policy>,
+    // 当前网络，由 network_watcher 定期刷新
+    network: Arc<RwLock<NetworkState>>,
+    blocklist: Option<Arc<Blocklist>>,
+    // doq / doh3，按配置顺序尝试
+    upstreams: Vec<QuicUpstream>,
+    events: Events,
+    family: Family,
 }
 
 impl DnsClient {
@@ -55,10 +170,156 @@ impl DnsClient {
                 servers.extend_from_slice(&ss)
             }
         }
+        let policies = DnsClient::load_policies(&config, &mut servers);
+        let network = if policies.iter().any(|p| p.conditional()) {
+            NetworkState::detect()
+        } else {
+            NetworkState::default()
+        };
+        let blocklist = config
+            .dns
+            .as_ref()
+            .and_then(|x| x.blocklist.as_ref())
+            .map(|x| Arc::new(Blocklist::new(x)));
+        let upstreams = config
+            .dns
+            .as_ref()
+            .and_then(|x| x.upstreams.as_ref())
+            .map(|x| QuicUpstream::load(x))
+            .unwrap_or_default();
+        let family = Family::new(&config.general);
 
         DnsClient {
             remote_dns_servers: servers,
             config: config,
+            policies,
+            network: Arc::new(RwLock::new(network)),
+            blocklist,
+            upstreams,
+            events: Events::default(),
+            family,
+        }
+    }
+
+    /// address family preference and NAT64 of general settings
+    pub fn family(&self) -> &Family {
+        &self.family
+    }
+
+    /// report lookups to the listeners of an instance
+    pub fn with_events(mut self, events: Events) -> DnsClient {
+        self.events = events;
+        self
+    }
+
+    fn load_policies(config: &Config, servers: &mut Vec<SocketAddr>) -> Vec<Policy> {
+        let mut policies = Vec::new();
+        let list = match config.dns.as_ref().and_then(|x| x.policy.as_ref()) {
+            Some(l) => l,
+            None => return policies,
+        };
+        for policy in list {
+            let server = match policy.server.parse::<SocketAddr>() {
+                Ok(x) => x,
+                Err(err) => {
+                    log::warn!("{} dns policy server:{}", err, policy.server);
+                    continue;
+                }
+            };
+            let conditional = policy.interface.is_some() || policy.ssid.is_some();
+            let mut domains = DomainSet::default();
+            let mut all = false;
+            for pattern in &policy.domains {
+                if pattern == "default" && !conditional {
+                    // 唯一的默认 upstream，validate 拒绝和 dns.servers 同时配置
+                    servers.clear();
+                    servers.push(server);
+                } else if pattern == "default" {
+                    all = true;
+                } else {
+                    domains.add(pattern);
+                }
+            }
+            if all || !domains.is_empty() {
+                policies.push(Policy {
+                    domains,
+                    all,
+                    server,
+                    interface: policy.interface.clone(),
+                    ssid: policy.ssid.clone(),
+                });
+            }
+        }
+        policies
+    }
+
+    /// periodically refresh the active network, None if no policy depends on it
+    pub fn network_watcher(&self) -> Option<BoxFuture<'static, ()>> {
+        if !self.policies.iter().any(|p| p.conditional()) {
+            return None;
+        }
+        let interval = self
+            .config
+            .dns
+            .as_ref()
+            .and_then(|x| x.network_check_interval)
+            .unwrap_or(DEFAULT_NETWORK_CHECK_INTERVAL);
+        let network = self.network.clone();
+        Some(
+            async move {
+                // 路由变化时立即检测，ssid 变化没有路由消息，仍然定期检测
+                let mut monitor = RouteMonitor::open();
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(interval)) => {}
+                        _ = monitor.changed() => {}
+                    }
+                    let current = match tokio::task::spawn_blocking(NetworkState::detect).await {
+                        Ok(x) => x,
+                        Err(_) => continue,
+                    };
+                    let mut network = network.write().unwrap();
+                    if *network != current {
+                        log::info!("network changed {:?} => {:?}", *network, current);
+                        *network = current;
+                    }
+                }
+            }
+            .boxed(),
+        )
+    }
+
+    pub fn blocklist(&self) -> Option<Arc<Blocklist>> {
+        self.blocklist.clone()
+    }
+
+    fn check_blocked(&self, host: &str) -> Result<()> {
+        match &self.blocklist {
+            Some(blocklist) if blocklist.check(host) => Err(crate::proxy::Error::DnsBlocked(host.to_string()).into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn policy_server(&self, host: &str) -> Option<&SocketAddr> {
+        let network = self.network.read().unwrap();
+        for policy in &self.policies {
+            if !network.matches(&policy.interface, &policy.ssid) {
+                continue;
+            }
+            if policy.all || policy.domains.matches(host) {
+                trace!("{} dns policy matched {} => {}", trace::sid(), host, policy.server);
+                trace::event(format_args!("dns policy matched {} => {}", host, policy.server));
+                return Some(&policy.server);
+            }
+        }
+        None
+    }
+
+    /// upstream for host, split dns policies first, then random one of servers
+    pub fn select_server(&self, host: &str) -> &SocketAddr {
+        match self.policy_server(host) {
+            Some(server) => server,
+            None => random_get!(self.remote_dns_servers),
         }
     }
     pub fn new_query(host: &String, ty: RecordType) -> Message {
@@ -78,104 +339,191 @@ impl DnsClient {
 
     /// domain string to ip
     pub async fn lookup(&self, host: &String) -> Result<Vec<IpAddr>> {
-        let GeneralSettings {
-            prefer_ipv6,
-            use_ipv6,
-            ..
-        } = self.config.general;
-        let mut tasks: Vec<BoxFuture<Result<Vec<IpAddr>>>> = Vec::new();
-        match (use_ipv6, prefer_ipv6) {
-            (true, true) => {
-                // only wait ipv6 result
-                let query = DnsClient::new_query(host, RecordType::AAAA);
-                let server = random_get!(self.remote_dns_servers);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
-            }
-            (true, false) => {
-                // wait the first result
-                let server = random_get!(self.remote_dns_servers);
-                let query = DnsClient::new_query(&host, RecordType::A);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
-                let query = DnsClient::new_query(&host, RecordType::AAAA);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
-            }
-            (false, ..) => {
-                // don't use ipv6
-                // just use ipv4
-                let server = random_get!(self.remote_dns_servers);
-                let query = DnsClient::new_query(&host, RecordType::A);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
+        self.lookup_ttl(host).await.map(|(ips, _)| ips)
+    }
+
+    /// like lookup, also returns the smallest ttl of the answers in seconds
+    pub async fn lookup_ttl(&self, host: &String) -> Result<(Vec<IpAddr>, u32)> {
+        let started = Instant::now();
+        let res = self.resolve(host).await;
+        match &res {
+            Ok((ips, _)) => {
+                self.events.dns_query(host, Ok(ips));
+                trace::event(format_args!("dns lookup {} => {:?} in {:?}", host, ips, started.elapsed()));
             }
-        };
+            Err(err) => {
+                self.events.dns_query(host, Err(&err.to_string()));
+                trace::event(format_args!("dns lookup {} failed {} in {:?}", host, err, started.elapsed()));
+            }
+        }
+        res
+    }
+
+    async fn resolve(&self, host: &String) -> Result<(Vec<IpAddr>, u32)> {
+        self.check_blocked(host)?;
+        // 同时查询 A 与 AAAA，结果按偏好排序，Dialer 会在两个地址族之间交替尝试
+        let mut types = Vec::new();
+        if self.family.query_ipv4() {
+            types.push(RecordType::A);
+        }
+        if self.family.query_ipv6() {
+            types.push(RecordType::AAAA);
+        }
+        let mut tasks: Vec<BoxFuture<Result<(Vec<IpAddr>, u32)>>> = Vec::new();
+        for ty in types {
+            let query = DnsClient::new_query(host, ty);
+            let v = query.to_vec()?;
+            tasks.push(self.do_lookup(v, &*host).boxed());
+        }
         let mut ips = Vec::new();
-        for mut res in future::join_all(tasks).await {
+        let mut ttl = None;
+        let mut last_err = None;
+        // 只要有一个地址族成功即可，v6 only 或 v4 only 的域名很常见
+        for res in future::join_all(tasks).await {
             match res {
-                Ok(ref mut x) => {
-                    ips.append(x);
+                Ok((mut x, t)) => {
+                    if !x.is_empty() {
+                        ttl = Some(ttl.map_or(t, |old: u32| old.min(t)));
+                    }
+                    ips.append(&mut x)
                 }
-                Err(err) => return Err(anyhow!("lookup failed error {}", err)),
+                Err(err) => last_err = Some(err),
             }
         }
-        Ok(ips)
-    }
-    pub async fn do_lookup(
-        request: Vec<u8>,
-        host: &str,
-        server: &SocketAddr,
-    ) -> Result<Vec<IpAddr>> {
-        trace!("lookup {} on DNS server {}", host, &server);
-        let socket = match server {
-            SocketAddr::V4(_v4) => {
-                // let bind_addr = get_default_ipv4_gateway()?;
-                let bind_addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-                create_bounded_udp_socket(bind_addr)?
+        if ips.is_empty() {
+            if let Some(err) = last_err {
+                return Err(anyhow!("lookup failed error {}", err));
             }
-            SocketAddr::V6(_v6) => {
-                // let bind_addr = get_default_ipv6_gateway()?;
-                let bind_addr = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
-                create_bounded_udp_socket(bind_addr)?
+        }
+        Ok((self.family.apply(ips), ttl.unwrap_or(0)))
+    }
+    /// lookup a single record type, A or AAAA
+    pub async fn lookup_record(&self, host: &String, ty: RecordType) -> Result<Vec<IpAddr>> {
+        self.check_blocked(host)?;
+        let query = DnsClient::new_query(host, ty);
+        let v = query.to_vec()?;
+        self.do_lookup(v, &*host).await.map(|(ips, _)| ips)
+    }
+
+    /// send raw dns request to upstream and return the raw response
+    /// a matched split dns policy is used directly, otherwise the quic upstreams are tried
+    /// in order before falling back to servers
+    pub async fn exchange(&self, host: &str, request: &[u8]) -> Result<Vec<u8>> {
+        let randomize_case = self.config.dns.as_ref().map_or(false, |x| x.randomize_case);
+        if let Some(server) = self.policy_server(host) {
+            return DnsClient::exchange_udp(request, server, randomize_case).await;
+        }
+        for upstream in &self.upstreams {
+            match upstream.exchange(request).await {
+                Ok(response) => return Ok(response),
+                Err(err) => {
+                    debug!("{} dns upstream {} failed {}", trace::sid(), upstream.address(), err);
+                    trace::event(format_args!("dns upstream {} failed {}", upstream.address(), err));
+                }
             }
-        };
-        match socket.send_to(&*request, server).await {
-            Ok(..) => {
-                let mut buf = vec![0u8; 512];
-                match socket.recv_from(&mut buf).await {
-                    Ok((n, ..)) => {
-                        let message = Message::from_bytes(&buf[..n])?;
-                        if message.response_code() != ResponseCode::NoError {
-                            return Err(anyhow!(
-                                "dns lookup response indicate failed {}",
-                                message.response_code()
-                            ));
-                        }
-                        let anwsers = message.answers();
-                        let mut ips = Vec::new();
-                        for anwser in anwsers {
-                            let rdata = anwser.rdata();
-                            match rdata {
-                                RData::A(ip) => ips.push(IpAddr::V4(ip.clone())),
-                                RData::AAAA(ipv6) => ips.push(IpAddr::V6(ipv6.clone())),
-                                _ => {}
-                            };
-                        }
-                        return Ok(ips);
-                    }
-                    Err(err) => return Err(anyhow!("error when recv from {}", err)),
+        }
+        if self.remote_dns_servers.is_empty() {
+            return Err(anyhow!("all dns upstreams failed for {}", host));
+        }
+        DnsClient::exchange_udp(request, random_get!(self.remote_dns_servers), randomize_case).await
+    }
+
+    // 每个查询新的 socket（随机源端口）与随机 id，只接受来自 server 且与查询完全匹配的响应
+    // 伪造的响应被丢弃后继续等待，直到超时
+    async fn exchange_udp(request: &[u8], server: &SocketAddr, randomize_case: bool) -> Result<Vec<u8>> {
+        let query = Outstanding::new(request, randomize_case)?;
+        let socket = DnsClient::new_socket(server)?;
+        socket
+            .send_to(&query.request, server)
+            .await
+            .map_err(|err| anyhow!("error when send to {}", err))?;
+        let mut buf = buffer::get(4096);
+        let receive = async {
+            loop {
+                let (n, peer) = socket
+                    .recv_from(&mut buf)
+                    .await
+                    .map_err(|err| anyhow!("error when recv from {}", err))?;
+                if peer != *server {
+                    debug!("dns response from unexpected {}, expected {}", peer, server);
+                    continue;
+                }
+                match query.accept(&buf[..n]) {
+                    Some(response) => return Ok(response),
+                    None => debug!("dns response from {} does not match the query, dropped", server),
                 }
             }
-            Err(err) => return Err(anyhow!("error when send to {}", err)),
         };
+        match tokio::time::timeout(Duration::from_secs(DNS_UDP_TIMEOUT), receive).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow!("dns query to {} timed out", server)),
+        }
+    }
+
+    fn new_socket(server: &SocketAddr) -> std::io::Result<tokio::net::UdpSocket> {
+        Dialer::default().bind_udp(Dialer::unspecified(server))
+    }
+
+    async fn do_lookup(&self, request: Vec<u8>, host: &str) -> Result<(Vec<IpAddr>, u32)> {
+        trace!("{} lookup {}", trace::sid(), host);
+        let response = self.exchange(host, &request).await?;
+        let message = Message::from_bytes(&response)?;
+        if message.response_code() != ResponseCode::NoError {
+            return Err(anyhow!(
+                "dns lookup response indicate failed {}",
+                message.response_code()
+            ));
+        }
+        let mut ips = Vec::new();
+        let mut ttl = u32::MAX;
+        for anwser in message.answers() {
+            match anwser.rdata() {
+                RData::A(ip) => ips.push(IpAddr::V4(ip.clone())),
+                RData::AAAA(ipv6) => ips.push(IpAddr::V6(ipv6.clone())),
+                _ => continue,
+            };
+            ttl = ttl.min(anwser.ttl());
+        }
+        if ips.is_empty() {
+            ttl = 0;
+        }
+        Ok((ips, ttl))
     }
 }
 
+#[test]
+fn test_outstanding_query() {
+    let host = "www.Example.com".to_string();
+    let mut message = DnsClient::new_query(&host, RecordType::A);
+    message.set_id(0x1234);
+    let request = message.to_vec().unwrap();
+    let query = Outstanding::new(&request, true).unwrap();
+    assert_eq!(query.question, 12..request.len());
+    assert!(query.request[12..].eq_ignore_ascii_case(&request[12..]));
+
+    let mut response = Message::from_bytes(&query.request).unwrap();
+    response.set_message_type(MessageType::Response);
+    response.add_answer(trust_dns_proto::rr::Record::from_rdata(
+        message.queries()[0].name().clone(),
+        60,
+        RData::A("1.2.3.4".parse().unwrap()),
+    ));
+    let response = response.to_vec().unwrap();
+    let accepted = Message::from_bytes(&query.accept(&response).unwrap()).unwrap();
+    assert_eq!(accepted.id(), 0x1234);
+    assert_eq!(accepted.queries()[0].name().to_utf8(), "www.Example.com.");
+    // id 不同
+    let mut spoofed = response.clone();
+    spoofed[0] ^= 0xff;
+    assert!(query.accept(&spoofed).is_none());
+    // 只改变大小写的 question
+    let mut spoofed = response.clone();
+    spoofed[13] ^= 0x20;
+    assert!(query.accept(&spoofed).is_none());
+    // 查询本身
+    assert!(query.accept(&query.request).is_none());
+}
+
 #[tokio::test]
 async fn lookup_test() {
     use tokio::net::UdpSocket;
@@ -0,0 +1,315 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::{future::BoxFuture, FutureExt};
+use log::{debug, error, info, trace};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::RwLock,
+};
+use trust_dns_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{Name, RData, Record, RecordType},
+    serialize::binary::{BinDecodable, BinEncodable},
+};
+
+use crate::{
+    common::family::Nat64,
+    net::udp::{RecvBatch, BATCH},
+};
+
+use super::{supervisor::Listen, BlockResponse, DnsClient};
+
+type TaskFuture = BoxFuture<'static, ()>;
+
+const DEFAULT_TTL: u32 = 60;
+// 每个 upstream 自己有超时，这里限制整个查询（包括 DNS64 的第二次查询），超时回复 SERVFAIL
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// dns inbound
+// host 或者 LAN 内的 client 可以将 resolver 指向 tunnel
+// A/AAAA 查询通过 DnsClient 解析（hosts，split dns 等策略都在 DnsClient 中生效）
+// 其他类型的查询原样转发到 upstream
+pub struct DnsServer;
+
+impl DnsServer {
+    /// udp and tcp accept loops on addr, supervised separately
+    pub fn listen(dns_client: Arc<RwLock<DnsClient>>, addr: SocketAddr) -> Vec<Listen> {
+        let udp = dns_client.clone();
+        vec![
+            Box::new(move || DnsServer::udp_listener(udp.clone(), addr)) as Listen,
+            Box::new(move || DnsServer::tcp_listener(dns_client.clone(), addr)) as Listen,
+        ]
+    }
+
+    fn udp_listener(dns_client: Arc<RwLock<DnsClient>>, addr: SocketAddr) -> TaskFuture {
+        async move {
+            let socket = match UdpSocket::bind(addr).await {
+                Ok(s) => Arc::new(s),
+                Err(err) => {
+                    error!("dns inbound bind udp {} failed {}", addr, err);
+                    return;
+                }
+            };
+            info!("Dns udp listening at {}", addr);
+            // 一次系统调用读取多个请求，每个请求单独处理与回复
+            let mut batch = RecvBatch::new(BATCH, 4096);
+            loop {
+                if let Err(err) = batch.recv(&socket).await {
+                    debug!("dns inbound recv failed {}", err);
+                    continue;
+                }
+                for (request, peer) in batch.datagrams() {
+                    let request = request.to_vec();
+                    let socket = socket.clone();
+                    let dns_client = dns_client.clone();
+                    tokio::spawn(async move {
+                        let response = match DnsServer::handle(dns_client, &request).await {
+                            Some(x) => x,
+                            None => return,
+                        };
+                        if let Err(err) = socket.send_to(&response, peer).await {
+                            debug!("dns inbound send to {} failed {}", peer, err);
+                        }
+                    });
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn tcp_listener(dns_client: Arc<RwLock<DnsClient>>, addr: SocketAddr) -> TaskFuture {
+        async move {
+            let listener = match TcpListener::bind(addr).await {
+                Ok(l) => l,
+                Err(err) => {
+                    error!("dns inbound bind tcp {} failed {}", addr, err);
+                    return;
+                }
+            };
+            info!("Dns tcp listening at {}", addr);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(DnsServer::handle_tcp(dns_client.clone(), stream));
+                    }
+                    Err(err) => {
+                        error!("dns inbound accept error {}", err);
+                        return;
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    // dns over tcp, 每个 message 前有 2 bytes 长度
+    pub async fn handle_tcp(dns_client: Arc<RwLock<DnsClient>>, mut stream: TcpStream) {
+        loop {
+            let mut len_buf = [0u8; 2];
+            if stream.read_exact(&mut len_buf).await.is_err() {
+                return;
+            }
+            let mut request = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+            if stream.read_exact(&mut request).await.is_err() {
+                return;
+            }
+            // 连 header 都不完整，无法回复
+            let response = match DnsServer::handle(dns_client.clone(), &request).await {
+                Some(x) => x,
+                None => return,
+            };
+            let mut buf = (response.len() as u16).to_be_bytes().to_vec();
+            buf.extend_from_slice(&response);
+            if stream.write_all(&buf).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// SERVFAIL on error or timeout, FORMERR if the request can't be parsed,
+    /// None if there is not even a header to answer
+    pub async fn handle(dns_client: Arc<RwLock<DnsClient>>, request: &[u8]) -> Option<Vec<u8>> {
+        let message = match Message::from_bytes(request) {
+            Ok(m) => m,
+            Err(err) => {
+                debug!("bad dns request {}", err);
+                return DnsServer::format_error(request);
+            }
+        };
+        let result = match tokio::time::timeout(RESOLVE_TIMEOUT, DnsServer::resolve(dns_client, &message, request)).await {
+            Ok(x) => x,
+            Err(_) => Err(anyhow!("timeout after {:?}", RESOLVE_TIMEOUT)),
+        };
+        match result {
+            Ok(response) => Some(response),
+            Err(err) => {
+                debug!("dns inbound resolve failed {}", err);
+                let mut response = DnsServer::new_response(&message);
+                response.set_response_code(ResponseCode::ServFail);
+                response.to_vec().ok()
+            }
+        }
+    }
+
+    // 只回复 header: 相同的 id，QR=1，opcode 与 RD 不变，RCODE=FORMERR
+    // 本身是响应的报文不回复，避免两个 server 互相回复
+    fn format_error(request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() < 12 || request[2] & 0x80 != 0 {
+            return None;
+        }
+        let mut response = vec![0u8; 12];
+        response[..2].copy_from_slice(&request[..2]);
+        response[2] = 0x80 | (request[2] & 0x79);
+        response[3] = 0x80 | ResponseCode::FormErr.low();
+        Some(response)
+    }
+
+    fn new_response(request: &Message) -> Message {
+        let mut response = Message::new();
+        response.set_id(request.id());
+        response.set_message_type(MessageType::Response);
+        response.set_op_code(request.op_code());
+        response.set_recursion_desired(request.recursion_desired());
+        response.set_recursion_available(true);
+        response.add_queries(request.queries().to_vec());
+        response
+    }
+
+    fn blocked_response(message: &Message, block: BlockResponse) -> Result<Vec<u8>> {
+        let mut response = DnsServer::new_response(message);
+        match block {
+            BlockResponse::NxDomain => {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+            BlockResponse::Zero => {
+                // 其他类型返回空的 NOERROR
+                if let Some(query) = message.queries().first() {
+                    let rdata = match query.query_type() {
+                        RecordType::A => Some(RData::A(Ipv4Addr::UNSPECIFIED)),
+                        RecordType::AAAA => Some(RData::AAAA(Ipv6Addr::UNSPECIFIED)),
+                        _ => None,
+                    };
+                    if let Some(rdata) = rdata {
+                        response.add_answer(Record::from_rdata(query.name().clone(), DEFAULT_TTL, rdata));
+                    }
+                }
+            }
+        }
+        Ok(response.to_vec()?)
+    }
+
+    async fn resolve(
+        dns_client: Arc<RwLock<DnsClient>>,
+        message: &Message,
+        request: &[u8],
+    ) -> Result<Vec<u8>> {
+        let query = match message.queries().first() {
+            Some(q) => q,
+            None => return Err(anyhow!("dns request without query")),
+        };
+        let ty = query.query_type();
+        let name: Name = query.name().clone();
+        let host = name.to_utf8();
+        let host = host.trim_end_matches('.').to_string();
+        if let Some(blocklist) = dns_client.read().await.blocklist() {
+            if blocklist.check(&host) {
+                return Ok(DnsServer::blocked_response(message, blocklist.response)?);
+            }
+        }
+        if ty != RecordType::A && ty != RecordType::AAAA {
+            trace!("forward dns query {} {}", host, ty);
+            return dns_client.read().await.exchange(&host, request).await;
+        }
+        let mut ips = dns_client.read().await.lookup_record(&host, ty).await?;
+        // DNS64: 没有 AAAA 记录时用 A 记录合成，只有 ipv6 的客户端经过 NAT64 访问
+        if ty == RecordType::AAAA && ips.is_empty() {
+            let client = dns_client.read().await;
+            if let Some(nat64) = client.family().nat64 {
+                ips = client
+                    .lookup_record(&host, RecordType::A)
+                    .await?
+                    .into_iter()
+                    .filter_map(|ip| match ip {
+                        IpAddr::V4(v4) if Nat64::translatable(v4) => Some(IpAddr::V6(nat64.synthesize(v4))),
+                        _ => None,
+                    })
+                    .collect();
+            }
+        }
+        trace!("dns inbound {} {} => {:?}", host, ty, ips);
+        let mut response = DnsServer::new_response(message);
+        for ip in ips {
+            let rdata = match (ip, ty) {
+                (IpAddr::V4(v4), RecordType::A) => RData::A(v4),
+                (IpAddr::V6(v6), RecordType::AAAA) => RData::AAAA(v6),
+                _ => continue,
+            };
+            response.add_answer(Record::from_rdata(name.clone(), DEFAULT_TTL, rdata));
+        }
+        Ok(response.to_vec()?)
+    }
+}
+
+#[tokio::test]
+async fn test_dns_server_handle() {
+    // 只回复 A 记录的 upstream
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((n, peer)) = upstream.recv_from(&mut buf).await {
+            let mut response = Message::from_bytes(&buf[..n]).unwrap();
+            response.set_message_type(MessageType::Response);
+            let name = response.queries()[0].name().clone();
+            response.add_answer(Record::from_rdata(name, 300, RData::A("1.2.3.4".parse().unwrap())));
+            upstream.send_to(&response.to_vec().unwrap(), peer).await.unwrap();
+        }
+    });
+    let config = crate::config::parse_from_str(&format!(
+        r#"{{
+            "general": {{ "prefer_ipv6": false, "use_ipv6": false }},
+            "inbounds": [], "outbounds": [], "routes": [],
+            "dns": {{ "bind": "127.0.0.1:0", "servers": ["{}"] }}
+        }}"#,
+        upstream_addr
+    ))
+    .unwrap();
+    let dns_client = Arc::new(RwLock::new(DnsClient::new(config)));
+
+    let mut query = DnsClient::new_query(&"example.com".to_string(), RecordType::A);
+    query.set_id(0x1234);
+    let response = DnsServer::handle(dns_client.clone(), &query.to_vec().unwrap()).await.unwrap();
+    let response = Message::from_bytes(&response).unwrap();
+    assert_eq!(response.id(), 0x1234);
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers()[0].rdata(), &RData::A("1.2.3.4".parse().unwrap()));
+
+    // header 完整但 question 被截断
+    let mut truncated = query.to_vec().unwrap();
+    truncated.truncate(16);
+    let response = DnsServer::handle(dns_client.clone(), &truncated).await.unwrap();
+    assert_eq!(response.len(), 12);
+    let response = Message::from_bytes(&response).unwrap();
+    assert_eq!(response.id(), 0x1234);
+    assert_eq!(response.message_type(), MessageType::Response);
+    assert_eq!(response.response_code(), ResponseCode::FormErr);
+
+    // 不到一个 header，或者本身就是响应
+    assert!(DnsServer::handle(dns_client.clone(), &[0x12, 0x34, 0x01]).await.is_none());
+    truncated[2] |= 0x80;
+    assert!(DnsServer::handle(dns_client.clone(), &truncated).await.is_none());
+
+    // 没有 question
+    let mut empty = Message::new();
+    empty.set_id(0x4321);
+    let response = DnsServer::handle(dns_client, &empty.to_vec().unwrap()).await.unwrap();
+    let response = Message::from_bytes(&response).unwrap();
+    assert_eq!(response.id(), 0x4321);
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+}
@@ -0,0 +1,230 @@
+// 基于 quic 的加密 dns upstream
+// doq: RFC 9250，每个查询一个 bidi stream，2 bytes 长度 + message，message id 必须为 0
+// doh3: RFC 8484 over http/3，POST application/dns-message
+//
+// 每个 upstream 一个 QuicConnector，连接复用；同一地址族的 upstream 共享 endpoint
+// 按配置顺序尝试，全部失败时 DnsClient 回退到普通 udp servers
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use log::debug;
+use quinn::SendStream;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::{
+    config::{DnsUpstreamConfig, QuicSettings, TlsSettings},
+    transport::{
+        h3::{
+            decode_frames, encode_frame, encode_varint, response_status, HeaderBlock,
+            FRAME_DATA, FRAME_HEADERS, FRAME_SETTINGS, QPACK_ACCEPT_DNS_MESSAGE, QPACK_AUTHORITY,
+            QPACK_CONTENT_LENGTH, QPACK_CONTENT_TYPE_DNS_MESSAGE, QPACK_METHOD_POST, QPACK_PATH,
+            QPACK_SCHEME_HTTPS, STREAM_CONTROL,
+        },
+        quic::QuicConnector,
+    },
+};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_PATH: &str = "/dns-query";
+// dns message 最大 65535，加上 http/3 frame header
+const MAX_RESPONSE: u64 = 65535 + 64;
+
+enum Kind {
+    Doq,
+    // 当前连接上的 control stream，连接重建后需要重新打开
+    Doh3 {
+        path: String,
+        control: Mutex<Option<(usize, SendStream)>>,
+    },
+}
+
+pub struct QuicUpstream {
+    address: SocketAddr,
+    server_name: String,
+    connector: QuicConnector,
+    kind: Kind,
+}
+
+impl QuicUpstream {
+    pub fn new(config: &DnsUpstreamConfig) -> Result<QuicUpstream> {
+        let (alpn, kind) = match config.protocol.as_str() {
+            "doq" => ("doq", Kind::Doq),
+            "doh3" => (
+                "h3",
+                Kind::Doh3 {
+                    path: config.path.clone().unwrap_or_else(|| DEFAULT_PATH.to_string()),
+                    control: Mutex::new(None),
+                },
+            ),
+            x => bail!("unknown dns upstream protocol {}", x),
+        };
+        let settings = QuicSettings {
+            tls: TlsSettings {
+                sni: Some(config.server_name.clone()),
+                alpn: Some(vec![alpn.to_string()]),
+                ..Default::default()
+            },
+            zero_rtt: false,
+        };
+        Ok(QuicUpstream {
+            address: config.address,
+            server_name: config.server_name.clone(),
+            connector: QuicConnector::new(config.address, &config.server_name, &settings)?,
+            kind,
+        })
+    }
+
+    /// upstreams in config order, sharing one endpoint per address family
+    pub fn load(configs: &[DnsUpstreamConfig]) -> Vec<QuicUpstream> {
+        let mut upstreams: Vec<QuicUpstream> = Vec::new();
+        for config in configs {
+            let mut upstream = match QuicUpstream::new(config) {
+                Ok(x) => x,
+                Err(err) => {
+                    log::warn!("{}, dns upstream {} ignored", err, config.address);
+                    continue;
+                }
+            };
+            if let Some(other) = upstreams.iter().find(|x| x.address.is_ipv4() == config.address.is_ipv4()) {
+                upstream.connector.share_endpoint(&other.connector);
+            }
+            upstreams.push(upstream);
+        }
+        upstreams
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// send a raw dns request, returns the raw response with the request's id
+    pub async fn exchange(&self, request: &[u8]) -> Result<Vec<u8>> {
+        if request.len() < 12 {
+            bail!("dns request too short");
+        }
+        let mut response = timeout(QUERY_TIMEOUT, async {
+            match &self.kind {
+                Kind::Doq => self.exchange_doq(request).await,
+                Kind::Doh3 { path, control } => self.exchange_doh3(request, path, control).await,
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("dns query to {} timeout", self.address))??;
+        if response.len() < 12 {
+            bail!("dns response from {} too short", self.address);
+        }
+        // doq 与 doh 都使用 id 0，还原为原始 id
+        response[..2].copy_from_slice(&request[..2]);
+        Ok(response)
+    }
+
+    async fn exchange_doq(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = self.connector.open_stream().await?;
+        let mut buf = Vec::with_capacity(request.len() + 2);
+        buf.extend_from_slice(&(request.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&request[2..]);
+        stream.write_all(&buf).await?;
+        // client 发送完查询后必须关闭 stream 的发送方向
+        stream.shutdown().await?;
+        let len = stream.read_u16().await?;
+        let mut response = vec![0u8; len as usize];
+        stream.read_exact(&mut response).await?;
+        Ok(response)
+    }
+
+    async fn ensure_control(&self, control: &Mutex<Option<(usize, SendStream)>>) -> Result<()> {
+        let id = self.connector.connection_id().await?;
+        let mut control = control.lock().await;
+        if matches!(&*control, Some((current, _)) if *current == id) {
+            return Ok(());
+        }
+        let (id, mut send) = self.connector.open_uni().await?;
+        let mut buf = Vec::new();
+        encode_varint(&mut buf, STREAM_CONTROL);
+        // 空的 SETTINGS，不使用 qpack dynamic table
+        encode_varint(&mut buf, FRAME_SETTINGS);
+        encode_varint(&mut buf, 0);
+        send.write_all(&buf).await?;
+        // control stream 在连接存活期间不能关闭
+        control.replace((id, send));
+        Ok(())
+    }
+
+    async fn exchange_doh3(
+        &self,
+        request: &[u8],
+        path: &str,
+        control: &Mutex<Option<(usize, SendStream)>>,
+    ) -> Result<Vec<u8>> {
+        self.ensure_control(control).await?;
+        let mut message = request.to_vec();
+        message[..2].copy_from_slice(&[0, 0]);
+        let headers = request_headers(&self.server_name, path, message.len());
+        let mut buf = Vec::with_capacity(headers.len() + message.len() + 16);
+        encode_frame(&mut buf, FRAME_HEADERS, &headers);
+        encode_frame(&mut buf, FRAME_DATA, &message);
+        let mut stream = self.connector.open_stream().await?;
+        stream.write_all(&buf).await?;
+        stream.shutdown().await?;
+        let mut response = Vec::new();
+        (&mut stream).take(MAX_RESPONSE).read_to_end(&mut response).await?;
+        parse_response(&response)
+    }
+}
+
+// 只使用 static table，不使用 huffman
+fn request_headers(authority: &str, path: &str, content_length: usize) -> Vec<u8> {
+    HeaderBlock::new()
+        .indexed(QPACK_METHOD_POST)
+        .indexed(QPACK_SCHEME_HTTPS)
+        .literal(QPACK_AUTHORITY, authority)
+        .literal(QPACK_PATH, path)
+        .indexed(QPACK_CONTENT_TYPE_DNS_MESSAGE)
+        .indexed(QPACK_ACCEPT_DNS_MESSAGE)
+        .literal(QPACK_CONTENT_LENGTH, &content_length.to_string())
+        .finish()
+}
+
+fn parse_response(data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    for (ty, payload) in decode_frames(data).ok_or_else(|| anyhow!("truncated http/3 frame"))? {
+        match ty {
+            FRAME_HEADERS => match response_status(payload) {
+                Some(200) | None => {}
+                Some(status) => bail!("doh3 response status {}", status),
+            },
+            FRAME_DATA => body.extend_from_slice(payload),
+            // 未知 frame 类型按规范忽略
+            _ => debug!("doh3 frame {} ignored", ty),
+        }
+    }
+    if body.is_empty() {
+        bail!("empty doh3 response");
+    }
+    Ok(body)
+}
+
+#[test]
+fn test_http3_codec() {
+    use crate::transport::h3::decode_varint;
+    let mut buf = Vec::new();
+    for value in [0u64, 63, 64, 16383, 16384, 1 << 30] {
+        buf.clear();
+        encode_varint(&mut buf, value);
+        assert_eq!(decode_varint(&buf), Some((value, buf.len())));
+    }
+    // HEADERS(:status 200) + DATA
+    let mut response = Vec::new();
+    encode_frame(&mut response, FRAME_HEADERS, &[0, 0, 0xc0 | 25]);
+    encode_frame(&mut response, FRAME_DATA, b"answer");
+    assert_eq!(parse_response(&response).unwrap(), b"answer");
+    let mut response = Vec::new();
+    encode_frame(&mut response, FRAME_HEADERS, &[0, 0, 0x50 | 24, 3, b'4', b'0', b'0']);
+    assert!(parse_response(&response).is_err());
+}
@@ -0,0 +1,331 @@
+// tunnel doctor: 检查运行环境中常见的问题，每一项给出结论与修复建议
+// tun: /dev/net/tun 能否打开，是否有 CAP_NET_ADMIN
+// 转发与路由: ip_forward、默认路由冲突（其他 vpn 占用默认路由）、rp_filter
+// dns: 通过每个 outbound 向 dns.servers 发送一次 tcp 查询
+// 系统相关的检查只支持 linux，其他平台跳过
+
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+use trust_dns_proto::{
+    op::Message,
+    rr::RecordType,
+    serialize::binary::{BinDecodable, BinEncodable},
+};
+
+use crate::{config::Config, proxy::Address};
+
+use super::{DnsClient, Fetcher};
+
+// 单个 outbound 查询 dns 的超时
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+const DNS_PROBE_HOST: &str = "example.com";
+// include/uapi/linux/capability.h
+const CAP_NET_ADMIN: u32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+            Status::Skip => "skip",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct Finding {
+    pub check: String,
+    pub status: Status,
+    pub message: String,
+    // what to do about it
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    fn new(check: &str, status: Status, message: String) -> Finding {
+        Finding {
+            check: check.to_string(),
+            status,
+            message,
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: &str) -> Finding {
+        self.hint = Some(hint.to_string());
+        self
+    }
+}
+
+pub struct Doctor {
+    config: Config,
+    fetcher: Fetcher,
+}
+
+impl Doctor {
+    pub fn new(config: Config, fetcher: Fetcher) -> Doctor {
+        Doctor { config, fetcher }
+    }
+
+    pub async fn run(&self, outbounds: &[String]) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        if self.config.inbounds.iter().any(|x| x.protocol == "tun") {
+            findings.extend(system::tun_device());
+        } else {
+            findings.push(Finding::new("tun", Status::Skip, "no tun inbound configured".to_string()));
+        }
+        findings.extend(system::forwarding());
+        findings.extend(system::routes());
+        findings.extend(system::rp_filter());
+        findings.extend(self.resolvers(outbounds).await);
+        findings
+    }
+
+    async fn resolvers(&self, outbounds: &[String]) -> Vec<Finding> {
+        let servers: Vec<SocketAddr> = self
+            .config
+            .dns
+            .as_ref()
+            .and_then(|x| x.servers.as_ref())
+            .map(|x| x.iter().filter_map(|x| x.parse().ok()).collect())
+            .unwrap_or_default();
+        let server = match servers.first() {
+            Some(x) => *x,
+            None => return vec![Finding::new("dns", Status::Skip, "no dns.servers configured".to_string())],
+        };
+        let mut findings = Vec::new();
+        for tag in outbounds {
+            let check = format!("dns via {}", tag);
+            let protocol = self.config.outbounds.iter().find(|x| &x.tag == tag).map(|x| x.protocol.as_str());
+            if matches!(protocol, Some("reject") | Some("blackhole")) {
+                findings.push(Finding::new(&check, Status::Skip, "outbound drops all traffic".to_string()));
+                continue;
+            }
+            let finding = match timeout(DNS_TIMEOUT, self.query(tag, server)).await {
+                Ok(Ok(answers)) => Finding::new(&check, Status::Ok, format!("{} answered with {} records", server, answers)),
+                Ok(Err(err)) => Finding::new(&check, Status::Fail, format!("{} {}", server, err))
+                    .hint("check the outbound settings and that the proxy server allows tcp to the resolver"),
+                Err(_) => Finding::new(&check, Status::Fail, format!("{} no answer after {:?}", server, DNS_TIMEOUT))
+                    .hint("the path through this outbound drops dns, try another resolver in dns.servers"),
+            };
+            findings.push(finding);
+        }
+        findings
+    }
+
+    // dns over tcp，经过 outbound 的连接不一定支持 udp
+    async fn query(&self, tag: &str, server: SocketAddr) -> Result<usize> {
+        let request = DnsClient::new_query(&DNS_PROBE_HOST.to_string(), RecordType::A);
+        let data = request.to_vec()?;
+        let mut stream = self.fetcher.with_outbound(tag).connect(Address::Ip(server)).await?;
+        stream.write_u16(data.len() as u16).await?;
+        stream.write_all(&data).await?;
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf).await?;
+        let response = Message::from_bytes(&buf)?;
+        if response.id() != request.id() {
+            return Err(anyhow!("response id mismatch"));
+        }
+        Ok(response.answers().len())
+    }
+}
+
+/// human readable findings, hints indented below
+pub fn report(findings: &[Finding]) -> String {
+    let width = findings.iter().map(|x| x.check.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for finding in findings {
+        out.push_str(&format!("[{:>4}] {:<width$}  {}\n", finding.status.to_string(), finding.check, finding.message, width = width));
+        if let Some(hint) = &finding.hint {
+            out.push_str(&format!("       {:<width$}  => {}\n", "", hint, width = width));
+        }
+    }
+    out
+}
+
+// /proc/self/status 中 CapEff 是十六进制的 capability 位图
+fn has_capability(status: &str, cap: u32) -> Option<bool> {
+    let hex = status.lines().find_map(|x| x.strip_prefix("CapEff:"))?.trim();
+    let bits = u64::from_str_radix(hex, 16).ok()?;
+    Some(bits & (1 << cap) != 0)
+}
+
+// /proc/net/route: Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+// 返回 (网卡, metric)
+fn default_routes(table: &str) -> Vec<(String, u32)> {
+    table
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .filter(|fields| fields.len() > 7 && fields[1] == "00000000" && fields[7] == "00000000")
+        .map(|fields| (fields[0].to_string(), fields[6].parse().unwrap_or(0)))
+        .collect()
+}
+
+// 其他 vpn 常用的网卡名
+fn is_vpn_interface(name: &str) -> bool {
+    ["tun", "utun", "wg", "tailscale", "ppp", "zt"].iter().any(|x| name.starts_with(x))
+}
+
+#[cfg(target_os = "linux")]
+mod system {
+    use std::fs;
+
+    use super::*;
+
+    fn read(path: &str) -> Option<String> {
+        fs::read_to_string(path).ok().map(|x| x.trim().to_string())
+    }
+
+    pub fn tun_device() -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let device = match fs::OpenOptions::new().read(true).write(true).open("/dev/net/tun") {
+            Ok(_) => Finding::new("tun device", Status::Ok, "/dev/net/tun can be opened".to_string()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Finding::new("tun device", Status::Fail, "/dev/net/tun does not exist".to_string())
+                    .hint("load the module with `modprobe tun`, in containers pass --device /dev/net/tun")
+            }
+            Err(err) => Finding::new("tun device", Status::Fail, format!("open /dev/net/tun failed {}", err))
+                .hint("run as root or grant the capability: setcap cap_net_admin+ep <path to tunnel>"),
+        };
+        findings.push(device);
+        let capability = match read("/proc/self/status").and_then(|x| has_capability(&x, CAP_NET_ADMIN)) {
+            Some(true) => Finding::new("CAP_NET_ADMIN", Status::Ok, "effective".to_string()),
+            Some(false) => Finding::new("CAP_NET_ADMIN", Status::Fail, "not effective, the tun device can not be configured".to_string())
+                .hint("run as root, setcap cap_net_admin+ep <path to tunnel>, or AmbientCapabilities=CAP_NET_ADMIN in the systemd unit"),
+            None => Finding::new("CAP_NET_ADMIN", Status::Skip, "unable to read /proc/self/status".to_string()),
+        };
+        findings.push(capability);
+        findings
+    }
+
+    pub fn forwarding() -> Vec<Finding> {
+        let mut findings = Vec::new();
+        for (check, path, sysctl) in [
+            ("ipv4 forwarding", "/proc/sys/net/ipv4/ip_forward", "net.ipv4.ip_forward"),
+            ("ipv6 forwarding", "/proc/sys/net/ipv6/conf/all/forwarding", "net.ipv6.conf.all.forwarding"),
+        ] {
+            let finding = match read(path).as_deref() {
+                Some("0") => Finding::new(check, Status::Warn, "disabled, only traffic of this host is proxied".to_string())
+                    .hint(&format!("needed when other devices use this host as gateway: sysctl -w {}=1", sysctl)),
+                Some(_) => Finding::new(check, Status::Ok, "enabled".to_string()),
+                None => Finding::new(check, Status::Skip, format!("unable to read {}", path)),
+            };
+            findings.push(finding);
+        }
+        findings
+    }
+
+    pub fn routes() -> Vec<Finding> {
+        let table = match read("/proc/net/route") {
+            Some(x) => x,
+            None => return vec![Finding::new("default route", Status::Skip, "unable to read /proc/net/route".to_string())],
+        };
+        let routes = default_routes(&table);
+        let lowest = match routes.iter().map(|x| x.1).min() {
+            Some(x) => x,
+            None => {
+                return vec![Finding::new("default route", Status::Fail, "no ipv4 default route".to_string())
+                    .hint("the host is offline or the network manager has not configured a gateway yet")]
+            }
+        };
+        let preferred: Vec<&str> = routes.iter().filter(|x| x.1 == lowest).map(|x| x.0.as_str()).collect();
+        let mut findings = Vec::new();
+        if preferred.len() > 1 {
+            findings.push(
+                Finding::new("default route", Status::Warn, format!("{} default routes with metric {}: {}", preferred.len(), lowest, preferred.join(", ")))
+                    .hint("give the routes different metrics, otherwise the kernel picks one of them unpredictably"),
+            );
+        } else {
+            findings.push(Finding::new("default route", Status::Ok, format!("via {}", preferred[0])));
+        }
+        let vpn: Vec<&str> = routes.iter().map(|x| x.0.as_str()).filter(|x| is_vpn_interface(x)).collect();
+        if !vpn.is_empty() {
+            findings.push(
+                Finding::new("other vpn", Status::Warn, format!("default route through {}", vpn.join(", ")))
+                    .hint("another vpn may capture the traffic before tunnel, stop it or exclude the proxy servers from it"),
+            );
+        }
+        findings
+    }
+
+    // 生效的是 all 与网卡各自配置中较大的值，这里只检查 all 与 default
+    pub fn rp_filter() -> Vec<Finding> {
+        let mut strict = Vec::new();
+        for name in ["all", "default"] {
+            if read(&format!("/proc/sys/net/ipv4/conf/{}/rp_filter", name)).as_deref() == Some("1") {
+                strict.push(name);
+            }
+        }
+        if strict.is_empty() {
+            return vec![Finding::new("rp_filter", Status::Ok, "not strict".to_string())];
+        }
+        vec![Finding::new("rp_filter", Status::Warn, format!("strict reverse path filtering on {}", strict.join(", ")))
+            .hint("replies routed back through the tun device may be dropped: sysctl -w net.ipv4.conf.all.rp_filter=2")]
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod system {
+    use super::*;
+
+    fn skipped(check: &str) -> Vec<Finding> {
+        vec![Finding::new(check, Status::Skip, "only checked on linux".to_string())]
+    }
+
+    pub fn tun_device() -> Vec<Finding> {
+        skipped("tun device")
+    }
+
+    pub fn forwarding() -> Vec<Finding> {
+        skipped("forwarding")
+    }
+
+    pub fn routes() -> Vec<Finding> {
+        skipped("default route")
+    }
+
+    pub fn rp_filter() -> Vec<Finding> {
+        skipped("rp_filter")
+    }
+}
+
+#[test]
+fn test_doctor() {
+    let status = "Name:\ttunnel\nCapInh:\t0000000000000000\nCapEff:\t0000000000001000\n";
+    assert_eq!(has_capability(status, CAP_NET_ADMIN), Some(true));
+    assert_eq!(has_capability("CapEff:\t0000000000000000", CAP_NET_ADMIN), Some(false));
+    assert_eq!(has_capability("Name:\ttunnel", CAP_NET_ADMIN), None);
+
+    let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                 eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+                 eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+                 wg0\t00000000\t00000000\t0001\t0\t0\t50\t00000000\t0\t0\t0\n";
+    assert_eq!(default_routes(table), vec![("eth0".to_string(), 100), ("wg0".to_string(), 50)]);
+    assert!(is_vpn_interface("wg0") && !is_vpn_interface("eth0"));
+
+    let findings = vec![
+        Finding::new("rp_filter", Status::Ok, "not strict".to_string()),
+        Finding::new("dns via proxy", Status::Fail, "8.8.8.8:53 connection refused".to_string()).hint("check the outbound"),
+    ];
+    let report = report(&findings);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[0], "[  ok] rp_filter      not strict");
+    assert_eq!(lines[1], "[FAIL] dns via proxy  8.8.8.8:53 connection refused");
+    assert_eq!(lines[2], "                      => check the outbound");
+}
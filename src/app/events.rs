@@ -0,0 +1,95 @@
+// 嵌入 tunnel 的程序（GUI、计费、日志）通过 EventListener 观察连接，不需要修改 Dispatcher
+// listener 在转发路径上同步调用，耗时的处理应该交给自己的线程或 task
+// Events 由 Instance 持有，reload 之后继续生效
+
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::proxy::Session;
+
+/// statistics of an ended session
+#[derive(Debug, Clone, Default)]
+pub struct SessionStats {
+    // client => remote
+    pub up: u64,
+    // remote => client
+    pub down: u64,
+    pub duration: Duration,
+    // relay error, None when the session ended normally
+    pub error: Option<String>,
+}
+
+/// observer of connections, all methods do nothing by default
+pub trait EventListener: Send + Sync {
+    /// the outbound connection is established
+    fn on_session_start(&self, _sess: &Session, _outbound: &str) {}
+
+    /// called once for every started session
+    fn on_session_end(&self, _sess: &Session, _outbound: &str, _stats: &SessionStats) {}
+
+    /// a lookup by the tunnel's dns client, Err is the error message
+    fn on_dns_query(&self, _host: &str, _result: Result<&[IpAddr], &str>) {}
+
+    /// rule is the position and condition in the config, e.g. "rules[2].domain"
+    fn on_rule_match(&self, _sess: &Session, _rule: &str, _outbound: &str) {}
+}
+
+/// registered listeners, cheap to clone and shared by all profiles
+#[derive(Clone, Default)]
+pub struct Events {
+    listeners: Arc<RwLock<Vec<Arc<dyn EventListener>>>>,
+}
+
+impl Events {
+    pub fn add(&self, listener: Arc<dyn EventListener>) {
+        self.listeners.write().unwrap().push(listener);
+    }
+
+    fn each<F: Fn(&dyn EventListener)>(&self, f: F) {
+        for listener in self.listeners.read().unwrap().iter() {
+            f(listener.as_ref());
+        }
+    }
+
+    pub fn session_start(&self, sess: &Session, outbound: &str) {
+        self.each(|x| x.on_session_start(sess, outbound));
+    }
+
+    pub fn session_end(&self, sess: &Session, outbound: &str, stats: &SessionStats) {
+        self.each(|x| x.on_session_end(sess, outbound, stats));
+    }
+
+    pub fn dns_query(&self, host: &str, result: Result<&[IpAddr], &str>) {
+        self.each(|x| x.on_dns_query(host, result));
+    }
+
+    pub fn rule_match(&self, sess: &Session, rule: &str, outbound: &str) {
+        self.each(|x| x.on_rule_match(sess, rule, outbound));
+    }
+}
+
+#[test]
+fn test_events() {
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl EventListener for Recorder {
+        fn on_dns_query(&self, host: &str, result: Result<&[IpAddr], &str>) {
+            self.0.lock().unwrap().push(format!("{} {:?}", host, result.map(|x| x.len())));
+        }
+    }
+
+    let events = Events::default();
+    let recorder = Arc::new(Recorder::default());
+    events.add(recorder.clone());
+    // clone 共享同一组 listener
+    let shared = events.clone();
+    shared.dns_query("example.com", Ok(&["1.1.1.1".parse().unwrap()]));
+    shared.dns_query("blocked.com", Err("blocked"));
+    assert_eq!(*recorder.0.lock().unwrap(), vec!["example.com Ok(1)", "blocked.com Err(\"blocked\")"]);
+}
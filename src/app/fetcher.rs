@@ -0,0 +1,206 @@
+use std::{convert::TryFrom, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+use crate::{
+    config::{DownloadConfig, TlsSettings},
+    proxy::{
+        Address, AnyStream, Dialer, Network, Session,
+        TcpOutboundHandlerTrait,
+    },
+    transport::tls::TlsConnector,
+    Context,
+};
+
+use super::OutboundManager;
+
+const DEFAULT_USER_AGENT: &str = "tunnel/0.1";
+const DEFAULT_TIMEOUT: u64 = 30;
+
+// 下载远程资源（rule provider，geo 数据等）
+// 这些下载本身往往需要经过已有的 outbound，所以可以通过 download.outbound 指定
+pub struct Fetcher {
+    ctx: Arc<Context>,
+    outbound_manager: Arc<OutboundManager>,
+    settings: DownloadConfig,
+}
+
+pub(super) struct Url {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+pub(super) fn parse_url(url: &str) -> Result<Url> {
+    let (tls, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+        (Some(x), _) => (false, x),
+        (_, Some(x)) => (true, x),
+        _ => bail!("unsupported url {}, only http:// and https:// are supported", url),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(idx) => {
+            let port = authority[idx + 1..]
+                .parse::<u16>()
+                .map_err(|err| anyhow!("bad port in url {} {}", url, err))?;
+            (&authority[..idx], port)
+        }
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        bail!("empty host in url {}", url);
+    }
+    Ok(Url {
+        tls,
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+impl Fetcher {
+    pub fn new(
+        ctx: Arc<Context>,
+        outbound_manager: Arc<OutboundManager>,
+        settings: Option<DownloadConfig>,
+    ) -> Fetcher {
+        Fetcher {
+            ctx,
+            outbound_manager,
+            settings: settings.unwrap_or_default(),
+        }
+    }
+
+    /// same settings but downloads through the given outbound
+    pub fn with_outbound(&self, outbound: &str) -> Fetcher {
+        let mut settings = self.settings.clone();
+        settings.outbound = Some(outbound.to_string());
+        Fetcher {
+            ctx: self.ctx.clone(),
+            outbound_manager: self.outbound_manager.clone(),
+            settings,
+        }
+    }
+
+    /// fetch url and return the response body
+    pub async fn get(&self, url: &str) -> Result<Vec<u8>> {
+        let wait = Duration::from_secs(self.settings.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        match timeout(wait, self.do_get(url)).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow!("fetch {} timeout after {:?}", url, wait)),
+        }
+    }
+
+    async fn do_get(&self, url: &str) -> Result<Vec<u8>> {
+        let url = parse_url(url)?;
+        let destination = Address::try_from((url.host.clone(), url.port))?;
+        let mut stream = self.connect(destination).await?;
+        if url.tls {
+            let connector = TlsConnector::new(&TlsSettings::default())?;
+            stream = Box::new(connector.connect(&url.host, stream).await?);
+        }
+        let request = self.build_request(&url);
+        trace!("fetching {}:{}{}", url.host, url.port, url.path);
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        if let Err(err) = stream.read_to_end(&mut response).await {
+            // 部分 https server 不发送 close_notify 直接关闭连接
+            if response.is_empty() || err.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Err(err.into());
+            }
+        }
+        parse_response(response)
+    }
+
+    pub(super) async fn connect(&self, destination: Address) -> Result<AnyStream> {
+        let tag = match &self.settings.outbound {
+            Some(tag) => tag,
+            None => {
+                let stream = Dialer::default().connect_tcp(self.ctx.dns_client.clone(), destination).await?;
+                return Ok(Box::new(stream));
+            }
+        };
+        let handler = match self.outbound_manager.get_handler(tag) {
+            Some(h) => h,
+            None => bail!("download outbound {} not found", tag),
+        };
+        let tcp = match &handler.tcp_handler {
+            Some(tcp) => tcp,
+            None => bail!("download outbound {} not have tcp handler", tag),
+        };
+        // 没有真正的 inbound 连接，local_peer 与 peer_address 用 unspecified 填充
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        let sess = Session {
+            id: Session::next_id(),
+            destination,
+            network: Network::TCP,
+            local_peer: unspecified,
+            peer_address: unspecified,
+            user: None,
+            inbound_tag: None,
+            app_protocol: None,
+            process: None,
+            uid: None,
+        };
+        tcp.handle(self.ctx.clone(), &sess).await
+    }
+
+    pub(super) fn build_request(&self, url: &Url) -> String {
+        // HTTP/1.0 避免 server 返回 chunked encoding
+        let mut request = format!("GET {} HTTP/1.0\r\n", url.path);
+        request.push_str(&format!("Host: {}\r\n", url.host));
+        let user_agent = self
+            .settings
+            .user_agent
+            .as_deref()
+            .unwrap_or(DEFAULT_USER_AGENT);
+        request.push_str(&format!("User-Agent: {}\r\n", user_agent));
+        if let Some(headers) = &self.settings.headers {
+            for (name, value) in headers {
+                request.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        request
+    }
+}
+
+fn parse_response(response: Vec<u8>) -> Result<Vec<u8>> {
+    let header_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(idx) => idx,
+        None => bail!("bad http response, header not complete"),
+    };
+    let header = String::from_utf8_lossy(&response[..header_end]);
+    let status_line = header.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        debug!("unexpected http response {}", status_line);
+        bail!("unexpected http status {}", status_line);
+    }
+    Ok(response[header_end + 4..].to_vec())
+}
+
+#[test]
+fn test_parse_url() {
+    let url = parse_url("http://example.com:8080/rules/ads.txt").unwrap();
+    assert_eq!(url.host, "example.com");
+    assert_eq!(url.port, 8080);
+    assert_eq!(url.path, "/rules/ads.txt");
+    let url = parse_url("http://example.com").unwrap();
+    assert_eq!(url.port, 80);
+    assert_eq!(url.path, "/");
+    assert!(!url.tls);
+    let url = parse_url("https://example.com/ads.txt").unwrap();
+    assert!(url.tls);
+    assert_eq!(url.port, 443);
+    assert!(parse_url("ftp://example.com").is_err());
+}
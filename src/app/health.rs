@@ -0,0 +1,105 @@
+// outbound 健康状态，连续 dial 失败时熔断
+// 连续失败 failures 次之后熔断 backoff 秒，期间新连接改用 fallback outbound
+// backoff 到期后放行一个连接试探，成功则恢复，失败则再熔断一个 backoff
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::CircuitBreakerSettings;
+
+const DEFAULT_FAILURES: u32 = 3;
+const DEFAULT_BACKOFF: u64 = 30;
+
+#[derive(Default)]
+struct State {
+    consecutive: u32,
+    open_until: Option<Instant>,
+}
+
+pub struct CircuitBreaker {
+    failures: u32,
+    backoff: Duration,
+    // 熔断期间使用的 outbound，没有配置时直接断开
+    pub fallback: Option<String>,
+    state: Mutex<State>,
+}
+
+/// state change caused by a dial result
+#[derive(Debug, PartialEq)]
+pub enum CircuitEvent {
+    Opened,
+    Closed,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: &CircuitBreakerSettings) -> CircuitBreaker {
+        CircuitBreaker {
+            failures: settings.failures.unwrap_or(DEFAULT_FAILURES).max(1),
+            backoff: Duration::from_secs(settings.backoff.unwrap_or(DEFAULT_BACKOFF)),
+            fallback: settings.fallback.clone(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// whether the outbound may be dialed now
+    pub fn allow(&self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if now < until => false,
+            // 到期后放行这一个连接试探，同时重新计时，试探结束前其他连接仍然走 fallback
+            Some(_) => {
+                state.open_until = Some(now + self.backoff);
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().open_until.is_some()
+    }
+
+    pub fn success(&self) -> Option<CircuitEvent> {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive = 0;
+        state.open_until.take().map(|_| CircuitEvent::Closed)
+    }
+
+    pub fn failure(&self) -> Option<CircuitEvent> {
+        self.failure_at(Instant::now())
+    }
+
+    fn failure_at(&self, now: Instant) -> Option<CircuitEvent> {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive = state.consecutive.saturating_add(1);
+        if state.open_until.is_some() || state.consecutive < self.failures {
+            return None;
+        }
+        state.open_until = Some(now + self.backoff);
+        Some(CircuitEvent::Opened)
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let breaker = CircuitBreaker::new(&CircuitBreakerSettings {
+        failures: Some(2),
+        backoff: Some(10),
+        fallback: None,
+    });
+    let now = Instant::now();
+    assert_eq!(breaker.failure_at(now), None);
+    assert_eq!(breaker.failure_at(now), Some(CircuitEvent::Opened));
+    assert!(!breaker.allow_at(now + Duration::from_secs(5)));
+    // 到期后只放行一个试探
+    assert!(breaker.allow_at(now + Duration::from_secs(10)));
+    assert!(!breaker.allow_at(now + Duration::from_secs(11)));
+    assert_eq!(breaker.success(), Some(CircuitEvent::Closed));
+    assert!(breaker.allow_at(now + Duration::from_secs(11)));
+}
@@ -1,77 +1,426 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use futures::FutureExt;
 use futures_util::future::BoxFuture;
 use log::{error, info};
-use std::str::FromStr;
-use std::sync::Arc;
-use std::{collections::HashMap, net::SocketAddr};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, SocketAddr};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
 
 use crate::{
-    config::{Inbound},
+    config::{Inbound, RelayInboundSettings, Socks5InboundSettings, TrojanInboundSettings, TunInboundSettings},
     proxy::{
-        socks::{TcpInboundHandler, UdpInboundHandler}, InboundHandler,
+        echo, relay, trojan, socks::{TcpInboundHandler, UdpInboundHandler}, InboundHandler,
     },
 };
 
-use super::{Dispatcher, InboundListener};
+use super::{supervisor, Dispatcher, DnsServer, InboundListener, Limiter};
+
+// 一个 inbound 的配置与正在运行的 listener
+// 同一协议可以配置任意多个，以 tag 区分
+struct Listener {
+    config: Inbound,
+    // dns 与 tun inbound 没有 handler
+    handler: Option<Arc<InboundHandler>>,
+    limiter: Arc<Limiter>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Listener {
+    fn running(&self) -> bool {
+        self.task.as_ref().map_or(false, |x| !x.is_finished())
+    }
+}
+
+// 没有经过 stop 的 listener（例如 manager 随旧的配置一起 drop）同样关闭 socket
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
 // 统一管理全部 inbound 协议
+// 每个 inbound 是一个独立的 task，可以通过 api 在运行时启动、停止、添加与删除
 pub struct InboundManager {
-    handlers: HashMap<String, Arc<InboundHandler>>,
-    configs: Vec<Inbound>,
+    listeners: Mutex<Vec<Listener>>,
+    dispatcher: Arc<Dispatcher>,
+    // 已经 abort 但可能还没有释放端口的 task，下一次 start 等待它们结束之后再 bind
+    stopping: Mutex<Vec<JoinHandle<()>>>,
+    // run 启动全部 inbound 之后为 true
+    started: watch::Sender<bool>,
+    // 还没有完成需要 root 的初始化（创建 tun 设备）的 inbound，切换用户之前等待
+    setup: Mutex<Vec<oneshot::Receiver<()>>>,
 }
 
-impl InboundManager {
-    pub fn new(config: Vec<Inbound>) -> InboundManager {
-        let mut handlers: HashMap<String, Arc<InboundHandler>> = HashMap::new();
-
-        // 迭代全部的inbound协议，并创建listener
-        for inbound in config.iter() {
-            let handler = match &*inbound.protocol {
-                "socks" => {
-                    let tcp = Arc::new(TcpInboundHandler);
-                    let udp = Arc::new(UdpInboundHandler);
-                    InboundHandler::new(inbound.tag.clone(), Some(tcp), Some(udp))
-                }
-                _ => {
-                    info!("unknown protocol: {} tag: {}", inbound.protocol, inbound.tag);
-                    continue;
-                }
+fn new_handler(inbound: &Inbound) -> Result<Option<InboundHandler>> {
+    let handler = match &*inbound.protocol {
+        "socks" => {
+            let settings = match inbound.settings.as_ref().map(|x| serde_json::from_str::<Socks5InboundSettings>(x.get())) {
+                Some(Ok(x)) => x,
+                Some(Err(err)) => bail!("{}, tag: {}", err, inbound.tag),
+                None => Socks5InboundSettings::default(),
+            };
+            let tcp = match TcpInboundHandler::new(&settings) {
+                Ok(x) => Arc::new(x),
+                Err(err) => bail!("{}, tag: {}", err, inbound.tag),
+            };
+            let udp = Arc::new(UdpInboundHandler);
+            InboundHandler::new(inbound.tag.clone(), Some(tcp), Some(udp))
+        }
+        "echo" => {
+            let tcp = Arc::new(echo::TcpInboundHandler);
+            InboundHandler::new(inbound.tag.clone(), Some(tcp), None)
+        }
+        "relay" => {
+            let settings = match inbound.settings.as_ref().map(|x| serde_json::from_str::<RelayInboundSettings>(x.get())) {
+                Some(Ok(x)) => x,
+                Some(Err(err)) => bail!("{}, tag: {}", err, inbound.tag),
+                None => bail!("no relay settings found! tag: {}", inbound.tag),
             };
-            handlers.insert(inbound.tag.clone(), Arc::new(handler));
+            let tcp = Arc::new(relay::TcpInboundHandler::new(&settings));
+            InboundHandler::new(inbound.tag.clone(), Some(tcp), None)
+        }
+        "trojan" => {
+            let settings = match inbound.settings.as_ref().map(|x| serde_json::from_str::<TrojanInboundSettings>(x.get())) {
+                Some(Ok(x)) => x,
+                Some(Err(err)) => bail!("{}, tag: {}", err, inbound.tag),
+                None => bail!("no trojan settings found! tag: {}", inbound.tag),
+            };
+            let tcp = match trojan::TcpInboundHandler::new(&settings) {
+                Ok(x) => Arc::new(x),
+                Err(err) => bail!("{}, tag: {}", err, inbound.tag),
+            };
+            InboundHandler::new(inbound.tag.clone(), Some(tcp), None)
+        }
+        // dns inbound 不经过 dispatcher，listen 时单独处理
+        "dns" => return Ok(None),
+        // tun 不监听端口，nat 之后的 tcp 连接直接交给 dispatcher
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        "tun" => {
+            tun_settings(inbound)?;
+            return Ok(None);
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        "tun" => bail!("tun inbound is not supported on this platform, tag: {}", inbound.tag),
+        _ => bail!("unknown protocol: {} tag: {}", inbound.protocol, inbound.tag),
+    };
+    Ok(Some(handler))
+}
+
+fn tun_settings(inbound: &Inbound) -> Result<TunInboundSettings> {
+    match inbound.settings.as_ref().map(|x| serde_json::from_str::<TunInboundSettings>(x.get())) {
+        Some(Ok(x)) => Ok(x),
+        Some(Err(err)) => bail!("{}, tag: {}", err, inbound.tag),
+        None => Ok(TunInboundSettings::default()),
+    }
+}
+
+// 创建设备并运行，设备需要 root 权限，失败时只有这个 inbound 不可用
+// 无论成功与否，创建结束之后通过 created 通知，之后才可以切换用户
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run_tun(
+    tag: String,
+    settings: TunInboundSettings,
+    dispatcher: Arc<Dispatcher>,
+    limiter: Arc<Limiter>,
+    created: oneshot::Sender<()>,
+) -> BoxFuture<'static, ()> {
+    use crate::proxy::tun::Tun;
+
+    async move {
+        let tun = Tun::new(&settings, dispatcher, limiter, tag.clone()).await;
+        let _ = created.send(());
+        let tun = match tun {
+            Ok(x) => x,
+            Err(err) => {
+                error!("create tun device failed {}, tag: {}", err, tag);
+                return;
+            }
+        };
+        info!("tun inbound {} started", tag);
+        if let Err(err) = tun.run().await {
+            error!("tun inbound {} stopped {}", tag, err);
+        }
+    }
+    .boxed()
+}
+
+// dns 默认 127.0.0.1:53，tun 没有监听地址，其他协议必须有 port
+// port 可以是范围与列表，每个端口一个 listener，共享同一个 handler
+// listen 是不带端口的 ip，ipv6 可以带或者不带 []
+fn listen_addrs(inbound: &Inbound) -> Result<Vec<SocketAddr>> {
+    let listen = inbound.listen.as_deref().unwrap_or("127.0.0.1");
+    let ip: IpAddr = listen
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|err| anyhow!("invalid listen field {} {}, tag: {}", listen, err, inbound.tag))?;
+    let ports = match (&inbound.port, inbound.protocol.as_str()) {
+        (Some(port), _) => port.ports().map_err(|err| anyhow!("{}, tag: {}", err, inbound.tag))?,
+        (None, "dns") => vec![53],
+        (_, "tun") => return Ok(Vec::new()),
+        (None, _) => bail!("missing port, tag: {}", inbound.tag),
+    };
+    if ports.is_empty() {
+        bail!("missing port, tag: {}", inbound.tag);
+    }
+    Ok(ports.into_iter().map(|port| SocketAddr::new(ip, port)).collect())
+}
+
+impl InboundManager {
+    pub fn new(config: Vec<Inbound>, dispatcher: Arc<Dispatcher>) -> InboundManager {
+        let manager = InboundManager {
+            listeners: Mutex::new(Vec::new()),
+            dispatcher,
+            stopping: Mutex::new(Vec::new()),
+            started: watch::channel(false).0,
+            setup: Mutex::new(Vec::new()),
+        };
+        // 迭代全部的inbound协议，并创建handler，listener 在 run 中启动
+        for inbound in config {
+            if let Err(err) = manager.add(inbound) {
+                error!("{}", err);
+            }
         }
-        InboundManager {
-            handlers,
-            configs: config,
+        manager
+    }
+
+    /// register an inbound, it is not started
+    pub fn add(&self, config: Inbound) -> Result<()> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.iter().any(|x| x.config.tag == config.tag) {
+            bail!("duplicate inbound tag {}", config.tag);
         }
+        listen_addrs(&config)?;
+        let handler = new_handler(&config)?.map(Arc::new);
+        let limiter = Arc::new(self.dispatcher.limiter().inbound(config.max_connections));
+        listeners.push(Listener {
+            config,
+            handler,
+            limiter,
+            task: None,
+        });
+        Ok(())
     }
-    pub fn listen(mut self, dispatcher: Arc<Dispatcher>) -> Result<BoxFuture<'static, ()>> {
+
+    /// stop and unregister an inbound
+    pub fn remove(&self, tag: &str) -> Result<()> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let index = listeners
+            .iter()
+            .position(|x| x.config.tag == tag)
+            .ok_or_else(|| anyhow!("unknown inbound {}", tag))?;
+        let mut listener = listeners.remove(index);
+        if let Some(task) = listener.task.take() {
+            task.abort();
+            self.stopping.lock().unwrap().push(task);
+        }
+        info!("inbound {} removed", tag);
+        Ok(())
+    }
+
+    /// start listening, must be called inside the runtime
+    pub fn start(&self, tag: &str) -> Result<()> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let listener = listeners
+            .iter_mut()
+            .find(|x| x.config.tag == tag)
+            .ok_or_else(|| anyhow!("unknown inbound {}", tag))?;
+        if listener.running() {
+            return Ok(());
+        }
+        if !matches!(&*listener.config.protocol, "dns" | "tun") && listener.handler.is_none() {
+            bail!("no handler for inbound {}", tag);
+        }
         let mut tasks = Vec::new();
-        for config in self.configs {
-            let dispatcher = dispatcher.clone();
-            if let Some(handler) = self.handlers.get_mut(&config.tag) {
-                let Inbound { port, listen, protocol, .. } = config;
-                // 除 tun 外，其他protocol都必须有port
-                let mut future = match protocol.as_str() {
-                    "tun" => {
-                        todo!()
-                    },
-                    _ => {
-                        let addr = match SocketAddr::from_str(format!("{}:{}", listen.unwrap(), port.unwrap()).as_str()) {
-                            Ok(x) => x,
-                            Err(err) => {
-                                error!("invalid listen or port field {}", err);
-                                continue;
-                            }
-                        };
-                        InboundListener::listen(dispatcher, handler.clone(), addr)?
-                    }
-                };
-                tasks.append(&mut future);
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if listener.config.protocol == "tun" {
+            let settings = tun_settings(&listener.config)?;
+            let (created, wait) = oneshot::channel();
+            // 只有第一次启动时等待，之后由 api 启动的 tun 在切换用户之后已经没有权限
+            if !*self.started.borrow() {
+                self.setup.lock().unwrap().push(wait);
+            }
+            let limiter = listener.limiter.clone();
+            tasks.push(run_tun(tag.to_string(), settings, self.dispatcher.clone(), limiter, created));
+        }
+        for addr in listen_addrs(&listener.config)? {
+            let dispatcher = self.dispatcher.clone();
+            let handler = listener.handler.clone();
+            let limiter = listener.limiter.clone();
+            let loops = match handler {
+                Some(handler) => InboundListener::listen(dispatcher, handler, limiter, addr),
+                None => DnsServer::listen(dispatcher.dns_client(), addr),
+            };
+            // panic 之后只重新创建这一个 loop 的 listener
+            for listen in loops {
+                tasks.push(supervisor::supervise(tag.to_string(), addr, listen));
+            }
+        }
+        // 全部端口的 tcp 与 udp listener 作为一个 task
+        let task = match tasks.len() {
+            1 => tasks.remove(0),
+            _ => futures::future::join_all(tasks).map(|_| ()).boxed(),
+        };
+        // stop 之后马上 start 同一个端口时，旧的 socket 可能还没有关闭
+        let stopping: Vec<JoinHandle<()>> = self.stopping.lock().unwrap().drain(..).collect();
+        listener.task = Some(tokio::spawn(async move {
+            futures::future::join_all(stopping).await;
+            task.await
+        }));
+        Ok(())
+    }
+
+    /// stop accepting, established connections are not affected
+    pub fn stop(&self, tag: &str) -> Result<()> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let listener = listeners
+            .iter_mut()
+            .find(|x| x.config.tag == tag)
+            .ok_or_else(|| anyhow!("unknown inbound {}", tag))?;
+        if let Some(task) = listener.task.take() {
+            task.abort();
+            self.stopping.lock().unwrap().push(task);
+            info!("inbound {} stopped", tag);
+        }
+        Ok(())
+    }
+
+    /// stop every inbound and wait until their sockets are closed, e.g. before a reload binds them again
+    pub async fn shutdown(&self) {
+        let mut tasks: Vec<JoinHandle<()>> = self.stopping.lock().unwrap().drain(..).collect();
+        for listener in self.listeners.lock().unwrap().iter_mut() {
+            if let Some(task) = listener.task.take() {
+                task.abort();
+                tasks.push(task);
+            }
+        }
+        futures::future::join_all(tasks).await;
+    }
+
+    pub fn snapshot(&self) -> serde_json::Value {
+        let listeners = self.listeners.lock().unwrap();
+        let inbounds: Vec<_> = listeners
+            .iter()
+            .map(|x| {
+                json!({
+                    "tag": x.config.tag,
+                    "protocol": x.config.protocol,
+                    "listen": listen_addrs(&x.config)
+                        .map(|x| x.iter().map(|x| x.to_string()).collect())
+                        .unwrap_or_else(|_| Vec::new()),
+                    "running": x.running(),
+                    "connections": x.limiter.snapshot(),
+                })
+            })
+            .collect();
+        json!({ "inbounds": inbounds, "connections": self.dispatcher.limiter().snapshot() })
+    }
+
+    /// start every registered inbound, the future never completes
+    pub fn run(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        async move {
+            let tags: Vec<String> = self
+                .listeners
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|x| x.config.tag.clone())
+                .collect();
+            for tag in tags {
+                if let Err(err) = self.start(&tag) {
+                    error!("{}", err);
+                }
             }
+            self.started.send_replace(true);
+            // 之后的启动与停止由 api 完成
+            futures::future::pending::<()>().await;
         }
-        Ok(async {
-            futures::future::join_all(tasks).await;
-            let a = 1;
-        }.boxed())
+        .boxed()
+    }
+
+    /// resolves once the inbounds started by run finished their privileged setup, e.g. creating tun devices
+    pub async fn setup_done(&self) {
+        let _ = self.started.subscribe().wait_for(|x| *x).await;
+        let pending: Vec<_> = self.setup.lock().unwrap().drain(..).collect();
+        futures::future::join_all(pending).await;
     }
 }
+
+#[test]
+fn test_inbound_config() {
+    use crate::config::PortList;
+
+    let inbound = |protocol: &str, port: Option<u16>, settings: Option<&str>| Inbound {
+        port: port.map(PortList::Port),
+        listen: None,
+        protocol: protocol.to_string(),
+        tag: format!("{}-in", protocol),
+        max_connections: None,
+        settings: settings.map(|x| serde_json::value::RawValue::from_string(x.to_string()).unwrap()),
+    };
+    assert_eq!(listen_addrs(&inbound("dns", None, None)).unwrap(), vec!["127.0.0.1:53".parse().unwrap()]);
+    assert_eq!(listen_addrs(&inbound("socks", Some(1080), None)).unwrap(), vec!["127.0.0.1:1080".parse().unwrap()]);
+    assert!(listen_addrs(&inbound("socks", None, None)).is_err());
+    let mut multi = inbound("socks", None, None);
+    multi.port = Some(PortList::Spec("1080-1081,8118".to_string()));
+    assert_eq!(listen_addrs(&multi).unwrap().len(), 3);
+    let mut ipv6 = inbound("socks", Some(1080), None);
+    ipv6.listen = Some("::1".to_string());
+    assert_eq!(listen_addrs(&ipv6).unwrap(), vec!["[::1]:1080".parse().unwrap()]);
+    ipv6.listen = Some("[::]".to_string());
+    assert_eq!(listen_addrs(&ipv6).unwrap(), vec!["[::]:1080".parse().unwrap()]);
+    ipv6.listen = Some("localhost".to_string());
+    assert!(listen_addrs(&ipv6).is_err());
+    assert!(new_handler(&inbound("dns", None, None)).unwrap().is_none());
+    assert!(new_handler(&inbound("echo", Some(7), None)).unwrap().is_some());
+    assert!(new_handler(&inbound("relay", Some(7), None)).is_err());
+    // tun 没有端口，settings 在注册时检查
+    let tun = inbound("tun", None, Some(r#"{"mtu": 9000, "dns-hijack": true, "tcp": {"mss-clamp": false}}"#));
+    assert!(listen_addrs(&tun).unwrap().is_empty());
+    let settings = tun_settings(&tun).unwrap();
+    assert_eq!(settings.mtu, Some(9000));
+    assert!(settings.dns_hijack);
+    assert_eq!(settings.tcp.mss_clamp, Some(false));
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    assert!(new_handler(&tun).unwrap().is_none());
+    assert!(new_handler(&inbound("tun", None, Some(r#"{"mtu": "large"}"#))).is_err());
+    assert!(new_handler(&inbound("unknown", Some(7), None)).is_err());
+}
+
+#[tokio::test]
+async fn test_inbound_restart() {
+    use crate::{config::PortList, testing::Harness};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    // 由系统分配一个空闲端口，inbound 需要固定的端口才能在重启之后连接同一个地址
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let echo = Inbound {
+        port: Some(PortList::Port(port)),
+        listen: None,
+        protocol: "echo".to_string(),
+        tag: "echo-in".to_string(),
+        max_connections: None,
+        settings: None,
+    };
+    let manager = InboundManager::new(vec![echo], Harness::new(Default::default()).dispatcher());
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let connect = || async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        TcpStream::connect(addr).await.is_ok()
+    };
+    manager.start("echo-in").unwrap();
+    assert!(connect().await);
+    // 旧的 socket 关闭之后才重新 bind
+    manager.stop("echo-in").unwrap();
+    manager.start("echo-in").unwrap();
+    assert!(connect().await);
+    manager.shutdown().await;
+    assert!(!connect().await);
+}
@@ -0,0 +1,271 @@
+// 并发连接限制
+// 全局一个 limiter（general.max_connections，全部 profile 共享），每个 inbound 一个（inbound.max_connections），连接需要同时拿到两者的许可
+// 超过限制的连接进入等待队列，最多等待 pending_timeout，超时后关闭
+// 队列已满时 listener 暂停 accept，新连接留在内核 backlog 中，backlog 满了之后由内核拒绝
+// tun 的 tcp 连接与 udp flow 同样计入所属 inbound 的 limiter，队列已满时直接拒绝
+// 不限制时只做计数
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::config::GeneralSettings;
+
+const DEFAULT_PENDING_CONNECTIONS: usize = 64;
+const DEFAULT_PENDING_TIMEOUT: u64 = 10;
+
+pub struct Limiter {
+    max: Option<usize>,
+    semaphore: Option<Arc<Semaphore>>,
+    // inbound 的 limiter 指向全局 limiter
+    parent: Option<Arc<Limiter>>,
+    pending: usize,
+    pending_timeout: Duration,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    dequeued: Notify,
+    accepted: AtomicU64,
+    delayed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// released when the connection ends
+pub struct Permit {
+    limiters: Vec<Arc<Limiter>>,
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        for limiter in &self.limiters {
+            limiter.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub enum Admission {
+    Ready(Permit),
+    Queued(Ticket),
+    Rejected,
+}
+
+/// a place in the pending queue, given up when dropped
+pub struct Ticket {
+    limiter: Arc<Limiter>,
+    chain: Vec<Arc<Limiter>>,
+}
+
+impl Ticket {
+    /// wait for a slot, None if it is not available within pending_timeout
+    pub async fn wait(self) -> Option<Permit> {
+        let chain = self.chain.clone();
+        let acquire = async {
+            let mut permits = Vec::new();
+            // 先等待 inbound 自己的许可，排队的连接不会占住全局的位置，影响其他 inbound
+            for semaphore in chain.iter().rev().filter_map(|x| x.semaphore.as_ref()) {
+                permits.push(semaphore.clone().acquire_owned().await.ok()?);
+            }
+            Some(permits)
+        };
+        let result = tokio::time::timeout(self.limiter.pending_timeout, acquire).await;
+        let limiter = self.limiter.clone();
+        // 先离开队列
+        drop(self);
+        match result {
+            Ok(Some(permits)) => Some(Limiter::grant(chain, permits)),
+            _ => {
+                limiter.reject(&chain);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.limiter.queued.fetch_sub(1, Ordering::AcqRel);
+        self.limiter.dequeued.notify_waiters();
+    }
+}
+
+impl Limiter {
+    fn new(max: Option<usize>, parent: Option<Arc<Limiter>>, pending: usize, pending_timeout: Duration) -> Limiter {
+        Limiter {
+            max,
+            semaphore: max.map(|x| Arc::new(Semaphore::new(x))),
+            parent,
+            pending,
+            pending_timeout,
+            active: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            dequeued: Notify::new(),
+            accepted: AtomicU64::new(0),
+            delayed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn global(general: &GeneralSettings) -> Limiter {
+        Limiter::new(
+            general.max_connections,
+            None,
+            general.pending_connections.unwrap_or(DEFAULT_PENDING_CONNECTIONS),
+            Duration::from_secs(general.pending_timeout.unwrap_or(DEFAULT_PENDING_TIMEOUT)),
+        )
+    }
+
+    /// limiter of an inbound, connections also count against self
+    pub fn inbound(self: &Arc<Self>, max: Option<usize>) -> Limiter {
+        Limiter::new(max, Some(self.clone()), self.pending, self.pending_timeout)
+    }
+
+    // 全局在前；try_acquire 不等待，wait 从 inbound 自己开始等待
+    fn chain(self: &Arc<Self>) -> Vec<Arc<Limiter>> {
+        let mut chain: Vec<Arc<Limiter>> = self.parent.iter().cloned().collect();
+        chain.push(self.clone());
+        chain
+    }
+
+    fn try_acquire(chain: &[Arc<Limiter>]) -> Option<Vec<OwnedSemaphorePermit>> {
+        let mut permits = Vec::new();
+        for semaphore in chain.iter().filter_map(|x| x.semaphore.as_ref()) {
+            permits.push(semaphore.clone().try_acquire_owned().ok()?);
+        }
+        Some(permits)
+    }
+
+    /// takes a slot or a place in the pending queue right away, call it before handing the connection to a task
+    pub fn admit(self: &Arc<Self>) -> Admission {
+        let chain = self.chain();
+        if let Some(permits) = Limiter::try_acquire(&chain) {
+            return Admission::Ready(Limiter::grant(chain, permits));
+        }
+        // 读取与占用队列位置是同一个原子操作，并发的连接不会超过 pending
+        let pending = self.pending;
+        let queued = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| if x < pending { Some(x + 1) } else { None });
+        if queued.is_err() {
+            self.reject(&chain);
+            return Admission::Rejected;
+        }
+        self.delayed.fetch_add(1, Ordering::Relaxed);
+        Admission::Queued(Ticket {
+            limiter: self.clone(),
+            chain,
+        })
+    }
+
+    /// wait for a slot, None if the connection should be rejected
+    pub async fn acquire(self: &Arc<Self>) -> Option<Permit> {
+        match self.admit() {
+            Admission::Ready(permit) => Some(permit),
+            Admission::Queued(ticket) => ticket.wait().await,
+            Admission::Rejected => None,
+        }
+    }
+
+    fn grant(chain: Vec<Arc<Limiter>>, permits: Vec<OwnedSemaphorePermit>) -> Permit {
+        for limiter in &chain {
+            limiter.active.fetch_add(1, Ordering::Relaxed);
+            limiter.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+        Permit {
+            limiters: chain,
+            _permits: permits,
+        }
+    }
+
+    fn reject(&self, chain: &[Arc<Limiter>]) {
+        for limiter in chain {
+            limiter.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// returns once the pending queue has room, the listener stops accepting meanwhile
+    pub async fn wait_queue(&self) {
+        loop {
+            let dequeued = self.dequeued.notified();
+            if self.pending == 0 || self.queued.load(Ordering::Acquire) < self.pending {
+                return;
+            }
+            dequeued.await;
+        }
+    }
+
+    /// {"max", "active", "queued", "accepted", "delayed", "rejected"}
+    pub fn snapshot(&self) -> Value {
+        json!({
+            "max": self.max,
+            "active": self.active.load(Ordering::Relaxed),
+            "queued": self.queued.load(Ordering::Relaxed),
+            "accepted": self.accepted.load(Ordering::Relaxed),
+            "delayed": self.delayed.load(Ordering::Relaxed),
+            "rejected": self.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_limiter() {
+    let global = Arc::new(Limiter::new(Some(2), None, 1, Duration::from_millis(50)));
+    let a = Arc::new(global.inbound(Some(1)));
+    let b = Arc::new(global.inbound(None));
+    let first = a.acquire().await.unwrap();
+    // a 已满，等待超时后拒绝
+    assert!(a.acquire().await.is_none());
+    let second = b.acquire().await.unwrap();
+    // 全局已满
+    assert!(b.acquire().await.is_none());
+    drop(first);
+    let _third = b.acquire().await.unwrap();
+    drop(second);
+    assert_eq!(global.snapshot()["active"], 1);
+    assert_eq!(global.snapshot()["accepted"], 3);
+    assert_eq!(global.snapshot()["rejected"], 2);
+    assert_eq!(a.snapshot()["rejected"], 1);
+    assert_eq!(b.snapshot()["delayed"], 1);
+}
+
+#[tokio::test]
+async fn test_limiter_admit() {
+    let limiter = Arc::new(Limiter::new(Some(1), None, 1, Duration::from_millis(50)));
+    let first = match limiter.admit() {
+        Admission::Ready(x) => x,
+        _ => panic!("expect ready"),
+    };
+    let ticket = match limiter.admit() {
+        Admission::Queued(x) => x,
+        _ => panic!("expect queued"),
+    };
+    // 队列已满，不等待直接拒绝
+    assert!(matches!(limiter.admit(), Admission::Rejected));
+    drop(first);
+    let _second = ticket.wait().await.unwrap();
+    assert_eq!(limiter.snapshot()["queued"], 0);
+    assert_eq!(limiter.snapshot()["rejected"], 1);
+}
+
+#[tokio::test]
+async fn test_limiter_queued_keeps_global() {
+    let global = Arc::new(Limiter::new(Some(2), None, 1, Duration::from_secs(1)));
+    let a = Arc::new(global.inbound(Some(1)));
+    let b = Arc::new(global.inbound(None));
+    let _first = a.acquire().await.unwrap();
+    let ticket = match a.admit() {
+        Admission::Queued(x) => x,
+        _ => panic!("expect queued"),
+    };
+    let waiting = tokio::spawn(ticket.wait());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    // a 排队的连接没有占用全局剩下的位置
+    assert!(matches!(b.admit(), Admission::Ready(_)));
+    waiting.abort();
+}
@@ -1,28 +1,31 @@
 use futures_util::{future::BoxFuture, FutureExt, StreamExt};
-use log::{error, info};
-use std::{io::Result, net::SocketAddr, sync::Arc};
+use log::{debug, error, info};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
     net::{TcpListener, UdpSocket},
 };
 
 use crate::{
+    common::systemd,
     proxy::{
-        Address, AnyInboundHandler, InboundResult, Network, Session,
-        TcpInboundHandlerTrait,
+        Address, AnyInboundHandler, Carried, InboundResult, Network, Session,
+        TcpInboundHandlerTrait, UdpFlow,
     },
 };
 
-use super::dispatcher::Dispatcher;
+use super::{dispatcher::Dispatcher, supervisor::{self, Listen}, trace, Admission, Limiter};
 
 pub struct InboundListener {}
 type TaskFuture = BoxFuture<'static, ()>;
 impl InboundListener {
+    /// tcp and udp accept loops on addr, supervised separately so a failing one does not restart the other
     pub fn listen(
         dispatcher: Arc<Dispatcher>,
         handler: AnyInboundHandler,
+        limiter: Arc<Limiter>,
         addr: SocketAddr,
-    ) -> Result<Vec<TaskFuture>> {
-        let mut tasks: Vec<TaskFuture> = vec![];
+    ) -> Vec<Listen> {
+        let mut loops: Vec<Listen> = vec![];
         if handler.has_tcp() {
             // 最初是 self.tcp_listener 写法，但会报
             // `self` does not live long enough, borrowed value does not live long enough. rustcE0597
@@ -33,54 +36,132 @@ impl InboundListener {
             // 2. tcp_listener 不能依赖self，listen调用 dispatcher.clone() 后将 cloned dispatcher 传给 tcp_listener
             // 这就要求 tcp_listener 改为 InboundListener
             // 实在不想在 listen 糅合一堆代码，我在这里采用 2
-            let f =
-                InboundListener::tcp_listener(handler.clone(), dispatcher.clone(), addr);
-            tasks.push(f);
+            let (handler, dispatcher) = (handler.clone(), dispatcher.clone());
+            loops.push(Box::new(move || {
+                InboundListener::tcp_listener(handler.clone(), dispatcher.clone(), limiter.clone(), addr)
+            }));
         }
         if handler.has_udp() {
-            let f =
-                InboundListener::udp_listener(handler.clone(), dispatcher.clone(), addr);
-            tasks.push(f);
+            loops.push(Box::new(move || {
+                InboundListener::udp_listener(handler.clone(), dispatcher.clone(), addr)
+            }));
         }
-        Ok(tasks)
+        loops
     }
     fn tcp_listener(
         handler: AnyInboundHandler,
         dispatcher: Arc<Dispatcher>,
+        limiter: Arc<Limiter>,
         addr: SocketAddr,
     ) -> TaskFuture {
         let task = async move {
-            let listener = TcpListener::bind(addr).await.unwrap();
+            // systemd socket activation 时使用传入的 socket
+            let listener = match systemd::listener(handler.tag(), addr) {
+                Some(listener) => listener.set_nonblocking(true).and_then(|_| TcpListener::from_std(listener)),
+                None => TcpListener::bind(addr).await,
+            };
+            let listener = match listener {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("bind {} failed {}", addr, err);
+                    return;
+                }
+            };
             info!("Tcp listening at {}", addr);
             loop {
+                // 等待队列已满时暂停 accept
+                limiter.wait_queue().await;
                 match listener.accept().await {
-                    Ok((conn, _)) => {
+                    Ok((conn, peer)) => {
                         let dispatcher = Arc::clone(&dispatcher);
                         let handler = handler.clone();
-                        tokio::spawn(async move {
-                            let addr = conn.peer_addr().expect("peer");
-                            let local = conn.local_addr().expect("local");
+                        // accept 之后马上占用许可或者队列位置，wait_queue 看到的计数不会被并发的连接超过
+                        let admission = limiter.admit();
+                        let id = Session::next_id();
+                        supervisor::spawn(handler.tag(), peer, trace::scope(id, async move {
+                            // 连接结束时释放
+                            let permit = match admission {
+                                Admission::Ready(x) => Some(x),
+                                Admission::Queued(ticket) => ticket.wait().await,
+                                Admission::Rejected => None,
+                            };
+                            let _permit = match permit {
+                                Some(x) => x,
+                                None => {
+                                    debug!("sid={} too many connections, {} from {} closed", id, handler.tag(), peer);
+                                    trace::event("closed, too many connections");
+                                    return;
+                                }
+                            };
+                            // 排队期间对端可能已经关闭
+                            let local = match conn.local_addr() {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    debug!("sid={} connection from {} closed while queued {}", id, peer, err);
+                                    return;
+                                }
+                            };
+                            let tag = handler.tag().to_string();
+                            trace::event(format_args!("accepted by inbound {} from {} on {}", tag, peer, local));
                             let session = Session {
-                                destination: Address::Ip(addr),
+                                id,
+                                destination: Address::Ip(peer),
                                 network: Network::TCP,
                                 local_peer: local,
-                                peer_address: conn.peer_addr().expect("peer")
+                                peer_address: peer,
+                                user: None,
+                                inbound_tag: Some(tag.clone()),
+                                app_protocol: None,
+                                process: None,
+                                uid: None,
                             };
+                            // inbound 返回的 session 可能是重新构造的，统一设置 inbound_tag 与 id
                             match TcpInboundHandlerTrait::handle(&*handler, session, conn).await {
                                 Ok(InboundResult::Stream(stream, mut sess)) => {
+                                    sess.id = id;
+                                    sess.inbound_tag = Some(tag);
+                                    trace::event(format_args!("inbound handshake done, destination {}", sess.destination));
                                     dispatcher.dispatch_tcp(stream, &mut sess).await;
                                 }
-                                Ok(InboundResult::Datagram(socket, sess)) => {
-                                    dispatcher.dispatch_udp(socket, sess).await;
+                                Ok(InboundResult::Datagram(socket, mut sess)) => {
+                                    sess.id = id;
+                                    sess.inbound_tag = Some(tag);
+                                    trace::event(format_args!("inbound handshake done, udp to {}", sess.destination));
+                                    dispatcher.dispatch_udp(UdpFlow::connected(socket), sess).await;
+                                }
+                                Ok(InboundResult::Tunneled(stream, mut sess)) => {
+                                    sess.id = id;
+                                    sess.inbound_tag = Some(tag);
+                                    trace::event(format_args!("inbound handshake done, destination {}", sess.destination));
+                                    dispatcher.dispatch_stream(stream, &mut sess, || {}).await;
+                                }
+                                Ok(InboundResult::Handled) => trace::event("handled by inbound"),
+                                Ok(InboundResult::Streams(mut streams)) => {
+                                    // 多路复用的每个 stream 与 udp flow 是独立的 session
+                                    while let Some((carried, mut sess)) = streams.recv().await {
+                                        sess.id = Session::next_id();
+                                        sess.inbound_tag = Some(tag.clone());
+                                        trace::event(format_args!("stream sid={} to {}", sess.id, sess.destination));
+                                        let dispatcher = dispatcher.clone();
+                                        let context = format!("{} => {}", sess.peer_address, sess.destination);
+                                        supervisor::spawn(&tag, context, trace::scope(sess.id, async move {
+                                            trace::event(format_args!("stream of connection sid={} to {}", id, sess.destination));
+                                            match carried {
+                                                Carried::Stream(stream) => dispatcher.dispatch_stream(stream, &mut sess, || {}).await,
+                                                Carried::Flow(flow) => dispatcher.dispatch_udp(flow, sess).await,
+                                            }
+                                        }));
+                                    }
                                 }
                                 Ok(InboundResult::NOT_SUPPORTED) => {
-                                    error!("not supported");
+                                    error!("sid={} not supported", id);
                                 }
                                 Err(err) => {
-                                    error!("handle tcp inbound failed err {}", err);
+                                    trace::event(format_args!("inbound handshake failed {}", err));
+                                    error!("sid={} handle tcp inbound failed err {}", id, err);
                                 }
                             }
-                        });
+                        }));
                     }
                     Err(err) => {
                         error!("accept error {}", err);
@@ -97,12 +178,16 @@ impl InboundListener {
         _dispatcher: Arc<Dispatcher>,
         addr: SocketAddr,
     ) -> TaskFuture {
-        let future = async move {
-            let _listener = UdpSocket::bind(addr).await.unwrap();
+        // udp 目前只占用端口，socks 的 udp 经由 UDP ASSOCIATE 建立的 socket 转发
+        async move {
+            let _listener = match UdpSocket::bind(addr).await {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("bind udp {} failed {}", addr, err);
+                    return;
+                }
+            };
             info!("Udp listen at {}", addr);
-            ()
-        }.boxed();
-        // todo!("udp listener")
-        future
+        }.boxed()
     }
 }
@@ -8,9 +8,25 @@ pub use dispatcher::Dispatcher;
 mod dns_client;
 pub use dns_client::DnsClient;
 
+mod dns_upstream;
+pub use dns_upstream::QuicUpstream;
+
+mod blocklist;
+pub use blocklist::{Blocklist, BlockResponse};
+
+mod dns_server;
+pub use dns_server::DnsServer;
+
 mod listener;
 pub use listener::InboundListener;
 
+mod limiter;
+pub use limiter::{Admission, Limiter};
+
+pub mod supervisor;
+
+pub mod trace;
+
 
 mod inbound;
 pub use inbound::InboundManager;
@@ -18,8 +34,39 @@ pub use inbound::InboundManager;
 mod outbound;
 pub use outbound::OutboundManager;
 
+mod server_cache;
+pub use server_cache::ServerCache;
+
+mod health;
+pub use health::{CircuitBreaker, CircuitEvent};
+
 mod sniffer;
-pub use sniffer::Sniffer;
+pub use sniffer::{QuicSniff, QuicSniffer, Sniffer};
 
 mod router;
-pub use router::Router;
+pub use router::{DomainSet, Router};
+
+mod rule_provider;
+pub use rule_provider::{provider_cache_path, RuleProviders, RuleSet};
+
+mod fetcher;
+pub use fetcher::Fetcher;
+
+pub mod bench;
+
+pub mod doctor;
+
+mod rewrite;
+pub use rewrite::Rewriter;
+
+mod capture;
+pub use capture::{capture_dir, Recorder};
+
+mod stats;
+pub use stats::Stats;
+
+mod events;
+pub use events::{EventListener, Events, SessionStats};
+
+mod api;
+pub use api::ApiServer;
@@ -1,24 +1,57 @@
 use std::{collections::HashMap, sync::Arc, convert::TryFrom};
+use futures::future::BoxFuture;
 use anyhow::{
     Result
 };
-use log::{error, info};
+use log::{error, info, warn};
+use tokio::sync::RwLock;
+
+use super::{CircuitBreaker, DnsClient, ServerCache};
 
 use crate::{
-    config::{Outbound, Socks5OutboundSettings},
-    proxy::{socks, OutboundHandler, Address, direct},
+    common::{monitor::DefaultInterface, ratelimit::Bandwidth},
+    config::{DialerSettings, Hysteria2OutboundSettings, Outbound, RejectOutboundSettings, RelayOutboundSettings, ShadowsocksOutboundSettings, Socks5OutboundSettings, TrojanOutboundSettings},
+    proxy::{socks, OutboundHandler, Address, ConnectionPool, Dialer, direct, reject, blackhole, relay, hysteria, shadowsocks, trojan, UdpLimit, UdpOverTcp, UdpOversizePolicy, ResolveStrategy},
 };
 
 // 管理全部的传出协议 outbound
 pub struct OutboundManager {
     pub handlers: HashMap<String, Arc<OutboundHandler>>,
+    // 配置了 circuit_breaker 的 outbound
+    pub circuits: HashMap<String, Arc<CircuitBreaker>>,
+    // 全部 outbound 的代理服务器域名
+    pub servers: Arc<ServerCache>,
+    // 有 outbound 开启 auto_detect_interface 时跟踪默认路由的网卡
+    pub default_interface: Option<Arc<DefaultInterface>>,
 }
 
 impl OutboundManager {
-    pub fn new(outbounds: Vec<Outbound>) -> Result<OutboundManager> {
+    pub fn new(outbounds: Vec<Outbound>, dialer: Option<DialerSettings>) -> Result<OutboundManager> {
         let mut handlers = HashMap::new();
+        let mut circuits = HashMap::new();
+        let servers = Arc::new(ServerCache::default());
+        let mut default_interface = None;
+        let global = dialer.unwrap_or_default();
         for outbound in outbounds.iter() {
-            let handler = match &*outbound.protocol {
+            let settings = match &outbound.dialer {
+                Some(x) => x.or(&global),
+                None => global.clone(),
+            };
+            let dialer = match Dialer::new(Some(&settings)) {
+                Ok(mut x) => {
+                    x.servers = Some(servers.clone());
+                    if settings.auto_detect_interface.unwrap_or(false) {
+                        let interface = default_interface.get_or_insert_with(|| Arc::new(DefaultInterface::default()));
+                        x.default_interface = Some(interface.clone());
+                    }
+                    Arc::new(x)
+                }
+                Err(err) => {
+                    error!("{}, tag: {}", err, outbound.tag);
+                    continue;
+                }
+            };
+            let mut handler = match &*outbound.protocol {
                 "socks" => {
                     let socks_settings = match &outbound.settings {
                         Some(settings) => match serde_json::from_str::<Socks5OutboundSettings>(settings.get()) {
@@ -42,35 +75,217 @@ impl OutboundManager {
                             continue
                         }
                     };
+                    servers.add(&addr);
                     let tcp = Arc::new(socks::TcpOutboundHandler {
-                        address: addr
-                    });
-                    let udp = Arc::new(socks::UdpOutboundHandler {
-                        addr: socks_settings.address.clone(),
-                        port: socks_settings.port,
+                        address: addr,
+                        dialer: dialer.clone(),
+                        pool: outbound.pool.as_ref().map(|x| Arc::new(ConnectionPool::new(x))),
                     });
-                    Arc::new(OutboundHandler::new(
-                        outbound.tag.clone(),
-                        Some(tcp),
-                        Some(udp),
-                    ))
+                    // 还没有实现 UDP ASSOCIATE，udp 只能经由 udp_over_tcp 转发
+                    OutboundHandler::new(outbound.tag.clone(), Some(tcp), None)
                 }
                 "shadowsocks" => {
-                    todo!()
+                    let ss_settings = match outbound.settings.as_ref().map(|x| serde_json::from_str::<ShadowsocksOutboundSettings>(x.get())) {
+                        Some(Ok(res)) => res,
+                        Some(Err(err)) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                        None => {
+                            error!("no shadowsocks settings found!");
+                            continue;
+                        }
+                    };
+                    if let Some(plugin) = &ss_settings.plugin {
+                        info!("shadowsocks plugin {} {:?}, tag: {}", plugin, ss_settings.plugin_opts, outbound.tag);
+                    }
+                    let tcp = match shadowsocks::TcpOutboundHandler::new(&ss_settings, dialer.clone()) {
+                        Ok(x) => {
+                            servers.add(x.server());
+                            Arc::new(x)
+                        }
+                        Err(err) => {
+                            error!("bad shadowsocks settings {}, tag: {}", err, outbound.tag);
+                            continue;
+                        }
+                    };
+                    OutboundHandler::new(outbound.tag.clone(), Some(tcp), None)
                 }
                 "direct" => {
-                    let tcp = Arc::new(direct::TcpOutboundHandler{});
-                    let udp = Arc::new(direct::UdpOutboundHandler{});
-                    Arc::new(OutboundHandler::new(outbound.tag.clone(), Some(tcp), Some(udp)))
+                    let tcp = Arc::new(direct::TcpOutboundHandler{ dialer: dialer.clone() });
+                    let udp = Arc::new(direct::UdpOutboundHandler{ dialer: dialer.clone() });
+                    OutboundHandler::new(outbound.tag.clone(), Some(tcp), Some(udp))
+                }
+                "relay" => {
+                    let relay_settings = match outbound.settings.as_ref().map(|x| serde_json::from_str::<RelayOutboundSettings>(x.get())) {
+                        Some(Ok(res)) => res,
+                        Some(Err(err)) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                        None => {
+                            error!("no relay settings found!");
+                            continue;
+                        }
+                    };
+                    let tcp = match relay::TcpOutboundHandler::new(&relay_settings, dialer.clone()) {
+                        Ok(x) => {
+                            servers.add(x.server());
+                            Arc::new(x)
+                        }
+                        Err(err) => {
+                            error!("bad relay server {}, tag: {}", err, outbound.tag);
+                            continue;
+                        }
+                    };
+                    // udp 经由 mux stream 转发，不需要配置 udp_over_tcp
+                    let mut handler = OutboundHandler::new(outbound.tag.clone(), Some(tcp.clone()), None);
+                    handler.udp_over_tcp = Some(UdpOverTcp::new(tcp));
+                    handler
+                }
+                "trojan" => {
+                    let trojan_settings = match outbound.settings.as_ref().map(|x| serde_json::from_str::<TrojanOutboundSettings>(x.get())) {
+                        Some(Ok(res)) => res,
+                        Some(Err(err)) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                        None => {
+                            error!("no trojan settings found!");
+                            continue;
+                        }
+                    };
+                    let tcp = match trojan::TcpOutboundHandler::new(&trojan_settings, dialer.clone(), outbound.pool.as_ref()) {
+                        Ok(x) => {
+                            servers.add(x.server());
+                            Arc::new(x)
+                        }
+                        Err(err) => {
+                            error!("bad trojan settings {}, tag: {}", err, outbound.tag);
+                            continue;
+                        }
+                    };
+                    OutboundHandler::new(outbound.tag.clone(), Some(tcp), None)
+                }
+                "hysteria2" => {
+                    let hysteria_settings = match outbound.settings.as_ref().map(|x| serde_json::from_str::<Hysteria2OutboundSettings>(x.get())) {
+                        Some(Ok(res)) => res,
+                        Some(Err(err)) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                        None => {
+                            error!("no hysteria2 settings found!");
+                            continue;
+                        }
+                    };
+                    let tcp = match hysteria::TcpOutboundHandler::new(&hysteria_settings, dialer.clone()) {
+                        Ok(x) => {
+                            servers.add(x.server());
+                            Arc::new(x)
+                        }
+                        Err(err) => {
+                            error!("bad hysteria2 settings {}, tag: {}", err, outbound.tag);
+                            continue;
+                        }
+                    };
+                    // udp datagram 还没有实现，路由到这里的 udp 被丢弃
+                    OutboundHandler::new(outbound.tag.clone(), Some(tcp), None)
+                }
+                "reject" => {
+                    let tcp = Arc::new(reject::TcpOutboundHandler{});
+                    let udp = Arc::new(reject::UdpOutboundHandler{});
+                    let mut handler = OutboundHandler::new(outbound.tag.clone(), Some(tcp), Some(udp));
+                    // 没有 settings 时只 RST
+                    match outbound.settings.as_ref().map(|x| serde_json::from_str::<RejectOutboundSettings>(x.get())) {
+                        Some(Ok(settings)) => match reject::BlockPage::new(&settings) {
+                            Ok(page) => handler.block_page = Some(Arc::new(page)),
+                            Err(err) => error!("{}, tag: {}", err, outbound.tag),
+                        },
+                        Some(Err(err)) => error!("{}, tag: {}", err, outbound.tag),
+                        None => {}
+                    }
+                    handler
+                }
+                "blackhole" => {
+                    let tcp = Arc::new(blackhole::TcpOutboundHandler{});
+                    let udp = Arc::new(blackhole::UdpOutboundHandler{});
+                    OutboundHandler::new(outbound.tag.clone(), Some(tcp), Some(udp))
                 }
                 _ => {
                     info!("found unsupported outbound {}", outbound.tag);
                     continue;
                 }
             };
-            handlers.insert(outbound.tag.clone(), handler);
+            let policy = match &outbound.udp_oversize {
+                Some(p) => match UdpOversizePolicy::try_from(p.as_str()) {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!("{}, tag: {}", err, outbound.tag);
+                        continue;
+                    }
+                },
+                None => UdpOversizePolicy::Drop,
+            };
+            handler.udp_limit = UdpLimit::new(&outbound.protocol, outbound.udp_max_payload, policy);
+            handler.bandwidth = Bandwidth::new(outbound.max_up, outbound.max_down);
+            if let Some(resolve) = &outbound.resolve {
+                match ResolveStrategy::try_from(resolve.as_str()) {
+                    // direct 自己用 DnsClient 解析，在 dispatcher 里再解析一次没有意义
+                    Ok(_) if outbound.protocol == "direct" => {
+                        warn!("resolve is ignored by direct outbound, tag: {}", outbound.tag)
+                    }
+                    Ok(x) => handler.resolve = x,
+                    Err(err) => {
+                        error!("{}, tag: {}", err, outbound.tag);
+                        continue;
+                    }
+                }
+            }
+            if outbound.udp_over_tcp.unwrap_or(false) {
+                match &handler.tcp_handler {
+                    Some(tcp) => handler.udp_over_tcp = Some(UdpOverTcp::new(tcp.clone())),
+                    None => error!("udp over tcp needs a tcp outbound, tag: {}", outbound.tag),
+                }
+            }
+            if let Some(settings) = &outbound.circuit_breaker {
+                circuits.insert(outbound.tag.clone(), Arc::new(CircuitBreaker::new(settings)));
+            }
+            handlers.insert(outbound.tag.clone(), Arc::new(handler));
+        }
+        for (tag, circuit) in &circuits {
+            match &circuit.fallback {
+                Some(fallback) if fallback == tag => error!("circuit breaker fallback of {} is itself", tag),
+                Some(fallback) if !handlers.contains_key(fallback) => {
+                    error!("circuit breaker fallback {} of {} not found", fallback, tag)
+                }
+                _ => {}
+            }
         }
-        Ok(OutboundManager { handlers })
+        Ok(OutboundManager { handlers, circuits, servers, default_interface })
+    }
+
+    /// keep the proxy server addresses resolved, None if no outbound has a server domain
+    pub fn server_resolver(&self, dns_client: Arc<RwLock<DnsClient>>) -> Option<BoxFuture<'static, ()>> {
+        if self.servers.is_empty() {
+            return None;
+        }
+        Some(self.servers.clone().refresh(dns_client))
+    }
+
+    /// follow default route changes, None if no outbound auto detects its interface
+    pub fn interface_monitor(&self) -> Option<BoxFuture<'static, ()>> {
+        self.default_interface.clone().map(|x| x.watch())
+    }
+
+    pub fn get_circuit(&self, tag: &str) -> Option<Arc<CircuitBreaker>> {
+        self.circuits.get(tag).cloned()
+    }
+    /// tags of all outbounds, sorted
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self.handlers.keys().cloned().collect();
+        tags.sort();
+        tags
     }
     pub fn get_handler(&self, tag: &str) -> Option<Arc<OutboundHandler>> {
         self.handlers.get(tag).and_then(|x| Some(x.clone()))
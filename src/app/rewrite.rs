@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+
+use crate::proxy::Address;
+
+// NAT loopback (hairpin)
+// LAN 内的 client 访问网关自己的公网 IP 时，应该直接到内网服务，而不是从 proxy 绕出去再回来
+// 所以在 dispatcher 路由之前将 public ip 改写为 internal ip
+pub struct Rewriter {
+    // ip:port 精确匹配，优先级高于只有 ip 的规则
+    addrs: HashMap<SocketAddr, SocketAddr>,
+    // 只有 ip 的规则保留原端口
+    ips: HashMap<IpAddr, IpAddr>,
+}
+
+enum Target {
+    Addr(SocketAddr),
+    Ip(IpAddr),
+}
+
+fn parse_target(str: &str) -> Result<Target> {
+    if let Ok(addr) = str.parse::<SocketAddr>() {
+        return Ok(Target::Addr(addr));
+    }
+    match str.parse::<IpAddr>() {
+        Ok(ip) => Ok(Target::Ip(ip)),
+        Err(err) => Err(anyhow!("invalid rewrite address {} {}", str, err)),
+    }
+}
+
+impl Rewriter {
+    pub fn new(rules: &Option<HashMap<String, String>>) -> Rewriter {
+        let mut rewriter = Rewriter {
+            addrs: HashMap::new(),
+            ips: HashMap::new(),
+        };
+        let rules = match rules {
+            Some(r) => r,
+            None => return rewriter,
+        };
+        for (from, to) in rules {
+            match (parse_target(from), parse_target(to)) {
+                (Ok(Target::Addr(from)), Ok(Target::Addr(to))) => {
+                    rewriter.addrs.insert(from, to);
+                }
+                (Ok(Target::Addr(from)), Ok(Target::Ip(to))) => {
+                    rewriter.addrs.insert(from, SocketAddr::new(to, from.port()));
+                }
+                (Ok(Target::Ip(from)), Ok(Target::Ip(to))) => {
+                    rewriter.ips.insert(from, to);
+                }
+                (Ok(Target::Ip(_)), Ok(Target::Addr(_))) => {
+                    warn!("rewrite {} => {} ignored, port is required on both sides", from, to);
+                }
+                (Err(err), _) | (_, Err(err)) => {
+                    warn!("{}", err);
+                }
+            }
+        }
+        rewriter
+    }
+
+    /// returns the rewritten destination, None if no rule matched
+    pub fn rewrite(&self, destination: &Address) -> Option<Address> {
+        let addr = match destination {
+            Address::Ip(addr) => addr,
+            Address::Domain(..) => return None,
+        };
+        if let Some(to) = self.addrs.get(addr) {
+            return Some(Address::Ip(*to));
+        }
+        self.ips
+            .get(&addr.ip())
+            .map(|ip| Address::Ip(SocketAddr::new(*ip, addr.port())))
+    }
+}
+
+#[test]
+fn test_rewrite() {
+    let mut rules = HashMap::new();
+    rules.insert("1.2.3.4".to_string(), "192.168.1.10".to_string());
+    rules.insert("1.2.3.4:8443".to_string(), "192.168.1.11:443".to_string());
+    let rewriter = Rewriter::new(&Some(rules));
+    let dest = Address::Ip("1.2.3.4:80".parse().unwrap());
+    assert_eq!(rewriter.rewrite(&dest).unwrap().to_string(), "192.168.1.10:80");
+    let dest = Address::Ip("1.2.3.4:8443".parse().unwrap());
+    assert_eq!(rewriter.rewrite(&dest).unwrap().to_string(), "192.168.1.11:443");
+    let dest = Address::Ip("5.6.7.8:80".parse().unwrap());
+    assert!(rewriter.rewrite(&dest).is_none());
+}
@@ -1,4 +1,4 @@
-use std::{io};
+use std::{collections::HashSet, io, net::IpAddr, sync::{Arc, Mutex}};
 
 use anyhow::{
     Result,
@@ -6,9 +6,20 @@ use anyhow::{
 };
 use ipnet::{IpNet};
 use log::{warn, debug};
+use lru_time_cache::LruCache;
 use regex::Regex;
 
-use crate::{proxy::{Session, Address}, config::Rule};
+use crate::{
+    proxy::{Session, Address, Network},
+    config::Rule,
+    common::{
+        cidr::CidrSet,
+        process::lookup_uid,
+        ratelimit::Bandwidth,
+    },
+};
+
+use super::{trace, RuleProviders, RuleSet};
 
 // https://v2ray.com/chapter_02/03_routing.html
 
@@ -18,21 +29,68 @@ pub trait ConditionMatcher: Sync + Send + Unpin {
 
 struct MatcherRule {
     target: String,
-    matcher: Box<dyn ConditionMatcher>
+    matcher: Box<dyn ConditionMatcher>,
+    // 同一条 config rule 拆出的 matcher 共享限速
+    bandwidth: Bandwidth,
+    // config 中的位置与条件，例如 "rules[2].domain"，用于 EventListener::on_rule_match
+    name: String,
 }
 
 impl MatcherRule {
-    pub fn new(target: String, matcher: Box<dyn ConditionMatcher>) -> MatcherRule {
+    pub fn new(target: String, matcher: Box<dyn ConditionMatcher>, bandwidth: Bandwidth, name: String) -> MatcherRule {
         MatcherRule {
             target,
-            matcher
+            matcher,
+            bandwidth,
+            name,
+        }
+    }
+}
+
+
+// 路由缓存的容量
+const ROUTE_CACHE_SIZE: usize = 4096;
+
+// 除了目标地址，rule 还可能用到 session 的 inbound，user，process 与 uid
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RouteKey {
+    destination: String,
+    udp: bool,
+    inbound: Option<String>,
+    user: Option<String>,
+    process: Option<String>,
+    uid: Option<u32>,
+}
+
+impl RouteKey {
+    fn new(sess: &Session) -> RouteKey {
+        RouteKey {
+            destination: sess.destination.to_string(),
+            udp: matches!(sess.network, Network::UDP),
+            inbound: sess.inbound_tag.clone(),
+            user: sess.user.clone(),
+            process: sess.process.clone(),
+            uid: sess.uid,
         }
     }
 }
 
+// 同一个目标的连接不再重复匹配全部 rule
+struct RouteCache {
+    // 引用的 rule provider 的 generation 之和，变化时清空
+    generation: u64,
+    // 匹配到的 rule 下标，None 表示没有匹配
+    routes: LruCache<RouteKey, Option<usize>>,
+}
 
 pub struct Router {
-    rules: Vec<MatcherRule>
+    rules: Vec<MatcherRule>,
+    // 有 process rule 时 dispatcher 预先查询 session 的进程
+    needs_process: bool,
+    // 有 uid rule 时 dispatcher 预先查询 session 的 socket owner
+    needs_uid: bool,
+    cache: Option<Mutex<RouteCache>>,
+    rule_sets: Vec<Arc<RuleSet>>,
 }
 
 macro_rules! try_rule {
@@ -48,46 +106,222 @@ macro_rules! try_rule {
 }
 
 impl Router {
-    pub fn new(rules: Vec<Rule>) -> Router {
+    pub fn new(rules: Vec<Rule>, providers: &RuleProviders) -> Router {
+        // schedule 的结果不只取决于 RouteKey
+        let cacheable = rules.iter().all(|x| x.schedule.is_none());
         let mut router = Self {
-            rules: Vec::new()
+            rules: Vec::new(),
+            needs_process: rules.iter().any(|x| x.process.is_some()),
+            needs_uid: rules.iter().any(|x| x.uid.is_some()),
+            cache: None,
+            rule_sets: Vec::new(),
         };
-        for rule in rules.iter() {
+        for (i, rule) in rules.iter().enumerate() {
+            let bandwidth = Bandwidth::new(rule.max_up, rule.max_down);
+            let schedules = match rule.schedule.as_ref().map(|x| Schedule::parse_all(x)) {
+                Some(Ok(x)) => Some(Arc::new(x)),
+                Some(Err(err)) => {
+                    warn!("{}, rules[{}] ignored", err, i);
+                    continue;
+                }
+                None => None,
+            };
+            let first = router.rules.len();
             if let Some(ref name) = rule.domain {
                 let matcher = try_rule!(DomainMatcher::new(name.clone()));
-                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher)))
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].domain", i)))
             }
             if let Some(ref cidr) = rule.ip {
                 let matcher = try_rule!(IpCidrMatcher::new(cidr.clone()));
-                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher)));
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].ip", i)));
+            }
+            if let Some(ref cidr) = rule.ip6 {
+                let matcher = try_rule!(IpCidrMatcher::new_v6(cidr.clone()));
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].ip6", i)));
+            }
+            if let Some(ref names) = rule.process {
+                let matcher = try_rule!(ProcessMatcher::new(names.clone()));
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].process", i)));
+            }
+            if let Some(ref users) = rule.uid {
+                let matcher = try_rule!(UidMatcher::new(users));
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].uid", i)));
+            }
+            if let Some(ref users) = rule.user {
+                let matcher = UserMatcher { users: users.clone() };
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].user", i)));
+            }
+            if let Some(ref tags) = rule.inbound {
+                let matcher = InboundMatcher { tags: tags.clone() };
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].inbound", i)));
+            }
+            if let Some(ref networks) = rule.network {
+                let matcher = try_rule!(NetworkMatcher::new(networks));
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].network", i)));
+            }
+            if let Some(ref names) = rule.rule_set {
+                let matcher = try_rule!(RuleSetMatcher::new(names, providers));
+                router.rule_sets.extend(matcher.sets.iter().cloned());
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].rule_set", i)));
             }
             if let Some(ref regexp) = rule.regexp {
                 let matcher = try_rule!(RegexpMatcher::new(regexp));
-                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher)));
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].regexp", i)));
             }
+            if let Some(ref ports) = rule.portRange {
+                let matcher = try_rule!(PortMatcher::new(ports));
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].portRange", i)));
+            }
+            if let Some(ref expression) = rule.and {
+                let matcher = try_rule!(AndMatcher::new(expression, providers));
+                router.needs_process |= matcher.needs_process;
+                router.rule_sets.extend(matcher.rule_sets.iter().cloned());
+                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].and", i)));
+            }
+            // 只有 schedule 时在时间段内匹配全部连接
+            if let Some(schedules) = schedules {
+                if router.rules.len() == first {
+                    let matcher = AnyMatcher {};
+                    router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher), bandwidth.clone(), format!("rules[{}].schedule", i)));
+                }
+                for matcher_rule in &mut router.rules[first..] {
+                    let inner = std::mem::replace(&mut matcher_rule.matcher, Box::new(AnyMatcher {}));
+                    matcher_rule.matcher = Box::new(ScheduleMatcher { schedules: schedules.clone(), inner });
+                }
+            }
+        }
+        if cacheable {
+            router.cache = Some(Mutex::new(RouteCache {
+                generation: router.generation(),
+                routes: LruCache::with_capacity(ROUTE_CACHE_SIZE),
+            }));
         }
         return router;
     }
 
+    fn generation(&self) -> u64 {
+        self.rule_sets.iter().map(|x| x.generation()).sum()
+    }
+
+    pub fn needs_process(&self) -> bool {
+        self.needs_process
+    }
+
+    pub fn needs_uid(&self) -> bool {
+        self.needs_uid
+    }
+
     pub fn route(&self, sess: &Session) -> Option<String> {
-        for rule in &self.rules {
-            if rule.matcher.apply(&sess) {
-                return Some(rule.target.clone())
+        self.route_with_bandwidth(sess).map(|(target, _)| target)
+    }
+
+    /// outbound tag and the bandwidth limits of the matched rule
+    pub fn route_with_bandwidth(&self, sess: &Session) -> Option<(String, Bandwidth)> {
+        self.route_with_rule(sess).map(|(target, bandwidth, _)| (target, bandwidth))
+    }
+
+    /// like route_with_bandwidth, also returns the name of the matched rule, e.g. "rules[2].domain"
+    pub fn route_with_rule(&self, sess: &Session) -> Option<(String, Bandwidth, &str)> {
+        let index = match &self.cache {
+            Some(cache) => {
+                let key = RouteKey::new(sess);
+                let generation = self.generation();
+                let mut cache = cache.lock().unwrap();
+                if cache.generation != generation {
+                    cache.generation = generation;
+                    cache.routes = LruCache::with_capacity(ROUTE_CACHE_SIZE);
+                }
+                match cache.routes.get(&key) {
+                    Some(index) => {
+                        trace::event("route cache hit");
+                        *index
+                    }
+                    None => {
+                        let index = self.match_rule(sess);
+                        cache.routes.insert(key, index);
+                        index
+                    }
+                }
+            }
+            None => self.match_rule(sess),
+        };
+        match index {
+            Some(i) => Some((self.rules[i].target.clone(), self.rules[i].bandwidth.clone(), self.rules[i].name.as_str())),
+            None => {
+                debug!("sid={} no routing found {:?}", sess.id, sess);
+                None
             }
         }
-        debug!("no routing found {:?}", sess);
-        return None
+    }
+
+    fn match_rule(&self, sess: &Session) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.matcher.apply(sess))
+    }
+}
+
+// 域名匹配，router 与 dns split policy 共用
+// example.com           完整匹配
+// full:example.com      完整匹配
+// *.example.com         子域名
+// domain:example.com    example.com 本身以及子域名
+// keyword:example       包含关键字
+#[derive(Default)]
+pub struct DomainSet {
+    full: HashSet<String>,
+    // 以 . 开头，只匹配子域名
+    suffix: Vec<String>,
+    keyword: Vec<String>,
+}
+
+impl DomainSet {
+    pub fn new(patterns: &Vec<String>) -> DomainSet {
+        let mut set = DomainSet::default();
+        for pattern in patterns {
+            set.add(pattern);
+        }
+        set
+    }
+
+    pub fn add(&mut self, pattern: &str) {
+        let pattern = pattern.trim().trim_end_matches('.').to_lowercase();
+        if let Some(x) = pattern.strip_prefix("*.") {
+            self.suffix.push(format!(".{}", x));
+        } else if let Some(x) = pattern.strip_prefix("domain:") {
+            self.full.insert(x.to_string());
+            self.suffix.push(format!(".{}", x));
+        } else if let Some(x) = pattern.strip_prefix("keyword:") {
+            self.keyword.push(x.to_string());
+        } else if let Some(x) = pattern.strip_prefix("full:") {
+            self.full.insert(x.to_string());
+        } else {
+            self.full.insert(pattern);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.full.is_empty() && self.suffix.is_empty() && self.keyword.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if self.full.contains(&name) {
+            return true;
+        }
+        if self.suffix.iter().any(|s| name.ends_with(s.as_str())) {
+            return true;
+        }
+        self.keyword.iter().any(|k| name.contains(k.as_str()))
     }
 }
 
 pub struct DomainMatcher {
-    value: Vec<String>
+    value: DomainSet
 }
 
 impl DomainMatcher {
     pub fn new(value: Vec<String>)-> io::Result<DomainMatcher> {
         Ok(Self {
-            value: value,
+            value: DomainSet::new(&value),
         })
     }
 }
@@ -96,7 +330,7 @@ impl ConditionMatcher for DomainMatcher {
     fn apply(&self, sess: &Session) -> bool {
         return match &sess.destination {
             Address::Domain(name, _) => {
-                self.value.contains(name)
+                self.value.matches(name)
             },
             _ => false
         }
@@ -104,11 +338,11 @@ impl ConditionMatcher for DomainMatcher {
 }
 
 pub struct IpCidrMatcher {
-    value: Vec<IpNet>
+    value: CidrSet
 }
 
 impl IpCidrMatcher {
-    pub fn new(value: Vec<String>) -> Result<IpCidrMatcher> {
+    fn parse(value: &Vec<String>) -> Result<Vec<IpNet>> {
         let mut ips = Vec::new();
         for ip in value.iter() {
             let cidr = match ip.parse::<IpNet>() {
@@ -119,25 +353,405 @@ impl IpCidrMatcher {
             };
             ips.push(cidr);
         }
-        
+        Ok(ips)
+    }
+
+    pub fn new(value: Vec<String>) -> Result<IpCidrMatcher> {
+        let ips = IpCidrMatcher::parse(&value)?;
+        Ok(Self {
+            value: ips.iter().collect()
+        })
+    }
+
+    /// IP-CIDR6, every cidr must be ipv6
+    pub fn new_v6(value: Vec<String>) -> Result<IpCidrMatcher> {
+        let ips = IpCidrMatcher::parse(&value)?;
+        if let Some(net) = ips.iter().find(|x| !matches!(x, IpNet::V6(..))) {
+            return Err(anyhow!("ip6 rule with ipv4 cidr {}", net))
+        }
         Ok(Self {
-            value: ips
+            value: ips.iter().collect()
         })
     }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.value.contains(&unmapped(ip))
+    }
+}
+
+// dual stack socket 上的 ipv4 连接是 ::ffff:a.b.c.d，按 ipv4 匹配
+pub fn unmapped(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(v6.to_ipv4().expect("mapped ipv4")),
+            _ => *ip,
+        },
+        IpAddr::V4(..) => *ip,
+    }
 }
 
 impl ConditionMatcher for IpCidrMatcher {
     fn apply(&self, sess: &Session) -> bool {
         match sess.destination {
-            Address::Ip(ip) => {
-                let i = &IpNet::from(ip.ip());
-                self.value.contains(i)
-            },
+            Address::Ip(ip) => self.contains(&ip.ip()),
             _ => false
         }
     }
 }
 
+// 只对本机发起的连接有效
+// peer_address 是本机进程的 socket，local_peer 是 tunnel inbound 的 socket
+// 进程由 dispatcher 在路由之前查询，见 Router::needs_process
+pub struct ProcessMatcher {
+    names: Vec<String>
+}
+
+impl ProcessMatcher {
+    pub fn new(names: Vec<String>) -> io::Result<Self> {
+        Ok(Self { names })
+    }
+}
+
+impl ConditionMatcher for ProcessMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        match &sess.process {
+            Some(name) => {
+                debug!("sid={} connection from {} owned by process {}", sess.id, sess.peer_address, name);
+                self.names.iter().any(|x| x == name)
+            },
+            None => false
+        }
+    }
+}
+
+pub struct InboundMatcher {
+    tags: Vec<String>
+}
+
+impl ConditionMatcher for InboundMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        match &sess.inbound_tag {
+            Some(tag) => self.tags.iter().any(|x| x == tag),
+            None => false
+        }
+    }
+}
+
+pub struct NetworkMatcher {
+    tcp: bool,
+    udp: bool,
+}
+
+impl NetworkMatcher {
+    pub fn new(networks: &Vec<String>) -> Result<NetworkMatcher> {
+        let mut matcher = NetworkMatcher { tcp: false, udp: false };
+        for network in networks {
+            match network.to_lowercase().as_str() {
+                "tcp" => matcher.tcp = true,
+                "udp" => matcher.udp = true,
+                _ => return Err(anyhow!("unknown network {}", network)),
+            }
+        }
+        Ok(matcher)
+    }
+}
+
+impl ConditionMatcher for NetworkMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        match sess.network {
+            Network::TCP => self.tcp,
+            Network::UDP => self.udp,
+        }
+    }
+}
+
+pub struct AnyMatcher {}
+
+impl ConditionMatcher for AnyMatcher {
+    fn apply(&self, _sess: &Session) -> bool {
+        true
+    }
+}
+
+// 时间段，本地时间
+// "mon-fri 09:00-18:00"  工作日
+// "sat,sun"              整天
+// "22:00-06:00"          每天，跨过午夜的部分属于开始的那一天
+// 星期可以是 mon..sun，范围 "fri-mon"，以及 weekdays，weekends
+#[derive(Debug, PartialEq)]
+pub struct Schedule {
+    // bit 0 为周一
+    days: u8,
+    // 一天中的分钟，[start, end)
+    start: u32,
+    end: u32,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+impl Schedule {
+    pub fn parse(value: &str) -> Result<Schedule> {
+        let mut schedule = Schedule {
+            days: 0x7f,
+            start: 0,
+            end: 24 * 60,
+        };
+        for part in value.split_whitespace() {
+            match part.find(':') {
+                Some(_) => {
+                    let (start, end) = part.split_once('-').ok_or_else(|| anyhow!("bad time range {}", value))?;
+                    schedule.start = parse_minute(start).ok_or_else(|| anyhow!("bad time {} in {}", start, value))?;
+                    schedule.end = parse_minute(end).ok_or_else(|| anyhow!("bad time {} in {}", end, value))?;
+                }
+                None => schedule.days = parse_days(part).ok_or_else(|| anyhow!("bad days {} in {}", part, value))?,
+            }
+        }
+        Ok(schedule)
+    }
+
+    pub fn parse_all(values: &[String]) -> Result<Vec<Schedule>> {
+        values.iter().map(|x| Schedule::parse(x)).collect()
+    }
+
+    /// weekday 0 is monday, minute of the day
+    pub fn contains(&self, weekday: u32, minute: u32) -> bool {
+        let day = |x: u32| self.days & (1 << (x % 7)) != 0;
+        if self.start <= self.end {
+            return day(weekday) && self.start <= minute && minute < self.end;
+        }
+        (day(weekday) && minute >= self.start) || (day(weekday + 6) && minute < self.end)
+    }
+}
+
+fn parse_minute(value: &str) -> Option<u32> {
+    let (hour, minute) = value.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+    if hour > 24 || minute > 59 || (hour == 24 && minute != 0) {
+        return None;
+    }
+    Some(hour * 60 + minute)
+}
+
+fn parse_days(value: &str) -> Option<u8> {
+    let day = |x: &str| WEEKDAYS.iter().position(|d| x.eq_ignore_ascii_case(d));
+    let mut days = 0u8;
+    for item in value.split(',') {
+        match item.to_lowercase().as_str() {
+            "weekdays" => days |= 0x1f,
+            "weekends" => days |= 0x60,
+            "daily" => days |= 0x7f,
+            item => match item.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to) = (day(from)?, day(to)?);
+                    let mut i = from;
+                    loop {
+                        days |= 1 << i;
+                        if i == to {
+                            break;
+                        }
+                        i = (i + 1) % 7;
+                    }
+                }
+                None => days |= 1 << day(item)?,
+            },
+        }
+    }
+    Some(days)
+}
+
+// (weekday, minute)，weekday 0 为周一
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn local_time() -> (u32, u32) {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    // tm_wday 0 为周日
+    (((tm.tm_wday + 6) % 7) as u32, (tm.tm_hour * 60 + tm.tm_min) as u32)
+}
+
+// 没有时区信息，使用 UTC
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn local_time() -> (u32, u32) {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    // 1970-01-01 是周四
+    (((secs / 86400 + 3) % 7) as u32, ((secs % 86400) / 60) as u32)
+}
+
+// 包装一条 rule 的 matcher，只在时间段内生效
+pub struct ScheduleMatcher {
+    schedules: Arc<Vec<Schedule>>,
+    inner: Box<dyn ConditionMatcher>,
+}
+
+impl ConditionMatcher for ScheduleMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        let (weekday, minute) = local_time();
+        self.schedules.iter().any(|x| x.contains(weekday, minute)) && self.inner.apply(sess)
+    }
+}
+
+// 目标端口，"443" 或者 "8000-9000"
+pub struct PortMatcher {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortMatcher {
+    pub fn new(values: &Vec<String>) -> Result<PortMatcher> {
+        let mut ranges = Vec::new();
+        for value in values {
+            let (start, end) = value.split_once('-').unwrap_or((value, value));
+            match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+                (Ok(start), Ok(end)) if start <= end => ranges.push((start, end)),
+                _ => return Err(anyhow!("bad port range {}", value)),
+            }
+        }
+        Ok(PortMatcher { ranges })
+    }
+}
+
+impl ConditionMatcher for PortMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        let port = sess.port();
+        self.ranges.iter().any(|(start, end)| *start <= port && port <= *end)
+    }
+}
+
+// 多个条件同时满足，例如 "DOMAIN-SUFFIX:example.com AND DST-PORT:8000-9000 AND NETWORK:udp"
+// 每个条件是 TYPE:value，value 可以用逗号分隔多个，满足其中一个即可
+// uid 不支持在 AND 中使用
+pub struct AndMatcher {
+    matchers: Vec<Box<dyn ConditionMatcher>>,
+    needs_process: bool,
+    rule_sets: Vec<Arc<RuleSet>>,
+}
+
+impl AndMatcher {
+    pub fn new(expression: &str, providers: &RuleProviders) -> Result<AndMatcher> {
+        let mut and = AndMatcher {
+            matchers: Vec::new(),
+            needs_process: false,
+            rule_sets: Vec::new(),
+        };
+        for condition in expression.split(" AND ") {
+            let (kind, value) = condition
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| anyhow!("bad condition {} in {}, expect TYPE:value", condition, expression))?;
+            let values: Vec<String> = value.split(',').map(|x| x.trim().to_string()).collect();
+            let matcher: Box<dyn ConditionMatcher> = match kind.trim().to_uppercase().as_str() {
+                "DOMAIN" => Box::new(DomainMatcher::new(values)?),
+                "DOMAIN-SUFFIX" => Box::new(DomainMatcher::new(values.iter().map(|x| format!("domain:{}", x)).collect())?),
+                "DOMAIN-KEYWORD" => Box::new(DomainMatcher::new(values.iter().map(|x| format!("keyword:{}", x)).collect())?),
+                "IP-CIDR" => Box::new(IpCidrMatcher::new(values)?),
+                "IP-CIDR6" => Box::new(IpCidrMatcher::new_v6(values)?),
+                "DST-PORT" => Box::new(PortMatcher::new(&values)?),
+                "NETWORK" => Box::new(NetworkMatcher::new(&values)?),
+                "PROCESS-NAME" => {
+                    and.needs_process = true;
+                    Box::new(ProcessMatcher::new(values)?)
+                }
+                "USER" => Box::new(UserMatcher { users: values }),
+                "INBOUND" => Box::new(InboundMatcher { tags: values }),
+                "RULE-SET" => {
+                    let matcher = RuleSetMatcher::new(&values, providers)?;
+                    and.rule_sets.extend(matcher.sets.iter().cloned());
+                    Box::new(matcher)
+                }
+                "REGEXP" => Box::new(RegexpMatcher::new(&values)?),
+                x => return Err(anyhow!("unsupported condition type {} in {}", x, expression)),
+            };
+            and.matchers.push(matcher);
+        }
+        Ok(and)
+    }
+}
+
+impl ConditionMatcher for AndMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        self.matchers.iter().all(|x| x.apply(sess))
+    }
+}
+
+// inbound 认证的用户，例如 socks 的 username
+pub struct UserMatcher {
+    users: Vec<String>
+}
+
+impl ConditionMatcher for UserMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        match &sess.user {
+            Some(user) => self.users.iter().any(|x| x == user),
+            None => false
+        }
+    }
+}
+
+// socket owner 的 uid，例如容器使用的系统用户
+// socket owner 由 dispatcher 在路由之前查询，见 Router::needs_uid
+pub struct UidMatcher {
+    uids: Vec<u32>
+}
+
+impl UidMatcher {
+    pub fn new(users: &Vec<String>) -> Result<Self> {
+        let mut uids = Vec::new();
+        for user in users {
+            let uid = match user.parse::<u32>() {
+                Ok(x) => x,
+                Err(_) => match lookup_uid(user) {
+                    Some(x) => x,
+                    None => return Err(anyhow!("unknown system user {}", user))
+                }
+            };
+            uids.push(uid);
+        }
+        Ok(Self { uids })
+    }
+}
+
+impl ConditionMatcher for UidMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        match sess.uid {
+            Some(uid) => {
+                debug!("sid={} connection from {} owned by uid {}", sess.id, sess.peer_address, uid);
+                self.uids.contains(&uid)
+            },
+            None => false
+        }
+    }
+}
+
+pub struct RuleSetMatcher {
+    sets: Vec<Arc<RuleSet>>
+}
+
+impl RuleSetMatcher {
+    pub fn new(names: &Vec<String>, providers: &RuleProviders) -> Result<Self> {
+        let mut sets = Vec::new();
+        for name in names {
+            match providers.get(name) {
+                Some(set) => sets.push(set),
+                None => return Err(anyhow!("rule provider {} not found", name))
+            }
+        }
+        Ok(Self { sets })
+    }
+}
+
+impl ConditionMatcher for RuleSetMatcher {
+    fn apply(&self, sess: &Session) -> bool {
+        match &sess.destination {
+            Address::Domain(name, _) => self.sets.iter().any(|x| x.matches_domain(name)),
+            Address::Ip(addr) => self.sets.iter().any(|x| x.matches_ip(&addr.ip())),
+        }
+    }
+}
+
 pub struct RegexpMatcher {
     values: Vec<Regex>
 }
@@ -167,4 +781,136 @@ impl ConditionMatcher for RegexpMatcher {
         }
         false
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_domain_set() {
+    let patterns = vec![
+        "example.com".to_string(),
+        "*.corp.example".to_string(),
+        "domain:google.com".to_string(),
+        "keyword:ads".to_string(),
+    ];
+    let set = DomainSet::new(&patterns);
+    assert!(set.matches("example.com"));
+    assert!(set.matches("Example.com."));
+    assert!(!set.matches("www.example.com"));
+    assert!(set.matches("git.corp.example"));
+    assert!(!set.matches("corp.example"));
+    assert!(set.matches("google.com"));
+    assert!(set.matches("www.google.com"));
+    assert!(!set.matches("notgoogle.com"));
+    assert!(set.matches("ads.tracker.net"));
+}
+
+#[test]
+fn test_ip_cidr_matcher() {
+    let matcher = IpCidrMatcher::new(vec!["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()]).unwrap();
+    assert!(matcher.contains(&"10.1.2.3".parse().unwrap()));
+    assert!(matcher.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    assert!(matcher.contains(&"2001:db8::1".parse().unwrap()));
+    assert!(!matcher.contains(&"2001:db9::1".parse().unwrap()));
+    assert!(IpCidrMatcher::new_v6(vec!["10.0.0.0/8".to_string()]).is_err());
+}
+
+#[test]
+fn test_route_cache() {
+    let rules: Vec<Rule> = serde_json::from_str(r#"[{ "domain": ["example.com"], "target": "proxy" }]"#).unwrap();
+    let router = Router::new(rules, &RuleProviders::default());
+    let mut sess = Session {
+        id: 0,
+        destination: Address::Domain("example.com".to_string(), 443),
+        network: Network::TCP,
+        local_peer: "127.0.0.1:1080".parse().unwrap(),
+        peer_address: "127.0.0.1:50000".parse().unwrap(),
+        user: None,
+        inbound_tag: None,
+        app_protocol: None,
+        process: None,
+        uid: None,
+    };
+    assert_eq!(router.route(&sess), Some("proxy".to_string()));
+    assert_eq!(router.route(&sess), Some("proxy".to_string()));
+    sess.destination = Address::Domain("example.org".to_string(), 443);
+    assert_eq!(router.route(&sess), None);
+    let cache = router.cache.as_ref().unwrap().lock().unwrap();
+    assert_eq!(cache.routes.len(), 2);
+}
+
+#[test]
+fn test_schedule() {
+    let work = Schedule::parse("mon-fri 09:00-18:00").unwrap();
+    assert!(work.contains(0, 9 * 60));
+    assert!(!work.contains(4, 18 * 60));
+    assert!(!work.contains(5, 10 * 60));
+    let night = Schedule::parse("fri-sun 22:00-06:00").unwrap();
+    assert!(night.contains(6, 23 * 60));
+    // 周一凌晨属于周日晚上
+    assert!(night.contains(0, 60));
+    assert!(!night.contains(4, 60));
+    assert_eq!(Schedule::parse("weekends").unwrap(), Schedule::parse("sat,sun 00:00-24:00").unwrap());
+    assert!(Schedule::parse("mon 9-18").is_err());
+    assert!(Schedule::parse("someday").is_err());
+    assert!(Schedule::parse("25:00-26:00").is_err());
+}
+
+#[test]
+fn test_and_rule() {
+    let rules: Vec<Rule> = serde_json::from_str(
+        r#"[
+            { "and": "DOMAIN-SUFFIX:example.com AND DST-PORT:8000-9000 AND NETWORK:udp", "target": "game" },
+            { "portRange": ["443"], "target": "proxy" }
+        ]"#,
+    )
+    .unwrap();
+    let router = Router::new(rules, &RuleProviders::default());
+    let mut sess = Session {
+        id: 0,
+        destination: Address::Domain("play.example.com".to_string(), 8443),
+        network: Network::UDP,
+        local_peer: "127.0.0.1:1080".parse().unwrap(),
+        peer_address: "127.0.0.1:50000".parse().unwrap(),
+        user: None,
+        inbound_tag: None,
+        app_protocol: None,
+        process: None,
+        uid: None,
+    };
+    assert_eq!(router.route_with_rule(&sess).map(|x| x.2.to_string()), Some("rules[0].and".to_string()));
+    sess.network = Network::TCP;
+    assert_eq!(router.route(&sess), None);
+    sess.destination = Address::Domain("play.example.com".to_string(), 443);
+    assert_eq!(router.route(&sess), Some("proxy".to_string()));
+    assert!(AndMatcher::new("DOMAIN:example.com AND UID:1000", &RuleProviders::default()).is_err());
+    assert!(AndMatcher::new("example.com", &RuleProviders::default()).is_err());
+}
+
+#[test]
+fn test_uid_rule() {
+    let rules: Vec<Rule> = serde_json::from_str(r#"[{ "uid": ["0", "1000"], "target": "direct" }]"#).unwrap();
+    let router = Router::new(rules, &RuleProviders::default());
+    assert!(router.needs_uid() && !router.needs_process());
+    let mut sess = Session {
+        id: 0,
+        destination: Address::Domain("example.com".to_string(), 443),
+        network: Network::TCP,
+        local_peer: "127.0.0.1:1080".parse().unwrap(),
+        peer_address: "127.0.0.1:50000".parse().unwrap(),
+        user: None,
+        inbound_tag: None,
+        app_protocol: None,
+        process: None,
+        uid: Some(1000),
+    };
+    assert_eq!(router.route(&sess), Some("direct".to_string()));
+    // 同一个 destination 不同 uid 的路由分别缓存
+    sess.uid = Some(1001);
+    assert_eq!(router.route(&sess), None);
+    sess.uid = None;
+    assert_eq!(router.route(&sess), None);
+    sess.uid = Some(0);
+    assert_eq!(router.route(&sess), Some("direct".to_string()));
+    assert_eq!(router.cache.as_ref().unwrap().lock().unwrap().routes.len(), 4);
+
+    assert_eq!(UidMatcher::new(&vec!["root".to_string()]).unwrap().uids, vec![0]);
+    assert!(UidMatcher::new(&vec!["no-such-user-here".to_string()]).is_err());
+}
@@ -0,0 +1,256 @@
+// 远程规则集 rule provider
+// 从 url 下载 domain 或 ipcidr 列表，缓存到磁盘，按 interval 定期刷新
+// 路由规则通过 rule_set 引用，例如 { "rule_set": ["ads"], "target": "reject" }
+//
+// 支持的格式: 每行一条，或者 clash 的 payload 列表
+// payload:
+//   - '+.example.com'
+// domain: "+.example.com" 匹配自身与子域名，".example.com" 只匹配子域名，其他为完整匹配
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+
+use crate::{common::cidr::CidrSet, config::RuleProviderConfig};
+
+use super::{router::unmapped, DomainSet, Fetcher};
+
+const DEFAULT_INTERVAL: u64 = 86400;
+const DEFAULT_CACHE_DIR: &str = "providers";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    Domain,
+    IpCidr,
+}
+
+#[derive(Default)]
+struct Payload {
+    domains: DomainSet,
+    cidrs: CidrSet,
+    // 每次 load 加一，router 据此清空路由缓存
+    generation: u64,
+}
+
+pub struct RuleSet {
+    pub name: String,
+    behavior: Behavior,
+    payload: RwLock<Payload>,
+}
+
+fn parse_payload(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && *line != "payload:")
+        .map(|line| {
+            line.trim_start_matches('-')
+                .trim()
+                .trim_matches(|c| c == '\'' || c == '"')
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+impl RuleSet {
+    fn load(&self, content: &str) {
+        let mut payload = Payload::default();
+        let items = parse_payload(content);
+        let count = items.len();
+        for item in items {
+            match self.behavior {
+                Behavior::Domain => {
+                    if let Some(x) = item.strip_prefix("+.") {
+                        payload.domains.add(&format!("domain:{}", x));
+                    } else if let Some(x) = item.strip_prefix('.') {
+                        payload.domains.add(&format!("*.{}", x));
+                    } else {
+                        payload.domains.add(&format!("full:{}", item));
+                    }
+                }
+                Behavior::IpCidr => match item.parse::<IpNet>() {
+                    Ok(x) => payload.cidrs.insert(&x),
+                    Err(_) => match item.parse::<IpAddr>() {
+                        Ok(ip) => payload.cidrs.insert(&IpNet::from(ip)),
+                        Err(err) => debug!("rule provider {} bad cidr {} {}", self.name, item, err),
+                    },
+                },
+            }
+        }
+        info!("rule provider {} loaded {} entries", self.name, count);
+        let mut current = self.payload.write().unwrap();
+        payload.generation = current.generation + 1;
+        *current = payload;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.payload.read().unwrap().generation
+    }
+
+    pub fn behavior(&self) -> Behavior {
+        self.behavior
+    }
+
+    pub fn matches_domain(&self, name: &str) -> bool {
+        self.behavior == Behavior::Domain && self.payload.read().unwrap().domains.matches(name)
+    }
+
+    pub fn matches_ip(&self, ip: &IpAddr) -> bool {
+        self.behavior == Behavior::IpCidr
+            && {
+                let ip = unmapped(ip);
+                self.payload.read().unwrap().cidrs.contains(&ip)
+            }
+    }
+}
+
+struct Provider {
+    set: Arc<RuleSet>,
+    url: String,
+    outbound: Option<String>,
+    cache: PathBuf,
+    interval: Duration,
+}
+
+impl Provider {
+    // 缓存还在有效期内的时间
+    fn cache_fresh_for(&self) -> Option<Duration> {
+        let modified = fs::metadata(&self.cache).and_then(|x| x.modified()).ok()?;
+        let age = SystemTime::now().duration_since(modified).ok()?;
+        self.interval.checked_sub(age)
+    }
+
+    async fn refresh(&self, fetcher: &Fetcher) {
+        let result = match &self.outbound {
+            Some(tag) => fetcher.with_outbound(tag).get(&self.url).await,
+            None => fetcher.get(&self.url).await,
+        };
+        let body = match result {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("fetch rule provider {} failed {}", self.set.name, err);
+                return;
+            }
+        };
+        let content = String::from_utf8_lossy(&body);
+        self.set.load(&content);
+        if let Some(dir) = self.cache.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Err(err) = fs::write(&self.cache, body) {
+            warn!("write rule provider cache {} failed {}", self.cache.display(), err);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RuleProviders {
+    providers: HashMap<String, Provider>,
+}
+
+/// file a rule provider is cached in, its directory is created before privileges are dropped
+pub fn provider_cache_path(name: &str, provider: &RuleProviderConfig) -> PathBuf {
+    match &provider.path {
+        Some(p) => PathBuf::from(p),
+        None => PathBuf::from(DEFAULT_CACHE_DIR).join(format!("{}.txt", name)),
+    }
+}
+
+impl RuleProviders {
+    /// loads disk caches synchronously, remote lists are fetched by watch
+    pub fn new(config: &Option<HashMap<String, RuleProviderConfig>>) -> RuleProviders {
+        let mut providers = HashMap::new();
+        for (name, provider) in config.iter().flatten() {
+            let behavior = match provider.behavior.as_str() {
+                "domain" => Behavior::Domain,
+                "ipcidr" => Behavior::IpCidr,
+                _ => {
+                    warn!("unknown rule provider behavior {}, provider {} ignored", provider.behavior, name);
+                    continue;
+                }
+            };
+            let set = Arc::new(RuleSet {
+                name: name.clone(),
+                behavior,
+                payload: RwLock::new(Payload::default()),
+            });
+            let cache = provider_cache_path(name, provider);
+            if let Ok(content) = fs::read_to_string(&cache) {
+                set.load(&content);
+            }
+            providers.insert(
+                name.clone(),
+                Provider {
+                    set,
+                    url: provider.url.clone(),
+                    outbound: provider.outbound.clone(),
+                    cache,
+                    interval: Duration::from_secs(provider.interval.unwrap_or(DEFAULT_INTERVAL)),
+                },
+            );
+        }
+        RuleProviders { providers }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<RuleSet>> {
+        self.providers.get(name).map(|x| x.set.clone())
+    }
+
+    /// refresh every provider on its interval, starting with the stale ones
+    pub fn watch(self, fetcher: Arc<Fetcher>) -> Vec<BoxFuture<'static, ()>> {
+        let mut tasks = Vec::new();
+        for (_, provider) in self.providers {
+            let fetcher = fetcher.clone();
+            tasks.push(
+                async move {
+                    if let Some(wait) = provider.cache_fresh_for() {
+                        tokio::time::sleep(wait).await;
+                    }
+                    loop {
+                        provider.refresh(&fetcher).await;
+                        tokio::time::sleep(provider.interval).await;
+                    }
+                }
+                .boxed(),
+            );
+        }
+        tasks
+    }
+}
+
+#[test]
+fn test_rule_set_payload() {
+    let set = RuleSet {
+        name: "test".to_string(),
+        behavior: Behavior::Domain,
+        payload: RwLock::new(Payload::default()),
+    };
+    set.load("payload:\n  - '+.ads.example'\n  - '.tracker.example'\n  - \"exact.example\"\n# comment\n");
+    assert!(set.matches_domain("ads.example"));
+    assert!(set.matches_domain("x.ads.example"));
+    assert!(!set.matches_domain("tracker.example"));
+    assert!(set.matches_domain("a.tracker.example"));
+    assert!(set.matches_domain("exact.example"));
+    assert!(!set.matches_domain("a.exact.example"));
+    assert!(!set.matches_ip(&"1.1.1.1".parse().unwrap()));
+
+    let set = RuleSet {
+        name: "cidr".to_string(),
+        behavior: Behavior::IpCidr,
+        payload: RwLock::new(Payload::default()),
+    };
+    set.load("10.0.0.0/8\n1.1.1.1\n");
+    assert!(set.matches_ip(&"10.1.2.3".parse().unwrap()));
+    assert!(set.matches_ip(&"1.1.1.1".parse().unwrap()));
+    assert!(!set.matches_ip(&"1.1.1.2".parse().unwrap()));
+}
@@ -0,0 +1,135 @@
+// 代理服务器域名的解析缓存
+// 启动时解析全部 outbound 的服务器域名，按 ttl 在后台刷新，Dialer 直接使用缓存的地址，建连不需要等待 dns
+// 刷新失败时继续使用旧的地址，resolver 故障不影响已经能用的配置
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use tokio::time::sleep_until;
+
+use crate::proxy::Address;
+
+use super::DnsClient;
+
+// ttl 为 0 或很小时也不要频繁查询
+const MIN_TTL: u64 = 30;
+const MAX_TTL: u64 = 3600;
+// 解析失败后重试的间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Entry {
+    ips: Vec<IpAddr>,
+    refresh_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct ServerCache {
+    entries: RwLock<HashMap<String, Entry>>,
+}
+
+impl ServerCache {
+    /// register a proxy server address, ip addresses need no cache
+    pub fn add(&self, address: &Address) {
+        if let Address::Domain(name, _) = address {
+            self.entries.write().unwrap().entry(name.clone()).or_insert_with(|| Entry {
+                ips: Vec::new(),
+                refresh_at: Instant::now(),
+            });
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().unwrap().is_empty()
+    }
+
+    /// cached ips of the server, possibly stale, None before the first successful lookup
+    pub fn get(&self, name: &str) -> Option<Vec<IpAddr>> {
+        match self.entries.read().unwrap().get(name) {
+            Some(entry) if !entry.ips.is_empty() => Some(entry.ips.clone()),
+            _ => None,
+        }
+    }
+
+    fn due(&self, now: Instant) -> Vec<String> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.refresh_at <= now)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn next_refresh(&self) -> Option<Instant> {
+        self.entries.read().unwrap().values().map(|x| x.refresh_at).min()
+    }
+
+    fn update(&self, name: &str, result: Result<(Vec<IpAddr>, u32)>, now: Instant) {
+        let mut entries = self.entries.write().unwrap();
+        let entry = match entries.get_mut(name) {
+            Some(x) => x,
+            None => return,
+        };
+        match result {
+            Ok((ips, ttl)) if !ips.is_empty() => {
+                debug!("proxy server {} resolved {:?}, ttl {}", name, ips, ttl);
+                let ttl = (ttl as u64).max(MIN_TTL).min(MAX_TTL);
+                entry.ips = ips;
+                entry.refresh_at = now + Duration::from_secs(ttl);
+            }
+            Ok(_) => {
+                warn!("proxy server {} resolved no ip, keep {:?}", name, entry.ips);
+                entry.refresh_at = now + RETRY_INTERVAL;
+            }
+            Err(err) => {
+                warn!("resolve proxy server {} failed {}, keep {:?}", name, err, entry.ips);
+                entry.refresh_at = now + RETRY_INTERVAL;
+            }
+        }
+    }
+
+    /// resolve all servers now and refresh them when their ttl expires
+    pub fn refresh(self: Arc<Self>, dns_client: Arc<tokio::sync::RwLock<DnsClient>>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            loop {
+                for name in self.due(Instant::now()) {
+                    let result = dns_client.read().await.lookup_ttl(&name).await;
+                    self.update(&name, result, Instant::now());
+                }
+                let next = match self.next_refresh() {
+                    Some(x) => x,
+                    None => return,
+                };
+                sleep_until(next.into()).await;
+            }
+        })
+    }
+}
+
+#[test]
+fn test_server_cache() {
+    use anyhow::anyhow;
+
+    let cache = ServerCache::default();
+    cache.add(&Address::Domain("proxy.example.com".to_string(), 1080));
+    cache.add(&Address::Ip("1.1.1.1:1080".parse().unwrap()));
+    let now = Instant::now();
+    assert_eq!(cache.due(now), vec!["proxy.example.com".to_string()]);
+    assert_eq!(cache.get("proxy.example.com"), None);
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    cache.update("proxy.example.com", Ok((vec![ip], 0)), now);
+    assert_eq!(cache.get("proxy.example.com"), Some(vec![ip]));
+    assert_eq!(cache.next_refresh(), Some(now + Duration::from_secs(MIN_TTL)));
+    // 解析失败时保留旧地址
+    cache.update("proxy.example.com", Err(anyhow!("timeout")), now);
+    assert_eq!(cache.get("proxy.example.com"), Some(vec![ip]));
+    assert_eq!(cache.next_refresh(), Some(now + RETRY_INTERVAL));
+}
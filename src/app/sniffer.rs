@@ -20,6 +20,7 @@
 
 use std::{
     cmp::min,
+    convert::TryFrom,
     io,
     ops::Range,
     pin::Pin,
@@ -31,6 +32,7 @@ use std::{
 use byteorder::{BigEndian, ByteOrder};
 
 use log::debug;
+use ring::{aead, hkdf};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
     time::timeout,
@@ -175,6 +177,263 @@ where
             buf: Vec::with_capacity(2048),
         }
     }
+
+    /// bytes read by sniff and not yet consumed
+    pub fn sniffed(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// the stream and the sniffed bytes not yet read
+    pub fn into_inner(self) -> (T, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+}
+
+/// ALPN protocols offered in a complete tls client hello record
+pub fn client_hello_alpn(data: &[u8]) -> Option<Vec<String>> {
+    if data.len() < 6 || data[0] != 0x16 || data[1] != 0x03 || data[5] != 0x01 {
+        return None;
+    }
+    let curr = &data[5..];
+    // session id, cipher suites, compression methods
+    let curr = truncate_before(curr, 38..39).ok()?;
+    let curr = truncate_before(curr, 0..2).ok()?;
+    let curr = truncate_before(curr, 0..1).ok()?;
+    let mut extensions = slice_at_range(curr, 0..2).ok()?;
+    while extensions.len() > 4 {
+        let ext_type = BigEndian::read_u16(&extensions[0..2]);
+        if ext_type == 16 {
+            // application_layer_protocol_negotiation, 2 bytes list length + (1 byte length + name)*
+            let extension = slice_at_range(extensions, 2..4).ok()?;
+            let mut list = slice_at_range(extension, 0..2).ok()?;
+            let mut protocols = Vec::new();
+            while !list.is_empty() {
+                let name = slice_at_range(list, 0..1).ok()?;
+                protocols.push(String::from_utf8_lossy(name).to_string());
+                list = &list[1 + name.len()..];
+            }
+            return Some(protocols);
+        }
+        extensions = truncate_before(extensions, 2..4).ok()?;
+    }
+    Some(Vec::new())
+}
+
+// server name in a complete tls handshake ClientHello message (without the record header)
+fn client_hello_server_name(hello: &[u8]) -> Option<String> {
+    if hello.first() != Some(&0x01) {
+        return None;
+    }
+    let curr = truncate_before(hello, 38..39).ok()?;
+    let curr = truncate_before(curr, 0..2).ok()?;
+    let curr = truncate_before(curr, 0..1).ok()?;
+    let mut extensions = slice_at_range(curr, 0..2).ok()?;
+    while extensions.len() > 4 {
+        if BigEndian::read_u16(&extensions[0..2]) == 0 {
+            let extension = slice_at_range(extensions, 2..4).ok()?;
+            let server_name = slice_at_range(extension, 3..5).ok()?;
+            return Some(String::from_utf8_lossy(server_name).into());
+        }
+        extensions = truncate_before(extensions, 2..4).ok()?;
+    }
+    None
+}
+
+// QUIC v1 的 Initial packet 使用由 destination connection id 派生的密钥加密（RFC 9001 5.2）
+// 任何观察者都可以解密，ClientHello 在 CRYPTO frame 中，可能被拆分到多个 frame 甚至多个 packet
+const QUIC_V1: u32 = 1;
+const QUIC_V1_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+// 超过之后放弃嗅探
+const QUIC_MAX_CRYPTO: usize = 16 * 1024;
+const QUIC_MAX_DATAGRAMS: usize = 4;
+
+struct OkmLen(usize);
+
+impl hkdf::KeyType for OkmLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// HKDF-Expand-Label (RFC 8446 7.1)，context 为空
+fn expand_label(prk: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Option<()> {
+    let len = (out.len() as u16).to_be_bytes();
+    let label_len = [6 + label.len() as u8];
+    let info: [&[u8]; 5] = [&len, &label_len, b"tls13 ", label, &[0]];
+    prk.expand(&info, OkmLen(out.len())).ok()?.fill(out).ok()
+}
+
+// client initial key, iv, header protection key
+fn quic_initial_secrets(dcid: &[u8]) -> Option<([u8; 16], [u8; 12], [u8; 16])> {
+    let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &QUIC_V1_SALT).extract(dcid);
+    let mut secret = [0u8; 32];
+    expand_label(&initial, b"client in", &mut secret)?;
+    let client = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+    let (mut key, mut iv, mut hp) = ([0u8; 16], [0u8; 12], [0u8; 16]);
+    expand_label(&client, b"quic key", &mut key)?;
+    expand_label(&client, b"quic iv", &mut iv)?;
+    expand_label(&client, b"quic hp", &mut hp)?;
+    Some((key, iv, hp))
+}
+
+fn quic_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = 1usize << (first >> 6);
+    let bytes = data.get(1..len)?;
+    let value = bytes.iter().fold((first & 0x3f) as u64, |v, b| v << 8 | *b as u64);
+    Some((value, len))
+}
+
+fn skip_varints(mut data: &[u8], count: usize) -> Option<&[u8]> {
+    for _ in 0..count {
+        data = &data[quic_varint(data)?.1..];
+    }
+    Some(data)
+}
+
+// 解密 data 开头的 Initial packet，返回明文 payload 与 packet 长度
+// 不是 QUIC v1 client Initial 或者解密失败返回 None
+fn open_quic_initial(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    // long header, fixed bit, type Initial
+    if data.len() < 7 || data[0] & 0xf0 != 0xc0 || BigEndian::read_u32(&data[1..5]) != QUIC_V1 {
+        return None;
+    }
+    let dcid = slice_at_range(data, 5..6).ok()?;
+    let mut pos = 6 + dcid.len();
+    pos += 1 + *data.get(pos)? as usize;
+    let (token_len, n) = quic_varint(data.get(pos..)?)?;
+    pos = pos.checked_add(n)?.checked_add(usize::try_from(token_len).ok()?)?;
+    let (length, n) = quic_varint(data.get(pos..)?)?;
+    let pn_offset = pos + n;
+    let end = pn_offset.checked_add(usize::try_from(length).ok()?)?;
+    // sample 必须在这个 packet 之内，不能取到合并在后面的 packet
+    if end > data.len() || pn_offset + 20 > end {
+        return None;
+    }
+    let (key, iv, hp) = quic_initial_secrets(dcid)?;
+    // header protection 的 sample 从 packet number 之后 4 bytes 开始
+    let hp = aead::quic::HeaderProtectionKey::new(&aead::quic::AES_128, &hp).ok()?;
+    let mask = hp.new_mask(data.get(pn_offset + 4..pn_offset + 20)?).ok()?;
+    let mut packet = data[..end].to_vec();
+    packet[0] ^= mask[0] & 0x0f;
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    // packet number 之后至少要有 16 bytes 的 aead tag
+    if (length as usize) < pn_len + 16 {
+        return None;
+    }
+    let mut nonce = iv;
+    for i in 0..pn_len {
+        packet[pn_offset + i] ^= mask[1 + i];
+        nonce[12 - pn_len + i] ^= packet[pn_offset + i];
+    }
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &key).ok()?);
+    let (header, payload) = packet.split_at_mut(pn_offset + pn_len);
+    let plain = key
+        .open_in_place(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::from(&*header), payload)
+        .ok()?;
+    Some((plain.to_vec(), end))
+}
+
+// 收集 CRYPTO frame，Initial 中只会出现 PADDING, PING, ACK, CRYPTO, CONNECTION_CLOSE
+fn collect_crypto_frames(mut payload: &[u8], frames: &mut Vec<(usize, Vec<u8>)>) -> Option<()> {
+    while let Some(&ty) = payload.first() {
+        payload = &payload[1..];
+        match ty {
+            0x00 | 0x01 => {}
+            0x02 | 0x03 => {
+                // largest acknowledged, ack delay, range count, first range, ranges, ecn counts
+                payload = skip_varints(payload, 2)?;
+                let (count, n) = quic_varint(payload)?;
+                let ranges = (count as usize).checked_mul(2)?.checked_add(1)?;
+                payload = skip_varints(&payload[n..], ranges)?;
+                if ty == 0x03 {
+                    payload = skip_varints(payload, 3)?;
+                }
+            }
+            0x06 => {
+                let (offset, n) = quic_varint(payload)?;
+                payload = &payload[n..];
+                let (len, n) = quic_varint(payload)?;
+                let data = payload.get(n..n.checked_add(len as usize)?)?;
+                frames.push((offset as usize, data.to_vec()));
+                payload = &payload[n + data.len()..];
+            }
+            0x1c => {
+                payload = skip_varints(payload, 2)?;
+                let (len, n) = quic_varint(payload)?;
+                payload = payload.get(n.checked_add(len as usize)?..)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+pub enum QuicSniff {
+    // ClientHello 还不完整，需要下一个 datagram
+    Pending,
+    Done(Option<String>),
+}
+
+/// sniffs the tls server name from the first client datagrams of a quic flow
+#[derive(Default)]
+pub struct QuicSniffer {
+    frames: Vec<(usize, Vec<u8>)>,
+    datagrams: usize,
+}
+
+impl QuicSniffer {
+    /// feed the next datagram sent by the client, in order
+    pub fn push(&mut self, datagram: &[u8]) -> QuicSniff {
+        self.datagrams += 1;
+        // 一个 datagram 中可能合并了多个 packet，Initial 总是在最前面
+        let mut rest = datagram;
+        let mut opened = false;
+        while let Some((payload, len)) = open_quic_initial(rest) {
+            opened = true;
+            if collect_crypto_frames(&payload, &mut self.frames).is_none() {
+                debug!("bad quic initial frames");
+                return QuicSniff::Done(None);
+            }
+            rest = &rest[len..];
+        }
+        if !opened {
+            return QuicSniff::Done(None);
+        }
+        let hello = self.assemble();
+        if hello.len() >= 4 {
+            let len = 4 + BigEndian::read_u24(&hello[1..4]) as usize;
+            if hello.len() >= len {
+                let server_name = client_hello_server_name(&hello[..len]);
+                debug!("quic initial sni {:?}", server_name);
+                return QuicSniff::Done(server_name);
+            }
+        }
+        let buffered: usize = self.frames.iter().map(|(_, x)| x.len()).sum();
+        if self.datagrams >= QUIC_MAX_DATAGRAMS || buffered > QUIC_MAX_CRYPTO {
+            return QuicSniff::Done(None);
+        }
+        QuicSniff::Pending
+    }
+
+    // 从 offset 0 开始连续的 crypto 数据
+    fn assemble(&mut self) -> Vec<u8> {
+        self.frames.sort_by_key(|(offset, _)| *offset);
+        let mut hello = Vec::new();
+        for (offset, data) in &self.frames {
+            if *offset > hello.len() {
+                break;
+            }
+            let skip = hello.len() - offset;
+            if skip < data.len() {
+                hello.extend_from_slice(&data[skip..]);
+            }
+        }
+        hello
+    }
 }
 
 impl<T: AsyncRead + Unpin> AsyncRead for Sniffer<T> {
@@ -190,7 +449,7 @@ impl<T: AsyncRead + Unpin> AsyncRead for Sniffer<T> {
             self.buf.drain(..accepted_len);
             Poll::Ready(Ok(()))
         } else {
-            AsyncRead::poll_read(self, cx, buf)
+            AsyncRead::poll_read(Pin::new(&mut self.stream), cx, buf)
         }
     }
 }
@@ -287,3 +546,63 @@ async fn test_server_name() {
     };
     assert!("c.msn.cn" == res.as_str());
 }
+
+#[test]
+fn test_quic_initial_sni() {
+    // RFC 9001 A.1
+    let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let (key, iv, hp) = quic_initial_secrets(&dcid).unwrap();
+    assert_eq!(key, [0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef, 0xcb, 0xe3, 0xb1, 0xa2, 0x2d]);
+    assert_eq!(iv, [0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5c]);
+    assert_eq!(hp, [0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e, 0x99, 0x33, 0xad, 0xed, 0xd2]);
+
+    // ClientHello: version, random, session id, cipher suites, compression, server_name extension
+    let name = b"quic.example";
+    let mut sni = vec![0x00, 0x00, 0x00, (name.len() + 5) as u8, 0x00, (name.len() + 3) as u8, 0x00, 0x00, name.len() as u8];
+    sni.extend_from_slice(name);
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00, 0x00, sni.len() as u8]);
+    body.extend_from_slice(&sni);
+    let mut hello = vec![0x01, 0x00, 0x00, body.len() as u8];
+    hello.extend_from_slice(&body);
+
+    // 拆成两个乱序的 CRYPTO frame，之后是 PADDING
+    let (a, b) = hello.split_at(20);
+    let mut payload = vec![0x06, 20, b.len() as u8];
+    payload.extend_from_slice(b);
+    payload.extend_from_slice(&[0x06, 0x00, a.len() as u8]);
+    payload.extend_from_slice(a);
+    payload.resize(200, 0);
+
+    let pn_offset = 1 + 4 + 1 + dcid.len() + 1 + 1 + 2;
+    let mut packet = vec![0xc0, 0x00, 0x00, 0x00, 0x01, dcid.len() as u8];
+    packet.extend_from_slice(&dcid);
+    packet.extend_from_slice(&[0x00, 0x00]);
+    packet.extend_from_slice(&((1 + payload.len() + 16) as u16 | 0x4000).to_be_bytes());
+    packet.push(0x00);
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &key).unwrap());
+    let aad = aead::Aad::from(packet.clone());
+    key.seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(iv), aad, &mut payload).unwrap();
+    packet.extend_from_slice(&payload);
+    let hp = aead::quic::HeaderProtectionKey::new(&aead::quic::AES_128, &hp).unwrap();
+    let mask = hp.new_mask(&packet[pn_offset + 4..pn_offset + 20]).unwrap();
+    packet[0] ^= mask[0] & 0x0f;
+    packet[pn_offset] ^= mask[1];
+
+    match QuicSniffer::default().push(&packet) {
+        QuicSniff::Done(Some(x)) => assert_eq!(x, "quic.example"),
+        _ => panic!("sni not found"),
+    }
+    assert!(matches!(QuicSniffer::default().push(b"not quic"), QuicSniff::Done(None)));
+
+    // length 太短时 sample 会越过 packet，token length 溢出，都不能 panic
+    let mut short = packet[..pn_offset - 2].to_vec();
+    short.extend_from_slice(&[0x40, 0x08]);
+    short.extend_from_slice(&[0u8; 40]);
+    assert!(open_quic_initial(&short).is_none());
+    let mut token = packet[..pn_offset - 3].to_vec();
+    token.extend_from_slice(&[0xff; 8]);
+    token.extend_from_slice(&[0u8; 40]);
+    assert!(open_quic_initial(&token).is_none());
+}
@@ -0,0 +1,252 @@
+// 按应用层协议统计流量
+// 连接建立后根据 app 发出的第一段数据判断协议，tls 进一步按 ALPN 区分 (tls/h2, tls/http/1.1)
+// ALPN 由 client 任意填写，只保留固定的几种，其余归为 tls/other，避免统计项无限增长
+// 统计按 outbound tag 分组，通过 api GET /stats/protocols 查看
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::{AnyStream, Network, Session};
+
+use super::sniffer::client_hello_alpn;
+
+const HTTP_METHODS: [&str; 9] = [
+    "GET ", "POST ", "PUT ", "HEAD ", "DELETE ", "OPTIONS ", "PATCH ", "CONNECT ", "TRACE ",
+];
+const UNKNOWN: &str = "unknown";
+
+/// classify the first bytes sent by the app
+pub fn detect(data: &[u8], port: u16, network: &Network) -> String {
+    if port == 53 {
+        return "dns".to_string();
+    }
+    match network {
+        Network::TCP => {
+            if data.len() > 5 && data[0] == 0x16 && data[1] == 0x03 {
+                return match client_hello_alpn(data).as_ref().and_then(|x| x.first()) {
+                    Some(alpn) => format!("tls/{}", alpn_protocol(alpn)),
+                    None => "tls".to_string(),
+                };
+            }
+            if HTTP_METHODS.iter().any(|m| data.starts_with(m.as_bytes())) {
+                return "http".to_string();
+            }
+        }
+        Network::UDP => {
+            // quic long header, fixed bit set, version 1 或 draft
+            if data.len() > 5 && data[0] & 0xc0 == 0xc0 && data[1..5] != [0, 0, 0, 0] {
+                return "quic".to_string();
+            }
+        }
+    }
+    UNKNOWN.to_string()
+}
+
+fn alpn_protocol(alpn: &str) -> &'static str {
+    match alpn {
+        "h2" => "h2",
+        "http/1.1" => "http/1.1",
+        "h3" => "h3",
+        _ => "other",
+    }
+}
+
+#[derive(Default)]
+pub struct Counter {
+    connections: AtomicU64,
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    // (outbound tag, protocol)
+    counters: Mutex<HashMap<(String, String), Arc<Counter>>>,
+    // (outbound tag, circuit breaker event) => count
+    circuits: Mutex<HashMap<(String, &'static str), u64>>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    fn counter(&self, tag: &str, protocol: String) -> Arc<Counter> {
+        let counter = self
+            .counters
+            .lock()
+            .unwrap()
+            .entry((tag.to_string(), protocol))
+            .or_default()
+            .clone();
+        counter.connections.fetch_add(1, Ordering::Relaxed);
+        counter
+    }
+
+    /// count a connection relayed outside of wrap, e.g. spliced in kernel
+    pub fn record(&self, tag: &str, protocol: String, up: u64, down: u64) {
+        let counter = self.counter(tag, protocol);
+        counter.up.fetch_add(up, Ordering::Relaxed);
+        counter.down.fetch_add(down, Ordering::Relaxed);
+    }
+
+    /// count a relayed datagram, e.g. dns or quic over udp
+    pub fn record_datagram(&self, tag: &str, data: &[u8], port: u16, up: bool) {
+        let counter = self.counter(tag, detect(data, port, &Network::UDP));
+        let bytes = if up { &counter.up } else { &counter.down };
+        bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn wrap(self: &Arc<Self>, tag: &str, sess: &Session, stream: AnyStream) -> AnyStream {
+        Box::new(StatsStream {
+            inner: stream,
+            stats: self.clone(),
+            tag: tag.to_string(),
+            port: sess.port(),
+            counter: None,
+            pending_down: 0,
+        })
+    }
+
+    /// count a circuit breaker event of an outbound: opened, closed or fallback
+    pub fn record_circuit(&self, tag: &str, event: &'static str) {
+        *self
+            .circuits
+            .lock()
+            .unwrap()
+            .entry((tag.to_string(), event))
+            .or_default() += 1;
+    }
+
+    /// {"<outbound>": {"opened", "closed", "fallback"}}
+    pub fn circuit_snapshot(&self) -> Value {
+        let mut result = serde_json::Map::new();
+        for ((tag, event), count) in self.circuits.lock().unwrap().iter() {
+            let entry = result
+                .entry(tag.clone())
+                .or_insert_with(|| json!({ "opened": 0, "closed": 0, "fallback": 0 }));
+            entry[*event] = json!(count);
+        }
+        Value::Object(result)
+    }
+
+    /// {"<outbound>": {"<protocol>": {"connections", "up", "down"}}}
+    pub fn snapshot(&self) -> Value {
+        let mut result = serde_json::Map::new();
+        for ((tag, protocol), counter) in self.counters.lock().unwrap().iter() {
+            let entry = result
+                .entry(tag.clone())
+                .or_insert_with(|| Value::Object(serde_json::Map::new()));
+            entry[protocol] = json!({
+                "connections": counter.connections.load(Ordering::Relaxed),
+                "up": counter.up.load(Ordering::Relaxed),
+                "down": counter.down.load(Ordering::Relaxed),
+            });
+        }
+        Value::Object(result)
+    }
+}
+
+// 读到的是 app 发出的数据，写入的是 remote 返回的数据
+// server 先发数据的协议在第一次读之前的下行流量暂存在 pending_down
+struct StatsStream {
+    inner: AnyStream,
+    stats: Arc<Stats>,
+    tag: String,
+    port: u16,
+    counter: Option<Arc<Counter>>,
+    pending_down: u64,
+}
+
+impl StatsStream {
+    fn classify(&mut self, data: &[u8]) -> Arc<Counter> {
+        let protocol = if data.is_empty() {
+            UNKNOWN.to_string()
+        } else {
+            detect(data, self.port, &Network::TCP)
+        };
+        let counter = self.stats.counter(&self.tag, protocol);
+        counter.down.fetch_add(self.pending_down, Ordering::Relaxed);
+        self.pending_down = 0;
+        self.counter.replace(counter.clone());
+        counter
+    }
+}
+
+impl Drop for StatsStream {
+    fn drop(&mut self) {
+        if self.counter.is_none() {
+            self.classify(&[]);
+        }
+    }
+}
+
+impl AsyncRead for StatsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let data = &buf.filled()[before..];
+            let counter = match &self.counter {
+                Some(c) => c.clone(),
+                None => self.classify(data),
+            };
+            counter.up.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl AsyncWrite for StatsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            match &self.counter {
+                Some(c) => {
+                    c.down.fetch_add(n as u64, Ordering::Relaxed);
+                }
+                None => self.pending_down += n as u64,
+            }
+        }
+        res
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn test_detect() {
+    assert_eq!(detect(b"GET / HTTP/1.1\r\n", 80, &Network::TCP), "http");
+    assert_eq!(detect(b"\x00\x1c", 53, &Network::TCP), "dns");
+    assert_eq!(detect(b"SSH-2.0-OpenSSH", 22, &Network::TCP), UNKNOWN);
+    assert_eq!(detect(&[0xc3, 0, 0, 0, 1, 8], 443, &Network::UDP), "quic");
+    assert_eq!(alpn_protocol("h2"), "h2");
+    assert_eq!(alpn_protocol("http/1.1"), "http/1.1");
+    assert_eq!(alpn_protocol("random-1234"), "other");
+}
@@ -0,0 +1,127 @@
+// inbound 的 accept loop 与每个连接的 task 都在这里启动
+// 连接 task panic 时记录 inbound 与 session，不影响其他连接
+// 每个 accept loop（一个地址的 tcp 或 udp）单独 supervise，panic 时只重新 bind 并启动这一个，间隔从 1s 开始指数增长到 60s，稳定运行 60s 之后重置
+// 次数通过 api GET /stats/crashes 查看
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use futures_util::future::BoxFuture;
+use lazy_static::lazy_static;
+use log::error;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const STABLE: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // (inbound tag, "accept" | "relay") => panics
+    static ref CRASHES: Mutex<HashMap<(String, &'static str), u64>> = Mutex::new(HashMap::new());
+}
+
+fn record(tag: &str, kind: &'static str) {
+    *CRASHES.lock().unwrap().entry((tag.to_string(), kind)).or_default() += 1;
+}
+
+fn panic_message(err: &(dyn Any + Send)) -> &str {
+    match err.downcast_ref::<&str>() {
+        Some(x) => x,
+        None => err.downcast_ref::<String>().map(|x| x.as_str()).unwrap_or("unknown panic"),
+    }
+}
+
+/// spawn a connection task of an inbound, a panic is logged with the context
+pub fn spawn<F, C>(tag: &str, context: C, future: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+    C: Display + Send + 'static,
+{
+    let tag = tag.to_string();
+    tokio::spawn(async move {
+        if let Err(err) = AssertUnwindSafe(future).catch_unwind().await {
+            record(&tag, "relay");
+            error!("inbound {} task {} panicked: {}", tag, context, panic_message(&*err));
+        }
+    })
+}
+
+/// makes an accept loop, called again for every restart
+pub type Listen = Box<dyn Fn() -> BoxFuture<'static, ()> + Send>;
+
+/// run the accept loop made by listen, started again after a panic
+/// the loop ending normally, e.g. bind failed, is not restarted
+pub fn supervise<F>(tag: String, addr: SocketAddr, listen: F) -> BoxFuture<'static, ()>
+where
+    F: Fn() -> BoxFuture<'static, ()> + Send + 'static,
+{
+    async move {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let err = match AssertUnwindSafe(listen()).catch_unwind().await {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            record(&tag, "accept");
+            let delay = if started.elapsed() >= STABLE { MIN_BACKOFF } else { backoff };
+            error!(
+                "inbound {} accept loop at {} panicked: {}, restarting in {:?}",
+                tag,
+                addr,
+                panic_message(&*err),
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            backoff = (delay * 2).min(MAX_BACKOFF);
+        }
+    }
+    .boxed()
+}
+
+/// {"<inbound>": {"accept", "relay"}}
+pub fn snapshot() -> Value {
+    let mut result = serde_json::Map::new();
+    for ((tag, kind), count) in CRASHES.lock().unwrap().iter() {
+        let entry = result
+            .entry(tag.clone())
+            .or_insert_with(|| json!({ "accept": 0, "relay": 0 }));
+        entry[*kind] = json!(count);
+    }
+    Value::Object(result)
+}
+
+#[tokio::test]
+async fn test_supervise_restart() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    supervise("supervise_in".to_string(), "127.0.0.1:1080".parse().unwrap(), move || {
+        let counter = counter.clone();
+        async move {
+            // 第一次 panic，重启之后正常结束
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("malformed packet");
+            }
+        }
+        .boxed()
+    })
+    .await;
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    spawn("supervise_in", "127.0.0.1:1 => example.com:443", async { panic!("relay") }).await.unwrap();
+    assert_eq!(snapshot()["supervise_in"], json!({ "accept": 1, "relay": 1 }));
+}
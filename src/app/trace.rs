@@ -0,0 +1,147 @@
+// 每个连接的事件时间线，排查难以复现的连接失败
+// session 在 inbound accept 时分配 id（Session::id），日志中以 sid=<id> 标注
+// accept 之后的整个连接 task 运行在 scope 中，dispatcher、router、dns 查询与 outbound 拨号通过 task local 找到当前 session 记录事件
+// 进行中的 session 全部保留，结束的保留最近 RECENT_SESSIONS 个，通过 api GET /sessions/trace?id=<id> 查看
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+
+const RECENT_SESSIONS: usize = 1024;
+// 长时间运行的 udp flow 等不会无限增长
+const MAX_EVENTS: usize = 256;
+
+struct Timeline {
+    id: u64,
+    started: SystemTime,
+    start: Instant,
+    events: Mutex<Vec<(Duration, String)>>,
+    ended: Mutex<Option<Duration>>,
+}
+
+impl Timeline {
+    fn push(&self, event: String) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() < MAX_EVENTS {
+            events.push((self.start.elapsed(), event));
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let ended = *self.ended.lock().unwrap();
+        let events: Vec<Value> = self
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(at, event)| json!({ "at_ms": at.as_millis() as u64, "event": event }))
+            .collect();
+        json!({
+            "id": self.id,
+            "started": self.started.duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0),
+            "live": ended.is_none(),
+            "duration_ms": ended.unwrap_or_else(|| self.start.elapsed()).as_millis() as u64,
+            "events": events,
+        })
+    }
+}
+
+lazy_static! {
+    static ref LIVE: Mutex<HashMap<u64, Arc<Timeline>>> = Mutex::new(HashMap::new());
+    static ref RECENT: Mutex<VecDeque<Arc<Timeline>>> = Mutex::new(VecDeque::new());
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<Timeline>;
+}
+
+// task 被 abort 或者 panic 时同样移到 RECENT
+struct Finish(Arc<Timeline>);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        *self.0.ended.lock().unwrap() = Some(self.0.start.elapsed());
+        LIVE.lock().unwrap().remove(&self.0.id);
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == RECENT_SESSIONS {
+            recent.pop_front();
+        }
+        recent.push_back(self.0.clone());
+    }
+}
+
+/// run the task of session id, events recorded inside it belong to the session
+pub async fn scope<F: Future>(id: u64, future: F) -> F::Output {
+    let timeline = Arc::new(Timeline {
+        id,
+        started: SystemTime::now(),
+        start: Instant::now(),
+        events: Mutex::new(Vec::new()),
+        ended: Mutex::new(None),
+    });
+    LIVE.lock().unwrap().insert(id, timeline.clone());
+    let _finish = Finish(timeline.clone());
+    CURRENT.scope(timeline, future).await
+}
+
+/// append to the timeline of the current session, nothing happens outside a scope
+pub fn event<D: Display>(event: D) {
+    let _ = CURRENT.try_with(|x| x.push(event.to_string()));
+}
+
+/// id of the current session
+pub fn current() -> Option<u64> {
+    CURRENT.try_with(|x| x.id).ok()
+}
+
+/// "sid=<id>" of the current session for log lines, "sid=-" outside a scope
+pub fn sid() -> Sid {
+    Sid(current())
+}
+
+pub struct Sid(Option<u64>);
+
+impl Display for Sid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "sid={}", id),
+            None => write!(f, "sid=-"),
+        }
+    }
+}
+
+/// the timeline of a live or recently finished session
+pub fn lookup(id: u64) -> Option<Value> {
+    if let Some(timeline) = LIVE.lock().unwrap().get(&id) {
+        return Some(timeline.to_json());
+    }
+    RECENT.lock().unwrap().iter().rev().find(|x| x.id == id).map(|x| x.to_json())
+}
+
+#[tokio::test]
+async fn test_trace() {
+    event("outside");
+    assert_eq!(sid().to_string(), "sid=-");
+    let id = u64::MAX - 1;
+    scope(id, async {
+        event(format_args!("accepted from {}", "127.0.0.1:50000"));
+        assert_eq!(current(), Some(id));
+        assert_eq!(lookup(id).unwrap()["live"], true);
+        // 同一个 task 中的嵌套调用
+        async { event("dial failed") }.await;
+    })
+    .await;
+    let timeline = lookup(id).unwrap();
+    assert_eq!(timeline["live"], false);
+    assert_eq!(timeline["events"][0]["event"], "accepted from 127.0.0.1:50000");
+    assert_eq!(timeline["events"][1]["event"], "dial failed");
+    assert_eq!(timeline["events"].as_array().unwrap().len(), 2);
+    assert!(lookup(u64::MAX).is_none());
+}
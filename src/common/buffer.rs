@@ -0,0 +1,265 @@
+// 全局 buffer pool
+// 按大小分为 SMALL(2k) MEDIUM(16k) LARGE(64k) 三类，drop 时归还
+// tun packet，udp relay，stream copy 等热路径不再每个 task 单独分配
+// 每类最多缓存 MAX_CACHED 个，超出的直接释放
+
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use lazy_static::lazy_static;
+
+use super::ratelimit::RateLimiter;
+use serde_json::{json, Value};
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const SMALL: usize = 2 * 1024;
+pub const MEDIUM: usize = 16 * 1024;
+pub const LARGE: usize = 64 * 1024;
+
+const CLASSES: [usize; 3] = [SMALL, MEDIUM, LARGE];
+const MAX_CACHED: usize = 256;
+
+#[derive(Default)]
+struct Class {
+    free: Mutex<Vec<Vec<u8>>>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    in_use: AtomicU64,
+}
+
+pub struct BufferPool {
+    classes: [Class; 3],
+}
+
+lazy_static! {
+    static ref POOL: BufferPool = BufferPool {
+        classes: Default::default(),
+    };
+}
+
+/// a buffer of size bytes, returned to the pool on drop, reused buffers keep stale contents
+/// sizes above LARGE are allocated without pooling
+pub fn get(size: usize) -> Buffer {
+    POOL.get(size)
+}
+
+/// pool usage per size class
+pub fn snapshot() -> Value {
+    POOL.snapshot()
+}
+
+impl BufferPool {
+    fn get(&'static self, size: usize) -> Buffer {
+        let idx = match CLASSES.iter().position(|x| *x >= size) {
+            Some(i) => i,
+            None => {
+                return Buffer {
+                    data: vec![0u8; size],
+                    len: size,
+                    class: None,
+                }
+            }
+        };
+        let class = &self.classes[idx];
+        class.in_use.fetch_add(1, Ordering::Relaxed);
+        let data = match class.free.lock().unwrap().pop() {
+            Some(data) => {
+                class.reused.fetch_add(1, Ordering::Relaxed);
+                data
+            }
+            None => {
+                class.allocated.fetch_add(1, Ordering::Relaxed);
+                vec![0u8; CLASSES[idx]]
+            }
+        };
+        Buffer {
+            data,
+            len: size,
+            class: Some(class),
+        }
+    }
+
+    fn snapshot(&self) -> Value {
+        let mut result = serde_json::Map::new();
+        for (size, class) in CLASSES.iter().zip(self.classes.iter()) {
+            result.insert(
+                size.to_string(),
+                json!({
+                    "allocated": class.allocated.load(Ordering::Relaxed),
+                    "reused": class.reused.load(Ordering::Relaxed),
+                    "in_use": class.in_use.load(Ordering::Relaxed),
+                    "cached": class.free.lock().unwrap().len(),
+                }),
+            );
+        }
+        Value::Object(result)
+    }
+}
+
+pub struct Buffer {
+    data: Vec<u8>,
+    // 请求的大小，deref 只暴露这部分
+    len: usize,
+    class: Option<&'static Class>,
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(class) = self.class {
+            class.in_use.fetch_sub(1, Ordering::Relaxed);
+            let mut free = class.free.lock().unwrap();
+            if free.len() < MAX_CACHED {
+                free.push(std::mem::take(&mut self.data));
+            }
+        }
+    }
+}
+
+/// last time bytes were relayed in either direction
+pub struct Activity {
+    start: Instant,
+    // milliseconds since start
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    pub fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+// 与 tokio::io::copy_bidirectional 相同，但每个方向使用 pool 中的 MEDIUM buffer
+// 写之前依次从 limits 中取令牌
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limits: &[&RateLimiter],
+    activity: &Activity,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = get(MEDIUM);
+    let mut total = 0u64;
+    // 上次 flush 之后是否写入过数据
+    let mut dirty = false;
+    loop {
+        // reader 暂时没有数据时 flush，tls 等有缓冲的 writer 不会一直留着已经写入的数据
+        let n = match reader.read(&mut buf).now_or_never() {
+            Some(res) => res?,
+            None => {
+                if dirty {
+                    writer.flush().await?;
+                    dirty = false;
+                }
+                reader.read(&mut buf).await?
+            }
+        };
+        if n == 0 {
+            writer.flush().await?;
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+        activity.touch();
+        for limit in limits {
+            limit.acquire(n).await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        dirty = true;
+        total += n as u64;
+    }
+}
+
+/// relay until both directions reached EOF, returns (a => b, b => a) bytes
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    copy_bidirectional_limited(a, b, &[], &[], &Activity::new()).await
+}
+
+/// copy_bidirectional with a => b throttled by up and b => a throttled by down, activity touched on every write
+pub async fn copy_bidirectional_limited<A, B>(
+    a: &mut A,
+    b: &mut B,
+    up: &[&RateLimiter],
+    down: &[&RateLimiter],
+    activity: &Activity,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_reader, mut a_writer) = split(a);
+    let (mut b_reader, mut b_writer) = split(b);
+    tokio::try_join!(
+        copy(&mut a_reader, &mut b_writer, up, activity),
+        copy(&mut b_reader, &mut a_writer, down, activity)
+    )
+}
+
+#[test]
+fn test_buffer_reuse() {
+    let before = POOL.classes[0].reused.load(Ordering::Relaxed);
+    let buf = get(100);
+    assert_eq!(buf.len(), 100);
+    drop(buf);
+    let buf = get(SMALL);
+    assert_eq!(buf.len(), SMALL);
+    assert!(POOL.classes[0].reused.load(Ordering::Relaxed) > before);
+    assert_eq!(get(LARGE + 1).len(), LARGE + 1);
+}
+
+#[tokio::test]
+async fn test_copy_flush() {
+    let (mut client, mut a) = tokio::io::duplex(1024);
+    let (b, mut server) = tokio::io::duplex(1024);
+    // 有缓冲的 writer，只写入不 flush 时对方收不到
+    let mut b = tokio::io::BufWriter::new(b);
+    tokio::spawn(async move {
+        let _ = copy(&mut a, &mut b, &[], &Activity::new()).await;
+    });
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    tokio::time::timeout(Duration::from_secs(1), server.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf, b"ping");
+}
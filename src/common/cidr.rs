@@ -0,0 +1,169 @@
+// ip cidr 集合，ipv4 与 ipv6 各一棵二叉前缀树
+// 规则加载时建树，查询最多走 32/128 层，与 cidr 的数量无关
+// 整个国家的 geoip 列表有几十万条，逐条 contains 太慢
+
+use std::{iter::FromIterator, net::IpAddr};
+
+use ipnet::IpNet;
+
+// children 为 0 表示没有子节点，根节点不会是任何节点的子节点
+#[derive(Default, Clone, Copy)]
+struct Node {
+    children: [u32; 2],
+    // 有 cidr 在这个节点结束
+    terminal: bool,
+}
+
+#[derive(Default)]
+struct Trie {
+    nodes: Vec<Node>,
+}
+
+// key 的低 width 位是地址，从最高位开始逐位向下
+fn bit(key: u128, width: u8, depth: u8) -> usize {
+    ((key >> (width - 1 - depth)) & 1) as usize
+}
+
+impl Trie {
+    fn insert(&mut self, key: u128, len: u8, width: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut current = 0;
+        for depth in 0..len {
+            let bit = bit(key, width, depth);
+            current = match self.nodes[current].children[bit] {
+                0 => {
+                    self.nodes.push(Node::default());
+                    let next = self.nodes.len() - 1;
+                    self.nodes[current].children[bit] = next as u32;
+                    next
+                }
+                next => next as usize,
+            };
+        }
+        self.nodes[current].terminal = true;
+    }
+
+    // 返回匹配到的最长前缀的长度
+    fn longest_match(&self, key: u128, width: u8) -> Option<u8> {
+        let mut current = self.nodes.first()?;
+        let mut best = None;
+        for depth in 0..=width {
+            if current.terminal {
+                best = Some(depth);
+            }
+            if depth == width {
+                break;
+            }
+            match current.children[bit(key, width, depth)] {
+                0 => break,
+                next => current = &self.nodes[next as usize],
+            }
+        }
+        best
+    }
+
+    // 任意一个前缀匹配即可，遇到第一个结束的 cidr 就返回
+    fn contains(&self, key: u128, width: u8) -> bool {
+        let mut current = match self.nodes.first() {
+            Some(x) => x,
+            None => return false,
+        };
+        for depth in 0..width {
+            if current.terminal {
+                return true;
+            }
+            match current.children[bit(key, width, depth)] {
+                0 => return false,
+                next => current = &self.nodes[next as usize],
+            }
+        }
+        current.terminal
+    }
+}
+
+fn key(ip: &IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(v4) => (u32::from(*v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(*v6), 128),
+    }
+}
+
+#[derive(Default)]
+pub struct CidrSet {
+    v4: Trie,
+    v6: Trie,
+    len: usize,
+}
+
+impl CidrSet {
+    pub fn insert(&mut self, net: &IpNet) {
+        let (key, width) = key(&net.addr());
+        let trie = match net {
+            IpNet::V4(..) => &mut self.v4,
+            IpNet::V6(..) => &mut self.v6,
+        };
+        trie.insert(key, net.prefix_len(), width);
+        self.len += 1;
+    }
+
+    /// ipv4 mapped ipv6 addresses are not unmapped here, see router::unmapped
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (key, width) = key(ip);
+        match ip {
+            IpAddr::V4(..) => self.v4.contains(key, width),
+            IpAddr::V6(..) => self.v6.contains(key, width),
+        }
+    }
+
+    /// prefix length of the most specific cidr containing ip
+    pub fn longest_match(&self, ip: &IpAddr) -> Option<u8> {
+        let (key, width) = key(ip);
+        match ip {
+            IpAddr::V4(..) => self.v4.longest_match(key, width),
+            IpAddr::V6(..) => self.v6.longest_match(key, width),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'a> FromIterator<&'a IpNet> for CidrSet {
+    fn from_iter<T: IntoIterator<Item = &'a IpNet>>(iter: T) -> Self {
+        let mut set = CidrSet::default();
+        for net in iter {
+            set.insert(net);
+        }
+        set
+    }
+}
+
+#[test]
+fn test_cidr_set() {
+    let nets: Vec<IpNet> = ["10.0.0.0/8", "10.1.0.0/16", "192.168.1.1/32", "2001:db8::/32", "0.0.0.0/0"]
+        .iter()
+        .map(|x| x.parse().unwrap())
+        .collect();
+    let set: CidrSet = nets[..4].iter().collect();
+    assert_eq!(set.len(), 4);
+    assert!(set.contains(&"10.2.3.4".parse().unwrap()));
+    assert_eq!(set.longest_match(&"10.1.3.4".parse().unwrap()), Some(16));
+    assert_eq!(set.longest_match(&"10.2.3.4".parse().unwrap()), Some(8));
+    assert!(set.contains(&"192.168.1.1".parse().unwrap()));
+    assert!(!set.contains(&"192.168.1.2".parse().unwrap()));
+    assert!(!set.contains(&"11.0.0.1".parse().unwrap()));
+    assert!(set.contains(&"2001:db8::1".parse().unwrap()));
+    assert!(!set.contains(&"2001:db9::1".parse().unwrap()));
+    // ipv4 与 ipv6 分开
+    assert!(!set.contains(&"::a00:1".parse().unwrap()));
+    let all: CidrSet = nets[4..].iter().collect();
+    assert!(all.contains(&"8.8.8.8".parse().unwrap()));
+    assert!(!CidrSet::default().contains(&"8.8.8.8".parse().unwrap()));
+}
@@ -0,0 +1,337 @@
+// 实验性: 使用 cgroup 程序让 tunnel 自己的连接绕过 tun 的默认路由，不需要 fwmark 与 ip rule
+// BPF_CGROUP_INET{4,6}_CONNECT 在路由查找之前执行，通过 bpf_setsockopt(SO_BINDTODEVICE) 把 socket 绑定到物理网卡
+// 跳过 loopback 与已经绑定网卡的 socket（dialer.interface），需要 linux 5.8+
+// 程序作用于整个 cgroup，systemd 下就是服务自己；进程在根 cgroup 时必须显式配置 cgroup
+// 没有 connect 的 udp socket（quic）不经过这个 hook，仍然需要 dialer.interface
+// 使用 BPF_LINK_CREATE，fd 关闭（进程退出）时自动 detach
+// 直连流量在内核中转发交给 splice feature，这里不处理
+
+#![cfg_attr(not(all(target_os = "linux", feature = "ebpf")), allow(dead_code))]
+
+use anyhow::Result;
+use log::warn;
+
+use crate::config::Config;
+
+const IFNAMSIZ: usize = 16;
+
+/// keeps the programs attached until dropped
+pub struct Bypass {
+    #[allow(dead_code)]
+    links: Vec<std::fs::File>,
+}
+
+/// attach the bypass programs if general.ebpf is set, must be called before privileges are dropped
+pub fn attach(config: &Config) -> Result<Option<Bypass>> {
+    let settings = match &config.general.ebpf {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    crate::init_logger();
+    if !cfg!(all(target_os = "linux", feature = "ebpf")) {
+        warn!("general.ebpf needs linux and the ebpf feature, ignored");
+        return Ok(None);
+    }
+    imp::attach(settings.interface.as_deref(), settings.cgroup.as_deref()).map(Some)
+}
+
+// struct bpf_insn
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_STX: u8 = 0x03;
+const BPF_JMP: u8 = 0x05;
+const BPF_ALU64: u8 = 0x07;
+const BPF_W: u8 = 0x00;
+const BPF_DW: u8 = 0x18;
+const BPF_IMM: u8 = 0x00;
+const BPF_MEM: u8 = 0x60;
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const BPF_ADD: u8 = 0x00;
+const BPF_MOV: u8 = 0xb0;
+const BPF_AND: u8 = 0x50;
+const BPF_RSH: u8 = 0x70;
+const BPF_JEQ: u8 = 0x10;
+const BPF_JNE: u8 = 0x50;
+const BPF_CALL: u8 = 0x80;
+const BPF_EXIT: u8 = 0x90;
+
+const BPF_FUNC_SETSOCKOPT: i32 = 49;
+const SOL_SOCKET: i32 = 1;
+const SO_BINDTODEVICE: i32 = 25;
+
+// struct bpf_sock_addr
+const USER_IP4: i16 = 4;
+const USER_IP6: i16 = 8;
+const SK: i16 = 64;
+// struct bpf_sock
+const BOUND_DEV_IF: i16 = 0;
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    let regs = if cfg!(target_endian = "little") {
+        dst | src << 4
+    } else {
+        dst << 4 | src
+    };
+    Insn { code, regs, off, imm }
+}
+
+fn mov_reg(dst: u8, src: u8) -> Insn {
+    insn(BPF_ALU64 | BPF_MOV | BPF_X, dst, src, 0, 0)
+}
+
+fn mov_imm(dst: u8, imm: i32) -> Insn {
+    insn(BPF_ALU64 | BPF_MOV | BPF_K, dst, 0, 0, imm)
+}
+
+fn load(size: u8, dst: u8, src: u8, off: i16) -> Insn {
+    insn(BPF_LDX | BPF_MEM | size, dst, src, off, 0)
+}
+
+fn store(size: u8, dst: u8, src: u8, off: i16) -> Insn {
+    insn(BPF_STX | BPF_MEM | size, dst, src, off, 0)
+}
+
+// 跳到最后的 r0 = 1; exit，由 program 填写偏移
+const TO_END: i16 = i16::MIN;
+
+fn jump(op: u8, dst: u8, imm: i32, off: i16) -> Insn {
+    insn(BPF_JMP | op | BPF_K, dst, 0, off, imm)
+}
+
+fn load_imm64(dst: u8, imm: u64) -> [Insn; 2] {
+    [
+        insn(BPF_LD | BPF_DW | BPF_IMM, dst, 0, 0, imm as u32 as i32),
+        insn(0, 0, 0, 0, (imm >> 32) as u32 as i32),
+    ]
+}
+
+// ctx 中按网络字节序保存的地址，按本机字节序读出
+fn loopback_v4_byte() -> [Insn; 1] {
+    if cfg!(target_endian = "little") {
+        [insn(BPF_ALU64 | BPF_AND | BPF_K, 2, 0, 0, 0xff)]
+    } else {
+        [insn(BPF_ALU64 | BPF_RSH | BPF_K, 2, 0, 0, 24)]
+    }
+}
+
+/// connect4/connect6 program, binds the socket to ifname unless it goes to loopback or is already bound
+fn program(ifname: &str, v6: bool) -> Vec<Insn> {
+    let mut name = [0u8; IFNAMSIZ];
+    let len = ifname.len().min(IFNAMSIZ - 1);
+    name[..len].copy_from_slice(&ifname.as_bytes()[..len]);
+
+    let mut checks = Vec::new();
+    // r6 = ctx
+    checks.push(mov_reg(6, 1));
+    if v6 {
+        // ::1，前三个字不为 0 时直接检查 sk
+        for i in 0..3 {
+            checks.push(load(BPF_W, 2, 6, USER_IP6 + i * 4));
+            checks.push(jump(BPF_JNE, 2, 0, 6 - 2 * i));
+        }
+        checks.push(load(BPF_W, 2, 6, USER_IP6 + 12));
+        checks.push(jump(BPF_JEQ, 2, u32::from_be(1) as i32, TO_END));
+    } else {
+        // 127.0.0.0/8
+        checks.push(load(BPF_W, 2, 6, USER_IP4));
+        checks.extend_from_slice(&loopback_v4_byte());
+        checks.push(jump(BPF_JEQ, 2, 127, TO_END));
+    }
+    checks.push(load(BPF_DW, 2, 6, SK));
+    checks.push(jump(BPF_JEQ, 2, 0, TO_END));
+    checks.push(load(BPF_W, 3, 2, BOUND_DEV_IF));
+    checks.push(jump(BPF_JNE, 3, 0, TO_END));
+
+    let mut call = Vec::new();
+    // 网卡名写入栈 r10-16
+    for (i, chunk) in name.chunks(8).enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(chunk);
+        call.extend_from_slice(&load_imm64(2, u64::from_ne_bytes(bytes)));
+        call.push(store(BPF_DW, 10, 2, -(IFNAMSIZ as i16) + i as i16 * 8));
+    }
+    call.push(mov_reg(1, 6));
+    call.push(mov_imm(2, SOL_SOCKET));
+    call.push(mov_imm(3, SO_BINDTODEVICE));
+    call.push(mov_reg(4, 10));
+    call.push(insn(BPF_ALU64 | BPF_ADD | BPF_K, 4, 0, 0, -(IFNAMSIZ as i32)));
+    call.push(mov_imm(5, IFNAMSIZ as i32));
+    call.push(insn(BPF_JMP | BPF_CALL, 0, 0, 0, BPF_FUNC_SETSOCKOPT));
+
+    let end = (checks.len() + call.len()) as i16;
+    for (i, x) in checks.iter_mut().enumerate() {
+        if x.off == TO_END {
+            x.off = end - i as i16 - 1;
+        }
+    }
+    let mut insns = checks;
+    insns.extend(call);
+    insns.push(mov_imm(0, 1));
+    insns.push(insn(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+    insns
+}
+
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+mod imp {
+    use std::{
+        fs::{self, File},
+        io,
+        os::unix::io::{AsRawFd, FromRawFd},
+        path::PathBuf,
+    };
+
+    use anyhow::{anyhow, bail, Result};
+    use log::info;
+
+    use super::{program, Bypass, Insn};
+    use crate::common::linux::get_default_interface;
+
+    const BPF_PROG_LOAD: libc::c_long = 5;
+    const BPF_LINK_CREATE: libc::c_long = 28;
+    const BPF_PROG_TYPE_CGROUP_SOCK_ADDR: u32 = 18;
+    const BPF_CGROUP_INET4_CONNECT: u32 = 10;
+    const BPF_CGROUP_INET6_CONNECT: u32 = 11;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ProgLoadAttr {
+        prog_type: u32,
+        insn_cnt: u32,
+        insns: u64,
+        license: u64,
+        log_level: u32,
+        log_size: u32,
+        log_buf: u64,
+        kern_version: u32,
+        prog_flags: u32,
+        prog_name: [u8; 16],
+        prog_ifindex: u32,
+        expected_attach_type: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct LinkCreateAttr {
+        prog_fd: u32,
+        target_fd: u32,
+        attach_type: u32,
+        flags: u32,
+    }
+
+    fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<File> {
+        let fd = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, std::mem::size_of::<T>() as u32) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd as i32) })
+    }
+
+    fn load(insns: &[Insn], attach_type: u32, name: &str) -> Result<File> {
+        let license = b"GPL\0";
+        let mut log = vec![0u8; 65536];
+        let mut attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 1,
+            log_size: log.len() as u32,
+            log_buf: log.as_mut_ptr() as u64,
+            expected_attach_type: attach_type,
+            ..Default::default()
+        };
+        attr.prog_name[..name.len()].copy_from_slice(name.as_bytes());
+        bpf(BPF_PROG_LOAD, &mut attr).map_err(|err| {
+            let end = log.iter().position(|x| *x == 0).unwrap_or(log.len());
+            anyhow!("load {} failed {} {}", name, err, String::from_utf8_lossy(&log[..end]))
+        })
+    }
+
+    // cgroup v2: /proc/self/cgroup 中 "0::/system.slice/tunnel.service"
+    fn own_cgroup() -> Result<PathBuf> {
+        let content = fs::read_to_string("/proc/self/cgroup")?;
+        let path = content
+            .lines()
+            .find_map(|x| x.strip_prefix("0::"))
+            .ok_or_else(|| anyhow!("cgroup v2 is not mounted"))?;
+        if path == "/" {
+            bail!("running in the root cgroup, set general.ebpf.cgroup explicitly");
+        }
+        Ok(PathBuf::from("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+    }
+
+    pub fn attach(interface: Option<&str>, cgroup: Option<&str>) -> Result<Bypass> {
+        let interface = match interface {
+            Some(x) => x.to_string(),
+            None => get_default_interface()?,
+        };
+        let cgroup = match cgroup {
+            Some(x) => PathBuf::from(x),
+            None => own_cgroup()?,
+        };
+        let target = File::open(&cgroup).map_err(|err| anyhow!("open cgroup {} failed {}", cgroup.display(), err))?;
+        let mut links = Vec::new();
+        for (attach_type, name, v6) in [
+            (BPF_CGROUP_INET4_CONNECT, "tunnel_connect4", false),
+            (BPF_CGROUP_INET6_CONNECT, "tunnel_connect6", true),
+        ] {
+            let prog = load(&program(&interface, v6), attach_type, name)?;
+            let mut attr = LinkCreateAttr {
+                prog_fd: prog.as_raw_fd() as u32,
+                target_fd: target.as_raw_fd() as u32,
+                attach_type,
+                flags: 0,
+            };
+            // link 持有 prog 的引用，prog fd 可以关闭
+            let link = bpf(BPF_LINK_CREATE, &mut attr).map_err(|err| anyhow!("attach {} failed {}", name, err))?;
+            links.push(link);
+        }
+        info!("connections of cgroup {} are bound to {}", cgroup.display(), interface);
+        Ok(Bypass { links })
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "ebpf")))]
+mod imp {
+    use anyhow::{bail, Result};
+
+    use super::Bypass;
+
+    pub fn attach(_interface: Option<&str>, _cgroup: Option<&str>) -> Result<Bypass> {
+        bail!("ebpf is not supported")
+    }
+}
+
+#[test]
+fn test_bypass_program() {
+    let insns = program("eth0", false);
+    let exit = insns.len() - 1;
+    assert_eq!(insns[exit], insn(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+    assert_eq!(insns[exit - 1], mov_imm(0, 1));
+    // ipv4 的每个条件跳转都落在 r0 = 1
+    for (i, x) in insns.iter().enumerate() {
+        if x.code == BPF_JMP | BPF_JEQ | BPF_K || x.code == BPF_JMP | BPF_JNE | BPF_K {
+            assert_eq!(i as i16 + x.off + 1, exit as i16 - 1);
+        }
+    }
+    // 网卡名以 0 结尾写入栈中
+    let i = insns.iter().position(|x| x.code == BPF_LD | BPF_DW | BPF_IMM).unwrap();
+    let name = insns[i].imm as u32 as u64 | (insns[i + 1].imm as u32 as u64) << 32;
+    assert_eq!(name, u64::from_ne_bytes(*b"eth0\0\0\0\0"));
+    // ipv6 不是 ::1 时跳到 sk 检查
+    let insns = program("eth0", true);
+    assert_eq!(insns.len(), exit + 1 + 5);
+    for i in [2, 4, 6] {
+        assert_eq!(insns[i + 1 + insns[i].off as usize], load(BPF_DW, 2, 6, SK));
+    }
+}
@@ -0,0 +1,232 @@
+// 地址族偏好与 NAT64
+// ip_strategy: prefer-ipv4 | prefer-ipv6 | ipv4-only | ipv6-only，未配置时由 use_ipv6 与 prefer_ipv6 决定
+// nat64_prefix: 只有 ipv6 的运营商网络上，ipv4 地址（解析结果与字面量）按 RFC 6052 嵌入前缀后通过 NAT64 访问
+//   dns inbound 对没有 AAAA 记录的域名用 A 记录合成 AAAA（DNS64）
+
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use anyhow::{anyhow, bail, Result};
+use ipnet::Ipv6Net;
+use log::warn;
+
+use crate::config::GeneralSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpStrategy {
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl TryFrom<&str> for IpStrategy {
+    type Error = anyhow::Error;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "prefer-ipv4" => Ok(IpStrategy::PreferIpv4),
+            "prefer-ipv6" => Ok(IpStrategy::PreferIpv6),
+            "ipv4-only" => Ok(IpStrategy::Ipv4Only),
+            "ipv6-only" => Ok(IpStrategy::Ipv6Only),
+            _ => Err(anyhow!("unknown ip strategy {}", value)),
+        }
+    }
+}
+
+/// NAT64 prefix, ipv4 addresses are embedded as in RFC 6052
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Nat64 {
+    prefix: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64 {
+    /// "64:ff9b::/96", the length must be one of 32, 40, 48, 56, 64 and 96
+    pub fn parse(value: &str) -> Result<Nat64> {
+        let net: Ipv6Net = value.parse().map_err(|err| anyhow!("bad nat64 prefix {} {}", value, err))?;
+        if ![32, 40, 48, 56, 64, 96].contains(&net.prefix_len()) {
+            bail!("bad nat64 prefix {}, length must be 32, 40, 48, 56, 64 or 96", value);
+        }
+        Ok(Nat64 {
+            prefix: net.network(),
+            len: net.prefix_len(),
+        })
+    }
+
+    /// only global unicast ipv4 is reachable through NAT64 (RFC 6052 3.1, RFC 6147 5.1.4)
+    pub fn translatable(ip: Ipv4Addr) -> bool {
+        let [a, b, c, _] = ip.octets();
+        !(ip.is_unspecified()
+            || a == 0
+            || ip.is_private()
+            || ip.is_loopback()
+            || ip.is_link_local()
+            || ip.is_multicast()
+            || ip.is_broadcast()
+            || ip.is_documentation()
+            // 100.64.0.0/10 运营商 NAT
+            || (a == 100 && (b & 0xc0) == 64)
+            // 192.0.0.0/24 协议分配
+            || (a == 192 && b == 0 && c == 0)
+            // 198.18.0.0/15 基准测试
+            || (a == 198 && (b & 0xfe) == 18)
+            // 240.0.0.0/4 保留
+            || a >= 240)
+    }
+
+    // bits 64..72 (u octet) 必须为 0，ipv4 跳过这个 byte
+    pub fn synthesize(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        let mut pos = self.len as usize / 8;
+        for byte in ip.octets() {
+            if pos == 8 {
+                pos += 1;
+            }
+            octets[pos] = byte;
+            pos += 1;
+        }
+        Ipv6Addr::from(octets)
+    }
+}
+
+/// address family policy applied to resolved and literal destinations
+#[derive(Debug, Clone, Copy)]
+pub struct Family {
+    pub strategy: IpStrategy,
+    pub nat64: Option<Nat64>,
+}
+
+impl Family {
+    pub fn new(general: &GeneralSettings) -> Family {
+        let legacy = match (general.use_ipv6, general.prefer_ipv6) {
+            (false, _) => IpStrategy::Ipv4Only,
+            (true, true) => IpStrategy::PreferIpv6,
+            (true, false) => IpStrategy::PreferIpv4,
+        };
+        let strategy = match general.ip_strategy.as_deref().map(IpStrategy::try_from) {
+            Some(Ok(x)) => x,
+            Some(Err(err)) => {
+                warn!("{}, use {:?}", err, legacy);
+                legacy
+            }
+            None => legacy,
+        };
+        let nat64 = match general.nat64_prefix.as_deref().map(Nat64::parse) {
+            Some(Ok(_)) if strategy == IpStrategy::Ipv4Only => {
+                warn!("nat64_prefix is ignored with ipv4 only");
+                None
+            }
+            Some(Ok(x)) => Some(x),
+            Some(Err(err)) => {
+                warn!("{}", err);
+                None
+            }
+            None => None,
+        };
+        Family { strategy, nat64 }
+    }
+
+    /// whether A records are needed, NAT64 synthesizes from them
+    pub fn query_ipv4(&self) -> bool {
+        self.strategy != IpStrategy::Ipv6Only || self.nat64.is_some()
+    }
+
+    pub fn query_ipv6(&self) -> bool {
+        self.strategy != IpStrategy::Ipv4Only
+    }
+
+    pub fn prefer_ipv6(&self) -> bool {
+        matches!(self.strategy, IpStrategy::PreferIpv6 | IpStrategy::Ipv6Only) || self.nat64.is_some()
+    }
+
+    /// translate with NAT64, drop the excluded family and put the preferred family first
+    pub fn apply(&self, ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        let mut result: Vec<IpAddr> = Vec::with_capacity(ips.len());
+        for ip in ips {
+            let ip = self.translate(ip);
+            let allowed = match self.strategy {
+                IpStrategy::Ipv4Only => ip.is_ipv4(),
+                IpStrategy::Ipv6Only => ip.is_ipv6(),
+                _ => true,
+            };
+            if allowed && !result.contains(&ip) {
+                result.push(ip);
+            }
+        }
+        // 稳定排序，同一地址族内保持 dns 返回的顺序
+        let prefer_ipv6 = self.prefer_ipv6();
+        result.sort_by_key(|x| x.is_ipv6() != prefer_ipv6);
+        result
+    }
+
+    /// literal ipv4 destinations are only translated, an explicit address is never dropped
+    /// private and other non global addresses are kept as is, NAT64 can't reach them
+    pub fn translate(&self, ip: IpAddr) -> IpAddr {
+        match (ip, &self.nat64) {
+            (IpAddr::V4(v4), Some(nat64)) if Nat64::translatable(v4) => IpAddr::V6(nat64.synthesize(v4)),
+            _ => ip,
+        }
+    }
+
+    pub fn translate_addr(&self, addr: SocketAddr) -> SocketAddr {
+        SocketAddr::new(self.translate(addr.ip()), addr.port())
+    }
+}
+
+#[test]
+fn test_family() {
+    let well_known = Nat64::parse("64:ff9b::/96").unwrap();
+    let v4: Ipv4Addr = "192.0.2.33".parse().unwrap();
+    assert_eq!(well_known.synthesize(v4), "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap());
+    // RFC 6052 2.4 的例子
+    assert_eq!(Nat64::parse("2001:db8::/32").unwrap().synthesize(v4), "2001:db8:c000:221::".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(Nat64::parse("2001:db8:100::/40").unwrap().synthesize(v4), "2001:db8:1c0:2:21::".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(Nat64::parse("2001:db8:122:300::/56").unwrap().synthesize(v4), "2001:db8:122:3c0:0:221::".parse::<Ipv6Addr>().unwrap());
+    assert!(Nat64::parse("64:ff9b::/80").is_err());
+
+    let ips: Vec<IpAddr> = vec!["1.1.1.1".parse().unwrap(), "2606:4700::1111".parse().unwrap(), "1.0.0.1".parse().unwrap()];
+    let family = |strategy, nat64| Family { strategy, nat64 };
+    assert_eq!(family(IpStrategy::Ipv4Only, None).apply(ips.clone()), vec![ips[0], ips[2]]);
+    assert_eq!(family(IpStrategy::PreferIpv6, None).apply(ips.clone()), vec![ips[1], ips[0], ips[2]]);
+    assert_eq!(family(IpStrategy::PreferIpv4, None).apply(ips.clone()), vec![ips[0], ips[2], ips[1]]);
+    let nat64 = family(IpStrategy::Ipv6Only, Some(well_known)).apply(ips.clone());
+    assert_eq!(nat64, vec![ips[1], "64:ff9b::101:101".parse::<IpAddr>().unwrap(), "64:ff9b::100:1".parse::<IpAddr>().unwrap()]);
+    assert!(family(IpStrategy::Ipv6Only, None).query_ipv6() && !family(IpStrategy::Ipv6Only, None).query_ipv4());
+}
+
+#[test]
+fn test_family_translate_excluded() {
+    let family = Family {
+        strategy: IpStrategy::PreferIpv6,
+        nat64: Some(Nat64::parse("64:ff9b::/96").unwrap()),
+    };
+    for ip in [
+        "0.0.0.0",
+        "10.1.2.3",
+        "100.64.0.1",
+        "100.127.255.254",
+        "127.0.0.1",
+        "169.254.1.1",
+        "172.16.0.1",
+        "192.0.0.8",
+        "192.0.2.1",
+        "192.168.1.1",
+        "198.18.0.1",
+        "198.51.100.1",
+        "203.0.113.1",
+        "224.0.0.251",
+        "240.0.0.1",
+        "255.255.255.255",
+    ] {
+        let ip: IpAddr = ip.parse().unwrap();
+        assert_eq!(family.translate(ip), ip, "{} must not be translated", ip);
+    }
+    assert_eq!(family.translate("100.128.0.1".parse().unwrap()), "64:ff9b::6480:1".parse::<IpAddr>().unwrap());
+    assert_eq!(family.translate("172.32.0.1".parse().unwrap()), "64:ff9b::ac20:1".parse::<IpAddr>().unwrap());
+    assert_eq!(
+        family.translate_addr("8.8.8.8:53".parse().unwrap()),
+        "[64:ff9b::808:808]:53".parse::<SocketAddr>().unwrap()
+    );
+}
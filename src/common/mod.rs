@@ -1,2 +1,13 @@
 #[cfg(target_os = "linux")]
 pub mod linux;
+pub mod buffer;
+pub mod cidr;
+pub mod ebpf;
+pub mod family;
+pub mod monitor;
+pub mod network;
+pub mod privilege;
+pub mod process;
+pub mod ratelimit;
+pub mod sysproxy;
+pub mod systemd;
@@ -0,0 +1,205 @@
+// 默认路由变化监听，Wi-Fi、有线与 LTE 之间切换时不需要重启
+// linux: netlink NETLINK_ROUTE，订阅 link/address/route 变化
+// macos: PF_ROUTE routing socket，内核的 RTM_* 消息
+// 收到任意消息后重新检测默认路由所在的网卡，其他系统退回定期检测
+
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use log::{debug, info};
+
+use super::network::default_interface;
+
+// 路由变化往往是一连串消息，等一会儿再检测
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// 自己的 tun 设备接管默认路由时不能绑定到它上面，否则出站连接会回到 tunnel
+fn is_tun(name: &str) -> bool {
+    name.starts_with("tun") || name.starts_with("utun")
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod imp {
+    use std::{
+        fs::File,
+        io::{self, Read},
+        os::unix::io::FromRawFd,
+    };
+
+    use tokio::io::unix::AsyncFd;
+
+    #[cfg(target_os = "linux")]
+    fn open() -> io::Result<File> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_LINK
+            | libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_ROUTE) as u32;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(file)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn open() -> io::Result<File> {
+        let fd = unsafe { libc::socket(libc::AF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(file)
+    }
+
+    pub struct RouteSocket {
+        fd: AsyncFd<File>,
+    }
+
+    impl RouteSocket {
+        pub fn open() -> io::Result<RouteSocket> {
+            Ok(RouteSocket { fd: AsyncFd::new(open()?)? })
+        }
+
+        // 消息内容不重要，读空之后返回
+        pub async fn wait(&self) -> io::Result<()> {
+            let mut buf = [0u8; 8192];
+            let mut guard = self.fd.readable().await?;
+            loop {
+                match guard.try_io(|fd| (&*fd.get_ref()).read(&mut buf)) {
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(err)) => return Err(err),
+                    Err(_would_block) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod imp {
+    use std::io;
+
+    pub struct RouteSocket;
+
+    impl RouteSocket {
+        pub fn open() -> io::Result<RouteSocket> {
+            Err(io::Error::new(io::ErrorKind::Other, "route monitor not supported"))
+        }
+
+        pub async fn wait(&self) -> io::Result<()> {
+            futures::future::pending().await
+        }
+    }
+}
+
+/// wakes up whenever the routing table or the interfaces change
+pub struct RouteMonitor {
+    socket: Option<imp::RouteSocket>,
+}
+
+impl RouteMonitor {
+    /// falls back to polling if the routing socket can not be opened
+    pub fn open() -> RouteMonitor {
+        let socket = match imp::RouteSocket::open() {
+            Ok(x) => Some(x),
+            Err(err) => {
+                debug!("open route monitor failed {}, poll every {:?}", err, POLL_INTERVAL);
+                None
+            }
+        };
+        RouteMonitor { socket }
+    }
+
+    /// routing socket errors switch to polling
+    pub async fn changed(&mut self) {
+        let socket = match &self.socket {
+            Some(x) => x,
+            None => return tokio::time::sleep(POLL_INTERVAL).await,
+        };
+        if let Err(err) = socket.wait().await {
+            debug!("route monitor failed {}, poll every {:?}", err, POLL_INTERVAL);
+            self.socket = None;
+            return;
+        }
+        tokio::time::sleep(SETTLE_DELAY).await;
+        // 等待期间的消息一起读掉，poll 一次不会阻塞
+        let _ = futures::FutureExt::now_or_never(socket.wait());
+    }
+}
+
+/// the interface of the default route, kept up to date by `watch`
+#[derive(Debug, Default)]
+pub struct DefaultInterface {
+    name: RwLock<Option<String>>,
+}
+
+impl DefaultInterface {
+    pub fn get(&self) -> Option<String> {
+        self.name.read().unwrap().clone()
+    }
+
+    // 返回是否发生了变化
+    fn update(&self, current: Option<String>) -> bool {
+        if current.as_deref().map_or(false, is_tun) {
+            return false;
+        }
+        let mut name = self.name.write().unwrap();
+        if *name == current {
+            return false;
+        }
+        info!("default interface changed {:?} => {:?}", *name, current);
+        *name = current;
+        true
+    }
+
+    pub fn watch(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let mut monitor = RouteMonitor::open();
+            loop {
+                if let Ok(current) = tokio::task::spawn_blocking(default_interface).await {
+                    self.update(current);
+                }
+                monitor.changed().await;
+            }
+        })
+    }
+}
+
+#[test]
+fn test_default_interface() {
+    let interface = DefaultInterface::default();
+    assert!(interface.update(Some("eth0".to_string())));
+    assert!(!interface.update(Some("eth0".to_string())));
+    // tun 接管默认路由时保留之前的网卡
+    assert!(!interface.update(Some("utun3".to_string())));
+    assert_eq!(interface.get(), Some("eth0".to_string()));
+    assert!(interface.update(Some("wlan0".to_string())));
+}
@@ -0,0 +1,111 @@
+// 当前所在网络，用于按网络选择 dns upstream 等策略
+// interface: 默认路由所在的网卡
+// ssid: 当前连接的 Wi-Fi，没有连接 Wi-Fi 时为 None
+// linux: /proc/net/route 与 iwgetid/nmcli
+// macos: route get default 与 networksetup
+
+use std::process::Command;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkState {
+    pub interface: Option<String>,
+    pub ssid: Option<String>,
+}
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let out = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if out.is_empty() {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::run;
+
+    // destination 为 00000000 的路由就是默认路由
+    pub fn default_interface() -> Option<String> {
+        let content = std::fs::read_to_string("/proc/net/route").ok()?;
+        content
+            .lines()
+            .skip(1)
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .find(|fields| fields.len() > 1 && fields[1] == "00000000")
+            .map(|fields| fields[0].to_string())
+    }
+
+    pub fn current_ssid(interface: &Option<String>) -> Option<String> {
+        let by_iwgetid = match interface {
+            Some(name) => run("iwgetid", &[name.as_str(), "-r"]),
+            None => run("iwgetid", &["-r"]),
+        };
+        if by_iwgetid.is_some() {
+            return by_iwgetid;
+        }
+        // yes:<ssid>
+        let out = run("nmcli", &["-t", "-f", "active,ssid", "dev", "wifi"])?;
+        out.lines()
+            .find_map(|line| line.strip_prefix("yes:"))
+            .map(|x| x.to_string())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use super::run;
+
+    pub fn default_interface() -> Option<String> {
+        let out = run("route", &["-n", "get", "default"])?;
+        out.lines()
+            .find_map(|line| line.trim().strip_prefix("interface:"))
+            .map(|x| x.trim().to_string())
+    }
+
+    // Current Wi-Fi Network: <ssid>
+    pub fn current_ssid(interface: &Option<String>) -> Option<String> {
+        let device = interface.clone().unwrap_or_else(|| "en0".to_string());
+        let out = run("networksetup", &["-getairportnetwork", device.as_str()])?;
+        out.split_once(": ").map(|(_, ssid)| ssid.trim().to_string())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod imp {
+    pub fn default_interface() -> Option<String> {
+        None
+    }
+
+    pub fn current_ssid(_: &Option<String>) -> Option<String> {
+        None
+    }
+}
+
+/// interface of the default route, reads the routing table or runs a command
+pub fn default_interface() -> Option<String> {
+    imp::default_interface()
+}
+
+impl NetworkState {
+    /// runs external commands, don't call on hot paths
+    pub fn detect() -> NetworkState {
+        let interface = imp::default_interface();
+        let ssid = imp::current_ssid(&interface);
+        NetworkState { interface, ssid }
+    }
+
+    /// None conditions match any network
+    pub fn matches(&self, interface: &Option<String>, ssid: &Option<String>) -> bool {
+        if interface.is_some() && *interface != self.interface {
+            return false;
+        }
+        if ssid.is_some() && *ssid != self.ssid {
+            return false;
+        }
+        true
+    }
+}
@@ -0,0 +1,356 @@
+// 以 root 启动，完成需要权限的操作之后切换到普通用户，只保留运行时需要的 capability
+// 顺序: 加载 ebpf、启动 instance 并等待 tun 设备创建完成、以 root 创建并 chown 之后要写入的目录，最后切换
+// uid 的切换（glibc 的 setresuid）作用于全部线程，capability 却属于单个线程
+// 切换期间让每个 runtime worker 阻塞在一个 task 中，分别设置 PR_SET_KEEPCAPS 与 capset，之后创建的线程继承 worker 的状态
+// 切换之后仍然需要的 capability:
+//   net_bind_service  reload 或者 accept loop 重启时重新监听 1024 以下的端口
+//   net_admin         SO_MARK
+//   net_raw           SO_BINDTODEVICE
+// tun 设备在切换之前已经创建，reload 之后新增的 tun inbound 会因为没有 net_admin 而失败
+// 日志只输出到 stdout，不需要提前打开文件；配置已经在切换之前读取
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use tokio::runtime::Handle;
+
+use crate::{
+    app::{capture_dir, provider_cache_path},
+    config::{Config, DialerSettings},
+};
+
+// linux/capability.h
+const CAPABILITIES: [(&str, u32); 3] = [("net_bind_service", 10), ("net_admin", 12), ("net_raw", 13)];
+
+fn capability(name: &str) -> Result<u32> {
+    let name = name.to_ascii_lowercase();
+    let name = name.trim_start_matches("cap_");
+    match CAPABILITIES.iter().find(|(x, _)| *x == name) {
+        Some((_, x)) => Ok(*x),
+        None => bail!("unsupported capability {}", name),
+    }
+}
+
+fn dialers(config: &Config) -> Vec<&DialerSettings> {
+    let mut dialers: Vec<&DialerSettings> = config.dialer.iter().collect();
+    dialers.extend(config.outbounds.iter().filter_map(|x| x.dialer.as_ref()));
+    for profile in config.profiles.iter().flatten() {
+        dialers.extend(profile.dialer.iter());
+        dialers.extend(profile.outbounds.iter().filter_map(|x| x.dialer.as_ref()));
+    }
+    dialers
+}
+
+/// capabilities the config needs after the switch, general.capabilities overrides them
+pub fn needed_capabilities(config: &Config) -> Result<Vec<u32>> {
+    if let Some(names) = &config.general.capabilities {
+        return names.iter().map(|x| capability(x)).collect();
+    }
+    let inbounds: Vec<_> = config
+        .inbounds
+        .iter()
+        .chain(config.profiles.iter().flatten().flat_map(|x| x.inbounds.iter()))
+        .collect();
+    let dialers = dialers(config);
+    let mut names = Vec::new();
+    let low_port = |x: &&crate::config::Inbound| match &x.port {
+        Some(ports) => ports.ports().map(|x| x.iter().any(|p| *p < 1024)).unwrap_or(false),
+        // dns 默认 53
+        None => x.protocol == "dns",
+    };
+    if inbounds.iter().any(low_port) || config.api.as_ref().map_or(false, |x| x.port < 1024) {
+        names.push("net_bind_service");
+    }
+    if dialers.iter().any(|x| x.fwmark.is_some()) {
+        names.push("net_admin");
+    }
+    if dialers.iter().any(|x| x.interface.is_some() || x.auto_detect_interface == Some(true)) {
+        names.push("net_raw");
+    }
+    names.into_iter().map(capability).collect()
+}
+
+fn cache_files(config: &Config) -> Vec<PathBuf> {
+    config
+        .rule_providers
+        .iter()
+        .flatten()
+        .map(|(name, provider)| provider_cache_path(name, provider))
+        .collect()
+}
+
+/// directories written after the switch: rule provider caches and api captures
+pub fn writable_dirs(config: &Config) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = cache_files(config)
+        .iter()
+        .filter_map(|x| x.parent().map(|x| x.to_path_buf()))
+        .filter(|x| !x.as_os_str().is_empty())
+        .collect();
+    if let Some(api) = &config.api {
+        dirs.push(capture_dir(api.capture_dir.as_deref()));
+    }
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+/// switch to general.user once the privileged setup is done, runtime is the one the instance runs on
+pub fn drop_privileges(config: &Config, runtime: &Handle) -> Result<()> {
+    let user = match &config.general.user {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let keep = needed_capabilities(config)?;
+    // 启动时以 root 下载的缓存文件同样交给切换之后的用户
+    let mut writable = writable_dirs(config);
+    writable.extend(cache_files(config).into_iter().filter(|x| x.is_file()));
+    imp::drop_privileges(user, config.general.group.as_deref(), &keep, &writable, runtime)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        ffi::CString,
+        fs,
+        os::unix::ffi::OsStrExt,
+        path::PathBuf,
+        sync::{mpsc, Arc, Condvar, Mutex},
+        time::Duration,
+    };
+
+    use anyhow::{anyhow, bail, Result};
+    use log::{info, warn};
+    use tokio::runtime::Handle;
+
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+    // 等待全部 worker 进入阻塞的 task，worker 一直被占用时放弃切换
+    const HOLD_TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    fn check(ret: libc::c_int, what: &str) -> Result<()> {
+        if ret < 0 {
+            bail!("{} failed {}", what, std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // 数字或者用户名，返回 uid 与主组
+    fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+        let name = CString::new(user)?;
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let ret = match user.parse::<libc::uid_t>() {
+            Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) },
+            Err(_) => unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) },
+        };
+        if ret != 0 || result.is_null() {
+            // 没有 passwd 记录的 uid 也可以使用，主组与 uid 相同
+            return match user.parse::<libc::uid_t>() {
+                Ok(uid) => Ok((uid, uid)),
+                Err(_) => Err(anyhow!("unknown user {}", user)),
+            };
+        }
+        Ok((pwd.pw_uid, pwd.pw_gid))
+    }
+
+    fn lookup_group(group: &str) -> Result<libc::gid_t> {
+        if let Ok(gid) = group.parse::<libc::gid_t>() {
+            return Ok(gid);
+        }
+        let name = CString::new(group)?;
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let ret = unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret != 0 || result.is_null() {
+            bail!("unknown group {}", group);
+        }
+        Ok(grp.gr_gid)
+    }
+
+    // 只作用于当前线程
+    fn set_capabilities(mask: u64) -> Result<()> {
+        let mut header = CapHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+        for (i, x) in data.iter_mut().enumerate() {
+            let bits = (mask >> (32 * i)) as u32;
+            x.effective = bits;
+            x.permitted = bits;
+        }
+        let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_mut_ptr()) };
+        check(ret as libc::c_int, "capset")?;
+        check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) }, "PR_SET_KEEPCAPS")
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Phase {
+        Hold,
+        // 切换完成，保留的 capability
+        Switched(u64),
+        Aborted,
+    }
+
+    // 每个 runtime worker 阻塞在一个 task 中，直到切换完成之后设置自己的 capability
+    struct Workers {
+        phase: Arc<(Mutex<Phase>, Condvar)>,
+        done: mpsc::Receiver<Result<()>>,
+        count: usize,
+    }
+
+    impl Workers {
+        fn hold(runtime: &Handle) -> Result<Workers> {
+            let count = runtime.metrics().num_workers();
+            let phase = Arc::new((Mutex::new(Phase::Hold), Condvar::new()));
+            let (ready_tx, ready) = mpsc::channel();
+            let (done_tx, done) = mpsc::channel();
+            for _ in 0..count {
+                let (phase, ready_tx, done_tx) = (phase.clone(), ready_tx.clone(), done_tx.clone());
+                // 从 runtime 之外 spawn，task 进入全局队列，阻塞的 worker 不会再执行其他 task，所以每个 worker 各执行一个
+                runtime.spawn(async move {
+                    let keep = check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) }, "PR_SET_KEEPCAPS");
+                    let _ = ready_tx.send(());
+                    let (lock, cvar) = &*phase;
+                    let mut current = lock.lock().unwrap();
+                    while *current == Phase::Hold {
+                        current = cvar.wait(current).unwrap();
+                    }
+                    let result = match *current {
+                        Phase::Switched(mask) => keep.and_then(|_| set_capabilities(mask)),
+                        _ => Ok(()),
+                    };
+                    drop(current);
+                    let _ = done_tx.send(result);
+                });
+            }
+            let workers = Workers { phase, done, count };
+            for _ in 0..count {
+                if ready.recv_timeout(HOLD_TIMEOUT).is_err() {
+                    workers.release(Phase::Aborted);
+                    bail!("runtime workers are busy, privileges not dropped");
+                }
+            }
+            Ok(workers)
+        }
+
+        fn release(&self, phase: Phase) {
+            let (lock, cvar) = &*self.phase;
+            *lock.lock().unwrap() = phase;
+            cvar.notify_all();
+        }
+
+        fn finish(self, mask: u64) -> Result<()> {
+            self.release(Phase::Switched(mask));
+            for _ in 0..self.count {
+                self.done
+                    .recv_timeout(HOLD_TIMEOUT)
+                    .map_err(|_| anyhow!("runtime worker did not set its capabilities"))??;
+            }
+            Ok(())
+        }
+    }
+
+    // uid 与 gid 作用于全部线程，capability 只设置当前线程
+    fn switch(uid: libc::uid_t, gid: libc::gid_t, mask: u64) -> Result<()> {
+        // 切换 uid 时保留 permitted capability，之后再缩小到 keep
+        check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) }, "PR_SET_KEEPCAPS")?;
+        check(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+        check(unsafe { libc::setresgid(gid, gid, gid) }, "setresgid")?;
+        check(unsafe { libc::setresuid(uid, uid, uid) }, "setresuid")?;
+        set_capabilities(mask)
+    }
+
+    pub fn drop_privileges(
+        user: &str,
+        group: Option<&str>,
+        keep: &[u32],
+        writable: &[PathBuf],
+        runtime: &Handle,
+    ) -> Result<()> {
+        if unsafe { libc::geteuid() } != 0 {
+            warn!("not running as root, general.user {} ignored", user);
+            return Ok(());
+        }
+        let (uid, primary) = lookup_user(user)?;
+        let gid = match group {
+            Some(x) => lookup_group(x)?,
+            None => primary,
+        };
+        // 以 root 创建目录，连同已经存在的文件交给切换之后的用户写入
+        for path in writable {
+            if !path.is_file() {
+                fs::create_dir_all(path).map_err(|err| anyhow!("create {} failed {}", path.display(), err))?;
+            }
+            let name = CString::new(path.as_os_str().as_bytes())?;
+            check(unsafe { libc::chown(name.as_ptr(), uid, gid) }, "chown")?;
+        }
+        let mask = keep.iter().fold(0u64, |mask, cap| mask | 1 << cap);
+        let workers = Workers::hold(runtime)?;
+        if let Err(err) = switch(uid, gid, mask) {
+            workers.release(Phase::Aborted);
+            return Err(err);
+        }
+        workers.finish(mask)?;
+        // 不能再切换回 root
+        if unsafe { libc::setuid(0) } == 0 {
+            bail!("privileges could not be dropped, setuid(0) still succeeds");
+        }
+        info!("switched to uid {} gid {}, capabilities kept {:?}", uid, gid, keep);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::path::PathBuf;
+
+    use anyhow::Result;
+    use log::warn;
+    use tokio::runtime::Handle;
+
+    pub fn drop_privileges(
+        user: &str,
+        _group: Option<&str>,
+        _keep: &[u32],
+        _writable: &[PathBuf],
+        _runtime: &Handle,
+    ) -> Result<()> {
+        warn!("switching user is only supported on linux, general.user {} ignored", user);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_needed_capabilities() {
+    let config = crate::config::parse_from_str(
+        r#"{
+            "general": { "prefer_ipv6": false, "use_ipv6": false, "user": "nobody" },
+            "inbounds": [{ "protocol": "socks", "port": [1080, "80-81"], "tag": "socks_in" }],
+            "outbounds": [{ "protocol": "direct", "tag": "direct", "dialer": { "fwmark": 255 } }],
+            "routes": []
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(needed_capabilities(&config).unwrap(), vec![10, 12]);
+    let mut config = config;
+    config.general.capabilities = Some(vec!["CAP_NET_RAW".to_string()]);
+    assert_eq!(needed_capabilities(&config).unwrap(), vec![13]);
+    config.general.capabilities = Some(vec!["sys_admin".to_string()]);
+    assert!(needed_capabilities(&config).is_err());
+}
@@ -0,0 +1,344 @@
+// 查找本机发起的连接所属的进程
+// linux: /proc/net/{tcp,tcp6,udp,udp6} 找到 socket inode 与 uid，再遍历 /proc/*/fd 找到持有该 inode 的进程
+// macos: lsof
+// 查找会读取 /proc 或运行 lsof，在 blocking 线程中执行，见 lookup_process_name
+
+use std::net::SocketAddr;
+
+use crate::proxy::Network;
+
+#[derive(Debug, Clone)]
+pub struct SocketOwner {
+    pub uid: u32,
+    pub inode: u64,
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        collections::HashMap,
+        fs,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        path::Path,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use lazy_static::lazy_static;
+    use log::trace;
+
+    use super::SocketOwner;
+    use crate::proxy::Network;
+
+    // /proc/net/tcp 中的地址是按主机字节序打印的 u32，例如 127.0.0.1:8080 => 0100007F:1F90
+    fn parse_addr(s: &str) -> Option<SocketAddr> {
+        let (ip, port) = s.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+        let ip: IpAddr = match ip.len() {
+            8 => {
+                let v = u32::from_str_radix(ip, 16).ok()?;
+                Ipv4Addr::from(v.to_le_bytes()).into()
+            }
+            32 => {
+                let mut octets = [0u8; 16];
+                for i in 0..4 {
+                    let v = u32::from_str_radix(&ip[i * 8..i * 8 + 8], 16).ok()?;
+                    octets[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+                }
+                let v6 = Ipv6Addr::from(octets);
+                // dual stack socket 上的 ipv4 连接
+                match v6.to_ipv4() {
+                    Some(v4) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => v4.into(),
+                    _ => v6.into(),
+                }
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    fn find_in_table(
+        path: &str,
+        local: SocketAddr,
+        remote: Option<SocketAddr>,
+    ) -> Option<SocketOwner> {
+        let content = fs::read_to_string(path).ok()?;
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                continue;
+            }
+            if parse_addr(fields[1]) != Some(local) {
+                continue;
+            }
+            if let Some(remote) = remote {
+                if parse_addr(fields[2]) != Some(remote) {
+                    continue;
+                }
+            }
+            let uid = fields[7].parse::<u32>().ok()?;
+            let inode = fields[9].parse::<u64>().ok()?;
+            return Some(SocketOwner { uid, inode });
+        }
+        None
+    }
+
+    pub fn find_socket_owner(
+        network: &Network,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Option<SocketOwner> {
+        match network {
+            Network::TCP => find_in_table("/proc/net/tcp", local, Some(remote))
+                .or_else(|| find_in_table("/proc/net/tcp6", local, Some(remote))),
+            // udp 可能没有 connect，只匹配本地地址
+            Network::UDP => find_in_table("/proc/net/udp", local, None)
+                .or_else(|| find_in_table("/proc/net/udp6", local, None)),
+        }
+    }
+
+    // 一次扫描 /proc/*/fd 得到全部 socket inode => pid，TTL 内命中时不再扫描
+    // 新连接的 inode 不在上一次的结果中，重新扫描
+    const INODE_TTL: Duration = Duration::from_secs(5);
+
+    struct InodeCache {
+        pids: HashMap<u64, u32>,
+        scanned: Option<Instant>,
+    }
+
+    lazy_static! {
+        static ref INODES: Mutex<InodeCache> = Mutex::new(InodeCache {
+            pids: HashMap::new(),
+            scanned: None,
+        });
+    }
+
+    fn scan_sockets() -> HashMap<u64, u32> {
+        let mut pids = HashMap::new();
+        let entries = match fs::read_dir("/proc") {
+            Ok(x) => x,
+            Err(_) => return pids,
+        };
+        for entry in entries.flatten() {
+            let pid = match entry.file_name().to_str().and_then(|x| x.parse::<u32>().ok()) {
+                Some(x) => x,
+                None => continue,
+            };
+            let fds = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+                Ok(x) => x,
+                // 没有权限读取其他用户的进程
+                Err(_) => continue,
+            };
+            for fd in fds.flatten() {
+                let link = match fs::read_link(fd.path()) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                let inode = link
+                    .to_str()
+                    .and_then(|x| x.strip_prefix("socket:["))
+                    .and_then(|x| x.strip_suffix(']'))
+                    .and_then(|x| x.parse::<u64>().ok());
+                if let Some(inode) = inode {
+                    pids.insert(inode, pid);
+                }
+            }
+        }
+        pids
+    }
+
+    fn find_pid(inode: u64) -> Option<u32> {
+        // 持有锁扫描，同时到达的查询等待这一次扫描的结果
+        let mut cache = INODES.lock().unwrap();
+        let fresh = cache.scanned.map_or(false, |x| x.elapsed() < INODE_TTL);
+        if fresh {
+            if let Some(pid) = cache.pids.get(&inode) {
+                return Some(*pid);
+            }
+        }
+        cache.pids = scan_sockets();
+        cache.scanned = Some(Instant::now());
+        cache.pids.get(&inode).copied()
+    }
+
+    pub fn find_process_name(
+        network: &Network,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Option<String> {
+        let owner = find_socket_owner(network, local, remote)?;
+        let pid = find_pid(owner.inode)?;
+        let name = process_name(pid);
+        trace!("socket {} owned by pid {} {:?}", local, pid, name);
+        name
+    }
+
+    // comm 最长 15 字节，优先使用 exe 的文件名
+    fn process_name(pid: u32) -> Option<String> {
+        if let Ok(exe) = fs::read_link(format!("/proc/{}/exe", pid)) {
+            if let Some(name) = Path::new(&exe).file_name() {
+                return Some(name.to_string_lossy().to_string());
+            }
+        }
+        fs::read_to_string(format!("/proc/{}/comm", pid))
+            .ok()
+            .map(|x| x.trim().to_string())
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(
+            parse_addr("0100007F:1F90"),
+            Some("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_addr("0000000000000000FFFF00000100007F:0050"),
+            Some("127.0.0.1:80".parse().unwrap())
+        );
+        assert_eq!(
+            parse_addr("00000000000000000000000001000000:0050"),
+            Some("[::1]:80".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_find_in_table() {
+        let path = std::env::temp_dir().join(format!("tunnel-proc-net-tcp-{}", std::process::id()));
+        fs::write(
+            &path,
+            "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+             0: 0100007F:0438 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1001 1 0 100 0 0 10 0\n\
+             1: 0100007F:C350 0100007F:0438 01 00000000:00000000 00:00000000 00000000  1000        0 2002 1 0 20 4 30 10 -1\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let owner = find_in_table(path, "127.0.0.1:50000".parse().unwrap(), Some("127.0.0.1:1080".parse().unwrap())).unwrap();
+        assert_eq!((owner.uid, owner.inode), (1000, 2002));
+        // udp 只匹配本地地址
+        let owner = find_in_table(path, "127.0.0.1:1080".parse().unwrap(), None).unwrap();
+        assert_eq!((owner.uid, owner.inode), (0, 1001));
+        assert!(find_in_table(path, "127.0.0.1:50000".parse().unwrap(), Some("127.0.0.1:1081".parse().unwrap())).is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_find_process_name() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let name = find_process_name(&Network::TCP, stream.local_addr().unwrap(), listener.local_addr().unwrap());
+        assert_eq!(name.as_deref(), exe.file_name().and_then(|x| x.to_str()));
+        // 第二次查询命中上一次扫描的 inode 缓存
+        let name = find_process_name(&Network::TCP, accepted.local_addr().unwrap(), stream.local_addr().unwrap());
+        assert_eq!(name.as_deref(), exe.file_name().and_then(|x| x.to_str()));
+        let owner = find_socket_owner(&Network::TCP, stream.local_addr().unwrap(), listener.local_addr().unwrap()).unwrap();
+        assert_eq!(owner.uid, unsafe { libc::getuid() });
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use std::{net::SocketAddr, process::Command};
+
+    use super::SocketOwner;
+    use crate::proxy::Network;
+
+    // lsof -F 输出每个字段一行，p 开头为 pid，c 开头为进程名，u 开头为 uid
+    fn lsof(network: &Network, local: SocketAddr) -> Option<String> {
+        let proto = match network {
+            Network::TCP => "TCP",
+            Network::UDP => "UDP",
+        };
+        let output = Command::new("lsof")
+            .arg("-nP")
+            .arg(format!("-i{}@{}", proto, local))
+            .arg("-Fcu")
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    pub fn find_socket_owner(
+        network: &Network,
+        local: SocketAddr,
+        _remote: SocketAddr,
+    ) -> Option<SocketOwner> {
+        let out = lsof(network, local)?;
+        let uid = out
+            .lines()
+            .find(|x| x.starts_with('u'))
+            .and_then(|x| x[1..].parse::<u32>().ok())?;
+        Some(SocketOwner { uid, inode: 0 })
+    }
+
+    pub fn find_process_name(
+        network: &Network,
+        local: SocketAddr,
+        _remote: SocketAddr,
+    ) -> Option<String> {
+        let out = lsof(network, local)?;
+        out.lines()
+            .find(|x| x.starts_with('c'))
+            .map(|x| x[1..].to_string())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod imp {
+    use std::net::SocketAddr;
+
+    use super::SocketOwner;
+    use crate::proxy::Network;
+
+    pub fn find_socket_owner(_: &Network, _: SocketAddr, _: SocketAddr) -> Option<SocketOwner> {
+        None
+    }
+
+    pub fn find_process_name(_: &Network, _: SocketAddr, _: SocketAddr) -> Option<String> {
+        None
+    }
+}
+
+/// resolve system user name to uid from /etc/passwd
+pub fn lookup_uid(name: &str) -> Option<u32> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 2 && fields[0] == name)
+        .and_then(|fields| fields[2].parse::<u32>().ok())
+}
+
+/// local is the address of the socket owned by the process, remote is its peer
+pub fn find_socket_owner(network: &Network, local: SocketAddr, remote: SocketAddr) -> Option<SocketOwner> {
+    imp::find_socket_owner(network, local, remote)
+}
+
+/// local is the address of the socket owned by the process, remote is its peer
+pub fn find_process_name(network: &Network, local: SocketAddr, remote: SocketAddr) -> Option<String> {
+    imp::find_process_name(network, local, remote)
+}
+
+/// find_socket_owner on the blocking pool, remotes are tried in order
+pub async fn lookup_socket_owner(network: Network, local: SocketAddr, remotes: Vec<SocketAddr>) -> Option<SocketOwner> {
+    tokio::task::spawn_blocking(move || {
+        remotes
+            .into_iter()
+            .find_map(|remote| imp::find_socket_owner(&network, local, remote))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// find_process_name on the blocking pool, it scans /proc or runs lsof
+pub async fn lookup_process_name(network: Network, local: SocketAddr, remote: SocketAddr) -> Option<String> {
+    tokio::task::spawn_blocking(move || imp::find_process_name(&network, local, remote))
+        .await
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,84 @@
+// 令牌桶限速，按字节计
+// outbound 与 route rule 都可以配置 max_up / max_down，同一个 outbound 或 rule 的所有连接共享一个桶
+// 桶容量为一秒的流量，允许欠账：一次读到的数据超过剩余令牌时先转发，之后按欠的字节数等待
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct RateLimiter {
+    // bytes per second
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            rate: bytes_per_sec as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    // 取走 n 个令牌，返回需要等待的时间
+    fn reserve(&self, n: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.last = now;
+        bucket.tokens -= n as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+
+    /// wait until n bytes may be sent
+    pub async fn acquire(&self, n: usize) {
+        let wait = self.reserve(n, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// upload and download limits of an outbound or a rule, None is unlimited
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    pub up: Option<Arc<RateLimiter>>,
+    pub down: Option<Arc<RateLimiter>>,
+}
+
+impl Bandwidth {
+    pub fn new(max_up: Option<u64>, max_down: Option<u64>) -> Bandwidth {
+        Bandwidth {
+            up: max_up.filter(|x| *x > 0).map(|x| Arc::new(RateLimiter::new(x))),
+            down: max_down.filter(|x| *x > 0).map(|x| Arc::new(RateLimiter::new(x))),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.up.is_none() && self.down.is_none()
+    }
+}
+
+#[test]
+fn test_rate_limiter_reserve() {
+    let limiter = RateLimiter::new(1024);
+    let now = Instant::now();
+    assert_eq!(limiter.reserve(512, now), Duration::ZERO);
+    // 剩余 512，欠 512 字节，等待 0.5s
+    assert_eq!(limiter.reserve(1024, now), Duration::from_millis(500));
+    // 一秒后补充 1024，扣掉欠账剩 512
+    assert_eq!(limiter.reserve(512, now + Duration::from_secs(1)), Duration::ZERO);
+}
@@ -0,0 +1,348 @@
+// 系统代理: 启动时把系统代理设置指向 socks inbound（或 api 提供的 pac），退出时恢复原来的设置
+// macos: networksetup，对每个启用的网络服务分别设置
+// windows: HKCU\...\Internet Settings 中的 AutoConfigURL
+//   WinINet 与 Chromium 把 ProxyServer 中的 socks= 当作 SOCKS4，socks inbound 只支持 SOCKS5，所以只能使用 pac
+//   已经运行的程序可能要重新打开才会读取新设置
+// 其他平台只打印 warning
+// 进程被强制结束时来不及恢复，需要手动关闭系统代理
+
+use std::{net::SocketAddr, process::Command};
+
+use anyhow::{anyhow, bail, Result};
+use ipnet::Ipv4Net;
+use log::{info, warn};
+
+use crate::{
+    app::ApiServer,
+    config::{Config, SystemProxyConfig},
+};
+
+// 本机与局域网默认不经过代理
+const DEFAULT_BYPASS: &[&str] = &["localhost", "127.0.0.1", "::1", "*.local", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"];
+
+#[cfg_attr(not(any(target_os = "macos", target_os = "windows")), allow(dead_code))]
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        bail!("{} {} failed {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// the socks inbound the system proxy points at, unspecified listen addresses become loopback
+pub fn proxy_address(config: &Config, settings: &SystemProxyConfig) -> Result<SocketAddr> {
+    let inbound = config
+        .inbounds
+        .iter()
+        .find(|x| match &settings.inbound {
+            Some(tag) => &x.tag == tag,
+            None => x.protocol == "socks",
+        })
+        .ok_or_else(|| anyhow!("system proxy needs a socks inbound"))?;
+    if inbound.protocol != "socks" {
+        bail!("system proxy inbound {} is not socks", inbound.tag);
+    }
+    let port = match inbound.port.as_ref().map(|x| x.ports()) {
+        Some(Ok(ports)) if !ports.is_empty() => ports[0],
+        _ => bail!("system proxy inbound {} has no port", inbound.tag),
+    };
+    // 与 inbound 的 listen 相同，ipv6 可以带或者不带 []
+    let listen = inbound.listen.as_deref().unwrap_or("127.0.0.1");
+    let ip: std::net::IpAddr = listen.trim_start_matches('[').trim_end_matches(']').parse()?;
+    let mut addr = SocketAddr::new(ip, port);
+    if addr.ip().is_unspecified() {
+        addr.set_ip(if addr.is_ipv4() { [127, 0, 0, 1].into() } else { std::net::Ipv6Addr::LOCALHOST.into() });
+    }
+    Ok(addr)
+}
+
+pub fn bypass(settings: &SystemProxyConfig) -> Vec<String> {
+    match &settings.bypass {
+        Some(x) => x.clone(),
+        None => DEFAULT_BYPASS.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+/// proxy auto-config script, bypassed hosts connect directly
+pub fn pac(proxy: SocketAddr, bypass: &[String]) -> String {
+    let mut conditions = vec!["isPlainHostName(host)".to_string()];
+    for entry in bypass {
+        match entry.parse::<Ipv4Net>() {
+            Ok(net) => conditions.push(format!("isInNet(host, \"{}\", \"{}\")", net.network(), net.netmask())),
+            Err(_) => conditions.push(format!("shExpMatch(host, \"{}\")", entry)),
+        }
+    }
+    format!(
+        "function FindProxyForURL(url, host) {{\n    if ({}) {{\n        return \"DIRECT\";\n    }}\n    return \"SOCKS5 {proxy}; DIRECT\";\n}}\n",
+        conditions.join(" ||\n        "),
+        proxy = proxy
+    )
+}
+
+/// restores the previous system proxy settings on drop
+pub struct SystemProxy {
+    saved: imp::Saved,
+}
+
+impl SystemProxy {
+    /// None if not configured or the settings can not be changed, errors are logged
+    pub fn apply(config: &Config) -> Option<SystemProxy> {
+        let settings = config.system_proxy.as_ref()?;
+        let proxy = match proxy_address(config, settings) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("{}", err);
+                return None;
+            }
+        };
+        let pac_url = match (&config.api, settings.pac) {
+            (Some(api), true) => {
+                let host = match api.listen.as_deref() {
+                    None | Some("0.0.0.0") => "127.0.0.1",
+                    Some(x) => x,
+                };
+                Some(format!("http://{}:{}{}", host, api.port, ApiServer::pac_path(api.secret.as_deref())))
+            }
+            (None, true) => {
+                warn!("system proxy pac is served by the api, configure api or disable pac");
+                None
+            }
+            (_, false) => None,
+        };
+        let saved = match imp::save() {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("read system proxy settings failed {}", err);
+                return None;
+            }
+        };
+        if let Err(err) = imp::set(proxy, &bypass(settings), pac_url.as_deref()) {
+            warn!("set system proxy failed {}", err);
+            let _ = imp::restore(&saved);
+            return None;
+        }
+        info!("system proxy set to {}", pac_url.unwrap_or_else(|| proxy.to_string()));
+        Some(SystemProxy { saved })
+    }
+}
+
+impl Drop for SystemProxy {
+    fn drop(&mut self) {
+        match imp::restore(&self.saved) {
+            Ok(_) => info!("system proxy restored"),
+            Err(err) => warn!("restore system proxy failed {}", err),
+        }
+    }
+}
+
+// networksetup -get* 的输出，例如 "Enabled: Yes\nServer: 127.0.0.1\nPort: 1080"
+#[cfg_attr(not(any(target_os = "macos", target_os = "windows")), allow(dead_code))]
+fn field<'a>(output: &'a str, name: &str) -> Option<&'a str> {
+    output.lines().find_map(|x| x.strip_prefix(name)?.strip_prefix(':')).map(|x| x.trim())
+}
+
+// reg query 的输出，例如 "    ProxyEnable    REG_DWORD    0x1"
+#[cfg_attr(not(any(target_os = "macos", target_os = "windows")), allow(dead_code))]
+fn reg_value(output: &str, name: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != name {
+            return None;
+        }
+        fields.next()?;
+        Some(fields.collect::<Vec<&str>>().join(" "))
+    })
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use super::*;
+
+    // service => (socks, pac, bypass) 原来的 networksetup 输出
+    pub struct Saved(Vec<(String, String, String, String)>);
+
+    // 第一行是说明，* 开头的服务已禁用
+    fn services() -> Result<Vec<String>> {
+        let out = run("networksetup", &["-listallnetworkservices"])?;
+        Ok(out.lines().skip(1).filter(|x| !x.is_empty() && !x.starts_with('*')).map(|x| x.to_string()).collect())
+    }
+
+    pub fn save() -> Result<Saved> {
+        let mut saved = Vec::new();
+        for service in services()? {
+            let socks = run("networksetup", &["-getsocksfirewallproxy", &service])?;
+            let pac = run("networksetup", &["-getautoproxyurl", &service])?;
+            let bypass = run("networksetup", &["-getproxybypassdomains", &service])?;
+            saved.push((service, socks, pac, bypass));
+        }
+        Ok(Saved(saved))
+    }
+
+    pub fn set(proxy: SocketAddr, bypass: &[String], pac: Option<&str>) -> Result<()> {
+        let host = proxy.ip().to_string();
+        let port = proxy.port().to_string();
+        for service in services()? {
+            let service = service.as_str();
+            match pac {
+                Some(url) => {
+                    run("networksetup", &["-setautoproxyurl", service, url])?;
+                    run("networksetup", &["-setautoproxystate", service, "on"])?;
+                }
+                None => {
+                    run("networksetup", &["-setsocksfirewallproxy", service, &host, &port])?;
+                    run("networksetup", &["-setsocksfirewallproxystate", service, "on"])?;
+                }
+            }
+            let mut args = vec!["-setproxybypassdomains", service];
+            args.extend(bypass.iter().map(|x| x.as_str()));
+            run("networksetup", &args)?;
+        }
+        Ok(())
+    }
+
+    pub fn restore(saved: &Saved) -> Result<()> {
+        for (service, socks, pac, bypass) in &saved.0 {
+            let service = service.as_str();
+            if let (Some(server), Some(port)) = (field(socks, "Server"), field(socks, "Port")) {
+                if !server.is_empty() && port != "0" {
+                    run("networksetup", &["-setsocksfirewallproxy", service, server, port])?;
+                }
+            }
+            let state = if field(socks, "Enabled") == Some("Yes") { "on" } else { "off" };
+            run("networksetup", &["-setsocksfirewallproxystate", service, state])?;
+            match field(pac, "URL") {
+                Some(url) if url != "(null)" && !url.is_empty() => {
+                    run("networksetup", &["-setautoproxyurl", service, url])?;
+                }
+                _ => {}
+            }
+            let state = if field(pac, "Enabled") == Some("Yes") { "on" } else { "off" };
+            run("networksetup", &["-setautoproxystate", service, state])?;
+            // 没有设置时输出 "There aren't any bypass domains set on <service>."
+            let mut args = vec!["-setproxybypassdomains", service];
+            let domains: Vec<&str> = bypass.lines().filter(|x| !x.is_empty() && !x.starts_with("There aren't")).collect();
+            if domains.is_empty() {
+                args.push("Empty");
+            } else {
+                args.extend(domains);
+            }
+            run("networksetup", &args)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod imp {
+    use super::*;
+
+    const KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+    const VALUES: [(&str, &str); 4] = [
+        ("ProxyEnable", "REG_DWORD"),
+        ("ProxyServer", "REG_SZ"),
+        ("ProxyOverride", "REG_SZ"),
+        ("AutoConfigURL", "REG_SZ"),
+    ];
+
+    // 原来的值，None 表示不存在
+    pub struct Saved(Vec<Option<String>>);
+
+    fn write(name: &str, ty: &str, value: &str) -> Result<()> {
+        run("reg", &["add", KEY, "/v", name, "/t", ty, "/d", value, "/f"]).map(|_| ())
+    }
+
+    fn delete(name: &str) -> Result<()> {
+        run("reg", &["delete", KEY, "/v", name, "/f"]).map(|_| ())
+    }
+
+    pub fn save() -> Result<Saved> {
+        let out = run("reg", &["query", KEY])?;
+        Ok(Saved(VALUES.iter().map(|(name, _)| reg_value(&out, name)).collect()))
+    }
+
+    // bypass 已经写在 pac 中
+    pub fn set(_proxy: SocketAddr, _bypass: &[String], pac: Option<&str>) -> Result<()> {
+        let url = match pac {
+            Some(x) => x,
+            None => bail!("windows reads socks= in ProxyServer as socks4, enable system_proxy.pac and api"),
+        };
+        write("AutoConfigURL", "REG_SZ", url)?;
+        write("ProxyEnable", "REG_DWORD", "0")?;
+        Ok(())
+    }
+
+    pub fn restore(saved: &Saved) -> Result<()> {
+        for ((name, ty), value) in VALUES.iter().zip(saved.0.iter()) {
+            match value {
+                // reg query 输出 0x1，reg add 接受十进制
+                Some(value) if *ty == "REG_DWORD" => {
+                    let value = u32::from_str_radix(value.trim_start_matches("0x"), 16).unwrap_or(0);
+                    write(name, ty, &value.to_string())?;
+                }
+                Some(value) => write(name, ty, value)?,
+                None => {
+                    let _ = delete(name);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+mod imp {
+    use super::*;
+
+    pub struct Saved;
+
+    pub fn save() -> Result<Saved> {
+        bail!("system proxy is only supported on macos and windows")
+    }
+
+    pub fn set(_proxy: SocketAddr, _bypass: &[String], _pac: Option<&str>) -> Result<()> {
+        bail!("system proxy is only supported on macos and windows")
+    }
+
+    pub fn restore(_saved: &Saved) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_system_proxy() {
+    let config = crate::config::parse_from_str(
+        r#"{
+            "general": { "prefer_ipv6": false, "use_ipv6": false },
+            "inbounds": [
+                { "protocol": "echo", "tag": "echo", "port": 7 },
+                { "protocol": "socks", "tag": "socks", "listen": "0.0.0.0", "port": "1080-1081" }
+            ],
+            "outbounds": [],
+            "routes": [],
+            "system_proxy": { "bypass": ["*.corp.example.com", "10.0.0.0/8"] }
+        }"#,
+    )
+    .unwrap();
+    let settings = config.system_proxy.clone().unwrap();
+    let proxy = proxy_address(&config, &settings).unwrap();
+    assert_eq!(proxy, "127.0.0.1:1080".parse().unwrap());
+    let echo = SystemProxyConfig {
+        inbound: Some("echo".to_string()),
+        ..Default::default()
+    };
+    assert!(proxy_address(&config, &echo).is_err());
+
+    let pac = pac(proxy, &bypass(&settings));
+    assert!(pac.contains("shExpMatch(host, \"*.corp.example.com\")"));
+    assert!(pac.contains("isInNet(host, \"10.0.0.0\", \"255.0.0.0\")"));
+    // SOCKS 是 SOCKS4，socks inbound 不支持
+    assert!(pac.contains("return \"SOCKS5 127.0.0.1:1080; DIRECT\";"));
+    assert_eq!(bypass(&SystemProxyConfig::default()).len(), DEFAULT_BYPASS.len());
+
+    assert_eq!(field("Enabled: Yes\nServer: 127.0.0.1\nPort: 1080", "Port"), Some("1080"));
+    assert_eq!(field("URL: (null)\nEnabled: No", "Enabled"), Some("No"));
+    let reg = "\r\nHKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings\r\n    ProxyEnable    REG_DWORD    0x1\r\n    ProxyServer    REG_SZ    socks=127.0.0.1:1080\r\n";
+    assert_eq!(reg_value(reg, "ProxyEnable").as_deref(), Some("0x1"));
+    assert_eq!(reg_value(reg, "ProxyServer").as_deref(), Some("socks=127.0.0.1:1080"));
+    assert_eq!(reg_value(reg, "AutoConfigURL"), None);
+}
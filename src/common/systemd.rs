@@ -0,0 +1,213 @@
+// systemd 集成
+// socket activation: systemd 预先创建 listener，通过 LISTEN_PID/LISTEN_FDS/LISTEN_FDNAMES 传给进程，fd 从 3 开始
+// inbound 先按监听地址，再按 tag（FileDescriptorName=）与端口匹配，没有匹配时自己 bind
+// systemd 一直持有这些 socket，重启期间新连接在队列中等待，不会被拒绝
+// notify: Type=notify 或 notify-reload，启动完成 READY=1，重新加载 RELOADING=1，退出 STOPPING=1
+// 不在 systemd 下运行时全部为空操作
+
+use std::net::{SocketAddr, TcpListener};
+
+use lazy_static::lazy_static;
+use log::{debug, info};
+
+const LISTEN_FDS_START: i32 = 3;
+
+struct Inherited {
+    name: Option<String>,
+    listener: TcpListener,
+}
+
+lazy_static! {
+    static ref INHERITED: Vec<Inherited> = imp::inherit();
+}
+
+// LISTEN_PID 不是自己时，环境变量是从父进程继承来的，不属于自己
+fn parse_env(pid: Option<&str>, fds: Option<&str>, names: Option<&str>, own_pid: u32) -> Vec<(i32, Option<String>)> {
+    if pid.and_then(|x| x.parse::<u32>().ok()) != Some(own_pid) {
+        return Vec::new();
+    }
+    let count = match fds.and_then(|x| x.parse::<i32>().ok()) {
+        Some(x) if x > 0 => x,
+        _ => return Vec::new(),
+    };
+    let names: Vec<&str> = names.map(|x| x.split(':').collect()).unwrap_or_default();
+    (0..count)
+        .map(|i| {
+            let name = names.get(i as usize).filter(|x| !x.is_empty()).map(|x| x.to_string());
+            (LISTEN_FDS_START + i, name)
+        })
+        .collect()
+}
+
+/// a listener passed by systemd for the inbound, the same socket can be taken again after a reload
+pub fn listener(tag: &str, addr: SocketAddr) -> Option<TcpListener> {
+    let inherited = INHERITED
+        .iter()
+        .find(|x| x.listener.local_addr().ok() == Some(addr))
+        .or_else(|| {
+            INHERITED.iter().find(|x| {
+                x.name.as_deref() == Some(tag) && x.listener.local_addr().map(|x| x.port()).ok() == Some(addr.port())
+            })
+        })?;
+    // 保留原来的 fd，reload 之后还可以再取
+    match inherited.listener.try_clone() {
+        Ok(x) => {
+            info!("inbound {} uses the socket from systemd for {}", tag, addr);
+            Some(x)
+        }
+        Err(err) => {
+            debug!("dup systemd socket failed {}", err);
+            None
+        }
+    }
+}
+
+/// send a state to the service manager, e.g. "READY=1"
+pub fn notify(state: &str) {
+    if let Err(err) = imp::notify(state) {
+        debug!("sd_notify {} failed {}", state, err);
+    }
+}
+
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Type=notify-reload requires the monotonic timestamp with RELOADING=1
+pub fn reloading() {
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", imp::monotonic_usec()));
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        env,
+        fs::File,
+        io,
+        net::TcpListener,
+        os::unix::{
+            ffi::OsStrExt,
+            io::{FromRawFd, IntoRawFd},
+        },
+    };
+
+    use log::warn;
+
+    use super::{parse_env, Inherited};
+
+    pub fn inherit() -> Vec<Inherited> {
+        let var = |key: &str| env::var(key).ok();
+        let fds = parse_env(
+            var("LISTEN_PID").as_deref(),
+            var("LISTEN_FDS").as_deref(),
+            var("LISTEN_FDNAMES").as_deref(),
+            std::process::id(),
+        );
+        // 子进程不应该再使用这些 fd
+        for key in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(key);
+        }
+        let mut inherited = Vec::new();
+        for (fd, name) in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // 只使用 ip 的 stream socket，其他类型的 fd 保持打开不动
+            let mut ty: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut ty as *mut libc::c_int as *mut libc::c_void, &mut len)
+            };
+            if ret < 0 || ty != libc::SOCK_STREAM || listener.local_addr().is_err() {
+                warn!("systemd fd {} {:?} is not a tcp listener, ignored", fd, name);
+                let _ = listener.into_raw_fd();
+                continue;
+            }
+            inherited.push(Inherited { name, listener });
+        }
+        inherited
+    }
+
+    pub fn notify(state: &str) -> io::Result<()> {
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let path = path.as_bytes();
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        if path.is_empty() || path.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid NOTIFY_SOCKET"));
+        }
+        for (i, b) in path.iter().enumerate() {
+            addr.sun_path[i] = *b as libc::c_char;
+        }
+        // @ 开头是 abstract namespace
+        if path[0] == b'@' {
+            addr.sun_path[0] = 0;
+        }
+        let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let _socket = unsafe { File::from_raw_fd(fd) };
+        let len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+        let ret = unsafe {
+            libc::sendto(
+                fd,
+                state.as_ptr() as *const libc::c_void,
+                state.len(),
+                libc::MSG_NOSIGNAL,
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                len as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn monotonic_usec() -> u64 {
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    use super::Inherited;
+
+    pub fn inherit() -> Vec<Inherited> {
+        Vec::new()
+    }
+
+    pub fn notify(_state: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn monotonic_usec() -> u64 {
+        0
+    }
+}
+
+#[test]
+fn test_listen_fds_env() {
+    assert!(parse_env(Some("100"), Some("2"), None, 200).is_empty());
+    assert!(parse_env(None, Some("2"), None, 200).is_empty());
+    assert!(parse_env(Some("200"), Some("0"), None, 200).is_empty());
+    assert_eq!(
+        parse_env(Some("200"), Some("3"), Some("socks_in::http_in"), 200),
+        vec![
+            (3, Some("socks_in".to_string())),
+            (4, None),
+            (5, Some("http_in".to_string()))
+        ]
+    );
+}
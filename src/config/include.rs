@@ -0,0 +1,120 @@
+// 顶层 "include": "rules.jsonc" 或 ["outbounds.jsonc", "dns.jsonc"]，把配置拆分到多个文件
+// 相对路径相对于声明 include 的文件所在目录，被 include 的文件也可以继续 include
+// 合并顺序固定为：当前文件，然后按 include 中列出的顺序，深度优先
+//   数组（inbounds、outbounds、routes、dns.servers 等）按这个顺序拼接，路由规则中当前文件的规则优先
+//   对象逐个 key 合并，同一个 key 的标量值以先出现的为准
+// 只在解析时展开，reload 重新读取全部文件
+
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use json_comments::StripComments;
+use serde_json::{Map, Value};
+
+const INCLUDE: &str = "include";
+
+/// the merged config as json, None if the config has no include
+/// path is the file the content was read from, relative includes are resolved from its directory
+pub fn expand(content: &str, path: Option<&Path>) -> Result<Option<String>> {
+    let mut root: Value = serde_json::from_str(content)?;
+    if root.get(INCLUDE).is_none() {
+        return Ok(None);
+    }
+    let base = path.and_then(Path::parent).unwrap_or_else(|| Path::new("."));
+    let mut stack: Vec<PathBuf> = path.and_then(|x| fs::canonicalize(x).ok()).into_iter().collect();
+    include(&mut root, base, &mut stack)?;
+    Ok(Some(serde_json::to_string(&root)?))
+}
+
+fn include(value: &mut Value, base: &Path, stack: &mut Vec<PathBuf>) -> Result<()> {
+    let object = match value {
+        Value::Object(x) => x,
+        _ => bail!("config must be an object"),
+    };
+    let paths = match object.remove(INCLUDE) {
+        None => return Ok(()),
+        Some(Value::String(x)) => vec![x],
+        Some(Value::Array(xs)) => xs
+            .into_iter()
+            .map(|x| match x {
+                Value::String(x) => Ok(x),
+                x => Err(anyhow!("include expects file paths, found {}", x)),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Some(x) => bail!("include expects file paths, found {}", x),
+    };
+    for path in paths {
+        let path = base.join(path);
+        let canonical = fs::canonicalize(&path).map_err(|err| anyhow!("include {} failed {}", path.display(), err))?;
+        if stack.contains(&canonical) {
+            bail!("include cycle at {}", path.display());
+        }
+        let mut content = String::new();
+        StripComments::new(fs::read(&canonical)?.as_slice()).read_to_string(&mut content)?;
+        let mut included: Value =
+            serde_json::from_str(&content).map_err(|err| anyhow!("include {} failed {}", path.display(), err))?;
+        stack.push(canonical);
+        include(&mut included, path.parent().unwrap_or(base), stack)?;
+        stack.pop();
+        merge(object, included);
+    }
+    Ok(())
+}
+
+// 已有的值优先，数组拼接在后面
+fn merge(base: &mut Map<String, Value>, other: Value) {
+    let other = match other {
+        Value::Object(x) => x,
+        _ => return,
+    };
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(a)), b @ Value::Object(_)) => merge(a, b),
+            (Some(Value::Array(a)), Value::Array(b)) => a.extend(b),
+            (Some(_), _) => {}
+            (None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join(format!("tunnel-include-{}", std::process::id()));
+    fs::create_dir_all(dir.join("rules")).unwrap();
+    fs::write(
+        dir.join("outbounds.jsonc"),
+        r#"{
+            // shared outbounds
+            "outbounds": [{"protocol": "direct", "tag": "direct"}],
+            "general": {"loglevel": "debug", "prefer_ipv6": true},
+            "include": "rules/ads.jsonc"
+        }"#,
+    )
+    .unwrap();
+    fs::write(dir.join("rules/ads.jsonc"), r#"{"routes": [{"domain": ["ads.example.com"], "outbound": "reject"}]}"#).unwrap();
+    let main = dir.join("config.jsonc");
+    let content = r#"{
+        "include": ["outbounds.jsonc"],
+        "general": {"loglevel": "info"},
+        "routes": [{"domain": ["example.com"], "outbound": "direct"}]
+    }"#;
+    let merged: Value = serde_json::from_str(&expand(content, Some(&main)).unwrap().unwrap()).unwrap();
+    assert!(merged.get(INCLUDE).is_none());
+    assert_eq!(merged["general"]["loglevel"], "info");
+    assert_eq!(merged["general"]["prefer_ipv6"], true);
+    assert_eq!(merged["outbounds"][0]["tag"], "direct");
+    // 当前文件的规则在前面
+    assert_eq!(merged["routes"][0]["domain"][0], "example.com");
+    assert_eq!(merged["routes"][1]["domain"][0], "ads.example.com");
+
+    assert!(expand(r#"{"routes": []}"#, Some(&main)).unwrap().is_none());
+    fs::write(dir.join("rules/ads.jsonc"), r#"{"include": "../outbounds.jsonc"}"#).unwrap();
+    assert!(expand(content, Some(&main)).unwrap_err().to_string().contains("cycle"));
+    let _ = fs::remove_dir_all(dir);
+}
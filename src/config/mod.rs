@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use json_comments::StripComments;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::value::RawValue;
@@ -7,8 +7,12 @@ use std::{
     fs::{self},
     io::{Read},
     net::SocketAddr,
+    path::Path,
 };
 
+mod include;
+mod validate;
+
 // https://v2ray.com/chapter_02/01_overview.html
 #[derive(Clone, Deserialize)]
 pub struct Config {
@@ -17,6 +21,85 @@ pub struct Config {
     pub outbounds: Vec<Outbound>,
     pub routes: Vec<Rule>,
     pub dns: Option<DnsConfig>,
+    pub download: Option<DownloadConfig>,
+    // destination rewrite map for NAT loopback, "public ip[:port]" => "internal ip[:port]"
+    pub rewrite: Option<HashMap<String, String>>,
+    pub api: Option<ApiConfig>,
+    #[serde(alias = "rule-providers")]
+    pub rule_providers: Option<HashMap<String, RuleProviderConfig>>,
+    // default dialer settings of all outbounds
+    pub dialer: Option<DialerSettings>,
+    // isolated tenants in the same process, see Profile
+    pub profiles: Option<Vec<Profile>>,
+    // point the os proxy settings at a socks inbound while running, macos and windows
+    #[serde(alias = "system-proxy")]
+    pub system_proxy: Option<SystemProxyConfig>,
+}
+
+#[derive(Clone, Deserialize, Default)]
+pub struct SystemProxyConfig {
+    // tag of a socks inbound, defaults to the first socks inbound
+    pub inbound: Option<String>,
+    // hosts and ipv4 cidrs that connect directly, defaults to localhost and private networks
+    pub bypass: Option<Vec<String>>,
+    // set the pac url served by the api at /proxy.pac instead of the socks proxy, requires api
+    // windows only supports the pac, its socks= proxy setting is socks4
+    #[serde(default)]
+    pub pac: bool,
+}
+
+// a tenant with its own inbounds, routing table, outbounds and stats
+// connections accepted by a profile's inbounds are only routed by its routes to its outbounds
+// dns, rule providers and the api are shared
+#[derive(Clone, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub inbounds: Vec<Inbound>,
+    pub outbounds: Vec<Outbound>,
+    pub routes: Vec<Rule>,
+    // defaults to the top level dialer
+    pub dialer: Option<DialerSettings>,
+}
+
+impl Config {
+    /// the config seen by a profile, top level inbounds, outbounds and routes replaced
+    pub fn for_profile(&self, profile: &Profile) -> Config {
+        let mut config = self.clone();
+        config.inbounds = profile.inbounds.clone();
+        config.outbounds = profile.outbounds.clone();
+        config.routes = profile.routes.clone();
+        config.dialer = match (&profile.dialer, &self.dialer) {
+            (Some(x), Some(base)) => Some(x.or(base)),
+            (x, base) => x.clone().or_else(|| base.clone()),
+        };
+        config.profiles = None;
+        config
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RuleProviderConfig {
+    // http:// or https://
+    pub url: String,
+    // domain | ipcidr
+    pub behavior: String,
+    // disk cache, defaults to providers/<name>.txt
+    pub path: Option<String>,
+    // refresh interval in seconds, defaults to one day
+    pub interval: Option<u64>,
+    // outbound used for fetching, defaults to download.outbound
+    pub outbound: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ApiConfig {
+    // defaults to 127.0.0.1
+    pub listen: Option<String>,
+    pub port: u16,
+    // bearer token, endpoints that expose traffic are disabled without it
+    pub secret: Option<String>,
+    // where captured sessions are written, defaults to ./capture
+    pub capture_dir: Option<String>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -24,16 +107,214 @@ pub struct Outbound {
     pub protocol: String,
     pub settings: Option<Box<RawValue>>,
     pub tag: String,
+    // max udp datagram payload that fits into one packet after protocol overhead
+    pub udp_max_payload: Option<usize>,
+    // what to do with oversize datagrams: drop | icmp
+    pub udp_oversize: Option<String>,
+    // tunnel udp over the tcp channel of this outbound, compatible with shadowsocks udp-over-tcp
+    #[serde(alias = "udp-over-tcp")]
+    pub udp_over_tcp: Option<bool>,
+    pub dialer: Option<DialerSettings>,
+    // bytes per second shared by all connections of this outbound, unlimited if not set
+    #[serde(alias = "max-up")]
+    pub max_up: Option<u64>,
+    #[serde(alias = "max-down")]
+    pub max_down: Option<u64>,
+    #[serde(alias = "circuit-breaker")]
+    pub circuit_breaker: Option<CircuitBreakerSettings>,
+    // keep warm connections to the proxy server, socks and trojan without transport, rejected for other protocols
+    pub pool: Option<PoolSettings>,
+    // where destination domains are resolved: local | remote | prefer-remote, default remote
+    // direct outbound always resolves with the tunnel's dns
+    pub resolve: Option<String>,
 }
 
-#[derive(Clone, Deserialize)]
+// idle connections to the proxy server that new sessions can use without waiting for connect
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct PoolSettings {
+    // idle connections kept, defaults to 2
+    pub size: Option<usize>,
+    // seconds an idle connection is kept, defaults to 60
+    #[serde(alias = "idle-ttl")]
+    pub idle_ttl: Option<u64>,
+}
+
+// consecutive dial failures open the circuit, new connections use fallback until backoff expires
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct CircuitBreakerSettings {
+    // consecutive failures before the circuit opens, defaults to 3
+    pub failures: Option<u32>,
+    // seconds before the outbound is tried again, defaults to 30
+    pub backoff: Option<u64>,
+    // outbound tag used while the circuit is open, connections are dropped if not set
+    pub fallback: Option<String>,
+}
+
+// socket options and connect behaviour of an outbound
+// the top level "dialer" provides defaults, an outbound's own settings override it per field
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct DialerSettings {
+    // bind outgoing sockets to this interface, e.g. "eth0"
+    pub interface: Option<String>,
+    // without interface, bind to the interface of the default route and follow its changes
+    #[serde(alias = "auto-detect-interface")]
+    pub auto_detect_interface: Option<bool>,
+    // local source ip
+    pub bind: Option<String>,
+    // linux SO_MARK
+    pub fwmark: Option<u32>,
+    // seconds, defaults to 10
+    #[serde(alias = "connect-timeout")]
+    pub connect_timeout: Option<u64>,
+    // milliseconds before racing the next address, defaults to 250
+    #[serde(alias = "happy-eyeballs-delay")]
+    pub happy_eyeballs_delay: Option<u64>,
+    // extra connect attempts after the first one failed or timed out, defaults to 0
+    pub retry: Option<u32>,
+    // TCP_NODELAY, defaults to true
+    pub nodelay: Option<bool>,
+    // seconds of idle before tcp keepalive probes, disabled by default
+    pub keepalive: Option<u64>,
+    // SO_SNDBUF / SO_RCVBUF in bytes
+    #[serde(alias = "send-buffer")]
+    pub send_buffer: Option<usize>,
+    #[serde(alias = "recv-buffer")]
+    pub recv_buffer: Option<usize>,
+    // client side tcp fast open, linux only
+    #[serde(alias = "tcp-fast-open")]
+    pub fast_open: Option<bool>,
+    // multipath tcp, linux 5.6+, falls back to tcp when unsupported
+    pub mptcp: Option<bool>,
+}
+
+impl DialerSettings {
+    /// fields not set here are taken from base
+    pub fn or(&self, base: &DialerSettings) -> DialerSettings {
+        DialerSettings {
+            interface: self.interface.clone().or_else(|| base.interface.clone()),
+            auto_detect_interface: self.auto_detect_interface.or(base.auto_detect_interface),
+            bind: self.bind.clone().or_else(|| base.bind.clone()),
+            fwmark: self.fwmark.or(base.fwmark),
+            connect_timeout: self.connect_timeout.or(base.connect_timeout),
+            happy_eyeballs_delay: self.happy_eyeballs_delay.or(base.happy_eyeballs_delay),
+            retry: self.retry.or(base.retry),
+            nodelay: self.nodelay.or(base.nodelay),
+            keepalive: self.keepalive.or(base.keepalive),
+            send_buffer: self.send_buffer.or(base.send_buffer),
+            recv_buffer: self.recv_buffer.or(base.recv_buffer),
+            fast_open: self.fast_open.or(base.fast_open),
+            mptcp: self.mptcp.or(base.mptcp),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Default)]
 pub struct GeneralSettings {
     pub prefer_ipv6: bool,
     pub use_ipv6: bool,
+    // prefer-ipv4 | prefer-ipv6 | ipv4-only | ipv6-only, replaces use_ipv6 and prefer_ipv6 when set
+    #[serde(alias = "ip-strategy")]
+    pub ip_strategy: Option<String>,
+    // e.g. "64:ff9b::/96" on ipv6 only networks, ipv4 destinations are reached through NAT64
+    #[serde(alias = "nat64-prefix")]
+    pub nat64_prefix: Option<String>,
+    // evaluate routing and dns, log the decision, but send everything direct
+    #[serde(default)]
+    pub dry_run: bool,
+    // seconds without bytes in either direction before a relayed tcp connection is closed, no idle timeout if not set or 0
+    #[serde(alias = "idle-timeout")]
+    pub idle_timeout: Option<u64>,
+    // seconds a relayed tcp connection may live at most, unlimited if not set
+    #[serde(alias = "max-lifetime")]
+    pub max_lifetime: Option<u64>,
+    // linux only, switch from root to this user once privileged setup is done, name or uid
+    pub user: Option<String>,
+    // name or gid, defaults to the primary group of user
+    pub group: Option<String>,
+    // kept after switching user, e.g. ["net_admin", "net_bind_service"], derived from the config if not set
+    pub capabilities: Option<Vec<String>>,
+    // concurrent connections over all inbounds and profiles, unlimited if not set
+    #[serde(alias = "max-connections")]
+    pub max_connections: Option<usize>,
+    // connections over a limit waiting for a slot, defaults to 64 per inbound, 0 rejects them at once
+    // when the queue is full the inbound stops accepting
+    #[serde(alias = "pending-connections")]
+    pub pending_connections: Option<usize>,
+    // seconds a connection waits in the queue before it is closed, defaults to 10
+    #[serde(alias = "pending-timeout")]
+    pub pending_timeout: Option<u64>,
+    // linux 5.8+ with the ebpf cargo feature, keep our own connections off the tun without fwmark rules
+    pub ebpf: Option<EbpfSettings>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct EbpfSettings {
+    // connections are bound to this interface, defaults to the interface of the default route
+    pub interface: Option<String>,
+    // cgroup v2 directory the program is attached to, defaults to the cgroup of the process
+    pub cgroup: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Socks5InboundSettings {
+    // username/password authentication (RFC 1929), no authentication if not set
+    pub users: Option<Vec<SocksUser>>,
+    // source cidrs allowed to connect, everyone if not set
+    pub allow: Option<Vec<String>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct TunInboundSettings {
+    // mtu of the tun device, defaults to 1500
+    pub mtu: Option<u16>,
+    // linux only, read and write coalesced tcp packets of up to 64KB (IFF_VNET_HDR with TSO/GRO)
+    #[serde(default)]
+    pub offload: bool,
+    // linux only, open the device with this many queues (IFF_MULTI_QUEUE), each served by its own task
+    pub queues: Option<usize>,
+    // answer every udp/tcp packet to port 53 with the internal dns, whatever its destination ip
+    #[serde(default, alias = "dns-hijack")]
+    pub dns_hijack: bool,
+    #[serde(default)]
+    pub tcp: TunTcpSettings,
+}
+
+// tcp from the tun device is terminated by the kernel stack behind a local listener
+// these tune that listener and the options of the SYN packets passing through the tun
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct TunTcpSettings {
+    // bytes, SO_RCVBUF of the local endpoint, larger buffers advertise larger (scaled) windows
+    #[serde(alias = "recv-window")]
+    pub recv_window: Option<usize>,
+    // bytes, SO_SNDBUF of the local endpoint
+    #[serde(alias = "send-buffer")]
+    pub send_buffer: Option<usize>,
+    // offer selective acks to apps, defaults to true
+    pub sack: Option<bool>,
+    // clamp the MSS of SYN packets to the tun mtu, defaults to true
+    #[serde(alias = "mss-clamp")]
+    pub mss_clamp: Option<bool>,
+    // delay acks to apps, defaults to true, linux only
+    #[serde(alias = "delayed-ack")]
+    pub delayed_ack: Option<bool>,
+    // disable nagle on the local endpoint
+    #[serde(default)]
+    pub nodelay: bool,
+    // seconds, wait for the second FIN after one side closed, defaults to 60
+    #[serde(alias = "fin-wait")]
+    pub fin_wait: Option<u64>,
+    // seconds, wait for the last ACK after both sides closed, defaults to 30
+    #[serde(alias = "last-ack")]
+    pub last_ack: Option<u64>,
+    // seconds, established connections without any packet are dropped, defaults to 7200
+    pub orphan: Option<u64>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-pub struct Socks5InboundSettings {}
+pub struct SocksUser {
+    pub username: String,
+    pub password: String,
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Socks5OutboundSettings {
@@ -55,27 +336,258 @@ pub struct ShadowsocksOutboundSettings {
     pub port: u16,
     pub password: String,
     pub method: String,
+    // SIP003 plugin，obfs-local/simple-obfs 的 http 模式内置实现，其他启动外部程序
+    pub plugin: Option<String>,
+    #[serde(alias = "plugin-opts")]
+    pub plugin_opts: Option<String>,
+}
+
+// tls transport settings, embedded in the settings of outbounds that ride on tls
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct TlsSettings {
+    // server name indication, defaults to the server address
+    pub sni: Option<String>,
+    pub alpn: Option<Vec<String>>,
+    // path of PEM encoded CA certificates, used instead of the builtin roots
+    pub ca: Option<String>,
+    // hex encoded sha256 of the server certificate (DER)
+    pub pins: Option<Vec<String>>,
+    // skip certificate verification, testing only
+    #[serde(default)]
+    pub insecure: bool,
+    // "1.2" or "1.3", lower negotiated versions are treated as downgrade
+    pub min_version: Option<String>,
+    // fail the connection instead of only warning when a downgrade is detected
+    #[serde(default)]
+    pub fail_on_downgrade: bool,
+}
+
+// tls termination for server inbounds
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TlsServerSettings {
+    // PEM certificate chain
+    pub cert: String,
+    // PEM private key, pkcs8 or rsa
+    pub key: String,
+    pub alpn: Option<Vec<String>>,
+    // seconds between checks of cert/key modification, 0 disables watching
+    pub reload_interval: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct QuicSettings {
+    #[serde(default)]
+    pub tls: TlsSettings,
+    // resume previous sessions with 0-RTT
+    #[serde(default)]
+    pub zero_rtt: bool,
+}
+
+// http/2 based transport between the outbound and its server, over tls with alpn h2
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TransportSettings {
+    // "h2" or "grpc"
+    pub network: String,
+    // h2 request path, defaults to "/"
+    pub path: Option<String>,
+    // :authority, defaults to the server address
+    pub host: Option<String>,
+    // grpc service, requests go to /<service_name>/Tun, defaults to GunService
+    #[serde(alias = "service-name")]
+    pub service_name: Option<String>,
+    #[serde(default)]
+    pub tls: TlsSettings,
+    // http/2 PING, answered by any h2 server
+    pub keepalive: Option<KeepaliveSettings>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RelayOutboundSettings {
+    pub address: String,
+    pub port: u16,
+    pub password: String,
+    pub mux: Option<MuxSettings>,
+    // carry relay connections in h2/grpc streams, e.g. through a CDN
+    pub transport: Option<TransportSettings>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrojanOutboundSettings {
+    pub address: String,
+    pub port: u16,
+    pub password: String,
+    // ignored when transport is set, the tls of the transport is used
+    #[serde(default)]
+    pub tls: TlsSettings,
+    // carry trojan connections in h2/grpc streams, e.g. through a CDN
+    pub transport: Option<TransportSettings>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Hysteria2OutboundSettings {
+    pub address: String,
+    pub port: u16,
+    pub password: String,
+    // bandwidth hints in Mbps, up-mbps enables brutal congestion control
+    #[serde(alias = "up-mbps")]
+    pub up_mbps: Option<u64>,
+    #[serde(alias = "down-mbps")]
+    pub down_mbps: Option<u64>,
+    // brutal | cubic | new-reno, defaults to brutal when up-mbps is set
+    pub congestion: Option<String>,
+    #[serde(default)]
+    pub tls: TlsSettings,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrojanInboundSettings {
+    pub passwords: Vec<String>,
+    pub tls: TlsServerSettings,
+    // "ip:port" of a decoy http server for clients that are not trojan, they are closed if not set
+    pub fallback: Option<String>,
+}
+
+// answer plain http connections routed to reject instead of resetting them, tls connections are still reset
+// a page with status 403 is shown, or the client is redirected if redirect is set
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct RejectOutboundSettings {
+    // html file, "{destination}" is replaced by the blocked host, a builtin page is used if not set
+    pub page: Option<String>,
+    // url for a 302 redirect instead of the page
+    pub redirect: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RelayInboundSettings {
+    pub password: String,
+    // seconds, relay connection without streams is closed after idle
+    pub idle_timeout: Option<u64>,
+}
+
+// multiplex many sessions over one connection to the proxy server
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct MuxSettings {
+    // max streams per connection
+    pub max_streams: Option<usize>,
+    // seconds, connection without streams is closed after idle
+    pub idle_timeout: Option<u64>,
+    // ping the server to detect dead connections, the server must be a relay inbound of this version
+    pub keepalive: Option<KeepaliveSettings>,
+}
+
+// application level pings on a multiplexed connection, dead connections are closed and dialed again
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct KeepaliveSettings {
+    // seconds between pings, default 15
+    pub interval: Option<u64>,
+    // intervals without any data from the peer before the connection is dead, default 3
+    pub tolerance: Option<u32>,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct Inbound {
-    pub port: Option<u16>,
+    // 1080, "1080-1090", "1080,8118" or [1080, "2000-2010"]
+    pub port: Option<PortList>,
     pub listen: Option<String>,
     pub protocol: String,
     pub tag: String,
+    // concurrent connections of this inbound, unlimited if not set
+    #[serde(alias = "max-connections")]
+    pub max_connections: Option<usize>,
     // domain or socket addr
     pub settings: Option<Box<RawValue>>,
 }
 
+// 一个 inbound 监听的全部端口共享同一个 handler
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PortList {
+    Port(u16),
+    Spec(String),
+    List(Vec<PortList>),
+}
+
+// 防止 "1-65535" 这样的配置打开几万个 listener
+const MAX_LISTEN_PORTS: usize = 1024;
+
+impl PortList {
+    /// every port in order, duplicates removed
+    pub fn ports(&self) -> Result<Vec<u16>> {
+        let mut ports = Vec::new();
+        self.collect(&mut ports)?;
+        let mut seen = std::collections::HashSet::new();
+        ports.retain(|x| seen.insert(*x));
+        if ports.len() > MAX_LISTEN_PORTS {
+            bail!("too many ports {}, at most {}", ports.len(), MAX_LISTEN_PORTS);
+        }
+        Ok(ports)
+    }
+
+    fn collect(&self, ports: &mut Vec<u16>) -> Result<()> {
+        match self {
+            PortList::Port(port) => ports.push(*port),
+            PortList::Spec(spec) => {
+                for item in spec.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                    let parse = |x: &str| x.trim().parse::<u16>().map_err(|_| anyhow!("invalid port {}", spec));
+                    match item.split_once('-') {
+                        Some((start, end)) => {
+                            let (start, end) = (parse(start)?, parse(end)?);
+                            if start > end || (end - start) as usize >= MAX_LISTEN_PORTS {
+                                bail!("invalid port range {}", item);
+                            }
+                            ports.extend(start..=end);
+                        }
+                        None => ports.push(parse(item)?),
+                    }
+                }
+            }
+            PortList::List(list) => {
+                for x in list {
+                    x.collect(ports)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Deserialize)]
 pub struct Rule {
     pub ip: Option<Vec<String>>,
+    // IP-CIDR6, ipv6 only
+    #[serde(alias = "ip-cidr6")]
+    pub ip6: Option<Vec<String>>,
+    // destination ports, "443" or "8000-9000"
     pub portRange: Option<Vec<String>>,
     pub domain: Option<Vec<String>>,
     pub domainSuffix: Option<Vec<String>>,
     pub domainKeyword: Option<Vec<String>>,
     pub regexp: Option<Vec<String>>,
+    // executable name of the local process owning the connection
+    pub process: Option<Vec<String>>,
+    // owner of the local socket, numeric uid or system user name
+    pub uid: Option<Vec<String>>,
+    // names of rule_providers
+    pub rule_set: Option<Vec<String>>,
+    // username authenticated by the inbound, e.g. socks users
+    pub user: Option<Vec<String>>,
+    // tags of the inbounds the connection arrived on
+    pub inbound: Option<Vec<String>>,
+    // "tcp" or "udp"
+    pub network: Option<Vec<String>>,
+    // local time ranges the rule applies in, e.g. "mon-fri 09:00-18:00", any matches
+    // other conditions of the rule only match within them, a rule with only schedule matches everything
+    #[serde(alias = "time")]
+    pub schedule: Option<Vec<String>>,
+    // conditions that must all match, e.g. "DOMAIN-SUFFIX:example.com AND DST-PORT:8000-9000 AND NETWORK:udp"
+    // types: DOMAIN, DOMAIN-SUFFIX, DOMAIN-KEYWORD, IP-CIDR, IP-CIDR6, DST-PORT, NETWORK, PROCESS-NAME, USER, INBOUND, RULE-SET, REGEXP
+    pub and: Option<String>,
     pub target: String,
+    // bytes per second shared by all connections matched by this rule, applied on top of the outbound's limits
+    #[serde(alias = "max-up")]
+    pub max_up: Option<u64>,
+    #[serde(alias = "max-down")]
+    pub max_down: Option<u64>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -84,32 +596,131 @@ pub struct DnsConfig {
     pub bind: String,
     pub servers: Option<Vec<String>>,
     pub hosts: Option<HashMap<String, Vec<String>>>,
+    // split dns, first matched policy wins, domains "default" matches everything
+    // an unconditional "default" is the default upstream and can not be combined with servers
+    pub policy: Option<Vec<DnsPolicy>>,
+    pub blocklist: Option<BlocklistConfig>,
+    // seconds between active network checks when policies depend on interface or ssid, defaults to 10
+    pub network_check_interval: Option<u64>,
+    // what to do when a domain can not be resolved locally
+    // fail: drop the session (default), proxy: send the domain to fallback_outbound for remote resolution
+    pub fail_policy: Option<String>,
+    pub fallback_outbound: Option<String>,
+    // encrypted upstreams over quic, tried in order before servers for domains without a policy
+    pub upstreams: Option<Vec<DnsUpstreamConfig>>,
+    // 0x20 encoding, randomize the letter case of names sent to udp servers and require it back
+    // some servers do not keep the case and every answer from them is dropped
+    #[serde(default, alias = "randomize-case")]
+    pub randomize_case: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DnsUpstreamConfig {
+    // doq (RFC 9250) or doh3
+    pub protocol: String,
+    // "ip:port", usually 853 for doq and 443 for doh3
+    pub address: SocketAddr,
+    // tls server name of the resolver
+    #[serde(alias = "server-name")]
+    pub server_name: String,
+    // doh3 request path, defaults to /dns-query
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct BlocklistConfig {
+    // local hosts-format or domain-per-line lists
+    pub files: Option<Vec<String>>,
+    // remote lists, fetched through download.outbound
+    pub urls: Option<Vec<String>>,
+    // refresh interval of remote lists in seconds, defaults to one day
+    pub refresh: Option<u64>,
+    // nxdomain | zero
+    pub response: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DnsPolicy {
+    // same patterns as routing domain rules: "example.com", "*.corp.example", "domain:example.com"
+    pub domains: Vec<String>,
+    // upstream socket addr, "10.0.0.2:53"
+    pub server: String,
+    // only applies while the default route goes through this interface
+    pub interface: Option<String>,
+    // only applies while connected to this Wi-Fi
+    pub ssid: Option<String>,
+}
+
+// settings used when fetching remote resources (rule providers, geo data ...)
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct DownloadConfig {
+    // outbound tag the download goes through, direct connect if absent
+    pub outbound: Option<String>,
+    pub user_agent: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    // seconds
+    pub timeout: Option<u64>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            general: GeneralSettings {
-                prefer_ipv6: false,
-                use_ipv6: false,
-            },
+            general: GeneralSettings::default(),
             inbounds: Vec::new(),
             outbounds: Vec::new(),
             routes: Vec::new(),
             dns: None,
+            download: None,
+            rewrite: None,
+            api: None,
+            rule_providers: None,
+            dialer: None,
+            profiles: None,
+            system_proxy: None,
         }
     }
 }
 
 pub fn parse_from_str(p: &str) -> Result<Config> {
+    parse_from_str_with_mode(p, false)
+}
+
+/// strict: unknown keys, unreachable rules and deprecated options are errors instead of warnings
+/// relative includes are resolved from the working directory
+pub fn parse_from_str_with_mode(p: &str, strict: bool) -> Result<Config> {
+    parse_with_path(p, None, strict)
+}
+
+fn parse_with_path(p: &str, path: Option<&Path>, strict: bool) -> Result<Config> {
     let mut str = StripComments::new(p.as_bytes());
     let mut s = String::new();
-    str.read_to_string(&mut s);
+    str.read_to_string(&mut s)?;
+    // 没有 include 时直接解析原文
+    if let Some(merged) = include::expand(&s, path)? {
+        s = merged;
+    }
     let json = serde_json::from_str(s.as_str())?;
+    validate::validate(s.as_str(), &json, strict)?;
     Ok(json)
 }
 
 pub fn load_from_file(path: &str) -> Result<Config> {
+    load_from_file_with_mode(path, false)
+}
+
+pub fn load_from_file_with_mode(path: &str, strict: bool) -> Result<Config> {
     let content = fs::read_to_string(path)?;
-    parse_from_str(&*content)
+    parse_with_path(&*content, Some(Path::new(path)), strict)
+}
+
+#[test]
+fn test_port_list() {
+    let ports = |x: &str| serde_json::from_str::<PortList>(x).unwrap().ports();
+    assert_eq!(ports("1080").unwrap(), vec![1080]);
+    assert_eq!(ports(r#""1080-1082""#).unwrap(), vec![1080, 1081, 1082]);
+    assert_eq!(ports(r#""1080, 8118""#).unwrap(), vec![1080, 8118]);
+    assert_eq!(ports(r#"[8118, "1080-1081", 8118]"#).unwrap(), vec![8118, 1080, 1081]);
+    assert!(ports(r#""1090-1080""#).is_err());
+    assert!(ports(r#""1-65535""#).is_err());
+    assert!(ports(r#""http""#).is_err());
 }
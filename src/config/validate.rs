@@ -0,0 +1,280 @@
+use anyhow::{bail, Result};
+use log::warn;
+
+use super::{Config, Outbound, Rule, TrojanOutboundSettings};
+
+// 配置检查
+// 默认模式只打印 warning，保证向前兼容（新版本的配置项在旧版本中被忽略）
+// strict 模式下全部作为错误，用于 CI 中校验配置
+pub fn validate(content: &str, config: &Config, strict: bool) -> Result<()> {
+    udp_oversize(config)?;
+    dns_default_policy(config)?;
+    dns_geosite(config)?;
+    outbound_pool(config)?;
+    let mut problems = Vec::new();
+    unknown_keys(content, &mut problems);
+    unreachable_rules(config, &mut problems);
+    deprecated_options(config, &mut problems);
+    dns_fail_policy(config, &mut problems);
+    profiles(config, &mut problems);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        warn!("config: {}", problem);
+    }
+    if strict {
+        bail!("invalid config in strict mode: {}", problems.join("; "));
+    }
+    Ok(())
+}
+
+fn unknown_keys(content: &str, problems: &mut Vec<String>) {
+    let mut deserializer = serde_json::Deserializer::from_str(content);
+    let mut unknown = Vec::new();
+    let res: Result<Config, _> = serde_ignored::deserialize(&mut deserializer, |path| {
+        unknown.push(path.to_string());
+    });
+    if let Err(err) = res {
+        // 正常解析已经成功，这里只用于检查
+        warn!("config: unknown key check skipped {}", err);
+        return;
+    }
+    for key in unknown {
+        problems.push(format!("unknown key {}", key));
+    }
+}
+
+fn is_catch_all(regexp: &str) -> bool {
+    matches!(regexp, ".*" | "^.*$" | ".+" | "^.+$")
+}
+
+fn unreachable_rules(config: &Config, problems: &mut Vec<String>) {
+    check_routes("", &config.routes, &config.outbounds, problems);
+    for profile in config.profiles.iter().flatten() {
+        let prefix = format!("profiles.{}.", profile.name);
+        check_routes(&prefix, &profile.routes, &profile.outbounds, problems);
+    }
+}
+
+// 每个 profile 只能路由到自己的 outbound
+fn check_routes(prefix: &str, routes: &[Rule], outbounds: &[Outbound], problems: &mut Vec<String>) {
+    let tags: Vec<&String> = outbounds.iter().map(|x| &x.tag).collect();
+    let mut catch_all: Option<usize> = None;
+    for (idx, rule) in routes.iter().enumerate() {
+        if !tags.contains(&&rule.target) {
+            problems.push(format!("{}routes[{}] target {} is not an outbound tag", prefix, idx, rule.target));
+        }
+        if let Some(prev) = catch_all {
+            problems.push(format!("{}routes[{}] is unreachable, {}routes[{}] matches everything", prefix, idx, prefix, prev));
+            continue;
+        }
+        let has_condition = rule.ip.is_some()
+            || rule.ip6.is_some()
+            || rule.portRange.is_some()
+            || rule.domain.is_some()
+            || rule.regexp.is_some()
+            || rule.process.is_some()
+            || rule.uid.is_some()
+            || rule.user.is_some()
+            || rule.inbound.is_some()
+            || rule.network.is_some()
+            || rule.rule_set.is_some()
+            || rule.schedule.is_some()
+            || rule.and.is_some();
+        if !has_condition {
+            problems.push(format!("{}routes[{}] has no condition and never matches", prefix, idx));
+        }
+        if let Some(regexp) = &rule.regexp {
+            if regexp.iter().any(|x| is_catch_all(x)) {
+                catch_all = Some(idx);
+            }
+        }
+    }
+}
+
+// 同一个 inbound tag 只能属于一个 profile
+fn profiles(config: &Config, problems: &mut Vec<String>) {
+    let mut names = Vec::new();
+    let mut inbound_tags: Vec<&String> = config.inbounds.iter().map(|x| &x.tag).collect();
+    for profile in config.profiles.iter().flatten() {
+        if names.contains(&&profile.name) {
+            problems.push(format!("duplicate profile {}", profile.name));
+        }
+        names.push(&profile.name);
+        for inbound in &profile.inbounds {
+            if inbound_tags.contains(&&inbound.tag) {
+                problems.push(format!("profiles.{} inbound tag {} is already used", profile.name, inbound.tag));
+            }
+            inbound_tags.push(&inbound.tag);
+        }
+    }
+}
+
+fn dns_fail_policy(config: &Config, problems: &mut Vec<String>) {
+    if let Some(dns) = &config.dns {
+        match (dns.fail_policy.as_deref(), &dns.fallback_outbound) {
+            (None, _) | (Some("fail"), _) => {}
+            (Some("proxy"), Some(tag)) => {
+                if !config.outbounds.iter().any(|x| &x.tag == tag) {
+                    problems.push(format!("dns.fallback_outbound {} is not an outbound tag", tag));
+                }
+            }
+            (Some("proxy"), None) => {
+                problems.push("dns.fail_policy proxy requires dns.fallback_outbound".to_string());
+            }
+            (Some(policy), _) => problems.push(format!("unknown dns.fail_policy {}", policy)),
+        }
+    }
+}
+
+// default policy 是唯一的默认 upstream，和 dns.servers 同时配置时无法确定使用哪一个
+fn dns_default_policy(config: &Config) -> Result<()> {
+    let dns = match &config.dns {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    // 指定了 interface 或 ssid 的 policy 只在对应网络下生效，不影响默认 upstream
+    let defaults = dns
+        .policy
+        .iter()
+        .flatten()
+        .filter(|x| x.interface.is_none() && x.ssid.is_none() && x.domains.iter().any(|x| x == "default"))
+        .count();
+    if defaults > 1 {
+        bail!("dns.policy default is set more than once");
+    }
+    if defaults == 1 && dns.servers.as_ref().map_or(false, |x| !x.is_empty()) {
+        bail!("dns.policy default conflicts with dns.servers, configure the default upstream in one of them");
+    }
+    Ok(())
+}
+
+// 没有 geosite 数据，忽略之后这些域名会悄悄走默认 upstream
+fn dns_geosite(config: &Config) -> Result<()> {
+    let policies = config.dns.iter().flat_map(|x| x.policy.iter().flatten());
+    for pattern in policies.flat_map(|x| x.domains.iter()) {
+        if pattern.starts_with("geosite:") {
+            bail!("dns.policy {} is not supported, list the domains instead", pattern);
+        }
+    }
+    Ok(())
+}
+
+// fragment 曾经被接受但和 drop 一样丢弃报文，不再悄悄降级
+fn udp_oversize(config: &Config) -> Result<()> {
+    let profiles = config.profiles.iter().flatten().flat_map(|x| x.outbounds.iter());
+    for outbound in config.outbounds.iter().chain(profiles) {
+        match outbound.udp_oversize.as_deref() {
+            None | Some("drop") | Some("icmp") => {}
+            Some("fragment") => bail!("outbound {} udp_oversize fragment is not supported, use drop or icmp", outbound.tag),
+            Some(policy) => bail!("outbound {} unknown udp_oversize {}", outbound.tag, policy),
+        }
+    }
+    Ok(())
+}
+
+// 连接池只有 socks（认证方法协商）与 trojan（tls 握手）有可以提前完成的握手
+// shadowsocks 的第一个数据包就是 salt 加上加密的目标地址，只能提前建立不发送数据的 tcp 连接，
+// 服务器会在握手超时后关闭这种连接，并且连接建立后长时间没有数据本身就是一个特征，所以不支持
+fn outbound_pool(config: &Config) -> Result<()> {
+    let profiles = config.profiles.iter().flatten().flat_map(|x| x.outbounds.iter());
+    for outbound in config.outbounds.iter().chain(profiles) {
+        if outbound.pool.is_none() {
+            continue;
+        }
+        match &*outbound.protocol {
+            "socks" => {}
+            "trojan" => {
+                let settings = outbound.settings.as_ref().map(|x| serde_json::from_str::<TrojanOutboundSettings>(x.get()));
+                if let Some(Ok(TrojanOutboundSettings { transport: Some(_), .. })) = settings {
+                    bail!(
+                        "outbound {} pool is not supported with a trojan transport, h2 already reuses one connection",
+                        outbound.tag
+                    );
+                }
+            }
+            "shadowsocks" => bail!(
+                "outbound {} pool is not supported by shadowsocks, the first packet already carries the destination",
+                outbound.tag
+            ),
+            protocol => bail!("outbound {} pool is not supported by {}", outbound.tag, protocol),
+        }
+    }
+    Ok(())
+}
+
+fn deprecated_options(config: &Config, problems: &mut Vec<String>) {
+    if let Some(dns) = &config.dns {
+        if dns.ip.is_some() {
+            problems.push("dns.ip is deprecated and has no effect, use dns.servers".to_string());
+        }
+    }
+}
+
+#[test]
+fn test_rule_conditions() {
+    let outbounds: Vec<Outbound> = serde_json::from_str(r#"[{"protocol": "direct", "tag": "direct"}]"#).unwrap();
+    let check = |rule: &str| {
+        let routes: Vec<Rule> = serde_json::from_str(&format!("[{}]", rule)).unwrap();
+        let mut problems = Vec::new();
+        check_routes("", &routes, &outbounds, &mut problems);
+        problems
+    };
+    assert_eq!(check(r#"{"target": "direct"}"#), vec!["routes[0] has no condition and never matches".to_string()]);
+    assert!(check(r#"{"portRange": ["8000-9000"], "target": "direct"}"#).is_empty());
+    assert!(check(r#"{"schedule": ["mon-fri 09:00-18:00"], "target": "direct"}"#).is_empty());
+    assert!(check(r#"{"and": "DOMAIN-SUFFIX:example.com AND NETWORK:udp", "target": "direct"}"#).is_empty());
+}
+
+#[test]
+fn test_udp_oversize() {
+    let config = |policy: &str| {
+        super::parse_from_str(&format!(
+            r#"{{"general": {{"prefer_ipv6": false, "use_ipv6": false}}, "inbounds": [], "outbounds": [{{"protocol": "direct", "tag": "direct", "udp_oversize": "{}"}}], "routes": []}}"#,
+            policy
+        ))
+    };
+    assert!(config("icmp").is_ok());
+    let err = config("fragment").unwrap_err().to_string();
+    assert!(err.contains("outbound direct") && err.contains("fragment"), "{}", err);
+    assert!(config("truncate").is_err());
+}
+
+#[test]
+fn test_dns_default_policy() {
+    let config = |dns: &str| {
+        super::parse_from_str(&format!(
+            r#"{{"general": {{"prefer_ipv6": false, "use_ipv6": false}}, "inbounds": [], "outbounds": [], "routes": [], "dns": {}}}"#,
+            dns
+        ))
+    };
+    let policy = r#"[{"domains": ["default"], "server": "1.1.1.1:53"}, {"domains": ["*.corp.example"], "server": "10.0.0.2:53"}]"#;
+    assert!(config(&format!(r#"{{"bind": "127.0.0.1:53", "policy": {}}}"#, policy)).is_ok());
+    let err = config(&format!(r#"{{"bind": "127.0.0.1:53", "servers": ["8.8.8.8:53"], "policy": {}}}"#, policy))
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("dns.servers"), "{}", err);
+    // 只在指定网络下生效的 default 可以和 servers 同时配置
+    let policy = r#"[{"domains": ["default"], "server": "10.0.0.2:53", "ssid": "office"}]"#;
+    assert!(config(&format!(r#"{{"bind": "127.0.0.1:53", "servers": ["8.8.8.8:53"], "policy": {}}}"#, policy)).is_ok());
+    let policy = r#"[{"domains": ["geosite:cn"], "server": "223.5.5.5:53"}]"#;
+    let err = config(&format!(r#"{{"bind": "127.0.0.1:53", "policy": {}}}"#, policy)).unwrap_err().to_string();
+    assert!(err.contains("geosite:cn"), "{}", err);
+}
+
+#[test]
+fn test_outbound_pool() {
+    let config = |protocol: &str, settings: &str| {
+        super::parse_from_str(&format!(
+            r#"{{"general": {{"prefer_ipv6": false, "use_ipv6": false}}, "inbounds": [], "outbounds": [{{"protocol": "{}", "tag": "proxy", "pool": {{"size": 2}}, "settings": {}}}], "routes": []}}"#,
+            protocol, settings
+        ))
+    };
+    assert!(config("socks", r#"{"address": "127.0.0.1", "port": 1080}"#).is_ok());
+    assert!(config("trojan", r#"{"address": "example.com", "port": 443, "password": "p"}"#).is_ok());
+    let err = config("shadowsocks", r#"{"address": "127.0.0.1", "port": 8388, "method": "aes-128-gcm", "password": "p"}"#)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("shadowsocks"), "{}", err);
+}
@@ -0,0 +1,241 @@
+// 以库的方式嵌入 tunnel（GUI、测试），不需要启动 tunnel 进程
+//
+// let instance = TunnelBuilder::new(config).start()?;
+// instance.stats(DEFAULT_PROFILE);
+// instance.reload(new_config).await?;
+// instance.add_listener(Arc::new(MyListener));
+// instance.shutdown();
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use log::{debug, info};
+use tokio::{
+    runtime::{Handle, Runtime},
+    task::JoinHandle,
+};
+
+use crate::{
+    app::{EventListener, Events, InboundManager, Stats},
+    build,
+    common::{privilege, sysproxy::SystemProxy},
+    config::Config,
+    init_logger, load_from_file, newRuntime, parse_from_str,
+};
+
+pub struct TunnelBuilder {
+    config: Config,
+    logger: bool,
+    runtime: Option<Handle>,
+    events: Events,
+}
+
+impl TunnelBuilder {
+    pub fn new(config: Config) -> TunnelBuilder {
+        TunnelBuilder {
+            config,
+            logger: false,
+            runtime: None,
+            events: Events::default(),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<TunnelBuilder> {
+        Ok(TunnelBuilder::new(load_from_file(path)?))
+    }
+
+    pub fn parse(config: &str) -> Result<TunnelBuilder> {
+        Ok(TunnelBuilder::new(parse_from_str(config)?))
+    }
+
+    /// install the built-in stdout logger, off by default so the host keeps its own
+    pub fn logger(mut self, enable: bool) -> TunnelBuilder {
+        self.logger = enable;
+        self
+    }
+
+    /// run on an existing runtime instead of a dedicated one
+    pub fn runtime(mut self, handle: Handle) -> TunnelBuilder {
+        self.runtime = Some(handle);
+        self
+    }
+
+    /// observe sessions, dns queries and rule matches
+    pub fn listener(self, listener: Arc<dyn EventListener>) -> TunnelBuilder {
+        self.events.add(listener);
+        self
+    }
+
+    pub fn start(self) -> Result<Instance> {
+        if self.logger {
+            init_logger();
+        }
+        let (runtime, handle) = match self.runtime {
+            Some(handle) => (None, handle),
+            None => {
+                let runtime = newRuntime();
+                let handle = runtime.handle().clone();
+                (Some(runtime), handle)
+            }
+        };
+        let running = Running::start(&handle, self.config, &self.events)?;
+        Ok(Instance {
+            runtime,
+            handle,
+            events: self.events,
+            running: Mutex::new(running),
+            reload: tokio::sync::Mutex::new(()),
+        })
+    }
+}
+
+struct Running {
+    config: Config,
+    stats: HashMap<String, Arc<Stats>>,
+    task: Option<JoinHandle<()>>,
+    inbounds: Vec<Arc<InboundManager>>,
+    // drop 时恢复原来的系统代理设置
+    system_proxy: Option<SystemProxy>,
+}
+
+impl Running {
+    fn start(handle: &Handle, config: Config, events: &Events) -> Result<Running> {
+        let _guard = handle.enter();
+        let (tasks, stats, inbounds) = build(&config, events)?;
+        let task = handle.spawn(async move {
+            futures::future::select_all(tasks).await;
+        });
+        let system_proxy = SystemProxy::apply(&config);
+        Ok(Running {
+            config,
+            stats,
+            task: Some(task),
+            inbounds,
+            system_proxy,
+        })
+    }
+}
+
+/// a running tunnel, stopped on shutdown or drop
+pub struct Instance {
+    // 没有指定 runtime 时自己持有
+    runtime: Option<Runtime>,
+    handle: Handle,
+    // 在 reload 之间保留
+    events: Events,
+    running: Mutex<Running>,
+    // 串行化 reload
+    reload: tokio::sync::Mutex<()>,
+}
+
+impl Instance {
+    pub fn config(&self) -> Config {
+        self.running.lock().unwrap().config.clone()
+    }
+
+    /// protocol stats of a profile, crate::DEFAULT_PROFILE for the top level inbounds
+    pub fn stats(&self, profile: &str) -> Option<Arc<Stats>> {
+        self.running.lock().unwrap().stats.get(profile).cloned()
+    }
+
+    /// register a listener, also receives the events after later reloads
+    pub fn add_listener(&self, listener: Arc<dyn EventListener>) {
+        self.events.add(listener);
+    }
+
+    pub fn profiles(&self) -> Vec<String> {
+        self.running.lock().unwrap().stats.keys().cloned().collect()
+    }
+
+    /// run a future on the instance's runtime, e.g. waiting for ctrl-c in a binary
+    /// must not be called from within an async context
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+
+    /// switch to general.user once the inbounds finished their privileged setup, e.g. the tun devices
+    /// only linux switches, a no-op without general.user; must not be called from within an async context
+    pub fn drop_privileges(&self) -> Result<()> {
+        let (config, inbounds) = {
+            let running = self.running.lock().unwrap();
+            (running.config.clone(), running.inbounds.clone())
+        };
+        if config.general.user.is_none() {
+            return Ok(());
+        }
+        self.handle.block_on(async {
+            for inbounds in &inbounds {
+                inbounds.setup_done().await;
+            }
+        });
+        privilege::drop_privileges(&config, &self.handle)
+    }
+
+    /// replace the running config
+    /// listeners are rebound, established connections are kept
+    /// the current config keeps running if the new one fails to build
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        let _reload = self.reload.lock().await;
+        // 先构造新的组件，失败时不影响正在运行的实例
+        let (tasks, stats, inbounds) = {
+            let _guard = self.handle.enter();
+            build(&config, &self.events)?
+        };
+        let (old, old_inbounds) = {
+            let mut running = self.running.lock().unwrap();
+            (running.task.take(), std::mem::take(&mut running.inbounds))
+        };
+        if let Some(old) = old {
+            old.abort();
+            if let Err(err) = old.await {
+                debug!("previous instance stopped {}", err);
+            }
+        }
+        // listener 是单独的 task，等待它们结束，释放端口
+        for inbound in old_inbounds {
+            inbound.shutdown().await;
+        }
+        let task = self.handle.spawn(async move {
+            futures::future::select_all(tasks).await;
+        });
+        // 先恢复原来的设置，新的设置保存的才是用户自己的设置
+        drop(self.running.lock().unwrap().system_proxy.take());
+        let system_proxy = SystemProxy::apply(&config);
+        *self.running.lock().unwrap() = Running {
+            config,
+            stats,
+            task: Some(task),
+            inbounds,
+            system_proxy,
+        };
+        info!("config reloaded");
+        Ok(())
+    }
+
+    /// stop all listeners and background tasks
+    pub fn shutdown(self) {
+        // 实际的清理在 Drop 中
+        drop(self);
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap();
+        if let Some(task) = running.task.take() {
+            task.abort();
+        }
+        // listener 的 task 在 drop 时 abort
+        running.inbounds.clear();
+        drop(running.system_proxy.take());
+        drop(running);
+        // 在 async context 中 drop runtime 会 panic
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
@@ -3,10 +3,13 @@ mod net;
 pub mod config;
 pub mod app;
 pub mod proxy;
+pub mod transport;
+mod instance;
+pub mod testing;
 
-use std::{sync::{Arc, Once}};
+use std::{collections::HashMap, sync::{Arc, Once}};
 
-use app::{Dispatcher, DnsClient, InboundManager, OutboundManager, Router};
+use app::{ApiServer, Dispatcher, DnsClient, Events, Fetcher, InboundManager, OutboundManager, Router, RuleProviders, Stats};
 use futures::future::BoxFuture;
 
 use log4rs::{
@@ -14,20 +17,32 @@ use log4rs::{
     config::{Appender, Logger, Root},
     encode::pattern::PatternEncoder,
 };
-use anyhow::{
-    anyhow
-};
 use tokio::{sync::{RwLock}};
 
-pub use self::config::{load_from_file, parse_from_str};
+pub use self::config::{load_from_file, load_from_file_with_mode, parse_from_str};
+pub use self::instance::{Instance, TunnelBuilder};
+pub use self::common::{ebpf, privilege, systemd};
+pub use self::app::{EventListener, SessionStats};
+
+// stats of the top level inbounds in the api
+pub const DEFAULT_PROFILE: &str = "default";
 
 pub struct Context {
     dns_client: Arc<RwLock<DnsClient>>,
+    events: Events,
 }
 
 impl Context {
     pub fn new(dns_client: Arc<RwLock<DnsClient>>) -> Self {
-        Context { dns_client }
+        Context {
+            dns_client,
+            events: Events::default(),
+        }
+    }
+
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
     }
 }
 
@@ -36,50 +51,146 @@ pub fn newRuntime() -> tokio::runtime::Runtime {
     runtime
 }
 
-pub fn start(config: config::Config, shutdown_handler: BoxFuture<'static, ()>) -> anyhow::Result<()> {
-    let mut tasks = Vec::new();
+pub(crate) fn init_logger() {
     let stdout_logger = ConsoleAppender::builder()
-    .encoder(Box::new(PatternEncoder::new(
-        "{d} {h({l})} {f}:{L} {m} {n}",
-    )))
+        .encoder(Box::new(PatternEncoder::new(
+            "{d} {h({l})} {f}:{L} {m} {n}",
+        )))
         .build();
-        let logger_config = log4rs::Config::builder()
+    let logger_config = log4rs::Config::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout_logger)))
         .logger(Logger::builder().build("tunnel", log::LevelFilter::Trace))
         .build(
             Root::builder()
-            .appender("stdout")
-            .build(log::LevelFilter::Error),
+                .appender("stdout")
+                .build(log::LevelFilter::Error),
         )
         .unwrap();
-        static ONCE: Once = Once::new();
-        ONCE.call_once(|| {
-            let _handler = log4rs::init_config(logger_config).unwrap();
-        });
-        
-        let inbound_manager = InboundManager::new(config.inbounds.clone());
-        let outbound_manager = Arc::new(OutboundManager::new(config.outbounds.clone())?);
-        let router = Arc::new(Router::new(config.routes.clone()));
-        let dns_client = Arc::new(RwLock::new(DnsClient::new(config.clone())));
-        let context = Arc::new(Context::new(dns_client.clone()));
-        
-        let dispatcher = Arc::new(Dispatcher::new(
-            context.clone(),
-            router.clone(),
-            dns_client.clone(),
-            outbound_manager.clone(),
-            config.clone(),
-        ));
-        
-    let inbound_futures = match inbound_manager.listen(dispatcher.clone()) {
-        Ok(x) => x,
-        Err(err) => {
-            return Err(anyhow!("{}", err));
-        }
-    };
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        let _handler = log4rs::init_config(logger_config).unwrap();
+    });
+}
+
+pub fn start(config: config::Config, shutdown_handler: BoxFuture<'static, ()>) -> anyhow::Result<()> {
+    init_logger();
+    let (mut tasks, _, _) = build(&config, &Events::default())?;
     tasks.push(shutdown_handler);
-    tasks.push(inbound_futures);
     let runtime = newRuntime();
     runtime.block_on(futures::future::select_all(tasks));
     Ok(())
 }
+
+/// measure the outbounds (all of them if tags is empty) and return the comparison table
+pub fn bench(config: &config::Config, tags: Vec<String>, url: &str, rounds: usize, duration: std::time::Duration) -> anyhow::Result<String> {
+    let outbound_manager = Arc::new(OutboundManager::new(config.outbounds.clone(), config.dialer.clone())?);
+    let tags = if tags.is_empty() { outbound_manager.tags() } else { tags };
+    if let Some(tag) = tags.iter().find(|x| outbound_manager.get_handler(x).is_none()) {
+        anyhow::bail!("outbound {} not found", tag);
+    }
+    let dns_client = Arc::new(RwLock::new(DnsClient::new(config.clone())));
+    let context = Arc::new(Context::new(dns_client));
+    let fetcher = Fetcher::new(context, outbound_manager, config.download.clone());
+    let bench = app::bench::Bench::new(fetcher, url, rounds, duration)?;
+    let runtime = newRuntime();
+    let mut results = runtime.block_on(async {
+        let mut results = Vec::new();
+        // 依次测量，避免互相抢占带宽
+        for tag in &tags {
+            results.push(bench.run(tag).await);
+        }
+        results
+    });
+    Ok(app::bench::table(&mut results))
+}
+
+/// check the environment and the resolvers through every outbound
+pub fn doctor(config: &config::Config) -> anyhow::Result<Vec<app::doctor::Finding>> {
+    let outbound_manager = Arc::new(OutboundManager::new(config.outbounds.clone(), config.dialer.clone())?);
+    let tags = outbound_manager.tags();
+    let dns_client = Arc::new(RwLock::new(DnsClient::new(config.clone())));
+    let context = Arc::new(Context::new(dns_client));
+    let fetcher = Fetcher::new(context, outbound_manager, config.download.clone());
+    let doctor = app::doctor::Doctor::new(config.clone(), fetcher);
+    let runtime = newRuntime();
+    Ok(runtime.block_on(doctor.run(&tags)))
+}
+
+// 组装全部组件，返回需要一直运行的 task、每个 profile 的统计以及 inbound，reload 时用来关闭旧的 listener
+// start 与 TunnelBuilder 共用
+#[allow(clippy::type_complexity)]
+pub(crate) fn build(
+    config: &config::Config,
+    events: &Events,
+) -> anyhow::Result<(Vec<BoxFuture<'static, ()>>, HashMap<String, Arc<Stats>>, Vec<Arc<InboundManager>>)> {
+    let mut tasks = Vec::new();
+    let outbound_manager = Arc::new(OutboundManager::new(config.outbounds.clone(), config.dialer.clone())?);
+    let rule_providers = RuleProviders::new(&config.rule_providers);
+    let router = Arc::new(Router::new(config.routes.clone(), &rule_providers));
+    let dns_client = DnsClient::new(config.clone()).with_events(events.clone());
+    let blocklist = dns_client.blocklist();
+    let network_watcher = dns_client.network_watcher();
+    let dns_client = Arc::new(RwLock::new(dns_client));
+    let context = Arc::new(Context::new(dns_client.clone()).with_events(events.clone()));
+    if let Some(resolver) = outbound_manager.server_resolver(dns_client.clone()) {
+        tasks.push(resolver);
+    }
+    if let Some(monitor) = outbound_manager.interface_monitor() {
+        tasks.push(monitor);
+    }
+
+    let dispatcher = Arc::new(Dispatcher::new(
+        context.clone(),
+        router.clone(),
+        dns_client.clone(),
+        outbound_manager.clone(),
+        config.clone(),
+    ));
+
+    let inbound_manager = Arc::new(InboundManager::new(config.inbounds.clone(), dispatcher.clone()));
+    tasks.push(inbound_manager.clone().run());
+    // 每个 profile 独立的 outbound，router 与 dispatcher，共享 dns 与 rule providers
+    let mut stats = HashMap::new();
+    stats.insert(DEFAULT_PROFILE.to_string(), dispatcher.stats());
+    let mut inbounds = HashMap::new();
+    inbounds.insert(DEFAULT_PROFILE.to_string(), inbound_manager);
+    for profile in config.profiles.iter().flatten() {
+        let profile_config = config.for_profile(profile);
+        let outbound_manager = Arc::new(OutboundManager::new(profile_config.outbounds.clone(), profile_config.dialer.clone())?);
+        if let Some(resolver) = outbound_manager.server_resolver(dns_client.clone()) {
+            tasks.push(resolver);
+        }
+        if let Some(monitor) = outbound_manager.interface_monitor() {
+            tasks.push(monitor);
+        }
+        let router = Arc::new(Router::new(profile_config.routes.clone(), &rule_providers));
+        let dispatcher = Arc::new(Dispatcher::new(
+            context.clone(),
+            router,
+            dns_client.clone(),
+            outbound_manager,
+            profile_config.clone(),
+        )
+        .with_limiter(dispatcher.limiter()));
+        stats.insert(profile.name.clone(), dispatcher.stats());
+        let inbound_manager = Arc::new(InboundManager::new(profile_config.inbounds.clone(), dispatcher));
+        tasks.push(inbound_manager.clone().run());
+        inbounds.insert(profile.name.clone(), inbound_manager);
+    }
+    if let Some(watcher) = network_watcher {
+        tasks.push(watcher);
+    }
+    let fetcher = Arc::new(Fetcher::new(context.clone(), outbound_manager.clone(), config.download.clone()));
+    tasks.append(&mut rule_providers.watch(fetcher.clone()));
+    if let Some(blocklist) = &blocklist {
+        tasks.push(blocklist.clone().watch(fetcher));
+    }
+    if let Some(api) = config.api.clone() {
+        let pac = config.system_proxy.as_ref().filter(|x| x.pac).and_then(|x| {
+            let proxy = common::sysproxy::proxy_address(config, x).ok()?;
+            Some(common::sysproxy::pac(proxy, &common::sysproxy::bypass(x)))
+        });
+        tasks.push(ApiServer::listen(api, dispatcher.recorder(), blocklist, stats.clone(), inbounds.clone(), pac));
+    }
+    Ok((tasks, stats, inbounds.into_values().collect()))
+}
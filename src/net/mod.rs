@@ -4,10 +4,14 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::TcpListener;
 
 mod stream;
-mod sys;
+pub(crate) mod sys;
+pub(crate) mod udp;
+#[cfg(all(target_os = "linux", feature = "splice"))]
+pub(crate) mod splice;
 pub struct ProxyTcpListener {
     inner: TcpListener,
 }
@@ -17,6 +21,30 @@ impl ProxyTcpListener {
         let listener = TcpListener::bind(SocketAddr::new(ip_addr, port)).await?;
         Ok(ProxyTcpListener { inner: listener })
     }
+
+    /// listener with SO_RCVBUF / SO_SNDBUF set before listen, accepted sockets inherit them
+    /// the receive buffer has to be set before the handshake for the window scale to follow it
+    pub fn with_buffers(
+        ip_addr: IpAddr,
+        port: u16,
+        recv_buffer: Option<usize>,
+        send_buffer: Option<usize>,
+    ) -> io::Result<ProxyTcpListener> {
+        let addr = SocketAddr::new(ip_addr, port);
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        if let Some(size) = recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(socket.into())?;
+        Ok(ProxyTcpListener { inner: listener })
+    }
 }
 
 impl Deref for ProxyTcpListener {
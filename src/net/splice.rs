@@ -0,0 +1,146 @@
+// linux splice(2) 零拷贝转发
+// socket => pipe => socket，数据不经过用户态，只用于两端都是普通 tcp 且不需要处理数据的连接（direct outbound）
+// 每个方向一个 pipe
+
+use std::{io, os::unix::io::AsRawFd, ptr};
+
+use tokio::{
+    io::Interest,
+    net::TcpStream,
+};
+
+const PIPE_SIZE: usize = 1 << 16;
+
+struct Pipe {
+    read: libc::c_int,
+    write: libc::c_int,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice(from: libc::c_int, to: libc::c_int, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+// from 读到 EOF 后关闭 to 的写端，返回转发的字节数
+async fn copy(from: &TcpStream, to: &TcpStream) -> io::Result<u64> {
+    let pipe = Pipe::new()?;
+    let mut total = 0u64;
+    loop {
+        let n = from
+            .async_io(Interest::READABLE, || splice(from.as_raw_fd(), pipe.write, PIPE_SIZE))
+            .await?;
+        if n == 0 {
+            break;
+        }
+        let mut pending = n;
+        while pending > 0 {
+            let m = to
+                .async_io(Interest::WRITABLE, || splice(pipe.read, to.as_raw_fd(), pending))
+                .await?;
+            pending -= m;
+        }
+        total += n as u64;
+    }
+    unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) };
+    Ok(total)
+}
+
+/// relay until both directions reached EOF, returns (a => b, b => a) bytes
+pub async fn relay(a: &TcpStream, b: &TcpStream) -> io::Result<(u64, u64)> {
+    tokio::try_join!(copy(a, b), copy(b, a))
+}
+
+// 与用户态拷贝对比吞吐，默认不运行:
+// cargo test --release --features splice bench_splice -- --ignored --nocapture
+#[tokio::test]
+#[ignore]
+async fn bench_splice() {
+    use std::time::Instant;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::common::buffer;
+
+    const TOTAL: usize = 1 << 30;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    // client => a, relay a => b, b => server，返回 MiB/s
+    async fn run(zero_copy: bool) -> f64 {
+        let (mut client, mut a) = pair().await;
+        let (mut b, mut server) = pair().await;
+        let task = tokio::spawn(async move {
+            match zero_copy {
+                true => relay(&a, &b).await.unwrap(),
+                false => buffer::copy_bidirectional(&mut a, &mut b).await.unwrap(),
+            }
+        });
+        let started = Instant::now();
+        let writer = tokio::spawn(async move {
+            let buf = vec![0u8; 1 << 16];
+            for _ in 0..TOTAL / buf.len() {
+                client.write_all(&buf).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+            client
+        });
+        let mut buf = vec![0u8; 1 << 16];
+        let mut received = 0;
+        loop {
+            match server.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => received += n,
+            }
+        }
+        let elapsed = started.elapsed();
+        assert_eq!(received, TOTAL);
+        server.shutdown().await.unwrap();
+        let _client = writer.await.unwrap();
+        task.await.unwrap();
+        TOTAL as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+    }
+
+    let copied = run(false).await;
+    let spliced = run(true).await;
+    println!("userspace copy {:.0} MiB/s, splice {:.0} MiB/s", copied, spliced);
+}
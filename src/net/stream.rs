@@ -10,7 +10,7 @@ use tokio::{
 };
 
 
-use crate::proxy::{create_bounded_tcp_socket};
+use crate::proxy::Dialer;
 
 
 #[pin_project]
@@ -22,7 +22,7 @@ pub struct ProxyStream {
 impl ProxyStream {
     // connect will bypass tun routes, always directly connect
     pub async fn connect(addr: SocketAddr) -> io::Result<ProxyStream> {
-        let socket = create_bounded_tcp_socket(addr.clone())?;
+        let socket = Dialer::default().tcp_socket(&addr)?;
         let stream = socket.connect(addr).await?;
         Ok(ProxyStream { inner: stream })
     }
@@ -1,4 +1,4 @@
-use std::os::unix::prelude::AsRawFd;
+use std::{mem, os::unix::prelude::AsRawFd};
 
 use socket2::Socket;
 
@@ -13,3 +13,78 @@ pub fn bind_to_device(socket: &Socket, interface_name: &str) {
         )
     };
 }
+
+// UDP generic segmentation offload
+// 内核支持时，一次 sendmsg 可以携带多个相同大小的 datagram，由内核（或网卡）切分
+pub fn udp_gso_supported<T: AsRawFd>(socket: &T) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut value as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    ret == 0
+}
+
+// SO_MARK，配合 ip rule fwmark 让出站流量绕过 tun 的路由
+pub fn set_mark<T: AsRawFd>(socket: &T, mark: u32) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const _ as *const _,
+            mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// TCP_FASTOPEN_CONNECT (4.11+)，connect 立即返回，第一次 write 的数据随 SYN 发送
+// 没有 cookie 时内核自动退回普通握手
+pub fn set_fast_open_connect<T: AsRawFd>(socket: &T) -> std::io::Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enable as *const _ as *const _,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// 立即 ack，不等待 delayed ack 定时器
+// 内核在之后的某些情况下会重新进入 delayed ack 模式，这里只影响连接开始阶段
+pub fn set_quick_ack<T: AsRawFd>(socket: &T) -> std::io::Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_QUICKACK,
+            &enable as *const _ as *const _,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// linux/in.h, 5.6+
+pub const IPPROTO_MPTCP: libc::c_int = 262;
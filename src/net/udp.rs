@@ -0,0 +1,195 @@
+// udp 批量收发：linux 上用 recvmmsg / sendmmsg 一次系统调用处理多个 datagram，其他平台逐个收发
+// 用于 dispatcher 的 udp flow（tun 与各 inbound 的 udp 都经过这里）与 dns inbound
+// 没有使用 UDP GSO（UDP_SEGMENT）：它要求一批报文大小相同；quic transport 由 quinn 收发，quinn 自己已经做了批量与 GSO
+// 超过 buffer 的 datagram 会被截断，截断的 datagram 直接丢弃，不当作完整的报文转发
+
+use std::{io, net::SocketAddr};
+
+use log::trace;
+use tokio::net::UdpSocket;
+
+/// datagrams per syscall
+pub const BATCH: usize = 32;
+
+/// reusable buffers for receiving up to `count` datagrams of at most `size` bytes at once
+pub struct RecvBatch {
+    // 多一个 byte，没有 MSG_TRUNC 的平台上长度超过 size 即被截断
+    bufs: Vec<Vec<u8>>,
+    size: usize,
+    // (buffer index, len, from) of the received datagrams
+    meta: Vec<(usize, usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    pub fn new(count: usize, size: usize) -> RecvBatch {
+        RecvBatch {
+            bufs: vec![vec![0u8; size + 1]; count.max(1)],
+            size,
+            meta: Vec::new(),
+        }
+    }
+
+    /// wait until at least one datagram arrives, returns the number of complete datagrams received,
+    /// truncated ones are dropped so it may be 0
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        imp::recv(socket, &mut self.bufs, &mut self.meta).await?;
+        let size = self.size;
+        self.meta.retain(|(_, n, from)| {
+            if *n > size {
+                trace!("udp datagram from {} larger than {} bytes, dropped", from, size);
+            }
+            *n <= size
+        });
+        Ok(self.meta.len())
+    }
+
+    /// datagrams received by the last recv
+    pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.meta.iter().map(move |(i, n, from)| (&self.bufs[*i][..*n], *from))
+    }
+}
+
+/// send all datagrams, stops at the first error
+pub async fn send_batch<D: AsRef<[u8]>>(socket: &UdpSocket, datagrams: &[(D, SocketAddr)]) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < datagrams.len() {
+        sent += imp::send(socket, &datagrams[sent..]).await?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{io, mem, net::SocketAddr, os::unix::io::AsRawFd, ptr};
+
+    use socket2::SockAddr;
+    use tokio::{io::Interest, net::UdpSocket};
+
+    pub async fn recv(socket: &UdpSocket, bufs: &mut [Vec<u8>], meta: &mut Vec<(usize, usize, SocketAddr)>) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        socket
+            .async_io(Interest::READABLE, || {
+                let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+                let mut iovecs: Vec<libc::iovec> = bufs
+                    .iter_mut()
+                    .map(|buf| libc::iovec {
+                        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                        iov_len: buf.len(),
+                    })
+                    .collect();
+                let mut msgs: Vec<libc::mmsghdr> = addrs
+                    .iter_mut()
+                    .zip(iovecs.iter_mut())
+                    .map(|(addr, iov)| {
+                        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                        msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                        msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                        msg.msg_hdr.msg_iov = iov;
+                        msg.msg_hdr.msg_iovlen = 1;
+                        msg
+                    })
+                    .collect();
+                // socket 是 non-blocking 的，没有数据时返回 EAGAIN，由 async_io 等待下一次 readable
+                let n = unsafe { libc::recvmmsg(fd, msgs.as_mut_ptr(), msgs.len() as _, 0, ptr::null_mut()) };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                meta.clear();
+                for (i, (msg, addr)) in msgs.iter().zip(addrs.iter()).take(n as usize).enumerate() {
+                    let from = unsafe { SockAddr::new(*addr, msg.msg_hdr.msg_namelen) };
+                    // 截断时 msg_len 是 buffer 的长度，标记为 usize::MAX 由 RecvBatch 丢弃
+                    let len = if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 { usize::MAX } else { msg.msg_len as usize };
+                    if let Some(from) = from.as_socket() {
+                        meta.push((i, len, from));
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn send<D: AsRef<[u8]>>(socket: &UdpSocket, datagrams: &[(D, SocketAddr)]) -> io::Result<usize> {
+        let fd = socket.as_raw_fd();
+        let addrs: Vec<SockAddr> = datagrams.iter().map(|(_, to)| SockAddr::from(*to)).collect();
+        socket
+            .async_io(Interest::WRITABLE, || {
+                let mut iovecs: Vec<libc::iovec> = datagrams
+                    .iter()
+                    .map(|(data, _)| libc::iovec {
+                        iov_base: data.as_ref().as_ptr() as *mut libc::c_void,
+                        iov_len: data.as_ref().len(),
+                    })
+                    .collect();
+                let mut msgs: Vec<libc::mmsghdr> = addrs
+                    .iter()
+                    .zip(iovecs.iter_mut())
+                    .map(|(addr, iov)| {
+                        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                        msg.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+                        msg.msg_hdr.msg_namelen = addr.len();
+                        msg.msg_hdr.msg_iov = iov;
+                        msg.msg_hdr.msg_iovlen = 1;
+                        msg
+                    })
+                    .collect();
+                // 只发出一部分时返回已发送的个数，剩下的由 send_batch 继续发送
+                let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as _, 0) };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            })
+            .await
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::{io, net::SocketAddr};
+
+    use tokio::net::UdpSocket;
+
+    pub async fn recv(socket: &UdpSocket, bufs: &mut [Vec<u8>], meta: &mut Vec<(usize, usize, SocketAddr)>) -> io::Result<()> {
+        let (n, from) = socket.recv_from(&mut bufs[0]).await?;
+        meta.clear();
+        meta.push((0, n, from));
+        Ok(())
+    }
+
+    pub async fn send<D: AsRef<[u8]>>(socket: &UdpSocket, datagrams: &[(D, SocketAddr)]) -> io::Result<usize> {
+        let (data, to) = &datagrams[0];
+        socket.send_to(data.as_ref(), *to).await?;
+        Ok(1)
+    }
+}
+
+#[tokio::test]
+async fn test_udp_batch() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let to = receiver.local_addr().unwrap();
+    let datagrams: Vec<(Vec<u8>, SocketAddr)> = (0..5u8).map(|i| (vec![i; i as usize + 1], to)).collect();
+    send_batch(&sender, &datagrams).await.unwrap();
+
+    let mut batch = RecvBatch::new(BATCH, 64);
+    let mut received = Vec::new();
+    while received.len() < datagrams.len() {
+        batch.recv(&receiver).await.unwrap();
+        for (data, from) in batch.datagrams() {
+            assert_eq!(from, sender.local_addr().unwrap());
+            received.push(data.to_vec());
+        }
+    }
+    let sent: Vec<Vec<u8>> = datagrams.into_iter().map(|(data, _)| data).collect();
+    assert_eq!(received, sent);
+
+    // 超过 buffer 的 datagram 被丢弃，不会截断后转发
+    let datagrams = vec![(vec![1u8; 65], to), (vec![2u8; 64], to)];
+    send_batch(&sender, &datagrams).await.unwrap();
+    let mut received = Vec::new();
+    while received.is_empty() {
+        batch.recv(&receiver).await.unwrap();
+        received.extend(batch.datagrams().map(|(data, _)| data.to_vec()));
+    }
+    assert_eq!(received, vec![vec![2u8; 64]]);
+}
@@ -0,0 +1,86 @@
+// blackhole outbound
+// 悄悄丢弃全部数据，client 只能等到超时，常用于 kill switch
+// 不回复任何数据，也不主动关闭连接，直到 BLACKHOLE_TIMEOUT 后才 EOF，避免连接永远挂着
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    time::{sleep, Sleep},
+};
+
+use crate::Context;
+
+use super::{AnyStream, Error, Session, TcpOutboundHandlerTrait, UdpOutboundHandlerTrait};
+
+const BLACKHOLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct BlackholeStream {
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl BlackholeStream {
+    fn new() -> BlackholeStream {
+        BlackholeStream {
+            deadline: Box::pin(sleep(BLACKHOLE_TIMEOUT)),
+        }
+    }
+}
+
+impl AsyncRead for BlackholeStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // 超时之后返回 EOF
+        self.deadline.as_mut().poll(cx).map(|_| Ok(()))
+    }
+}
+
+impl AsyncWrite for BlackholeStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct TcpOutboundHandler {}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    async fn handle(&self, _ctx: Arc<Context>, _sess: &Session) -> anyhow::Result<AnyStream> {
+        Ok(Box::new(BlackholeStream::new()))
+    }
+}
+
+pub struct UdpOutboundHandler {}
+
+#[async_trait]
+impl UdpOutboundHandlerTrait for UdpOutboundHandler {
+    // UdpOutboundHandlerTrait 需要返回真实的 socket，由 udp relay 根据 Blackholed 丢弃 datagram
+    async fn handle(&self, _ctx: Arc<Context>, sess: &Session) -> anyhow::Result<UdpSocket> {
+        Err(Error::Blackholed(sess.destination.to_string()).into())
+    }
+}
@@ -0,0 +1,121 @@
+// 一条连接上发往多个 destination 的 udp 报文，例如 relay 的 cmd UDP 与 trojan 的 UDP ASSOCIATE
+// 每个 destination 一个 UdpFlow，和 tcp 一样交给 dispatcher 路由，回复以 flow 的 destination 作为来源写回连接
+
+use std::collections::HashMap;
+
+use log::debug;
+use tokio::sync::mpsc;
+
+use super::{Address, Carried, Network, Session, UdpFlow};
+
+// 一条连接同时存在的 flow 上限，超过之后新 destination 的报文被丢弃
+const MAX_FLOWS: usize = 256;
+const FLOW_CAPACITY: usize = 64;
+
+pub struct UdpDemux {
+    sess: Session,
+    // destination => 发送给 dispatcher 的一端，dispatcher 结束 flow 之后 closed
+    flows: HashMap<String, mpsc::Sender<Vec<u8>>>,
+    dispatch: mpsc::UnboundedSender<(Carried, Session)>,
+    replies: mpsc::Sender<(Address, Vec<u8>)>,
+}
+
+impl UdpDemux {
+    /// replies of every flow arrive on the returned channel with the destination they come from
+    pub fn new(
+        sess: Session,
+        dispatch: mpsc::UnboundedSender<(Carried, Session)>,
+    ) -> (UdpDemux, mpsc::Receiver<(Address, Vec<u8>)>) {
+        let (replies, rx) = mpsc::channel(FLOW_CAPACITY);
+        let demux = UdpDemux {
+            sess,
+            flows: HashMap::new(),
+            dispatch,
+            replies,
+        };
+        (demux, rx)
+    }
+
+    /// forward a client datagram to the flow of its destination, false once nothing is dispatched anymore
+    pub async fn send(&mut self, destination: Address, mut payload: Vec<u8>) -> bool {
+        let key = destination.to_string();
+        if let Some(tx) = self.flows.get(&key) {
+            match tx.send(payload).await {
+                Ok(()) => return true,
+                // flow 已经空闲超时结束，重新创建
+                Err(err) => payload = err.0,
+            }
+        }
+        self.flows.retain(|_, tx| !tx.is_closed());
+        if self.flows.len() >= MAX_FLOWS {
+            debug!("sid={} too many udp flows, datagram to {} dropped", self.sess.id, destination);
+            return true;
+        }
+        let (flow, mut inbound) = UdpFlow::pair(FLOW_CAPACITY);
+        let mut sess = self.sess.clone();
+        sess.destination = destination.clone();
+        sess.network = Network::UDP;
+        if self.dispatch.send((Carried::Flow(flow), sess)).is_err() {
+            return false;
+        }
+        let replies = self.replies.clone();
+        tokio::spawn(async move {
+            while let Some(reply) = inbound.rx.recv().await {
+                if replies.send((destination.clone(), reply)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let _ = inbound.tx.send(payload).await;
+        self.flows.insert(key, inbound.tx);
+        true
+    }
+}
+
+#[tokio::test]
+async fn test_udp_demux() {
+    let sess = Session {
+        id: 1,
+        destination: "0.0.0.0:0".parse().unwrap(),
+        local_peer: "127.0.0.1:1080".parse().unwrap(),
+        peer_address: "127.0.0.1:50000".parse().unwrap(),
+        network: Network::TCP,
+        user: None,
+        inbound_tag: None,
+        app_protocol: None,
+        process: None,
+        uid: None,
+    };
+    let (tx, mut dispatched) = mpsc::unbounded_channel();
+    let (mut demux, mut replies) = UdpDemux::new(sess, tx);
+    let dns: Address = "1.1.1.1:53".parse().unwrap();
+    let quic: Address = "8.8.8.8:443".parse().unwrap();
+    assert!(demux.send(dns.clone(), b"query".to_vec()).await);
+    assert!(demux.send(quic, b"initial".to_vec()).await);
+    assert!(demux.send(dns, b"query2".to_vec()).await);
+
+    let mut flows = Vec::new();
+    while let Ok((carried, sess)) = dispatched.try_recv() {
+        match carried {
+            Carried::Flow(flow) => flows.push((flow, sess)),
+            Carried::Stream(_) => panic!("unexpected stream"),
+        }
+    }
+    // 同一个 destination 只有一个 flow
+    assert_eq!(flows.len(), 2);
+    let (flow, sess) = &mut flows[0];
+    assert_eq!(sess.destination.to_string(), "1.1.1.1:53");
+    assert!(matches!(sess.network, Network::UDP));
+    assert_eq!(flow.rx.recv().await.unwrap(), b"query");
+    assert_eq!(flow.rx.recv().await.unwrap(), b"query2");
+    flow.tx.send(b"answer".to_vec()).await.unwrap();
+    let (from, reply) = replies.recv().await.unwrap();
+    assert_eq!(from.to_string(), "1.1.1.1:53");
+    assert_eq!(reply, b"answer");
+
+    // dispatcher 结束 flow 之后同一个 destination 重新 dispatch
+    let (_, sess) = flows.remove(0);
+    assert!(demux.send(sess.destination, b"again".to_vec()).await);
+    let (carried, _) = dispatched.try_recv().unwrap();
+    assert!(matches!(carried, Carried::Flow(_)));
+}
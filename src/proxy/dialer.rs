@@ -0,0 +1,368 @@
+// 出站连接的创建
+// 每个 outbound 一个 Dialer，负责 socket 选项（绑定网卡，绑定地址，fwmark），超时与 happy eyeballs
+// 各协议实现只需要调用 connect_tcp / connect_udp，embedder 也可以用自定义的 DialerSettings 创建 handler
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{debug, trace, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, TcpKeepalive, Type};
+use tokio::{
+    net::{TcpSocket, TcpStream, UdpSocket},
+    sync::RwLock,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    app::{trace, DnsClient, ServerCache},
+    common::monitor::DefaultInterface,
+    config::DialerSettings,
+};
+
+use super::{Address, Error};
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+// RFC 8305 推荐 250ms
+const DEFAULT_HAPPY_EYEBALLS_DELAY: u64 = 250;
+// 第 n 次重试前等待 n * RETRY_BACKOFF
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    pub interface: Option<String>,
+    pub bind: Option<IpAddr>,
+    pub fwmark: Option<u32>,
+    pub connect_timeout: Duration,
+    pub happy_eyeballs_delay: Duration,
+    pub retry: u32,
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    pub fast_open: bool,
+    pub mptcp: bool,
+    // 代理服务器域名的解析缓存，由 OutboundManager 在后台刷新
+    pub servers: Option<Arc<ServerCache>>,
+    // auto_detect_interface，没有配置 interface 时绑定到默认路由所在的网卡
+    pub default_interface: Option<Arc<DefaultInterface>>,
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Dialer {
+            interface: None,
+            bind: None,
+            fwmark: None,
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT),
+            happy_eyeballs_delay: Duration::from_millis(DEFAULT_HAPPY_EYEBALLS_DELAY),
+            retry: 0,
+            nodelay: true,
+            keepalive: None,
+            send_buffer: None,
+            recv_buffer: None,
+            fast_open: false,
+            mptcp: false,
+            servers: None,
+            default_interface: None,
+        }
+    }
+}
+
+// 按照 RFC 8305，从第一个地址的协议族开始，ipv6 与 ipv4 交替尝试
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(|x| x.is_ipv6() == first_v6);
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+    result
+}
+
+impl Dialer {
+    pub fn new(settings: Option<&DialerSettings>) -> Result<Dialer> {
+        let mut dialer = Dialer::default();
+        let settings = match settings {
+            Some(s) => s,
+            None => return Ok(dialer),
+        };
+        dialer.interface = settings.interface.clone();
+        dialer.bind = match &settings.bind {
+            Some(ip) => Some(ip.parse::<IpAddr>().map_err(|err| anyhow!("bad bind address {} {}", ip, err))?),
+            None => None,
+        };
+        dialer.fwmark = settings.fwmark;
+        if let Some(t) = settings.connect_timeout {
+            dialer.connect_timeout = Duration::from_secs(t);
+        }
+        if let Some(d) = settings.happy_eyeballs_delay {
+            dialer.happy_eyeballs_delay = Duration::from_millis(d);
+        }
+        dialer.retry = settings.retry.unwrap_or(0);
+        dialer.nodelay = settings.nodelay.unwrap_or(true);
+        dialer.keepalive = settings.keepalive.map(Duration::from_secs);
+        dialer.send_buffer = settings.send_buffer;
+        dialer.recv_buffer = settings.recv_buffer;
+        dialer.fast_open = settings.fast_open.unwrap_or(false);
+        dialer.mptcp = settings.mptcp.unwrap_or(false);
+        if (dialer.fast_open || dialer.mptcp) && !cfg!(target_os = "linux") {
+            warn!("tcp fast open and mptcp are only supported on linux, ignored");
+        }
+        Ok(dialer)
+    }
+
+    fn new_socket(&self, target: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        let domain = match target {
+            SocketAddr::V4(..) => Domain::IPV4,
+            SocketAddr::V6(..) => Domain::IPV6,
+        };
+        let socket = self.create(domain, ty, protocol)?;
+        let interface = self.interface();
+        #[cfg(target_os = "linux")]
+        {
+            if self.fast_open && ty == Type::STREAM {
+                if let Err(err) = crate::net::sys::linux::set_fast_open_connect(&socket) {
+                    debug!("enable tcp fast open failed {}", err);
+                }
+            }
+            if let Some(mark) = self.fwmark {
+                crate::net::sys::linux::set_mark(&socket, mark)?;
+            }
+            if let Some(name) = &interface {
+                crate::net::sys::linux::bind_to_device(&socket, name);
+            }
+        }
+        #[cfg(target_os = "macos")]
+        {
+            if let Some(name) = &interface {
+                bind_to_interface_index(&socket, target, name)?;
+            }
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if ty == Type::STREAM {
+            socket.set_nodelay(self.nodelay)?;
+            if let Some(idle) = self.keepalive {
+                socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+            }
+        }
+        if let Some(ip) = self.bind {
+            // 协议族不一致时无法绑定，交给系统选择
+            if ip.is_ipv4() == target.is_ipv4() {
+                socket.bind(&SockAddr::from(SocketAddr::new(ip, 0)))?;
+            }
+        }
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    fn interface(&self) -> Option<String> {
+        self.interface
+            .clone()
+            .or_else(|| self.default_interface.as_ref().and_then(|x| x.get()))
+    }
+
+    // mptcp 在内核不支持时退回普通 tcp
+    fn create(&self, domain: Domain, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.mptcp && ty == Type::STREAM {
+                let mptcp = Protocol::from(crate::net::sys::linux::IPPROTO_MPTCP);
+                match Socket::new(domain, ty, Some(mptcp)) {
+                    Ok(socket) => return Ok(socket),
+                    Err(err) => debug!("mptcp socket failed {}, fallback to tcp", err),
+                }
+            }
+        }
+        Socket::new(domain, ty, Some(protocol))
+    }
+
+    pub fn tcp_socket(&self, target: &SocketAddr) -> io::Result<TcpSocket> {
+        let socket = self.new_socket(target, Type::STREAM, Protocol::TCP)?;
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+
+    /// udp socket bound to local, socket options applied for target's family
+    pub fn bind_udp(&self, local: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = self.new_socket(&local, Type::DGRAM, Protocol::UDP)?;
+        if self.bind.is_none() {
+            socket.bind(&SockAddr::from(local))?;
+        }
+        UdpSocket::from_std(socket.into())
+    }
+
+    pub async fn resolve(&self, dns_client: Arc<RwLock<DnsClient>>, addr: &Address) -> Result<Vec<SocketAddr>> {
+        match addr {
+            Address::Domain(name, port) => {
+                if let Some(ips) = self.servers.as_ref().and_then(|x| x.get(name)) {
+                    let ips = dns_client.read().await.family().apply(ips);
+                    if !ips.is_empty() {
+                        return Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect());
+                    }
+                }
+                // 区分解析失败与被拦截，dispatcher 可以据此改用远端解析
+                let ips = match dns_client.read().await.lookup(name).await {
+                    Ok(x) => x,
+                    Err(err) if err.downcast_ref::<Error>().is_some() => return Err(err),
+                    Err(err) => return Err(Error::ResolveFailed(format!("{} {}", name, err)).into()),
+                };
+                if ips.is_empty() {
+                    return Err(Error::ResolveFailed(format!("{} no ip found", name)).into());
+                }
+                Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect())
+            }
+            // ipv6 only 的网络上 ipv4 字面量经过 NAT64
+            Address::Ip(addr) => Ok(vec![dns_client.read().await.family().translate_addr(*addr)]),
+        }
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        trace!("{} connecting to {}", trace::sid(), addr);
+        trace::event(format_args!("connecting to {}", addr));
+        self.tcp_socket(&addr)?.connect(addr).await
+    }
+
+    // 第一个连接 happy_eyeballs_delay 内没有结果时并发尝试下一个，先成功的胜出
+    async fn connect_any(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let mut addrs = interleave(addrs).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        loop {
+            if let Some(addr) = addrs.next() {
+                attempts.push(self.connect_addr(addr));
+            }
+            if attempts.is_empty() {
+                return Err(last_err
+                    .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "no address to connect")));
+            }
+            tokio::select! {
+                res = attempts.next() => match res {
+                    Some(Ok(stream)) => {
+                        trace::event(format_args!("connected to {:?}", stream.peer_addr()));
+                        return Ok(stream);
+                    }
+                    Some(Err(err)) => {
+                        debug!("{} connect attempt failed {}", trace::sid(), err);
+                        trace::event(format_args!("connect attempt failed {}", err));
+                        last_err = Some(err);
+                    }
+                    None => {}
+                },
+                _ = sleep(self.happy_eyeballs_delay), if !addrs.as_slice().is_empty() => {}
+            }
+        }
+    }
+
+    /// every attempt is bounded by connect_timeout, failed attempts are retried `retry` times
+    pub async fn connect_tcp(&self, dns_client: Arc<RwLock<DnsClient>>, addr: Address) -> Result<TcpStream> {
+        let addrs = self.resolve(dns_client, &addr).await?;
+        trace!("{} resolved remote addr {} => {:?}", trace::sid(), addr, addrs);
+        let mut attempt = 0;
+        loop {
+            let err = match timeout(self.connect_timeout, self.connect_any(addrs.clone())).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => {
+                    debug!("{} error when connect to {}, error {}", trace::sid(), addr, err);
+                    anyhow!("connect to {} failed {}", addr, err)
+                }
+                Err(_) => anyhow!("connect to {} timeout after {:?}", addr, self.connect_timeout),
+            };
+            trace::event(&err);
+            if attempt >= self.retry {
+                return Err(err);
+            }
+            attempt += 1;
+            debug!("{} {}, retry {}/{}", trace::sid(), err, attempt, self.retry);
+            sleep(RETRY_BACKOFF * attempt).await;
+        }
+    }
+
+    pub async fn connect_udp(&self, dns_client: Arc<RwLock<DnsClient>>, local: SocketAddr, peer: Address) -> Result<UdpSocket> {
+        let addrs = self.resolve(dns_client, &peer).await?;
+        // 优先使用与 local 相同协议族的地址
+        let target = addrs
+            .iter()
+            .find(|x| x.is_ipv4() == local.is_ipv4())
+            .unwrap_or(&addrs[0]);
+        // local 是 inbound 的地址，tun 时甚至是目标地址，只用来选择协议族
+        let socket = self.bind_udp(Dialer::unspecified(target))?;
+        socket.connect(target).await?;
+        Ok(socket)
+    }
+
+    /// unspecified local address of the same family as target
+    pub fn unspecified(target: &SocketAddr) -> SocketAddr {
+        match target {
+            SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        }
+    }
+}
+
+// macos 没有 SO_BINDTODEVICE，使用 IP_BOUND_IF / IPV6_BOUND_IF
+// netinet/in.h, netinet6/in6.h
+#[cfg(target_os = "macos")]
+const IP_BOUND_IF: libc::c_int = 25;
+#[cfg(target_os = "macos")]
+const IPV6_BOUND_IF: libc::c_int = 125;
+
+#[cfg(target_os = "macos")]
+fn bind_to_interface_index(socket: &Socket, target: &SocketAddr, name: &str) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::io::AsRawFd};
+    let name = CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (level, opt) = match target {
+        SocketAddr::V4(..) => (libc::IPPROTO_IP, IP_BOUND_IF),
+        SocketAddr::V6(..) => (libc::IPPROTO_IPV6, IPV6_BOUND_IF),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            opt,
+            &index as *const _ as *const _,
+            std::mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_interleave() {
+    let addrs: Vec<SocketAddr> = vec![
+        "[::1]:80".parse().unwrap(),
+        "[::2]:80".parse().unwrap(),
+        "[::3]:80".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+    ];
+    let result: Vec<String> = interleave(addrs).iter().map(|x| x.to_string()).collect();
+    assert_eq!(result, vec!["[::1]:80", "1.1.1.1:80", "[::2]:80", "[::3]:80"]);
+}
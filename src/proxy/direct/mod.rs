@@ -1,27 +1,36 @@
 use std::{sync::Arc};
 
 use async_trait::async_trait;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::UdpSocket;
 
 use crate::Context;
 
-use super::{TcpOutboundHandlerTrait, Session, UdpOutboundHandlerTrait, connect_to_remote_tcp, connect_to_remote_udp};
+use super::{TcpOutboundHandlerTrait, Session, AnyStream, UdpOutboundHandlerTrait, Dialer};
 
-pub struct TcpOutboundHandler{}
+pub struct TcpOutboundHandler {
+    pub dialer: Arc<Dialer>,
+}
 
 #[async_trait]
 impl TcpOutboundHandlerTrait for TcpOutboundHandler {
-    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<TcpStream> {
-        connect_to_remote_tcp(ctx.dns_client.clone(), sess.destination.clone()).await
+    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<AnyStream> {
+        let stream = self.dialer.connect_tcp(ctx.dns_client.clone(), sess.destination.clone()).await?;
+        Ok(Box::new(stream))
+    }
+
+    fn dialer(&self) -> Option<Arc<Dialer>> {
+        Some(self.dialer.clone())
     }
 }
 
-pub struct UdpOutboundHandler{}
+pub struct UdpOutboundHandler {
+    pub dialer: Arc<Dialer>,
+}
 
 #[async_trait]
 impl UdpOutboundHandlerTrait for UdpOutboundHandler {
     async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<UdpSocket> {
-        connect_to_remote_udp(ctx.dns_client.clone(), sess.local_peer, sess.destination.clone()
+        self.dialer.connect_udp(ctx.dns_client.clone(), sess.local_peer, sess.destination.clone()
     ).await
     }
 }
\ No newline at end of file
@@ -0,0 +1,45 @@
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::common::buffer;
+
+use super::{InboundResult, Session, TcpInboundHandlerTrait};
+
+// 诊断用的 inbound，不经过 dispatcher
+// 连接建立后先返回 server 当前时间（unix 毫秒），之后原样 echo 收到的数据
+// 脚本或者编排系统可以用来检查进程是否存活，以及测量本地处理延迟
+pub struct TcpInboundHandler;
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, sess: Session, mut stream: TcpStream) -> io::Result<InboundResult> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        stream.write_all(format!("{}\n", now).as_bytes()).await?;
+        let mut buf = buffer::get(buffer::SMALL);
+        loop {
+            let n = match stream.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) => {
+                    debug!("echo read from {} failed {}", sess.peer_address, err);
+                    break;
+                }
+            };
+            stream.write_all(&buf[..n]).await?;
+        }
+        trace!("echo session {} finished", sess.peer_address);
+        Ok(InboundResult::Handled)
+    }
+}
@@ -0,0 +1,127 @@
+// brutal 拥塞控制，与 hysteria 相同
+// 不根据丢包降低速率，而是按配置的带宽发送：窗口 = 带宽 * rtt / 确认率
+// 丢包越多发得越多，补偿丢失的部分，高丢包的线路上比 cubic 快得多
+// 带宽必须如实配置，过高会挤占整条线路
+
+use std::{
+    any::Any,
+    time::{Duration, Instant},
+};
+
+use quinn::congestion::{Controller, ControllerFactory};
+use quinn_proto::RttEstimator;
+
+const MAX_DATAGRAM_SIZE: u64 = 1200;
+const MIN_WINDOW: u64 = 16 * MAX_DATAGRAM_SIZE;
+// 统计最近几秒的确认率
+const SLOTS: usize = 5;
+// 样本太少时不调整
+const MIN_SAMPLES: u64 = 50 * MAX_DATAGRAM_SIZE;
+// 确认率的下限，防止严重丢包时窗口无限放大
+const MIN_ACK_RATE: f64 = 0.8;
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    second: u64,
+    acked: u64,
+    lost: u64,
+}
+
+#[derive(Clone)]
+pub struct Brutal {
+    // bytes per second
+    bps: u64,
+    start: Instant,
+    rtt: Duration,
+    slots: [Slot; SLOTS],
+}
+
+impl Brutal {
+    pub fn new(bps: u64, now: Instant) -> Brutal {
+        Brutal {
+            bps,
+            start: now,
+            rtt: Duration::from_millis(100),
+            slots: [Slot::default(); SLOTS],
+        }
+    }
+
+    fn slot(&mut self, now: Instant) -> &mut Slot {
+        let second = now.saturating_duration_since(self.start).as_secs();
+        let slot = &mut self.slots[second as usize % SLOTS];
+        if slot.second != second {
+            *slot = Slot { second, acked: 0, lost: 0 };
+        }
+        slot
+    }
+
+    fn ack_rate(&self) -> f64 {
+        let newest = self.slots.iter().map(|x| x.second).max().unwrap_or(0);
+        let (acked, lost) = self
+            .slots
+            .iter()
+            .filter(|x| x.second + SLOTS as u64 > newest)
+            .fold((0, 0), |(a, l), x| (a + x.acked, l + x.lost));
+        if acked + lost < MIN_SAMPLES {
+            return 1.0;
+        }
+        (acked as f64 / (acked + lost) as f64).max(MIN_ACK_RATE)
+    }
+}
+
+impl Controller for Brutal {
+    fn on_ack(&mut self, now: Instant, _sent: Instant, bytes: u64, _app_limited: bool, rtt: &RttEstimator) {
+        self.rtt = rtt.get();
+        self.slot(now).acked += bytes;
+    }
+
+    fn on_congestion_event(&mut self, now: Instant, _sent: Instant, _is_persistent_congestion: bool, lost_bytes: u64) {
+        self.slot(now).lost += lost_bytes;
+    }
+
+    fn window(&self) -> u64 {
+        let window = self.bps as f64 * self.rtt.as_secs_f64() / self.ack_rate();
+        (window as u64).max(MIN_WINDOW)
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+
+    fn initial_window(&self) -> u64 {
+        MIN_WINDOW
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+pub struct BrutalConfig {
+    pub bps: u64,
+}
+
+impl ControllerFactory for BrutalConfig {
+    fn build(&self, now: Instant) -> Box<dyn Controller> {
+        Box::new(Brutal::new(self.bps, now))
+    }
+}
+
+#[test]
+fn test_brutal_window() {
+    let now = Instant::now();
+    // 100 Mbps, rtt 100ms
+    let mut brutal = Brutal::new(100_000_000 / 8, now);
+    assert_eq!(brutal.window(), 1_250_000);
+    let later = now + Duration::from_millis(1500);
+    brutal.slot(later).acked += 90 * MAX_DATAGRAM_SIZE;
+    brutal.slot(later).lost += 10 * MAX_DATAGRAM_SIZE;
+    assert!((brutal.ack_rate() - 0.9).abs() < 1e-9);
+    // 丢包过多时确认率有下限
+    brutal.slot(later).lost += 1000 * MAX_DATAGRAM_SIZE;
+    assert_eq!(brutal.ack_rate(), MIN_ACK_RATE);
+    // 超出统计窗口的样本被丢弃
+    let much_later = now + Duration::from_secs(10);
+    brutal.slot(much_later).acked += MAX_DATAGRAM_SIZE;
+    assert_eq!(brutal.ack_rate(), 1.0);
+}
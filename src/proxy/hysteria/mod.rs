@@ -0,0 +1,169 @@
+// hysteria2 协议，基于 quic，适合高丢包的线路
+// https://v2.hysteria.network/docs/developers/Protocol/
+//
+// 认证: 连接建立后发送 http/3 请求 POST https://hysteria/auth
+// hysteria-auth: password, hysteria-cc-rx: 客户端接收速率（bytes/s，0 表示未知），hysteria-padding: 随机字符串
+// 服务器返回 233 表示成功，其他状态码（通常伪装为普通网站）表示失败
+//
+// tcp: 每个连接一个 bidi stream，开头
+// |<-0x401 varint->|<-addr len varint->|<-"host:port"->|<-padding len varint->|<-padding->|
+// 服务器回复
+// |<-status 1 byte->|<-msg len varint->|<-msg->|<-padding len varint->|<-padding->|
+// status 0 之后是原始数据
+//
+// udp: quic datagram，还没有实现，hysteria2 outbound 不转发 udp
+// |<-session id 4 bytes->|<-packet id 2 bytes->|<-frag id 1 byte->|<-frag count 1 byte->|<-addr len varint->|<-addr->|<-payload->|
+
+use anyhow::{anyhow, bail, Result};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::transport::h3::{decode_varint, encode_varint};
+
+use super::Address;
+
+mod brutal;
+mod outbound;
+
+pub use self::brutal::{Brutal, BrutalConfig};
+pub use self::outbound::TcpOutboundHandler;
+
+const TCP_REQUEST: u64 = 0x401;
+const STATUS_OK: u8 = 0;
+// 地址与消息的长度上限，防止恶意的长度
+const MAX_ADDRESS_LEN: u64 = 2048;
+const MAX_MESSAGE_LEN: u64 = 2048;
+const MAX_PADDING_LEN: u64 = 4096;
+
+fn padding(min: usize, max: usize) -> String {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(min..max);
+    rng.sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+pub fn auth_padding() -> String {
+    padding(256, 2048)
+}
+
+pub fn tcp_request(destination: &Address) -> Vec<u8> {
+    let address = destination.to_string();
+    let padding = padding(64, 512);
+    let mut buf = Vec::with_capacity(address.len() + padding.len() + 8);
+    encode_varint(&mut buf, TCP_REQUEST);
+    encode_varint(&mut buf, address.len() as u64);
+    buf.extend_from_slice(address.as_bytes());
+    encode_varint(&mut buf, padding.len() as u64);
+    buf.extend_from_slice(padding.as_bytes());
+    buf
+}
+
+async fn read_varint<T: AsyncRead + Unpin>(stream: &mut T) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    buf[0] = stream.read_u8().await?;
+    let len = 1 << (buf[0] >> 6);
+    stream.read_exact(&mut buf[1..len]).await?;
+    decode_varint(&buf[..len])
+        .map(|(value, _)| value)
+        .ok_or_else(|| anyhow!("invalid varint"))
+}
+
+async fn read_bytes<T: AsyncRead + Unpin>(stream: &mut T, max: u64) -> Result<Vec<u8>> {
+    let len = read_varint(stream).await?;
+    if len > max {
+        bail!("hysteria2 field too long {}", len);
+    }
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// read the server's reply to a tcp request, the stream carries raw data afterwards
+pub async fn read_tcp_response<T: AsyncRead + Unpin>(stream: &mut T) -> Result<()> {
+    let status = stream.read_u8().await?;
+    let message = read_bytes(stream, MAX_MESSAGE_LEN).await?;
+    read_bytes(stream, MAX_PADDING_LEN).await?;
+    if status != STATUS_OK {
+        bail!("hysteria2 server refused, {}", String::from_utf8_lossy(&message));
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UdpMessage {
+    pub session_id: u32,
+    pub packet_id: u16,
+    pub frag_id: u8,
+    pub frag_count: u8,
+    // "host:port"
+    pub address: String,
+    pub payload: Vec<u8>,
+}
+
+impl UdpMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.address.len() + self.payload.len() + 16);
+        buf.extend_from_slice(&self.session_id.to_be_bytes());
+        buf.extend_from_slice(&self.packet_id.to_be_bytes());
+        buf.push(self.frag_id);
+        buf.push(self.frag_count);
+        encode_varint(&mut buf, self.address.len() as u64);
+        buf.extend_from_slice(self.address.as_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<UdpMessage> {
+        if data.len() < 9 {
+            bail!("hysteria2 udp message too short");
+        }
+        let (len, n) = decode_varint(&data[8..]).ok_or_else(|| anyhow!("invalid varint"))?;
+        let start = 8 + n;
+        if len > MAX_ADDRESS_LEN || data.len() < start + len as usize {
+            bail!("hysteria2 udp message address truncated");
+        }
+        let end = start + len as usize;
+        Ok(UdpMessage {
+            session_id: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            packet_id: u16::from_be_bytes([data[4], data[5]]),
+            frag_id: data[6],
+            frag_count: data[7],
+            address: String::from_utf8(data[start..end].to_vec())?,
+            payload: data[end..].to_vec(),
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_hysteria2_codec() {
+    let destination: Address = "example.com:443".parse().unwrap();
+    let request = tcp_request(&destination);
+    // 0x401 是两字节 varint
+    assert_eq!(&request[..3], &[0x44, 0x01, 15]);
+    assert_eq!(&request[3..18], b"example.com:443");
+
+    let mut response = vec![STATUS_OK];
+    encode_varint(&mut response, 0);
+    encode_varint(&mut response, 3);
+    response.extend_from_slice(b"pad");
+    response.extend_from_slice(b"data");
+    let mut stream = &response[..];
+    read_tcp_response(&mut stream).await.unwrap();
+    assert_eq!(stream, b"data");
+
+    let mut refused = vec![1];
+    encode_varint(&mut refused, 6);
+    refused.extend_from_slice(b"denied");
+    encode_varint(&mut refused, 0);
+    assert!(read_tcp_response(&mut &refused[..]).await.is_err());
+
+    let message = UdpMessage {
+        session_id: 7,
+        packet_id: 1,
+        frag_id: 0,
+        frag_count: 1,
+        address: "1.1.1.1:53".to_string(),
+        payload: b"query".to_vec(),
+    };
+    assert_eq!(UdpMessage::decode(&message.encode()).unwrap(), message);
+    assert!(UdpMessage::decode(&message.encode()[..12]).is_err());
+}
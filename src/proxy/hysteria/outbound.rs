@@ -0,0 +1,195 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use log::{debug, trace, warn};
+use quinn::{
+    congestion::{CubicConfig, NewRenoConfig},
+    SendStream, TransportConfig,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use crate::{
+    config::{Hysteria2OutboundSettings, QuicSettings},
+    proxy::{Address, AnyStream, Dialer, Session, TcpOutboundHandlerTrait},
+    transport::{
+        h3::{
+            decode_frames, encode_frame, encode_varint, response_status, HeaderBlock,
+            FRAME_HEADERS, FRAME_SETTINGS, QPACK_AUTHORITY, QPACK_METHOD_POST, QPACK_PATH,
+            QPACK_SCHEME_HTTPS, STREAM_CONTROL,
+        },
+        quic::QuicConnector,
+    },
+    Context,
+};
+
+use super::{auth_padding, read_tcp_response, tcp_request, BrutalConfig};
+
+const STATUS_AUTH_OK: u16 = 233;
+const MAX_AUTH_RESPONSE: u64 = 8192;
+
+fn mbps_to_bps(mbps: u64) -> u64 {
+    mbps * 1_000_000 / 8
+}
+
+fn transport_config(settings: &Hysteria2OutboundSettings) -> Result<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    let up = settings.up_mbps.map(mbps_to_bps);
+    match (settings.congestion.as_deref(), up) {
+        (Some("brutal") | None, Some(bps)) => {
+            transport.congestion_controller_factory(BrutalConfig { bps });
+        }
+        (Some("brutal"), None) => bail!("brutal congestion control requires up-mbps"),
+        (None, None) | (Some("cubic"), _) => {
+            transport.congestion_controller_factory(CubicConfig::default());
+        }
+        (Some("new-reno"), _) => {
+            transport.congestion_controller_factory(NewRenoConfig::default());
+        }
+        // quinn 0.8 没有 bbr
+        (Some("bbr"), _) => {
+            warn!("bbr congestion control is not available, cubic is used");
+            transport.congestion_controller_factory(CubicConfig::default());
+        }
+        (Some(x), _) => bail!("unknown congestion control {}", x),
+    }
+    Ok(transport)
+}
+
+pub struct TcpOutboundHandler {
+    server: Address,
+    password: String,
+    // 告诉服务器的接收速率，0 表示未知
+    down_bps: u64,
+    quic: QuicSettings,
+    // TransportConfig 不能 clone，每次创建 connector 时重新构造
+    settings: Hysteria2OutboundSettings,
+    dialer: Arc<Dialer>,
+    // 需要解析服务器地址，第一次连接时创建
+    // quic 使用自己的 udp socket，dialer 只用于解析
+    connector: Mutex<Option<Arc<QuicConnector>>>,
+    // 已认证的连接与它的 http/3 control stream，连接重建后需要重新认证
+    auth: Mutex<Option<(usize, SendStream)>>,
+}
+
+impl TcpOutboundHandler {
+    pub fn new(settings: &Hysteria2OutboundSettings, dialer: Arc<Dialer>) -> Result<TcpOutboundHandler> {
+        let server = Address::try_from((settings.address.clone(), settings.port))?;
+        let mut quic = QuicSettings {
+            tls: settings.tls.clone(),
+            zero_rtt: false,
+        };
+        if quic.tls.alpn.is_none() {
+            quic.tls.alpn = Some(vec!["h3".to_string()]);
+        }
+        // 提前检查拥塞控制配置
+        transport_config(settings)?;
+        Ok(TcpOutboundHandler {
+            server,
+            password: settings.password.clone(),
+            down_bps: settings.down_mbps.map(mbps_to_bps).unwrap_or(0),
+            quic,
+            settings: settings.clone(),
+            dialer,
+            connector: Mutex::new(None),
+            auth: Mutex::new(None),
+        })
+    }
+
+    pub fn server(&self) -> &Address {
+        &self.server
+    }
+
+    async fn connector(&self, ctx: Arc<Context>) -> Result<Arc<QuicConnector>> {
+        let mut connector = self.connector.lock().await;
+        if let Some(x) = &*connector {
+            return Ok(x.clone());
+        }
+        let addrs = self.dialer.resolve(ctx.dns_client.clone(), &self.server).await?;
+        let mut quic = QuicConnector::new(addrs[0], &self.server.host(), &self.quic)?;
+        quic.set_transport(transport_config(&self.settings)?);
+        let quic = Arc::new(quic);
+        connector.replace(quic.clone());
+        Ok(quic)
+    }
+
+    async fn authenticate(&self, connector: &QuicConnector) -> Result<()> {
+        let id = connector.connection_id().await?;
+        let mut auth = self.auth.lock().await;
+        if matches!(&*auth, Some((current, _)) if *current == id) {
+            return Ok(());
+        }
+        let (id, mut control) = connector.open_uni().await?;
+        let mut buf = Vec::new();
+        encode_varint(&mut buf, STREAM_CONTROL);
+        encode_varint(&mut buf, FRAME_SETTINGS);
+        encode_varint(&mut buf, 0);
+        control.write_all(&buf).await?;
+
+        let headers = HeaderBlock::new()
+            .indexed(QPACK_METHOD_POST)
+            .indexed(QPACK_SCHEME_HTTPS)
+            .literal(QPACK_AUTHORITY, "hysteria")
+            .literal(QPACK_PATH, "/auth")
+            .field("hysteria-auth", &self.password)
+            .field("hysteria-cc-rx", &self.down_bps.to_string())
+            .field("hysteria-padding", &auth_padding())
+            .finish();
+        let mut request = Vec::new();
+        encode_frame(&mut request, FRAME_HEADERS, &headers);
+        let mut stream = connector.open_stream().await?;
+        stream.write_all(&request).await?;
+        stream.shutdown().await?;
+        let mut response = Vec::new();
+        (&mut stream).take(MAX_AUTH_RESPONSE).read_to_end(&mut response).await?;
+        let frames = decode_frames(&response).ok_or_else(|| anyhow!("truncated http/3 frame"))?;
+        let headers = frames
+            .iter()
+            .find(|(ty, _)| *ty == FRAME_HEADERS)
+            .ok_or_else(|| anyhow!("hysteria2 auth to {} got no response", self.server))?;
+        match response_status(headers.1) {
+            Some(STATUS_AUTH_OK) => {}
+            // huffman 编码的状态码无法识别，由之后的请求判断
+            None => debug!("hysteria2 auth status of {} unknown", self.server),
+            Some(status) => bail!("hysteria2 auth to {} failed, status {}", self.server, status),
+        }
+        trace!("hysteria2 connection authenticated to {}", self.server);
+        // control stream 在连接存活期间不能关闭
+        auth.replace((id, control));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> Result<AnyStream> {
+        let connector = self.connector(ctx).await?;
+        self.authenticate(&connector).await?;
+        let mut stream = connector.open_stream().await?;
+        stream.write_all(&tcp_request(&sess.destination)).await?;
+        read_tcp_response(&mut stream).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[test]
+fn test_transport_config() {
+    let mut settings = Hysteria2OutboundSettings {
+        address: "example.com".to_string(),
+        port: 443,
+        password: "secret".to_string(),
+        up_mbps: None,
+        down_mbps: Some(100),
+        congestion: Some("brutal".to_string()),
+        tls: Default::default(),
+    };
+    assert!(transport_config(&settings).is_err());
+    settings.up_mbps = Some(20);
+    assert!(transport_config(&settings).is_ok());
+    settings.congestion = Some("vegas".to_string());
+    assert!(transport_config(&settings).is_err());
+    assert_eq!(mbps_to_bps(100), 12_500_000);
+}
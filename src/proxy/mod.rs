@@ -1,28 +1,42 @@
 use core::fmt;
 use std::{
     io,
-    net::{IpAddr, SocketAddr}, sync::Arc, convert::TryFrom, fmt::Display, ops::Add,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6}, sync::{atomic::{AtomicU64, Ordering}, Arc}, convert::TryFrom, fmt::Display, ops::Add,
+    str::FromStr,
 };
 
 use anyhow::{
     anyhow
 };
 use async_trait::async_trait;
-use log::{trace, debug};
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use log::{debug, trace};
 use thiserror::Error;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::{TcpSocket, UdpSocket, TcpStream}, sync::RwLock,
+    net::{UdpSocket, TcpStream}, sync::mpsc,
 };
 
-use crate::{app::DnsClient, Context};
+use crate::{common::ratelimit::Bandwidth, Context};
 
-#[cfg(target_os = "unix")]
-mod tun;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod tun;
 pub mod socks;
 pub mod direct;
-mod shadowsocks;
+pub mod echo;
+pub mod reject;
+pub mod blackhole;
+pub mod relay;
+pub mod hysteria;
+pub mod trojan;
+pub mod dialer;
+pub use dialer::Dialer;
+pub mod pool;
+pub use pool::ConnectionPool;
+pub mod uot;
+pub use uot::{UdpOverTcp, UotReader, UotStream, UotWriter, UOT_MAGIC_ADDRESS};
+pub mod demux;
+pub use demux::UdpDemux;
+pub mod shadowsocks;
 pub enum NetworkType {
     TCP,
     UDP,
@@ -85,10 +99,88 @@ impl TryFrom<(String, u16)> for Address {
         Ok(address)
     }
 }
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AddressError {
+    #[error("missing port in {0}")]
+    MissingPort(String),
+    #[error("invalid port in {0}")]
+    InvalidPort(String),
+    #[error("invalid host in {0}")]
+    InvalidHost(String),
+    #[error("unknown ipv6 scope in {0}")]
+    UnknownScope(String),
+}
+
+// ipv6 zone，数字或者网卡名，例如 fe80::1%2 fe80::1%eth0
+fn parse_scope_id(scope: &str) -> Option<u32> {
+    if let Ok(id) = scope.parse::<u32>() {
+        return Some(id);
+    }
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    {
+        let name = std::ffi::CString::new(scope).ok()?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => None,
+            id => Some(id),
+        }
+    }
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    None
+}
+
+// host:port
+// [ipv6]:port，[fe80::1%eth0]:port
+// 国际化域名转换为 punycode
+impl FromStr for Address {
+    type Err = AddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            match rest.split_once("]:") {
+                Some(x) => x,
+                None if rest.ends_with(']') => return Err(AddressError::MissingPort(s.to_string())),
+                None => return Err(AddressError::InvalidHost(s.to_string())),
+            }
+        } else {
+            match s.rsplit_once(':') {
+                // 没有括号的 ipv6 无法区分端口
+                Some((host, _)) if host.contains(':') => return Err(AddressError::InvalidHost(s.to_string())),
+                Some(x) => x,
+                None => return Err(AddressError::MissingPort(s.to_string())),
+            }
+        };
+        if port.is_empty() {
+            return Err(AddressError::MissingPort(s.to_string()));
+        }
+        let port = port.parse::<u16>().map_err(|_| AddressError::InvalidPort(s.to_string()))?;
+        if host.contains(':') {
+            let (ip, scope_id) = match host.split_once('%') {
+                Some((ip, scope)) => match parse_scope_id(scope) {
+                    Some(id) => (ip, id),
+                    None => return Err(AddressError::UnknownScope(s.to_string())),
+                },
+                None => (host, 0),
+            };
+            let ip = ip.parse::<Ipv6Addr>().map_err(|_| AddressError::InvalidHost(s.to_string()))?;
+            return Ok(Address::Ip(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))));
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Address::Ip(SocketAddr::new(ip, port)));
+        }
+        if host.is_empty() || host.len() > 253 {
+            return Err(AddressError::InvalidHost(s.to_string()));
+        }
+        let name = trust_dns_proto::rr::Name::from_utf8(host)
+            .map_err(|_| AddressError::InvalidHost(s.to_string()))?;
+        Ok(Address::Domain(name.to_ascii().trim_end_matches('.').to_string(), port))
+    }
+}
+
 pub fn addr_to_tuple(str: &str) -> (String, u16){
-    let addrs: Vec<&str> = str.split(":").collect();
-    let buf = &*addrs;
-    (buf[0].to_owned(), u16::from_str_radix(buf[1], 10).unwrap())
+    match str.parse::<Address>().expect("invalid address") {
+        Address::Domain(name, port) => (name, port),
+        Address::Ip(addr) => (addr.ip().to_string(), addr.port()),
+    }
 }
 impl Into<String> for Address {
     fn into(self) -> String {
@@ -109,17 +201,36 @@ pub enum Network {
 // connection session
 #[derive(Debug, Clone)]
 pub struct Session {
+    // inbound accept 时分配，见 app::trace
+    pub id: u64,
     // 真正要连接的 remote
     pub destination: Address,
     // 连接到本地代理服务器的remote
     // local_peer <=> tunnel
+    // inbound 监听多个端口时是实际接受连接的那个
     pub local_peer: SocketAddr,
     // 连接到本地的对端socket
     pub peer_address: SocketAddr,
     
-    pub network: Network
+    pub network: Network,
+    // inbound 认证通过的用户
+    pub user: Option<String>,
+    // 接收连接的 inbound
+    pub inbound_tag: Option<String>,
+    // 嗅探到的应用层协议，见 app::stats::detect
+    pub app_protocol: Option<String>,
+    // 发起连接的本机进程，只在路由需要时查询
+    pub process: Option<String>,
+    // 本机发起的连接的 socket owner uid，只在路由需要时查询
+    pub uid: Option<u32>,
 }
 impl Session {
+    /// unique in the process, 0 is never returned
+    pub fn next_id() -> u64 {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    }
+
     pub fn port (&self) -> u16{
         match self.destination {
             Address::Domain(_, p) => p,
@@ -128,53 +239,85 @@ impl Session {
     }
 }
 
-pub fn create_bounded_udp_socket(addr: IpAddr) -> io::Result<UdpSocket> {
-    let socket = match addr {
-        IpAddr::V4(..) => Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?,
-        IpAddr::V6(..) => Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?,
-    };
-    // let s: SockAddr = ;
-    match socket.bind(&SockAddr::from(SocketAddr::new(addr, 0))) {
-        Ok(..) => {},
-        Err(err) => {
-            log::error!("failed to bind socket {}", err.to_string())
-        }
-    }
-    match socket.set_nonblocking(true) {
-        Ok(..) => {},
-        Err(err) => {
-            log::error!("failed to set non blocking {}", err)
-        }
-    }
-    Ok(UdpSocket::from_std(socket.into())?)
-}
-
-pub fn create_bounded_tcp_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
-    let socket = match addr {
-        SocketAddr::V4(..) => Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?,
-        SocketAddr::V6(..) => Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?,
-    };
-    socket.bind(&addr.into());
-    socket.set_nonblocking(true);
-    Ok(TcpSocket::from_std_stream(socket.into()))
-}
-
-
-
 // ----------------------------
 // INBOUND
 pub enum InboundResult {
     Stream(TcpStream, Session),
     Datagram(UdpSocket, Session),
+    // stream unwrapped by the inbound (e.g. tls), dispatched as the connection itself
+    Tunneled(AnyStream, Session),
+    // inbound handled the connection by itself, nothing to dispatch
+    Handled,
+    // multiplexed inbound, every stream or udp flow carried by the connection is dispatched
+    Streams(mpsc::UnboundedReceiver<(Carried, Session)>),
     NOT_SUPPORTED
 }
 
+/// a connection carried inside a multiplexed inbound connection
+pub enum Carried {
+    Stream(AnyStream),
+    Flow(UdpFlow),
+}
+
+/// datagrams of one udp flow between an inbound and the dispatcher
+/// the destination is fixed by the session, the dispatcher sends replies on tx
+pub struct UdpFlow {
+    pub rx: mpsc::Receiver<Vec<u8>>,
+    pub tx: mpsc::Sender<Vec<u8>>,
+    // 可以回复 icmp too big 的 inbound（tun）设置，dispatcher 发送 outbound 的 max_payload
+    pub too_big: Option<mpsc::UnboundedSender<usize>>,
+}
+
+impl UdpFlow {
+    /// the end given to the dispatcher and the end kept by the inbound
+    pub fn pair(capacity: usize) -> (UdpFlow, UdpFlow) {
+        let (up_tx, up_rx) = mpsc::channel(capacity);
+        let (down_tx, down_rx) = mpsc::channel(capacity);
+        (
+            UdpFlow { rx: up_rx, tx: down_tx, too_big: None },
+            UdpFlow { rx: down_rx, tx: up_tx, too_big: None },
+        )
+    }
+
+    /// flow of a socket connected to the client
+    pub fn connected(socket: UdpSocket) -> UdpFlow {
+        let (flow, mut inbound) = UdpFlow::pair(64);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; u16::MAX as usize];
+            loop {
+                tokio::select! {
+                    n = socket.recv(&mut buf) => {
+                        let n = match n {
+                            Ok(n) => n,
+                            Err(_) => return,
+                        };
+                        if inbound.tx.send(buf[..n].to_vec()).await.is_err() {
+                            return;
+                        }
+                    }
+                    reply = inbound.rx.recv() => {
+                        let reply = match reply {
+                            Some(x) => x,
+                            None => return,
+                        };
+                        if socket.send(&reply).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        flow
+    }
+}
+
 pub type AnyTcpInboundHandler = Arc<dyn TcpInboundHandlerTrait>;
 pub type AnyUdpInboundHandler = Arc<dyn UdpInboundHandlerTrait>;
 pub type AnyInboundHandler = Arc<dyn InboundHandlerTrait>;
 pub trait InboundHandlerTrait: TcpInboundHandlerTrait + UdpInboundHandlerTrait + Sync + Send {
     fn has_tcp(&self) -> bool;
     fn has_udp(&self) -> bool;
+    fn tag(&self) -> &str;
 }
 
 pub struct InboundHandler {
@@ -190,6 +333,9 @@ impl InboundHandlerTrait for InboundHandler {
     fn has_udp(&self) -> bool {
         self.udp_handler.is_some()
     }
+    fn tag(&self) -> &str {
+        &self.tag
+    }
 }
 
 #[async_trait]
@@ -249,13 +395,27 @@ pub trait TcpOutboundHandlerTrait: Send + Sync + Unpin {
     // remote addr should be connected directly
     // no proxy involved
     // fn remote_addr(&self) -> OutboundConnect;
-    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<TcpStream>;
+    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<AnyStream>;
+
+    /// Some when handle is a plain tcp connection to the destination without any protocol,
+    /// the dispatcher may then relay the sockets itself, e.g. with splice
+    fn dialer(&self) -> Option<Arc<Dialer>> {
+        None
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("connect to {0}:{1} failed")]
-    ConnectError(String, u16)
+    ConnectError(String, u16),
+    #[error("connection to {0} rejected")]
+    Rejected(String),
+    #[error("connection to {0} blackholed")]
+    Blackholed(String),
+    #[error("resolve {0} failed")]
+    ResolveFailed(String),
+    #[error("{0} blocked by dns blocklist")]
+    DnsBlocked(String),
 }
 
 #[async_trait]
@@ -272,53 +432,162 @@ pub struct OutboundHandler {
     pub tag: String,
     pub tcp_handler: Option<AnyTcpOutboundHandler>,
     pub udp_handler: Option<AnyUdpOutboundHandler>,
+    pub udp_limit: UdpLimit,
+    pub bandwidth: Bandwidth,
+    // 配置后 udp 经由 tcp_handler 转发，代替 udp_handler
+    pub udp_over_tcp: Option<UdpOverTcp>,
+    // reject outbound 对明文 http 连接的回复，代替 RST
+    pub block_page: Option<Arc<reject::BlockPage>>,
+    pub resolve: ResolveStrategy,
 }
 
 impl OutboundHandler {
     pub fn new(tag: String, tcp: Option<AnyTcpOutboundHandler>, udp: Option<AnyUdpOutboundHandler>) -> OutboundHandler {
-        OutboundHandler { tag , tcp_handler: tcp, udp_handler: udp }
+        OutboundHandler { tag , tcp_handler: tcp, udp_handler: udp, udp_limit: UdpLimit::default(), bandwidth: Bandwidth::default(), udp_over_tcp: None, block_page: None, resolve: ResolveStrategy::Remote }
+    }
+
+    /// whether udp sessions routed to this outbound can be relayed
+    pub fn supports_udp(&self) -> bool {
+        self.udp_handler.is_some() || self.udp_over_tcp.is_some()
+    }
+}
+
+// 大的 QUIC/DNS 报文加上协议头之后可能超过路径 MTU，之前会被悄悄截断
+// IPv6 header(40) + UDP header(8)
+const UDP_PATH_MTU: usize = 1500 - 48;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UdpOversizePolicy {
+    // drop and log
+    Drop,
+    // tun inbound replies ICMP too big, so the application lowers its datagram size
+    IcmpTooBig,
+}
+
+impl TryFrom<&str> for UdpOversizePolicy {
+    type Error = anyhow::Error;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "drop" => Ok(UdpOversizePolicy::Drop),
+            // dispatcher 转发的是 payload，没有协议层分片（socks FRAG 等）可用
+            "fragment" => Err(anyhow!("udp oversize policy fragment is not supported, use drop or icmp")),
+            "icmp" => Ok(UdpOversizePolicy::IcmpTooBig),
+            _ => Err(anyhow!("unknown udp oversize policy {}", value)),
+        }
+    }
+}
+
+/// where the domain of a destination is resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResolveStrategy {
+    // dispatcher 用 DnsClient 解析，outbound 只收到 ip
+    Local,
+    // 域名原样交给 outbound，代理协议由 server 解析，避免 dns 泄漏与污染
+    Remote,
+    // 先交给 server 解析，失败时本地解析后重试一次
+    PreferRemote,
+}
+
+impl TryFrom<&str> for ResolveStrategy {
+    type Error = anyhow::Error;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "local" => Ok(ResolveStrategy::Local),
+            "remote" => Ok(ResolveStrategy::Remote),
+            "prefer-remote" => Ok(ResolveStrategy::PreferRemote),
+            _ => Err(anyhow!("unknown resolve strategy {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UdpLimit {
+    pub max_payload: usize,
+    pub policy: UdpOversizePolicy,
+}
+
+impl Default for UdpLimit {
+    fn default() -> Self {
+        UdpLimit { max_payload: UDP_PATH_MTU, policy: UdpOversizePolicy::Drop }
+    }
+}
+
+impl UdpLimit {
+    // header 最长的情况，socks: RSV(2) + FRAG(1) + ATYP(1) + IPv6(16) + PORT(2)
+    // shadowsocks: salt(32) + tag(16) + ATYP(1) + IPv6(16) + PORT(2)
+    pub fn protocol_overhead(protocol: &str) -> usize {
+        match protocol {
+            "socks" => 22,
+            "shadowsocks" => 67,
+            _ => 0,
+        }
+    }
+    pub fn new(protocol: &str, max_payload: Option<usize>, policy: UdpOversizePolicy) -> UdpLimit {
+        let max_payload = max_payload.unwrap_or(UDP_PATH_MTU - UdpLimit::protocol_overhead(protocol));
+        UdpLimit { max_payload, policy }
+    }
+    /// Ok if the datagram fits, otherwise the policy the relay should apply
+    pub fn check(&self, len: usize) -> Result<(), UdpOversizePolicy> {
+        if len <= self.max_payload {
+            return Ok(());
+        }
+        trace!("udp datagram {} bytes exceeds limit {}, policy {:?}", len, self.max_payload, self.policy);
+        Err(self.policy)
+    }
+    /// whether a datagram relay forwards the datagram
+    pub fn admit(&self, len: usize) -> bool {
+        match self.check(len) {
+            Ok(()) => true,
+            Err(UdpOversizePolicy::Drop) => false,
+            // icmp 由 inbound 回复（UdpFlow::too_big）
+            Err(policy) => {
+                debug!("udp datagram {} bytes dropped, policy {:?}", len, policy);
+                false
+            }
+        }
     }
 }
 
 pub trait StreamWrapperTrait: AsyncRead + AsyncWrite + Send + Sync + Unpin{}
 impl<T> StreamWrapperTrait for T where T: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
 
+// outbound 可能在 tcp 之上再包装一层 transport（tls 等），所以返回 boxed stream
+pub type AnyStream = Box<dyn StreamWrapperTrait>;
+
+#[test]
+fn test_address_from_str() {
+    assert_eq!("example.com:443".parse::<Address>().unwrap().to_string(), "example.com:443");
+    assert_eq!("1.2.3.4:80".parse::<Address>().unwrap().to_string(), "1.2.3.4:80");
+    assert_eq!("[::1]:443".parse::<Address>().unwrap().to_string(), "[::1]:443");
+    match "[fe80::1%2]:443".parse::<Address>().unwrap() {
+        Address::Ip(SocketAddr::V6(v6)) => assert_eq!(v6.scope_id(), 2),
+        _ => panic!("expect ipv6"),
+    }
+    assert_eq!("bücher.de:80".parse::<Address>().unwrap().to_string(), "xn--bcher-kva.de:80");
+    assert_eq!("example.com".parse::<Address>().unwrap_err(), AddressError::MissingPort("example.com".to_string()));
+    assert_eq!("[::1]".parse::<Address>().unwrap_err(), AddressError::MissingPort("[::1]".to_string()));
+    assert_eq!("example.com:99999".parse::<Address>().unwrap_err(), AddressError::InvalidPort("example.com:99999".to_string()));
+    assert_eq!("::1:443".parse::<Address>().unwrap_err(), AddressError::InvalidHost("::1:443".to_string()));
+}
 
-pub async fn connect_to_remote_tcp(dns_client:Arc<RwLock<DnsClient>>, addr: Address) -> anyhow::Result<TcpStream>{
-    let socket_addr = name_to_socket_addr(dns_client, addr).await?;
-    trace!("resolved remote addr {}", socket_addr);
-    TcpStream::connect(socket_addr).await.or_else(|err| {
-        debug!("error when connect to {}, error {}", socket_addr, err);
-        Err(err.into())
-    })
-}
-
-pub async fn name_to_socket_addr(dns_client: Arc<RwLock<DnsClient>>, addr: Address) -> anyhow::Result<SocketAddr> {
-    let socket_addr = match addr {
-        Address::Domain(name, port) => {
-            match dns_client.read().await.lookup(&name).await {
-                Ok(ips) => {
-                    // TODO connect to multiple ips
-                    let ip = if let Some(ip) = ips.get(0) {
-                        ip
-                    }else {
-                        return Err(anyhow!("dns not ip found"))
-                    };
-                    SocketAddr::new(ip.clone(), port)
-                },
-                Err(e) => {
-                    return Err(e)
-                }
-            }
-        },
-        Address::Ip(addr) => addr
-    };
-    Ok(socket_addr)
-}
-
-pub async fn connect_to_remote_udp(dns_client: Arc<RwLock<DnsClient>>, local: SocketAddr, peer: Address) -> anyhow::Result<UdpSocket> {
-    let socket = UdpSocket::bind(local).await?;
-    let socket_addr = name_to_socket_addr(dns_client, peer).await?;
-    UdpSocket::connect(&socket, socket_addr).await?;
-    Ok(socket)
-}
\ No newline at end of file
+#[test]
+fn test_udp_limit() {
+    assert_eq!(UdpLimit::new("socks", None, UdpOversizePolicy::Drop).max_payload, UDP_PATH_MTU - 22);
+    for policy in [UdpOversizePolicy::Drop, UdpOversizePolicy::IcmpTooBig] {
+        let limit = UdpLimit::new("direct", Some(1200), policy);
+        assert_eq!(limit.check(1200), Ok(()));
+        assert_eq!(limit.check(1201), Err(policy));
+        assert!(limit.admit(1200));
+        assert!(!limit.admit(1201));
+    }
+    assert!(UdpOversizePolicy::try_from("fragment").is_err());
+}
+
+#[test]
+fn test_resolve_strategy() {
+    assert_eq!(ResolveStrategy::try_from("local").unwrap(), ResolveStrategy::Local);
+    assert_eq!(ResolveStrategy::try_from("prefer-remote").unwrap(), ResolveStrategy::PreferRemote);
+    assert!(ResolveStrategy::try_from("prefer_remote").is_err());
+    let handler = OutboundHandler::new("proxy".to_string(), None, None);
+    assert_eq!(handler.resolve, ResolveStrategy::Remote);
+}
@@ -0,0 +1,145 @@
+// 到代理服务器的空闲连接池
+// 提前建好连接并完成协议里与目标无关的那部分握手（socks 的认证方法协商、trojan 的 tls 握手），新连接到来时直接取出使用，省掉建连的 rtt
+// shadowsocks 的第一个数据包就包含目标地址，没有可以提前完成的握手
+// 连接只用一次，取出后由调用方在后台补齐到 size 个
+// 空闲超过 idle_ttl 的连接丢弃，服务器一般会主动关闭长时间空闲的连接
+
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::task::noop_waker_ref;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::config::PoolSettings;
+
+const DEFAULT_SIZE: usize = 2;
+const DEFAULT_IDLE_TTL: u64 = 60;
+
+pub struct ConnectionPool<T> {
+    size: usize,
+    idle_ttl: Duration,
+    idle: Mutex<VecDeque<(Instant, T)>>,
+    // 同一时间只有一个补齐任务
+    refilling: AtomicBool,
+}
+
+impl<T> ConnectionPool<T> {
+    pub fn new(settings: &PoolSettings) -> ConnectionPool<T> {
+        let size = settings.size.unwrap_or(DEFAULT_SIZE);
+        ConnectionPool {
+            size,
+            idle_ttl: Duration::from_secs(settings.idle_ttl.unwrap_or(DEFAULT_IDLE_TTL)),
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            refilling: AtomicBool::new(false),
+        }
+    }
+
+    /// take the newest idle connection that is not expired and passes `alive`
+    pub fn take_with(&self, alive: impl FnMut(&mut T) -> bool) -> Option<T> {
+        self.take_at(Instant::now(), alive)
+    }
+
+    fn take_at(&self, now: Instant, mut alive: impl FnMut(&mut T) -> bool) -> Option<T> {
+        let mut idle = self.idle.lock().unwrap();
+        // 队尾是最新放入的，队头的连接最先过期
+        while let Some((since, _)) = idle.front() {
+            if now.duration_since(*since) < self.idle_ttl {
+                break;
+            }
+            idle.pop_front();
+        }
+        while let Some((_, mut conn)) = idle.pop_back() {
+            if alive(&mut conn) {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    /// put a fresh connection, returns false if the pool is already full
+    pub fn put(&self, conn: T) -> bool {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() >= self.size {
+            return false;
+        }
+        idle.push_back((Instant::now(), conn));
+        true
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.idle.lock().unwrap().len() >= self.size
+    }
+
+    /// returns true if the caller should start refilling, call `refill_done` after it finished
+    pub fn begin_refill(&self) -> bool {
+        self.size > 0 && !self.is_full() && !self.refilling.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn refill_done(&self) {
+        self.refilling.store(false, Ordering::Release);
+    }
+}
+
+/// an idle proxy connection must have nothing to read, readable data or eof means the server gave up on it
+pub fn is_idle(stream: &TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    match stream.try_read(&mut buf) {
+        Err(err) => err.kind() == ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
+
+/// is_idle for a stream with its own framing, e.g. tls
+/// records that carry no data (tls 1.3 session tickets) are consumed, only data or eof count
+pub fn is_idle_stream<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut buf = [0u8; 1];
+    let mut buf = ReadBuf::new(&mut buf);
+    let mut cx = std::task::Context::from_waker(noop_waker_ref());
+    Pin::new(stream).poll_read(&mut cx, &mut buf).is_pending()
+}
+
+#[test]
+fn test_connection_pool() {
+    let pool = ConnectionPool::new(&PoolSettings {
+        size: Some(2),
+        idle_ttl: Some(10),
+    });
+    assert!(pool.begin_refill());
+    assert!(!pool.begin_refill());
+    assert!(pool.put(1));
+    assert!(pool.put(2));
+    assert!(!pool.put(3));
+    pool.refill_done();
+    assert!(!pool.begin_refill());
+    let now = Instant::now();
+    // 最新的连接先取出，不可用的被丢弃
+    assert_eq!(pool.take_at(now, |x| *x != 2), Some(1));
+    assert_eq!(pool.take_at(now, |_| true), None);
+    pool.put(4);
+    assert_eq!(pool.take_at(now + Duration::from_secs(10), |_| true), None);
+}
+
+#[tokio::test]
+async fn test_is_idle_stream() {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut client, mut server) = tokio::io::duplex(64);
+    assert!(is_idle_stream(&mut client));
+    server.write_all(b"x").await.unwrap();
+    assert!(!is_idle_stream(&mut client));
+    drop(server);
+    let (mut client, server) = tokio::io::duplex(64);
+    drop(server);
+    assert!(!is_idle_stream(&mut client));
+}
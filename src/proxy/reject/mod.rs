@@ -0,0 +1,123 @@
+// reject outbound
+// 路由到 reject 的连接立即被拒绝，client 马上就能得到错误，不需要等待超时
+// tcp: dispatcher 收到 Rejected 后以 RST 关闭 inbound 连接
+// udp: 目前没有 tun inbound，无法回复 ICMP port unreachable，只返回 Rejected
+// 配置了 settings 时，明文 http 连接收到 403 页面或者 302 跳转，用户能看到被拦截的原因
+// https 无法在不做中间人的情况下回复页面，仍然 RST
+
+use std::{fs, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+use crate::{config::RejectOutboundSettings, Context};
+
+use super::{AnyStream, Error, Session, TcpOutboundHandlerTrait, UdpOutboundHandlerTrait};
+
+pub struct TcpOutboundHandler {}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    async fn handle(&self, _ctx: Arc<Context>, sess: &Session) -> anyhow::Result<AnyStream> {
+        Err(Error::Rejected(sess.destination.to_string()).into())
+    }
+}
+
+pub struct UdpOutboundHandler {}
+
+#[async_trait]
+impl UdpOutboundHandlerTrait for UdpOutboundHandler {
+    async fn handle(&self, _ctx: Arc<Context>, sess: &Session) -> anyhow::Result<UdpSocket> {
+        Err(Error::Rejected(sess.destination.to_string()).into())
+    }
+}
+
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Blocked</title></head>\n<body><h1>Access blocked</h1><p>{destination} is blocked by the network policy.</p></body></html>\n";
+
+/// http response for plain http connections routed to reject
+pub struct BlockPage {
+    page: String,
+    redirect: Option<String>,
+}
+
+impl BlockPage {
+    pub fn new(settings: &RejectOutboundSettings) -> Result<BlockPage> {
+        let page = match &settings.page {
+            Some(path) => fs::read_to_string(path).map_err(|err| anyhow!("read block page {} failed {}", path, err))?,
+            None => DEFAULT_PAGE.to_string(),
+        };
+        Ok(BlockPage {
+            page,
+            redirect: settings.redirect.clone(),
+        })
+    }
+
+    /// the complete response, destination is the blocked host
+    pub fn response(&self, destination: &str) -> Vec<u8> {
+        let (head, body) = match &self.redirect {
+            Some(url) => (format!("HTTP/1.1 302 Found\r\nLocation: {}\r\n", url), String::new()),
+            None => (
+                "HTTP/1.1 403 Forbidden\r\nContent-Type: text/html; charset=utf-8\r\n".to_string(),
+                self.page.replace("{destination}", &escape_html(destination)),
+            ),
+        };
+        format!(
+            "{}Content-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+            head,
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// SO_LINGER 为 0 时 close 会发送 RST 而不是 FIN
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn reset_on_close(fd: std::os::unix::io::RawFd) {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const _ as *const _,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+}
+
+#[test]
+fn test_block_page() {
+    let page = BlockPage::new(&RejectOutboundSettings::default()).unwrap();
+    let response = String::from_utf8(page.response("<ads>.example.com")).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+    assert!(body.contains("&lt;ads&gt;.example.com is blocked"));
+    let settings = RejectOutboundSettings {
+        page: None,
+        redirect: Some("http://portal.lan/blocked".to_string()),
+    };
+    let response = BlockPage::new(&settings).unwrap().response("ads.example.com");
+    assert!(response.starts_with(b"HTTP/1.1 302 Found\r\nLocation: http://portal.lan/blocked\r\n"));
+    assert!(response.ends_with(b"Content-Length: 0\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n"));
+}
@@ -0,0 +1,95 @@
+use std::{io, time::Duration};
+
+use async_trait::async_trait;
+use log::{debug, trace};
+use tokio::{
+    io::{split, AsyncReadExt},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::{
+    config::RelayInboundSettings,
+    proxy::{Carried, InboundResult, Session, TcpInboundHandlerTrait, UdpDemux},
+    transport::mux::{MuxSession, MuxStream},
+};
+
+use super::{handshake_as_server, psk, read_address, read_datagram, write_datagram, CMD_TCP, CMD_UDP};
+
+const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+
+pub struct TcpInboundHandler {
+    psk: [u8; 32],
+    idle_timeout: Duration,
+}
+
+impl TcpInboundHandler {
+    pub fn new(settings: &RelayInboundSettings) -> TcpInboundHandler {
+        TcpInboundHandler {
+            psk: psk(&settings.password),
+            idle_timeout: Duration::from_secs(settings.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT)),
+        }
+    }
+}
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, sess: Session, stream: TcpStream) -> io::Result<InboundResult> {
+        let stream = handshake_as_server(stream, &self.psk)
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, err.to_string()))?;
+        trace!("relay client {} authenticated", sess.peer_address);
+        let session = MuxSession::server(Box::new(stream), self.idle_timeout);
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(stream) = session.accept().await {
+                let tx = tx.clone();
+                let sess = sess.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = accept_stream(stream, sess, tx).await {
+                        debug!("relay stream failed {}", err);
+                    }
+                });
+            }
+        });
+        Ok(InboundResult::Streams(rx))
+    }
+}
+
+async fn accept_stream(
+    mut stream: MuxStream,
+    mut sess: Session,
+    tx: mpsc::UnboundedSender<(Carried, Session)>,
+) -> anyhow::Result<()> {
+    let cmd = stream.read_u8().await?;
+    let destination = read_address(&mut stream).await?;
+    match cmd {
+        CMD_TCP => {
+            sess.destination = destination;
+            let _ = tx.send((Carried::Stream(Box::new(stream)), sess));
+        }
+        CMD_UDP => relay_udp(stream, sess, tx).await,
+        _ => anyhow::bail!("unknown relay cmd {}", cmd),
+    }
+    Ok(())
+}
+
+// 每个 datagram 的 destination 一个 flow，与 tcp 一样经过路由，由 outbound 发出
+async fn relay_udp(stream: MuxStream, sess: Session, tx: mpsc::UnboundedSender<(Carried, Session)>) {
+    let (mut reader, mut writer) = split(stream);
+    let (mut demux, mut replies) = UdpDemux::new(sess, tx);
+    let downlink = tokio::spawn(async move {
+        while let Some((from, payload)) = replies.recv().await {
+            if write_datagram(&mut writer, &from, &payload).await.is_err() {
+                return;
+            }
+        }
+    });
+    // 读取出错或者 stream 关闭时结束，demux drop 之后全部 flow 随之结束
+    while let Ok((destination, payload)) = read_datagram(&mut reader).await {
+        if !demux.send(destination, payload).await {
+            break;
+        }
+    }
+    downlink.abort();
+}
@@ -0,0 +1,335 @@
+// 两个 tunnel 实例之间的 relay 协议，不依赖第三方协议实现
+//
+// 握手: Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s，psk 由 password 经 PBKDF2 派生，只有知道 password 的一方才能完成握手
+// 握手与之后的每个 noise message 前都有 2 bytes 长度
+// 握手完成后在 noise 连接上运行 mux（transport::mux），每个 mux stream 对应一个 session
+//
+// 每个 stream 开头
+// |<-cmd 1 byte->|<-atyp 1 byte->|<-addr->|<-port 2 bytes->|
+// cmd TCP 之后是原始数据
+// cmd UDP 之后每个 datagram 为
+// |<-atyp 1 byte->|<-addr->|<-port 2 bytes->|<-length 2 bytes->|<-payload->|
+
+use std::{
+    cmp::min,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroU32,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, bail, Result};
+use byteorder::{BigEndian, ByteOrder};
+use futures::ready;
+use ring::pbkdf2;
+use snow::{Builder, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use super::Address;
+
+mod inbound;
+mod outbound;
+
+pub use self::inbound::TcpInboundHandler;
+pub use self::outbound::TcpOutboundHandler;
+
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD: usize = MAX_MESSAGE - TAG_LEN;
+
+pub const CMD_TCP: u8 = 0x01;
+pub const CMD_UDP: u8 = 0x03;
+const TYPE_IPV4: u8 = 0x01;
+const TYPE_DOMAIN: u8 = 0x03;
+const TYPE_IPV6: u8 = 0x04;
+
+// psk 需要在第一个 message 之前确定，salt 只能是固定的，用来区分其他使用同一 password 的协议
+const PSK_SALT: &[u8] = b"tunnel relay noise psk v1";
+const PSK_ITERATIONS: u32 = 100_000;
+
+/// derived once per handler, the handshake only uses the result
+pub fn psk(password: &str) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    let iterations = NonZeroU32::new(PSK_ITERATIONS).unwrap();
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, PSK_SALT, password.as_bytes(), &mut key);
+    key
+}
+
+async fn read_message<T: AsyncRead + Unpin>(stream: &mut T) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0u8; BigEndian::read_u16(&len) as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn write_message<T: AsyncWrite + Unpin>(stream: &mut T, data: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(2 + data.len());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf).await
+}
+
+pub async fn handshake_as_client<T>(mut stream: T, psk: &[u8; 32]) -> Result<NoiseStream<T>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut noise = Builder::new(NOISE_PARAMS.parse()?)
+        .psk(0, psk)
+        .build_initiator()?;
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    // -> psk, e
+    let n = noise.write_message(&[], &mut buf)?;
+    write_message(&mut stream, &buf[..n]).await?;
+    // <- e, ee
+    let msg = read_message(&mut stream).await?;
+    noise
+        .read_message(&msg, &mut buf)
+        .map_err(|err| anyhow!("relay handshake failed, wrong password? {}", err))?;
+    Ok(NoiseStream::new(stream, noise.into_transport_mode()?))
+}
+
+pub async fn handshake_as_server<T>(mut stream: T, psk: &[u8; 32]) -> Result<NoiseStream<T>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut noise = Builder::new(NOISE_PARAMS.parse()?)
+        .psk(0, psk)
+        .build_responder()?;
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    let msg = read_message(&mut stream).await?;
+    noise
+        .read_message(&msg, &mut buf)
+        .map_err(|err| anyhow!("relay handshake failed, wrong password? {}", err))?;
+    let n = noise.write_message(&[], &mut buf)?;
+    write_message(&mut stream, &buf[..n]).await?;
+    Ok(NoiseStream::new(stream, noise.into_transport_mode()?))
+}
+
+pub struct NoiseStream<T> {
+    inner: T,
+    noise: TransportState,
+    // 从 inner 读到但还不足一个完整 message 的数据
+    read_buf: Vec<u8>,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    // 已加密但还没有全部写入 inner 的 message
+    write_buf: Vec<u8>,
+    written: usize,
+}
+
+impl<T> NoiseStream<T> {
+    fn new(inner: T, noise: TransportState) -> NoiseStream<T> {
+        NoiseStream {
+            inner,
+            noise,
+            read_buf: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+            write_buf: Vec::new(),
+            written: 0,
+        }
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for NoiseStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = min(this.plain.len() - this.plain_pos, buf.remaining());
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            // 已经有完整的 message
+            if this.read_buf.len() >= 2 {
+                let len = BigEndian::read_u16(&this.read_buf[..2]) as usize;
+                if this.read_buf.len() >= 2 + len {
+                    this.plain.resize(MAX_MESSAGE, 0);
+                    let n = this
+                        .noise
+                        .read_message(&this.read_buf[2..2 + len], &mut this.plain)
+                        .map_err(invalid_data)?;
+                    this.plain.truncate(n);
+                    this.plain_pos = 0;
+                    this.read_buf.drain(..2 + len);
+                    continue;
+                }
+            }
+            let mut tmp = [0u8; 8192];
+            let mut tmp_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+            if tmp_buf.filled().is_empty() {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.read_buf.extend_from_slice(tmp_buf.filled());
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> NoiseStream<T> {
+    // 把 write_buf 中已加密的 message 全部写入 inner
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for NoiseStream<T> {
+    // 上一个 message 写完之后才接收新的数据，加密后的 message 留在 write_buf 中
+    // 没有写完的部分在之后的 write / flush / shutdown 中继续写，调用方不需要用同样的数据重试
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        let n = min(buf.len(), MAX_PAYLOAD);
+        this.write_buf.resize(2 + n + TAG_LEN, 0);
+        let len = this
+            .noise
+            .write_message(&buf[..n], &mut this.write_buf[2..])
+            .map_err(invalid_data)?;
+        BigEndian::write_u16(&mut this.write_buf[..2], len as u16);
+        this.write_buf.truncate(2 + len);
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+pub fn write_address(buf: &mut Vec<u8>, address: &Address) {
+    match address {
+        Address::Domain(name, _) => {
+            buf.push(TYPE_DOMAIN);
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(name.as_bytes());
+        }
+        Address::Ip(addr) => match addr.ip() {
+            IpAddr::V4(v4) => {
+                buf.push(TYPE_IPV4);
+                buf.extend_from_slice(&v4.octets());
+            }
+            IpAddr::V6(v6) => {
+                buf.push(TYPE_IPV6);
+                buf.extend_from_slice(&v6.octets());
+            }
+        },
+    }
+    buf.extend_from_slice(&address.port().to_be_bytes());
+}
+
+pub async fn read_address<T: AsyncRead + Unpin>(stream: &mut T) -> Result<Address> {
+    let atyp = stream.read_u8().await?;
+    let address = match atyp {
+        TYPE_IPV4 => {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            let port = stream.read_u16().await?;
+            Address::Ip(SocketAddr::new(Ipv4Addr::from(buf).into(), port))
+        }
+        TYPE_IPV6 => {
+            let mut buf = [0u8; 16];
+            stream.read_exact(&mut buf).await?;
+            let port = stream.read_u16().await?;
+            Address::Ip(SocketAddr::new(Ipv6Addr::from(buf).into(), port))
+        }
+        TYPE_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut buf = vec![0u8; len as usize];
+            stream.read_exact(&mut buf).await?;
+            let port = stream.read_u16().await?;
+            Address::Domain(String::from_utf8(buf)?, port)
+        }
+        _ => bail!("unknown relay atyp {}", atyp),
+    };
+    Ok(address)
+}
+
+pub async fn write_datagram<T>(stream: &mut T, address: &Address, payload: &[u8]) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    if payload.len() > u16::MAX as usize {
+        bail!("relay datagram too large {}", payload.len());
+    }
+    let mut buf = Vec::with_capacity(payload.len() + 24);
+    write_address(&mut buf, address);
+    buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf.extend_from_slice(payload);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+pub async fn read_datagram<T: AsyncRead + Unpin>(stream: &mut T) -> Result<(Address, Vec<u8>)> {
+    let address = read_address(stream).await?;
+    let len = stream.read_u16().await?;
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok((address, payload))
+}
+
+#[tokio::test]
+async fn test_noise_stream() {
+    let (client, server) = tokio::io::duplex(1024);
+    let key = psk("password");
+    let server = tokio::spawn(async move {
+        let mut stream = handshake_as_server(server, &key).await.unwrap();
+        let address = read_address(&mut stream).await.unwrap();
+        let mut buf = vec![0u8; 100000];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(buf.iter().all(|x| *x == 7));
+        address
+    });
+    let mut stream = handshake_as_client(client, &key).await.unwrap();
+    let mut buf = Vec::new();
+    write_address(&mut buf, &Address::Domain("example.com".to_string(), 443));
+    buf.extend(std::iter::repeat(7u8).take(100000));
+    stream.write_all(&buf).await.unwrap();
+    // 最后一个 message 可能还在 write_buf 中
+    stream.flush().await.unwrap();
+    assert_eq!(server.await.unwrap().to_string(), "example.com:443");
+
+    let (client, server) = tokio::io::duplex(1024);
+    tokio::spawn(async move {
+        let _ = handshake_as_server(server, &psk("other")).await;
+    });
+    assert!(handshake_as_client(client, &key).await.is_err());
+}
@@ -0,0 +1,103 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::trace;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    config::RelayOutboundSettings,
+    proxy::{Address, AnyStream, Dialer, Session, TcpOutboundHandlerTrait, UOT_MAGIC_ADDRESS},
+    transport::{
+        h2::H2Client,
+        mux::{MuxPool, MuxStream},
+        tls::TlsConnector,
+    },
+    Context,
+};
+
+use super::{handshake_as_client, psk, write_address, CMD_TCP, CMD_UDP};
+
+pub struct TcpOutboundHandler {
+    server: Address,
+    psk: [u8; 32],
+    pool: MuxPool,
+    dialer: Arc<Dialer>,
+    // relay 连接作为 h2/grpc stream，全部 stream 复用一条 tls 连接
+    // 只有 client 端，server 端需要放在能转发 h2/grpc 的反向代理之后，目前 relay inbound 不支持
+    // (client, tls, sni 默认使用的 server 地址)
+    h2: Option<(H2Client, TlsConnector, String)>,
+}
+
+impl TcpOutboundHandler {
+    pub fn new(settings: &RelayOutboundSettings, dialer: Arc<Dialer>) -> Result<TcpOutboundHandler> {
+        let server = Address::try_from((settings.address.clone(), settings.port))?;
+        let h2 = match &settings.transport {
+            Some(transport) => {
+                let mut tls = transport.tls.clone();
+                tls.alpn.get_or_insert_with(|| vec!["h2".to_string()]);
+                Some((H2Client::new(transport, &settings.address)?, TlsConnector::new(&tls)?, settings.address.clone()))
+            }
+            None => None,
+        };
+        Ok(TcpOutboundHandler {
+            server,
+            psk: psk(&settings.password),
+            pool: MuxPool::new(&settings.mux.clone().unwrap_or_default()),
+            dialer,
+            h2,
+        })
+    }
+
+    pub fn server(&self) -> &Address {
+        &self.server
+    }
+
+    async fn open(&self, ctx: Arc<Context>, cmd: u8, destination: &Address) -> Result<MuxStream> {
+        let mut stream = self
+            .pool
+            .open(|| async {
+                let stream: AnyStream = match &self.h2 {
+                    Some((client, connector, host)) => {
+                        let stream = client
+                            .open(|| async {
+                                let stream = self.dialer.connect_tcp(ctx.dns_client.clone(), self.server.clone()).await?;
+                                let stream: AnyStream = Box::new(connector.connect(host, stream).await?);
+                                Ok(stream)
+                            })
+                            .await?;
+                        Box::new(handshake_as_client(stream, &self.psk).await?)
+                    }
+                    None => {
+                        let stream = self.dialer.connect_tcp(ctx.dns_client.clone(), self.server.clone()).await?;
+                        Box::new(handshake_as_client(stream, &self.psk).await?)
+                    }
+                };
+                trace!("relay connection established to {}", self.server);
+                Ok(stream)
+            })
+            .await?;
+        let mut buf = vec![cmd];
+        write_address(&mut buf, destination);
+        stream.write_all(&buf).await?;
+        Ok(stream)
+    }
+
+    /// open a udp association, datagrams are exchanged with read_datagram / write_datagram
+    pub async fn associate(&self, ctx: Arc<Context>) -> Result<MuxStream> {
+        let unspecified = Address::Ip(([0, 0, 0, 0], 0).into());
+        self.open(ctx, CMD_UDP, &unspecified).await
+    }
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> Result<AnyStream> {
+        // udp over tcp 的隧道使用 relay 自己的 udp 命令，datagram 格式相同
+        let stream = match &sess.destination {
+            Address::Domain(name, _) if name == UOT_MAGIC_ADDRESS => self.associate(ctx).await?,
+            _ => self.open(ctx, CMD_TCP, &sess.destination).await?,
+        };
+        Ok(Box::new(stream))
+    }
+}
@@ -113,7 +113,7 @@ pub struct AeadEncryptor {
 
 impl AeadEncryptor {
     pub fn new(valid_key_from_hkdf: &[u8], algorithm: &'static Algorithm) -> anyhow::Result<Self> {
-        let nonce_sequence = NonceSequenceGenerator::new(algorithm.key_len());
+        let nonce_sequence = NonceSequenceGenerator::new(algorithm.nonce_len());
         let key = UnboundKey::new(&algorithm, valid_key_from_hkdf)
             .map_err(|_| anyhow!("unboundKey failed"))?;
         Ok(Self {
@@ -140,9 +140,9 @@ pub struct AeadDecryptor {
 }
 
 impl AeadDecryptor {
-    pub fn new(psk: &[u8], algorithm: &'static Algorithm) -> anyhow::Result<Self> {
-        let nonce_sequence = NonceSequenceGenerator::new(algorithm.key_len());
-        let key = UnboundKey::new(&algorithm, psk).map_err(|_| anyhow!("unboundKey failed"))?;
+    pub fn new(valid_key_from_hkdf: &[u8], algorithm: &'static Algorithm) -> anyhow::Result<Self> {
+        let nonce_sequence = NonceSequenceGenerator::new(algorithm.nonce_len());
+        let key = UnboundKey::new(&algorithm, valid_key_from_hkdf).map_err(|_| anyhow!("unboundKey failed"))?;
         Ok(Self {
             key: LessSafeKey::new(key),
             nonce: nonce_sequence,
@@ -175,7 +175,7 @@ impl AEADCipher {
         let s = String::from("ss-subkey");
         let info = s.as_bytes();
         let key = hkdf(psk, salt, info, self.algorithm.key_len())?;
-        AeadDecryptor::new(psk, self.algorithm)
+        AeadDecryptor::new(key.as_ref(), self.algorithm)
     }
     pub fn key_len(&self) -> usize {
         self.algorithm.key_len()
@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::BytesMut;
 use futures::ready;
 use rand::{prelude::StdRng, Rng, SeedableRng};
 use std::{
@@ -9,10 +9,15 @@ use std::{
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use self::cipher::{
-    password_to_cipher_key, AEADCipher, AeadDecryptor, AeadEncryptor, Method, INFOS,
+    password_to_cipher_key, AEADCipher, AeadDecryptor, AeadEncryptor, CipherInfo, Method, INFOS,
 };
 
 mod cipher;
+mod outbound;
+mod plugin;
+
+pub use self::outbound::TcpOutboundHandler;
+pub use self::plugin::{ObfsHttpStream, Plugin};
 
 const MAX_PAYLOAD_LEN: usize = 0x3fff;
 
@@ -23,11 +28,6 @@ enum ReadState {
     WaitingPayload(usize),
 }
 
-enum WriteState {
-    WaitingSalt,
-    WaitingChunk,
-    WritingChunk(usize),
-}
 // shadowsocks 协议分析
 // https://chaochaogege.com/2022/05/24/58/
 // 针对 0x3fff 的处理
@@ -38,72 +38,123 @@ enum WriteState {
 // shadowsocks-rust encrypted poll_write 多次循环全部将数据写完，而不是返回一次最多写入的bytes
 struct ShadowsocksStream<T> {
     stream: T,
+    // 还没有解密的数据
     read_buf: BytesMut,
     read_state: ReadState,
+    // 解密之后还没有被读走的 payload
+    plain: BytesMut,
 
+    // 加密之后还没有写完的 chunk，written 之前的部分已经写入 stream
     write_buf: BytesMut,
-    write_state: WriteState,
+    written: usize,
+    // write_buf 中的 chunk 对应 caller 的 bytes
+    pending: usize,
 
     psk: Vec<u8>,
-    // WaitingSalt 阶段才能初始化
     cipher: AEADCipher,
+    // 第一次写入时生成 salt 并初始化
     encryptor: Option<AeadEncryptor>,
+    // 读到对方的 salt 之后初始化
     decryptor: Option<AeadDecryptor>,
 }
 
 // https://github.com/v2fly/v2ray-core/blob/ca5695244c383870aed1976a59ae6e5eda94f999/proxy/shadowsocks/config.go#L228
 
+fn cipher_info(method: &str) -> io::Result<&'static CipherInfo> {
+    INFOS.get(method).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported shadowsocks method {}", method))
+    })
+}
+
 impl<T> ShadowsocksStream<T> {
     /// method:
     /// 1. aes-128-gcm
     /// 2. aes-256-gcm
     pub fn new(stream: T, method: &str, password: String) -> io::Result<Self> {
-        let m = INFOS.get(&method).unwrap();
+        let m = cipher_info(method)?;
         let strong_password = password_to_cipher_key(&*password, m.key_len)?;
         let cipher = AEADCipher::new(m.algorithm);
         Ok(Self {
             stream,
             read_buf: BytesMut::new(),
-            write_buf: BytesMut::new(),
             read_state: ReadState::WaitingSalt,
-            write_state: WriteState::WaitingSalt,
+            plain: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            written: 0,
+            pending: 0,
             cipher,
             encryptor: None,
             decryptor: None,
             psk: strong_password,
         })
     }
+
+    // length(2) tag(x) + payload(length) tag(x)，第一个 chunk 之前是 salt
+    fn encrypt_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.encryptor.is_none() {
+            // https://github.com/v2fly/v2ray-core/blob/0746740b1072185634ef0873f1607f922a28efea/proxy/shadowsocks/protocol.go#L104
+            // secure random number
+            let mut salt = vec![0u8; self.cipher.key_len()];
+            StdRng::from_entropy().fill(&mut salt[..]);
+            let encryptor = self
+                .cipher
+                .encryptor(&self.psk, &salt)
+                .map_err(|_| map_crypto_error())?;
+            self.encryptor.replace(encryptor);
+            self.write_buf.extend_from_slice(&salt);
+        }
+        let enc = self.encryptor.as_mut().unwrap();
+        let mut length = BytesMut::from(&u16::to_be_bytes(data.len() as u16)[..]);
+        enc.encrypt(&mut length).map_err(|_| map_crypto_error())?;
+        let mut payload = BytesMut::from(data);
+        enc.encrypt(&mut payload).map_err(|_| map_crypto_error())?;
+        self.write_buf.extend_from_slice(&length);
+        self.write_buf.extend_from_slice(&payload);
+        self.written = 0;
+        self.pending = data.len();
+        Ok(())
+    }
 }
 
 impl<T> ShadowsocksStream<T>
 where
     T: AsyncRead + Unpin,
 {
-    fn poll_read_exact(&mut self, cx: &mut Context<'_>, size: usize) -> Poll<io::Result<usize>> {
+    // read_buf 中至少有 size bytes，还没有读到任何数据就 EOF 时返回 false
+    fn poll_fill(&mut self, cx: &mut Context<'_>, size: usize) -> Poll<io::Result<bool>> {
+        let mut chunk = [0u8; 4096];
         while self.read_buf.len() < size {
-            let len = self.read_buf.len();
-            let additional = size - len;
-            self.read_buf.reserve(additional);
-            let dst_buf = unsafe {
-                // [u8] => *[u8] => *[MaybeUninit<u8>] => [MaybeUninit<u8>] => &mut [MaybeUninit<u8>]
-                &mut *(&mut self.read_buf[len..len + additional] as *mut _ as *mut _)
-            };
-            let mut read_buf = ReadBuf::uninit(dst_buf);
+            let mut read_buf = ReadBuf::new(&mut chunk);
             ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read_buf))?;
             let n = read_buf.filled().len();
             if n == 0 {
-                //  If the difference is 0, EOF has been reached.
                 if self.read_buf.is_empty() {
-                    // ok
-                    return Ok(0).into();
-                } else {
-                    // read_buf还有数据，但 read 却返回0，说明 EOF
-                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF!")).into();
+                    return Ok(false).into();
                 }
+                // read_buf还有数据，但 read 却返回0，说明 EOF
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF!")).into();
             }
-            self.read_buf.advance(n);
+            self.read_buf.extend_from_slice(read_buf.filled());
         }
-        Ok(size).into()
+        Ok(true).into()
+    }
+}
+
+impl<T> ShadowsocksStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.written..]))?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into()).into();
+            }
+            self.written += n;
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Ok(()).into()
     }
 }
 
@@ -120,50 +171,54 @@ where
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
         loop {
-            match self.read_state {
+            if !me.plain.is_empty() {
+                let n = usize::min(buf.remaining(), me.plain.len());
+                buf.put_slice(&me.plain.split_to(n));
+                return Ok(()).into();
+            }
+            match me.read_state {
                 ReadState::WaitingSalt => {
-                    let salt_len = self.cipher.key_len();
-                    ready!(self.poll_read_exact(cx, salt_len))?;
-                    let decryptor = self
+                    let salt_len = me.cipher.key_len();
+                    if !ready!(me.poll_fill(cx, salt_len))? {
+                        return Ok(()).into();
+                    }
+                    let salt = me.read_buf.split_to(salt_len);
+                    let decryptor = me
                         .cipher
-                        .decryptor(&self.psk, &self.read_buf[..salt_len])
-                        .map_err(|x| map_crypto_error())?;
-                    self.decryptor.replace(decryptor);
-                    self.read_buf.clear();
-                    self.read_state = ReadState::WaitingLength;
+                        .decryptor(&me.psk, &salt)
+                        .map_err(|_| map_crypto_error())?;
+                    me.decryptor.replace(decryptor);
+                    me.read_state = ReadState::WaitingLength;
                 }
                 ReadState::WaitingLength => {
-                    let tag_len = self.cipher.tag_len();
-                    let encrypted_length_field_len = 2 + tag_len;
-                    ready!(self.poll_read_exact(cx, encrypted_length_field_len))?;
+                    let encrypted_length_field_len = 2 + me.cipher.tag_len();
+                    // chunk 之间 EOF 是正常结束
+                    if !ready!(me.poll_fill(cx, encrypted_length_field_len))? {
+                        return Ok(()).into();
+                    }
+                    let mut field = me.read_buf.split_to(encrypted_length_field_len);
                     // decryptor should always be Some
-                    let me = &mut *self;
                     let dec = me.decryptor.as_mut().unwrap();
-                    dec.decrypt(&mut me.read_buf)
-                        .map_err(|x| map_crypto_error())?;
-                    let buf = &self.read_buf;
-                    let n = u16::from_be_bytes([buf[0], buf[1]]);
-                    self.read_state = ReadState::WaitingPayload(n as usize);
-                    self.read_buf.clear();
+                    dec.decrypt(&mut field).map_err(|_| map_crypto_error())?;
+                    let n = u16::from_be_bytes([field[0], field[1]]) as usize;
+                    if n > MAX_PAYLOAD_LEN {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "payload too large")).into();
+                    }
+                    me.read_state = ReadState::WaitingPayload(n);
                 }
                 ReadState::WaitingPayload(n) => {
-                    let encrypted_payload_field_len = self.cipher.tag_len() + n;
-                    ready!(self.poll_read_exact(cx, encrypted_payload_field_len))?;
-                    let me = &mut *self;
-                    let dec = me.decryptor.as_mut().unwrap();
-                    dec.decrypt(&mut me.read_buf)
-                        .map_err(|x| map_crypto_error())?;
-                    assert!(n == self.read_buf.len());
-                    let remaining = usize::min(buf.remaining(), self.read_buf.len());
-                    buf.put_slice(&self.read_buf[..remaining]);
-                    if remaining < n {
-                        self.read_state = ReadState::WaitingPayload(n - remaining);
-                    } else {
-                        self.read_buf.clear();
-                        self.read_state = ReadState::WaitingLength;
+                    let encrypted_payload_field_len = n + me.cipher.tag_len();
+                    if !ready!(me.poll_fill(cx, encrypted_payload_field_len))? {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF!")).into();
                     }
-                    return Ok(()).into();
+                    let mut payload = me.read_buf.split_to(encrypted_payload_field_len);
+                    let dec = me.decryptor.as_mut().unwrap();
+                    dec.decrypt(&mut payload).map_err(|_| map_crypto_error())?;
+                    payload.truncate(n);
+                    me.plain = payload;
+                    me.read_state = ReadState::WaitingLength;
                 }
             }
         }
@@ -175,76 +230,34 @@ where
     T: Unpin + AsyncWrite,
 {
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.stream).poll_flush(cx)
+        let me = &mut *self;
+        ready!(me.poll_write_chunk(cx))?;
+        Pin::new(&mut me.stream).poll_flush(cx)
     }
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.stream).poll_shutdown(cx)
+        let me = &mut *self;
+        ready!(me.poll_write_chunk(cx))?;
+        Pin::new(&mut me.stream).poll_shutdown(cx)
     }
+    // 每次最多加密 MAX_PAYLOAD_LEN，chunk 全部写完才返回
+    // 返回 Pending 时 caller 会用同样的 buf 再次调用，这时只需要继续写上一次加密的 chunk
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-        mut buf: &[u8],
+        buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
         let me = &mut *self;
-        buf = &buf[..MAX_PAYLOAD_LEN];
-        loop {
-            match me.write_state {
-                WriteState::WaitingSalt => {
-                    let salt_len = me.cipher.key_len();
-                    // https://github.com/v2fly/v2ray-core/blob/0746740b1072185634ef0873f1607f922a28efea/proxy/shadowsocks/protocol.go#L104
-                    // secure random number
-                    let mut srn = rand::rngs::StdRng::from_entropy();
-                    for i in 0..salt_len {
-                        me.write_buf[i] = srn.gen();
-                    }
-                    let encryptor = me
-                        .cipher
-                        .encryptor(&me.psk, &me.write_buf[..salt_len])
-                        .map_err(|x| map_crypto_error())?;
-                    // salt 写走
-                    ready!(Pin::new(&mut me.stream).poll_write(cx, &me.write_buf[..salt_len]))?;
-                    me.write_buf.clear();
-                    me.encryptor.replace(encryptor);
-                    me.write_state = WriteState::WaitingChunk;
-                }
-                WriteState::WaitingChunk => {
-                    // length(2) tag(x) + payload(length) tag(x)
-                    let encrypted_payload_length = 2 + me.cipher.tag_len() * 2 + buf.len();
-                    let real_payload_len = buf.len() as u16;
-
-                    me.write_buf.reserve(encrypted_payload_length);
-                    me.write_buf.put_slice(&u16::to_be_bytes(real_payload_len));
-                    let enc = me.encryptor.as_mut().unwrap();
-                    enc.encrypt(&mut me.write_buf)
-                        .map_err(|x| map_crypto_error())?;
-
-                    unsafe {
-                        me.write_buf.set_len(2 + me.cipher.tag_len());
-                    }
-                    me.write_buf.put_slice(&buf);
-                    enc.encrypt(&mut me.write_buf)
-                        .map_err(|x| map_crypto_error())?;
-                    unsafe { me.write_buf.set_len(encrypted_payload_length) };
-                    me.write_state = WriteState::WritingChunk(0);
-                }
-                WriteState::WritingChunk(ref mut total_len) => {
-                    // write all
-                    // so always return Ok(buf.len())
-                    while (*total_len < me.write_buf.len()) {
-                        let n =
-                            ready!(Pin::new(&mut me.stream)
-                                .poll_write(cx, &me.write_buf[*total_len..]))?;
-                        *total_len += n;
-                    }
-                    me.write_buf.clear();
-                    me.write_state = WriteState::WaitingChunk;
-                    return Ok(buf.len()).into();
-                }
+        if me.write_buf.is_empty() {
+            if buf.is_empty() {
+                return Ok(0).into();
             }
+            me.encrypt_chunk(&buf[..usize::min(buf.len(), MAX_PAYLOAD_LEN)])?;
         }
+        ready!(me.poll_write_chunk(cx))?;
+        Ok(me.pending).into()
     }
 }
 
@@ -271,7 +284,7 @@ impl ShadowsocksDatagram {
     /// 1. aes-128-gcm
     /// 2. aes-256-gcm
     pub fn new(method: & str, password: &str) -> io::Result<Self> {
-        let m = INFOS.get(&method).unwrap();
+        let m = cipher_info(method)?;
         let strong_password = password_to_cipher_key(password, m.key_len)?;
         let cipher = AEADCipher::new(m.algorithm);
         Ok(Self {
@@ -282,11 +295,8 @@ impl ShadowsocksDatagram {
     pub fn encrypt(&self, mut buf: BytesMut) -> io::Result<Vec<u8>> {
         // generate salt
         let salt_len = self.cipher.key_len();
-        let mut encrypted_buf = Vec::new();
-        let mut rng = StdRng::from_entropy();
-        for i in 0..salt_len {
-            encrypted_buf[i] = rng.gen();
-        }
+        let mut encrypted_buf = vec![0u8; salt_len];
+        StdRng::from_entropy().fill(&mut encrypted_buf[..]);
         let mut encryptor = self
             .cipher
             .encryptor(&self.psk, &encrypted_buf[..salt_len])
@@ -322,3 +332,38 @@ fn stream_test() {
     let x = Method::AES_192_GCM;
     println!("{}", x.to_string());
 }
+
+#[tokio::test]
+async fn test_shadowsocks_stream() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    assert!(ShadowsocksStream::new(tokio::io::duplex(64).0, "rc4-md5", "password".to_string()).is_err());
+    let (a, b) = tokio::io::duplex(1024);
+    let mut client = ShadowsocksStream::new(a, "aes-256-gcm", "password".to_string()).unwrap();
+    let mut server = ShadowsocksStream::new(b, "aes-256-gcm", "password".to_string()).unwrap();
+    // 超过 MAX_PAYLOAD_LEN，分成多个 chunk
+    let data: Vec<u8> = (0..40000).map(|x| x as u8).collect();
+    let expected = data.clone();
+    let writer = tokio::spawn(async move {
+        client.write_all(&data).await.unwrap();
+        client.flush().await.unwrap();
+        client
+    });
+    let mut received = vec![0u8; expected.len()];
+    server.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, expected);
+    let mut client = writer.await.unwrap();
+    server.write_all(b"pong").await.unwrap();
+    server.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, b"pong");
+
+    // password 不同时无法解密
+    let (a, b) = tokio::io::duplex(1024);
+    let mut client = ShadowsocksStream::new(a, "aes-128-gcm", "password".to_string()).unwrap();
+    let mut server = ShadowsocksStream::new(b, "aes-128-gcm", "wrong".to_string()).unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    assert!(server.read_exact(&mut buf).await.is_err());
+}
@@ -0,0 +1,69 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::trace;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    config::ShadowsocksOutboundSettings,
+    proxy::{relay::write_address, Address, AnyStream, Dialer, Session, TcpOutboundHandlerTrait},
+    Context,
+};
+
+use super::{cipher::INFOS, Plugin, ShadowsocksStream};
+
+pub struct TcpOutboundHandler {
+    server: Address,
+    method: String,
+    password: String,
+    // 有 plugin 时拨号 Plugin::address，连接由 Plugin::wrap 包装之后再加密
+    plugin: Option<Plugin>,
+    dialer: Arc<Dialer>,
+}
+
+impl TcpOutboundHandler {
+    pub fn new(settings: &ShadowsocksOutboundSettings, dialer: Arc<Dialer>) -> Result<TcpOutboundHandler> {
+        let server = Address::try_from((settings.address.clone(), settings.port))?;
+        if !INFOS.contains_key(settings.method.as_str()) {
+            bail!("unsupported shadowsocks method {}", settings.method);
+        }
+        let plugin = match &settings.plugin {
+            Some(name) => Some(Plugin::new(name, settings.plugin_opts.as_deref(), &server)?),
+            None => None,
+        };
+        Ok(TcpOutboundHandler {
+            server,
+            method: settings.method.clone(),
+            password: settings.password.clone(),
+            plugin,
+            dialer,
+        })
+    }
+
+    pub fn server(&self) -> &Address {
+        &self.server
+    }
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> Result<AnyStream> {
+        let target = match &self.plugin {
+            Some(plugin) => plugin.address(&self.server),
+            None => self.server.clone(),
+        };
+        let stream: AnyStream = Box::new(self.dialer.connect_tcp(ctx.dns_client.clone(), target).await?);
+        let stream = match &self.plugin {
+            Some(plugin) => plugin.wrap(stream, &self.server),
+            None => stream,
+        };
+        trace!("shadowsocks connection established to {}", self.server);
+        let mut stream = ShadowsocksStream::new(stream, &self.method, self.password.clone())?;
+        // 第一个 chunk 是目标地址，格式与 socks5 相同
+        let mut buf = Vec::new();
+        write_address(&mut buf, &sess.destination);
+        stream.write_all(&buf).await?;
+        Ok(Box::new(stream))
+    }
+}
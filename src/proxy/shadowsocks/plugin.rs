@@ -0,0 +1,312 @@
+// SIP003 plugin
+// https://shadowsocks.org/doc/sip003.html
+// 外部 plugin 是一个子进程，通过环境变量告诉它服务器地址与本地监听地址，连接服务器改为连接它的本地端口
+// SS_REMOTE_HOST SS_REMOTE_PORT SS_LOCAL_HOST SS_LOCAL_PORT SS_PLUGIN_OPTIONS
+//
+// simple-obfs 的 http 模式内置实现，不需要安装 obfs-local
+// 第一次写入时加上一个 websocket upgrade 请求头，第一次读取时跳过服务器的响应头，之后是原始数据
+// obfs=tls 与 v2ray-plugin（websocket）仍然启动外部 plugin
+
+use std::{
+    cmp::min,
+    io,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    pin::Pin,
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, Result};
+use futures::ready;
+use log::{info, warn};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::{Address, AnyStream};
+
+const DEFAULT_OBFS_HOST: &str = "cloudfront.net";
+const HEADER_END: &[u8] = b"\r\n\r\n";
+// 响应头不应该这么大，超过时认为不是 obfs 服务器
+const MAX_HEADER_LEN: usize = 8192;
+
+/// "obfs=http;obfs-host=www.bing.com" => [("obfs", "http"), ("obfs-host", "www.bing.com")]
+pub fn parse_options(opts: &str) -> Vec<(String, String)> {
+    opts.split(';')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.split_once('=') {
+            Some((k, v)) => (k.trim().to_string(), v.trim().to_string()),
+            None => (x.to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn option<'a>(opts: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    opts.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+pub enum Plugin {
+    ObfsHttp { host: String },
+    External(ExternalPlugin),
+}
+
+impl Plugin {
+    pub fn new(name: &str, opts: Option<&str>, server: &Address) -> Result<Plugin> {
+        let parsed = parse_options(opts.unwrap_or_default());
+        let builtin = matches!(name, "obfs-local" | "simple-obfs");
+        if builtin && option(&parsed, "obfs") == Some("http") {
+            let host = option(&parsed, "obfs-host").unwrap_or(DEFAULT_OBFS_HOST);
+            return Ok(Plugin::ObfsHttp { host: host.to_string() });
+        }
+        Ok(Plugin::External(ExternalPlugin::start(name, opts.unwrap_or_default(), server)?))
+    }
+
+    /// the address to dial instead of the server
+    pub fn address(&self, server: &Address) -> Address {
+        match self {
+            Plugin::ObfsHttp { .. } => server.clone(),
+            Plugin::External(plugin) => Address::Ip(plugin.local_addr()),
+        }
+    }
+
+    /// wrap the dialed connection, external plugins do it in their own process
+    pub fn wrap(&self, stream: AnyStream, server: &Address) -> AnyStream {
+        match self {
+            Plugin::ObfsHttp { host } => Box::new(ObfsHttpStream::new(stream, host, server.port())),
+            Plugin::External(_) => stream,
+        }
+    }
+}
+
+pub struct ExternalPlugin {
+    name: String,
+    opts: String,
+    server: Address,
+    local: SocketAddr,
+    child: Mutex<Child>,
+}
+
+impl ExternalPlugin {
+    fn start(name: &str, opts: &str, server: &Address) -> Result<ExternalPlugin> {
+        let local = free_local_addr()?;
+        let child = ExternalPlugin::spawn(name, opts, server, local)?;
+        Ok(ExternalPlugin {
+            name: name.to_string(),
+            opts: opts.to_string(),
+            server: server.clone(),
+            local,
+            child: Mutex::new(child),
+        })
+    }
+
+    fn spawn(name: &str, opts: &str, server: &Address, local: SocketAddr) -> Result<Child> {
+        let (host, port) = match server {
+            Address::Domain(name, port) => (name.clone(), *port),
+            Address::Ip(addr) => (addr.ip().to_string(), addr.port()),
+        };
+        let child = Command::new(name)
+            .env("SS_REMOTE_HOST", host)
+            .env("SS_REMOTE_PORT", port.to_string())
+            .env("SS_LOCAL_HOST", local.ip().to_string())
+            .env("SS_LOCAL_PORT", local.port().to_string())
+            .env("SS_PLUGIN_OPTIONS", opts)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|err| anyhow!("start plugin {} failed {}", name, err))?;
+        info!("plugin {} started, pid {}, listening on {}", name, child.id(), local);
+        Ok(child)
+    }
+
+    // plugin 退出时重新启动
+    fn local_addr(&self) -> SocketAddr {
+        let mut child = self.child.lock().unwrap();
+        if let Ok(Some(status)) = child.try_wait() {
+            warn!("plugin {} exited {}, restarting", self.name, status);
+            match ExternalPlugin::spawn(&self.name, &self.opts, &self.server, self.local) {
+                Ok(x) => *child = x,
+                Err(err) => warn!("{}", err),
+            }
+        }
+        self.local
+    }
+}
+
+impl Drop for ExternalPlugin {
+    fn drop(&mut self) {
+        let child = self.child.get_mut().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+// 让系统分配一个空闲端口交给 plugin 监听
+fn free_local_addr() -> io::Result<SocketAddr> {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn request_header(host: &str, port: u16, body_len: usize) -> Vec<u8> {
+    let host = if port == 80 { host.to_string() } else { format!("{}:{}", host, port) };
+    let key: [u8; 16] = rand::thread_rng().gen();
+    format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl/7.{}.{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nContent-Length: {}\r\n\r\n",
+        host,
+        rand::thread_rng().gen_range(0..52),
+        rand::thread_rng().gen_range(0..2),
+        base64(&key),
+        body_len,
+    )
+    .into_bytes()
+}
+
+pub struct ObfsHttpStream<T> {
+    inner: T,
+    host: String,
+    port: u16,
+    // 还没有发出请求头
+    request_sent: bool,
+    // 响应头读完之前缓存读到的数据
+    header_done: bool,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    // 请求头与第一次写入的数据一起发出
+    write_buf: Vec<u8>,
+    written: usize,
+    pending: usize,
+}
+
+impl<T> ObfsHttpStream<T> {
+    pub fn new(inner: T, host: &str, port: u16) -> ObfsHttpStream<T> {
+        ObfsHttpStream {
+            inner,
+            host: host.to_string(),
+            port,
+            request_sent: false,
+            header_done: false,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+            written: 0,
+            pending: 0,
+        }
+    }
+}
+
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(HEADER_END.len()).position(|x| x == HEADER_END).map(|x| x + HEADER_END.len())
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ObfsHttpStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while !this.header_done {
+            if let Some(end) = find_header_end(&this.read_buf) {
+                this.header_done = true;
+                this.read_pos = end;
+                break;
+            }
+            if this.read_buf.len() > MAX_HEADER_LEN {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "obfs http header too large")));
+            }
+            let mut tmp = [0u8; 4096];
+            let mut tmp_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+            if tmp_buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.read_buf.extend_from_slice(tmp_buf.filled());
+        }
+        // 响应头之后剩余的数据
+        if this.read_pos < this.read_buf.len() {
+            let n = min(buf.remaining(), this.read_buf.len() - this.read_pos);
+            buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+            this.read_pos += n;
+            if this.read_pos == this.read_buf.len() {
+                this.read_buf = Vec::new();
+                this.read_pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ObfsHttpStream<T> {
+    // 请求头没写完时返回 Pending，调用方会用同样的数据重试
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        if this.request_sent && this.write_buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if !this.request_sent {
+            this.write_buf = request_header(&this.host, this.port, buf.len());
+            this.write_buf.extend_from_slice(buf);
+            this.written = 0;
+            this.pending = buf.len();
+            this.request_sent = true;
+        }
+        while this.written < this.write_buf.len() {
+            let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.write_buf[this.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.written += n;
+        }
+        this.write_buf = Vec::new();
+        Poll::Ready(Ok(this.pending))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_obfs_http() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    assert_eq!(base64(b"hello"), "aGVsbG8=");
+    assert_eq!(
+        parse_options("obfs=http; obfs-host=www.bing.com"),
+        vec![
+            ("obfs".to_string(), "http".to_string()),
+            ("obfs-host".to_string(), "www.bing.com".to_string())
+        ]
+    );
+    let (client, mut server) = tokio::io::duplex(4096);
+    let mut client = ObfsHttpStream::new(client, "www.bing.com", 8388);
+    client.write_all(b"payload").await.unwrap();
+    let mut buf = vec![0u8; 4096];
+    let n = server.read(&mut buf).await.unwrap();
+    let request = String::from_utf8_lossy(&buf[..n]).to_string();
+    assert!(request.starts_with("GET / HTTP/1.1\r\nHost: www.bing.com:8388\r\n"));
+    assert!(request.ends_with("Content-Length: 7\r\n\r\npayload"));
+    server
+        .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\nreply")
+        .await
+        .unwrap();
+    let mut reply = [0u8; 5];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"reply");
+}
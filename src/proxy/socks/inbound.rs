@@ -1,7 +1,9 @@
-use log::error;
-use std::io;
+use ipnet::IpNet;
+use log::{debug, error};
+use std::{collections::HashMap, io, net::IpAddr};
 
 use crate::{
+    config::Socks5InboundSettings,
     proxy::{
         socks::handshake_as_server, Session, InboundResult, TcpInboundHandlerTrait,
         UdpInboundHandlerTrait,
@@ -10,12 +12,51 @@ use crate::{
 use async_trait::async_trait;
 use tokio::net::{TcpStream, UdpSocket};
 
-pub struct TcpInboundHandler;
+pub struct TcpInboundHandler {
+    // username => password
+    users: HashMap<String, String>,
+    // 为空时不限制来源
+    allow: Vec<IpNet>,
+}
+
+impl TcpInboundHandler {
+    pub fn new(settings: &Socks5InboundSettings) -> anyhow::Result<TcpInboundHandler> {
+        let users = settings
+            .users
+            .iter()
+            .flatten()
+            .map(|x| (x.username.clone(), x.password.clone()))
+            .collect();
+        let mut allow = Vec::new();
+        for cidr in settings.allow.iter().flatten() {
+            allow.push(cidr.parse::<IpNet>().map_err(|err| anyhow::anyhow!("invalid allow cidr {} {}", cidr, err))?);
+        }
+        Ok(TcpInboundHandler { users, allow })
+    }
+
+    fn allowed(&self, ip: IpAddr) -> bool {
+        if self.allow.is_empty() {
+            return true;
+        }
+        // dual stack listener 收到的 ipv4 连接
+        let ip = match ip {
+            IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                IpAddr::V4(v6.to_ipv4().expect("mapped ipv4"))
+            }
+            _ => ip,
+        };
+        self.allow.iter().any(|x| x.contains(&ip))
+    }
+}
 
 #[async_trait]
 impl TcpInboundHandlerTrait for TcpInboundHandler {
-    async fn handle(&self, _conn: Session, mut stream: TcpStream) -> io::Result<InboundResult> {
-        let session = match handshake_as_server(&mut stream).await {
+    async fn handle(&self, conn: Session, mut stream: TcpStream) -> io::Result<InboundResult> {
+        if !self.allowed(conn.peer_address.ip()) {
+            debug!("socks client {} not allowed", conn.peer_address);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "source not allowed"));
+        }
+        let session = match handshake_as_server(&mut stream, &self.users).await {
             Ok(session) => session,
             Err(err) => {
                 error!("failed to process socks inbound {}", err);
@@ -44,3 +85,16 @@ impl UdpInboundHandlerTrait for UdpInboundHandler {
         Ok(InboundResult::Datagram(socket, conn))
     }
 }
+
+#[test]
+fn test_allowed() {
+    let settings = Socks5InboundSettings {
+        users: None,
+        allow: Some(vec!["10.0.0.0/8".to_string()]),
+    };
+    let handler = TcpInboundHandler::new(&settings).unwrap();
+    assert!(handler.allowed("10.1.2.3".parse().unwrap()));
+    assert!(handler.allowed("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!handler.allowed("192.168.1.1".parse().unwrap()));
+    assert!(TcpInboundHandler::new(&Socks5InboundSettings::default()).unwrap().allowed("1.1.1.1".parse().unwrap()));
+}
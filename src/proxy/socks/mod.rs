@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     convert::TryInto,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     slice,
@@ -22,10 +23,15 @@ mod udp;
 pub use self::inbound::TcpInboundHandler;
 pub use self::inbound::UdpInboundHandler;
 pub use self::outbound::TcpOutboundHandler;
-pub use self::outbound::UdpOutboundHandler;
 
 use super::{Network, StreamWrapperTrait};
 const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+// https://datatracker.ietf.org/doc/html/rfc1929
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0x01;
 const CMD_CONNECT: u8 = 0x01;
 const CMD_BIND: u8 = 0x02;
 const CMD_UDP_ASSOCIATE: u8 = 0x03;
@@ -34,6 +40,15 @@ const TYPE_DOMAIN: u8 = 0x03;
 const TYPE_IPV6: u8 = 0x04;
 // as client
 pub async fn handshake_as_client<T>(stream: &mut T, session: &Session) -> Result<()>
+where
+    T: StreamWrapperTrait,
+{
+    negotiate_as_client(stream).await?;
+    connect_as_client(stream, session).await
+}
+
+// 方法协商与目标无关，可以在连接池里提前完成
+pub async fn negotiate_as_client<T>(stream: &mut T) -> Result<()>
 where
     T: StreamWrapperTrait,
 {
@@ -43,6 +58,13 @@ where
     if buf[1] != NO_AUTHENTICATION_REQUIRED {
         return Err(anyhow!("only no authentication supported {:?}", &buf));
     }
+    Ok(())
+}
+
+pub async fn connect_as_client<T>(stream: &mut T, session: &Session) -> Result<()>
+where
+    T: StreamWrapperTrait,
+{
     let mut buf = Vec::new();
     build_request(&mut buf, session);
     stream.write_all(&*buf).await?;
@@ -82,15 +104,51 @@ fn build_request(buf: &mut Vec<u8>, session: &Session) {
     buf.push(port as u8);
 }
 
+async fn read_auth_field(stream: &mut TcpStream) -> Result<String> {
+    let len = stream.read_u8().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
+// username/password 子协商，返回认证通过的用户名
+async fn authenticate(stream: &mut TcpStream, users: &HashMap<String, String>) -> Result<String> {
+    let version = stream.read_u8().await?;
+    if version != AUTH_VERSION {
+        bail!("unknown auth version {}", version);
+    }
+    let username = read_auth_field(stream).await?;
+    let password = read_auth_field(stream).await?;
+    if users.get(&username) != Some(&password) {
+        stream.write_all(&[AUTH_VERSION, AUTH_FAILURE]).await?;
+        bail!("socks auth failed, user {}", username);
+    }
+    stream.write_all(&[AUTH_VERSION, AUTH_SUCCESS]).await?;
+    Ok(username)
+}
+
 // as server
-pub async fn handshake_as_server(stream: &mut TcpStream) -> Result<Session> {
-    let mut buf = vec![0; 3];
+// users 为空时不需要认证
+pub async fn handshake_as_server(stream: &mut TcpStream, users: &HashMap<String, String>) -> Result<Session> {
+    let mut buf = vec![0; 2];
     stream.read_exact(&mut buf).await?;
     let version = buf[0];
     if version != 0x05 {
         bail!("only version 5 supported {}", &version)
     };
-    stream.write_all(&[0x05, 0x00]).await?;
+    let methods = buf[1] as usize;
+    buf.resize(methods, 0);
+    stream.read_exact(&mut buf).await?;
+    let user = if users.is_empty() {
+        stream.write_all(&[0x05, NO_AUTHENTICATION_REQUIRED]).await?;
+        None
+    } else if buf.contains(&USERNAME_PASSWORD) {
+        stream.write_all(&[0x05, USERNAME_PASSWORD]).await?;
+        Some(authenticate(stream, users).await?)
+    } else {
+        stream.write_all(&[0x05, NO_ACCEPTABLE_METHODS]).await?;
+        bail!("socks client does not support username/password auth");
+    };
     buf.resize(4, 0);
     stream.read_exact(&mut buf).await?;
     let address: Address = match buf[3] {
@@ -139,10 +197,16 @@ pub async fn handshake_as_server(stream: &mut TcpStream) -> Result<Session> {
     let buf = [0x05, 0x00, 0x00, 0x01, 0x00, 0x00,0x00,0x00, 0x00,0x00];
     stream.write_all(&buf).await;
     let res = Session {
+        id: 0,
         destination: address,
         network: Network::TCP,
         local_peer: stream.local_addr().expect("local"),
         peer_address: stream.peer_addr().expect("peer"),
+        user,
+        inbound_tag: None,
+        app_protocol: None,
+        process: None,
+        uid: None,
     };
     Ok(res)
 }
@@ -2,45 +2,86 @@ use std::{sync::Arc};
 
 use async_trait::async_trait;
 use log::{debug, trace};
-use tokio::{net::{TcpStream, UdpSocket}};
+use tokio::net::TcpStream;
 
 use crate::{
     proxy::{
-        connect_to_remote_tcp, Address, Session, TcpOutboundHandlerTrait,
-        UdpOutboundHandlerTrait,
+        pool, Address, AnyStream, ConnectionPool, Dialer, Session, TcpOutboundHandlerTrait,
     },
     Context,
 };
 
-use super::handshake_as_client;
+use super::{connect_as_client, handshake_as_client, negotiate_as_client};
 
 pub struct TcpOutboundHandler {
-    pub address: Address
+    pub address: Address,
+    pub dialer: Arc<Dialer>,
+    // 已经完成方法协商的空闲连接
+    pub pool: Option<Arc<ConnectionPool<TcpStream>>>,
+}
+
+impl TcpOutboundHandler {
+    // 后台把连接池补满，第一个连接之后连接池才开始工作
+    fn refill(&self, ctx: &Arc<Context>, pool: &Arc<ConnectionPool<TcpStream>>) {
+        if !pool.begin_refill() {
+            return;
+        }
+        let pool = pool.clone();
+        let dialer = self.dialer.clone();
+        let address = self.address.clone();
+        let dns_client = ctx.dns_client.clone();
+        tokio::spawn(async move {
+            while !pool.is_full() {
+                let mut stream = match dialer.connect_tcp(dns_client.clone(), address.clone()).await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        debug!("warm up connection to socks proxy server {} failed {}", address, err);
+                        break;
+                    }
+                };
+                if let Err(err) = negotiate_as_client(&mut stream).await {
+                    debug!("warm up connection to socks proxy server {} failed {}", address, err);
+                    break;
+                }
+                if !pool.put(stream) {
+                    break;
+                }
+            }
+            pool.refill_done();
+        });
+    }
+
+    async fn connect_pooled(&self, pool: &ConnectionPool<TcpStream>, session: &Session) -> Option<TcpStream> {
+        let mut stream = pool.take_with(|x| pool::is_idle(x))?;
+        trace!("reuse idle connection to socks proxy server {}", self.address);
+        match connect_as_client(&mut stream, session).await {
+            Ok(_) => Some(stream),
+            Err(err) => {
+                debug!("idle connection to socks proxy server {} failed {}", self.address, err);
+                None
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl TcpOutboundHandlerTrait for TcpOutboundHandler {
-    async fn handle(&self, ctx: Arc<Context>, session: &Session) -> anyhow::Result<TcpStream> {
+    async fn handle(&self, ctx: Arc<Context>, session: &Session) -> anyhow::Result<AnyStream> {
+        if let Some(pool) = &self.pool {
+            let stream = self.connect_pooled(pool, session).await;
+            self.refill(&ctx, pool);
+            if let Some(stream) = stream {
+                return Ok(Box::new(stream));
+            }
+        }
         trace!("connect to socks proxy server {}", self.address);
-        let mut stream = connect_to_remote_tcp(ctx.dns_client.clone(), self.address.clone()).await?;
+        let mut stream = self.dialer.connect_tcp(ctx.dns_client.clone(), self.address.clone()).await?;
         match handshake_as_client(&mut stream, &session).await {
             Err(err) => {
                 debug!("{}", err);
             }
             _ => {}
         }
-        Ok(stream)
-    }
-}
-
-pub struct UdpOutboundHandler {
-    pub addr: String,
-    pub port: u16,
-}
-
-#[async_trait]
-impl UdpOutboundHandlerTrait for UdpOutboundHandler {
-    async fn handle(&self, _ctx: Arc<Context>, _session: &Session) -> anyhow::Result<UdpSocket> {
-        todo!()
+        Ok(Box::new(stream))
     }
 }
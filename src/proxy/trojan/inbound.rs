@@ -0,0 +1,198 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Once},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{debug, trace};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    common::buffer,
+    config::TrojanInboundSettings,
+    proxy::{relay::read_address, Address, Carried, InboundResult, Session, TcpInboundHandlerTrait, UdpDemux},
+    transport::tls::TlsAcceptor,
+};
+
+use super::{password_hash, read_packet, write_packet, CMD_CONNECT, CMD_UDP_ASSOCIATE, CRLF, HASH_LEN};
+
+// tls 握手到读完 trojan 请求的时间，读 hash 时超时交给 fallback，其余阶段超时关闭连接
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TcpInboundHandler {
+    acceptor: Arc<TlsAcceptor>,
+    passwords: HashSet<Vec<u8>>,
+    fallback: Option<SocketAddr>,
+    // 证书 reload 需要在 runtime 中启动
+    watch: Once,
+}
+
+impl TcpInboundHandler {
+    pub fn new(settings: &TrojanInboundSettings) -> anyhow::Result<TcpInboundHandler> {
+        let fallback = match &settings.fallback {
+            Some(x) => Some(x.parse::<SocketAddr>()?),
+            None => None,
+        };
+        Ok(TcpInboundHandler {
+            acceptor: Arc::new(TlsAcceptor::new(&settings.tls)?),
+            passwords: settings.passwords.iter().map(|x| password_hash(x)).collect(),
+            fallback,
+            watch: Once::new(),
+        })
+    }
+
+    // 读取 hash + CRLF 到 head，一旦出现不可能是 trojan header 的字节立即停止
+    // 返回是否是合法用户
+    async fn read_head<T>(&self, stream: &mut T, head: &mut Vec<u8>) -> io::Result<bool>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; HASH_LEN + 2];
+        while head.len() < HASH_LEN + CRLF.len() {
+            let n = stream.read(&mut buf[..HASH_LEN + CRLF.len() - head.len()]).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+            head.extend_from_slice(&buf[..n]);
+            let hash = &head[..head.len().min(HASH_LEN)];
+            let crlf = &head[hash.len()..];
+            if !hash.iter().all(|x| x.is_ascii_digit() || (b'a'..=b'f').contains(x))
+                || !CRLF.starts_with(crlf)
+            {
+                return Ok(false);
+            }
+        }
+        Ok(self.passwords.contains(&head[..HASH_LEN]))
+    }
+
+    // 非 trojan client 转发给 fallback，已经读到的数据原样发送
+    async fn fallback<T>(&self, mut stream: T, head: Vec<u8>, sess: &Session)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let addr = match self.fallback {
+            Some(x) => x,
+            None => {
+                debug!("not a trojan client {}, closed", sess.peer_address);
+                return;
+            }
+        };
+        trace!("not a trojan client {}, fallback to {}", sess.peer_address, addr);
+        let mut remote = match TcpStream::connect(addr).await {
+            Ok(x) => x,
+            Err(err) => {
+                debug!("connect to trojan fallback {} failed {}", addr, err);
+                return;
+            }
+        };
+        if let Err(err) = remote.write_all(&head).await {
+            debug!("write to trojan fallback {} failed {}", addr, err);
+            return;
+        }
+        if let Err(err) = buffer::copy_bidirectional(&mut stream, &mut remote).await {
+            debug!("trojan fallback {} error {}", addr, err);
+        }
+    }
+}
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, mut sess: Session, stream: TcpStream) -> io::Result<InboundResult> {
+        self.watch.call_once(|| {
+            tokio::spawn(self.acceptor.clone().watch());
+        });
+        // tls 握手与 trojan 请求共用一个期限，慢速 client 不能一直占用连接
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let mut stream = match timeout_at(deadline, self.acceptor.accept(stream)).await {
+            Ok(x) => x.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "trojan tls handshake timeout")),
+        };
+        let mut head = Vec::with_capacity(HASH_LEN + CRLF.len());
+        let valid = match timeout_at(deadline, self.read_head(&mut stream, &mut head)).await {
+            Ok(x) => x?,
+            Err(_) => false,
+        };
+        if !valid {
+            self.fallback(stream, head, &sess).await;
+            return Ok(InboundResult::Handled);
+        }
+        let (cmd, destination) = match timeout_at(deadline, read_request(&mut stream)).await {
+            Ok(x) => x?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "trojan request timeout")),
+        };
+        match cmd {
+            CMD_CONNECT => {
+                trace!("trojan client {} => {}", sess.peer_address, destination);
+                sess.destination = destination;
+                Ok(InboundResult::Tunneled(Box::new(stream), sess))
+            }
+            CMD_UDP_ASSOCIATE => {
+                trace!("trojan client {} udp associate", sess.peer_address);
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(relay_udp(stream, sess, tx));
+                Ok(InboundResult::Streams(rx))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown trojan cmd {}", cmd),
+            )),
+        }
+    }
+}
+
+// cmd + address + CRLF
+async fn read_request<T>(stream: &mut T) -> io::Result<(u8, Address)>
+where
+    T: AsyncRead + Unpin,
+{
+    let cmd = stream.read_u8().await?;
+    let destination = read_address(stream)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await?;
+    if crlf != CRLF {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad trojan request"));
+    }
+    Ok((cmd, destination))
+}
+
+// 每个 packet 的 destination 一个 flow，与 tcp 一样经过路由，由 outbound 发出
+// 请求中的地址只是占位，以每个 packet 的地址为准
+async fn relay_udp<T>(stream: T, sess: Session, tx: mpsc::UnboundedSender<(Carried, Session)>)
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = split(stream);
+    let (mut demux, mut replies) = UdpDemux::new(sess, tx);
+    let downlink = tokio::spawn(async move {
+        while let Some((from, payload)) = replies.recv().await {
+            if write_packet(&mut writer, &from, &payload).await.is_err() {
+                return;
+            }
+        }
+    });
+    // 读取出错或者连接关闭时结束，demux drop 之后全部 flow 随之结束
+    loop {
+        match read_packet(&mut reader).await {
+            Ok((destination, payload)) => {
+                if !demux.send(destination, payload).await {
+                    break;
+                }
+            }
+            Err(err) => {
+                debug!("trojan udp associate closed {}", err);
+                break;
+            }
+        }
+    }
+    downlink.abort();
+}
@@ -0,0 +1,90 @@
+// trojan 协议，tls 之上
+//
+// |<-hex(SHA224(password)) 56 bytes->|<-CRLF->|<-cmd 1 byte->|<-atyp 1 byte->|<-addr->|<-port 2 bytes->|<-CRLF->|<-payload->|
+// 地址格式与 socks5 相同
+// UDP ASSOCIATE 之后 payload 是连续的 udp packet
+// |<-atyp 1 byte->|<-addr->|<-port 2 bytes->|<-length 2 bytes->|<-CRLF->|<-payload->|
+// 不是合法 trojan 请求的连接（浏览器、探测）转发给 fallback，看起来就是普通的 https 网站
+// outbound 可以把 trojan 连接放在 h2/grpc stream 中（transport::h2），用于只转发 grpc 的 CDN
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    relay::{read_address, write_address},
+    Address,
+};
+
+mod inbound;
+mod outbound;
+
+pub use self::inbound::TcpInboundHandler;
+pub use self::outbound::TcpOutboundHandler;
+
+pub const HASH_LEN: usize = 56;
+pub const CRLF: &[u8] = b"\r\n";
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+/// hex(SHA224(password)), lower case
+pub fn password_hash(password: &str) -> Vec<u8> {
+    Sha224::digest(password.as_bytes())
+        .iter()
+        .flat_map(|b| format!("{:02x}", b).into_bytes())
+        .collect()
+}
+
+/// udp packet of an associated trojan connection
+pub async fn read_packet<T: AsyncRead + Unpin>(stream: &mut T) -> Result<(Address, Vec<u8>)> {
+    let address = read_address(stream).await?;
+    let len = stream.read_u16().await?;
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await?;
+    if crlf != CRLF {
+        bail!("bad trojan udp packet");
+    }
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok((address, payload))
+}
+
+pub async fn write_packet<T>(stream: &mut T, address: &Address, payload: &[u8]) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    if payload.len() > u16::MAX as usize {
+        bail!("trojan udp packet too large {}", payload.len());
+    }
+    let mut buf = Vec::with_capacity(payload.len() + 26);
+    write_address(&mut buf, address);
+    buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf.extend_from_slice(CRLF);
+    buf.extend_from_slice(payload);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+#[test]
+fn test_password_hash() {
+    let hash = password_hash("password");
+    assert_eq!(hash.len(), HASH_LEN);
+    assert_eq!(
+        std::str::from_utf8(&hash).unwrap(),
+        "d63dc919e201d7bc4c825630d2cf25fdc93d4b2f0d46706d29038d01"
+    );
+}
+
+#[tokio::test]
+async fn test_udp_packet() {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    let dns: Address = "1.1.1.1:53".parse().unwrap();
+    write_packet(&mut client, &dns, b"query").await.unwrap();
+    write_packet(&mut client, &Address::Domain("example.com".to_string(), 443), b"").await.unwrap();
+    let (address, payload) = read_packet(&mut server).await.unwrap();
+    assert_eq!(address.to_string(), "1.1.1.1:53");
+    assert_eq!(payload, b"query");
+    let (address, payload) = read_packet(&mut server).await.unwrap();
+    assert_eq!(address.to_string(), "example.com:443");
+    assert!(payload.is_empty());
+}
@@ -0,0 +1,160 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::{debug, trace};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio_rustls::client::TlsStream;
+
+use crate::{
+    config::{PoolSettings, TrojanOutboundSettings},
+    proxy::{
+        pool, relay::write_address, Address, AnyStream, ConnectionPool, Dialer, Session, TcpOutboundHandlerTrait,
+    },
+    transport::{h2::H2Client, tls::TlsConnector},
+    Context,
+};
+
+use super::{password_hash, CMD_CONNECT, CRLF};
+
+pub struct TcpOutboundHandler {
+    server: Address,
+    // 没有配置 sni 时使用
+    host: String,
+    hash: Vec<u8>,
+    dialer: Arc<Dialer>,
+    tls: Arc<TlsConnector>,
+    // trojan 连接作为 h2/grpc stream，全部 stream 复用一条 tls 连接，tls 使用 transport 的配置
+    h2: Option<H2Client>,
+    // 已经完成 tls 握手、还没有发送请求的空闲连接，请求头之前的部分与目标无关
+    pool: Option<Arc<ConnectionPool<TlsStream<TcpStream>>>>,
+}
+
+impl TcpOutboundHandler {
+    pub fn new(
+        settings: &TrojanOutboundSettings,
+        dialer: Arc<Dialer>,
+        pool: Option<&PoolSettings>,
+    ) -> Result<TcpOutboundHandler> {
+        let server = Address::try_from((settings.address.clone(), settings.port))?;
+        // h2 已经在一条连接上复用全部 stream
+        if pool.is_some() && settings.transport.is_some() {
+            bail!("connection pool is not supported with a trojan transport");
+        }
+        let (tls, h2) = match &settings.transport {
+            Some(transport) => {
+                let mut tls = transport.tls.clone();
+                tls.alpn.get_or_insert_with(|| vec!["h2".to_string()]);
+                (TlsConnector::new(&tls)?, Some(H2Client::new(transport, &settings.address)?))
+            }
+            None => (TlsConnector::new(&settings.tls)?, None),
+        };
+        Ok(TcpOutboundHandler {
+            server,
+            host: settings.address.clone(),
+            hash: password_hash(&settings.password),
+            dialer,
+            tls: Arc::new(tls),
+            h2,
+            pool: pool.map(|x| Arc::new(ConnectionPool::new(x))),
+        })
+    }
+
+    pub fn server(&self) -> &Address {
+        &self.server
+    }
+
+    // 后台把连接池补满，第一个连接之后连接池才开始工作
+    fn refill(&self, ctx: &Arc<Context>, pool: &Arc<ConnectionPool<TlsStream<TcpStream>>>) {
+        if !pool.begin_refill() {
+            return;
+        }
+        let pool = pool.clone();
+        let dialer = self.dialer.clone();
+        let tls = self.tls.clone();
+        let (server, host) = (self.server.clone(), self.host.clone());
+        let dns_client = ctx.dns_client.clone();
+        tokio::spawn(async move {
+            while !pool.is_full() {
+                let stream = match dialer.connect_tcp(dns_client.clone(), server.clone()).await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        debug!("warm up connection to trojan server {} failed {}", server, err);
+                        break;
+                    }
+                };
+                let stream = match tls.connect(&host, stream).await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        debug!("warm up connection to trojan server {} failed {}", server, err);
+                        break;
+                    }
+                };
+                if !pool.put(stream) {
+                    break;
+                }
+            }
+            pool.refill_done();
+        });
+    }
+
+    async fn connect(&self, ctx: Arc<Context>) -> Result<AnyStream> {
+        if let Some(pool) = &self.pool {
+            let stream = pool.take_with(|x| pool::is_idle_stream(x));
+            self.refill(&ctx, pool);
+            if let Some(stream) = stream {
+                trace!("reuse idle connection to trojan server {}", self.server);
+                return Ok(Box::new(stream));
+            }
+        }
+        let stream: AnyStream = match &self.h2 {
+            Some(client) => Box::new(
+                client
+                    .open(|| async {
+                        let stream = self.dialer.connect_tcp(ctx.dns_client.clone(), self.server.clone()).await?;
+                        let stream: AnyStream = Box::new(self.tls.connect(&self.host, stream).await?);
+                        Ok(stream)
+                    })
+                    .await?,
+            ),
+            None => {
+                let stream = self.dialer.connect_tcp(ctx.dns_client.clone(), self.server.clone()).await?;
+                Box::new(self.tls.connect(&self.host, stream).await?)
+            }
+        };
+        trace!("trojan connection established to {}", self.server);
+        Ok(stream)
+    }
+}
+
+pub fn request(hash: &[u8], cmd: u8, destination: &Address) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(hash.len() + 32);
+    buf.extend_from_slice(hash);
+    buf.extend_from_slice(CRLF);
+    buf.push(cmd);
+    write_address(&mut buf, destination);
+    buf.extend_from_slice(CRLF);
+    buf
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> Result<AnyStream> {
+        let mut stream = self.connect(ctx).await?;
+        stream.write_all(&request(&self.hash, CMD_CONNECT, &sess.destination)).await?;
+        stream.flush().await?;
+        Ok(stream)
+    }
+}
+
+#[test]
+fn test_trojan_request() {
+    let hash = password_hash("password");
+    let buf = request(&hash, CMD_CONNECT, &Address::Domain("example.com".to_string(), 443));
+    assert_eq!(&buf[..hash.len()], &hash[..]);
+    let rest = &buf[hash.len()..];
+    assert_eq!(&rest[..3], &[b'\r', b'\n', CMD_CONNECT]);
+    assert_eq!(rest[3], 0x03);
+    assert_eq!(rest[4] as usize, "example.com".len());
+    assert_eq!(&rest[rest.len() - 4..], &[0x01, 0xbb, b'\r', b'\n']);
+}
@@ -0,0 +1,67 @@
+// dns hijack
+// 目标端口为 53 的 udp packet 不管目标 ip 是什么都交给内部的 dns 处理，构造 udp 回复写回 tun
+// 写死 resolver（8.8.8.8）的设备与 app 也能使用 fake ip 与 split dns
+// tcp 53 在 TcpTun 的 listener 接收之后由 DnsServer 处理
+
+use std::sync::Arc;
+
+use log::trace;
+use tokio::sync::{mpsc, RwLock};
+
+use crate::app::{DnsClient, DnsServer};
+
+use super::udp::{build_reply, parse_udp, Datagram};
+
+pub const DNS_PORT: u16 = 53;
+
+/// udp packet to port 53
+pub fn parse_dns_query(packet: &[u8]) -> Option<Datagram> {
+    parse_udp(packet).filter(|x| x.dst.port() == DNS_PORT)
+}
+
+pub struct DnsHijack {
+    dns_client: Arc<RwLock<DnsClient>>,
+    // 写回 tun 的 packet
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl DnsHijack {
+    pub fn new(dns_client: Arc<RwLock<DnsClient>>, tx: mpsc::UnboundedSender<Vec<u8>>) -> DnsHijack {
+        DnsHijack { dns_client, tx }
+    }
+
+    pub fn handle(&self, query: Datagram) {
+        trace!("hijack dns query from {} to {}", query.src, query.dst);
+        let dns_client = self.dns_client.clone();
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let response = match DnsServer::handle(dns_client, &query.payload).await {
+                Some(x) => x,
+                None => return,
+            };
+            let _ = tx.send(build_reply(&query, &response));
+        });
+    }
+}
+
+#[test]
+fn test_dns_hijack_packet() {
+    let query = Datagram {
+        src: "10.0.0.2:40000".parse().unwrap(),
+        dst: "8.8.8.8:53".parse().unwrap(),
+        payload: vec![1, 2, 3],
+    };
+    let reply = build_reply(&query, &[4, 5, 6, 7]);
+    assert_eq!(&reply[12..16], &[8, 8, 8, 8]);
+    assert_eq!(&reply[16..20], &[10, 0, 0, 2]);
+    // 回复的方向反过来，不是发往 53 的请求
+    assert_eq!(parse_dns_query(&reply), None);
+    // 交换地址之后可以解析回来
+    let mut request = reply.clone();
+    request[12..16].copy_from_slice(&[10, 0, 0, 2]);
+    request[16..20].copy_from_slice(&[8, 8, 8, 8]);
+    request[20..24].copy_from_slice(&[0x9c, 0x40, 0, 53]);
+    let parsed = parse_dns_query(&request).unwrap();
+    assert_eq!(parsed.src, query.src);
+    assert_eq!(parsed.payload, vec![4, 5, 6, 7]);
+}
@@ -0,0 +1,231 @@
+// tun 中的 icmp echo (ping)
+// 之前 tun 只处理 tcp，ping 经过 tun 时一直超时，容易误以为网络不通
+//
+// Relay: 用 unprivileged ping socket (SOCK_DGRAM + IPPROTO_ICMP) 真正 ping 目标，收到回复后再构造 echo reply 写回 tun
+//        linux 需要 net.ipv4.ping_group_range 包含当前 gid
+// Synthesize: 直接构造 echo reply，只能说明 tunnel 本身在工作，不代表目标可达
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, trace};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::mpsc, time::timeout};
+
+use crate::common::buffer;
+
+const PROTO_ICMPV4: u8 = 1;
+const PROTO_ICMPV6: u8 = 58;
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+const IPV6_HEADER_LEN: usize = 40;
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IcmpMode {
+    Relay,
+    Synthesize,
+}
+
+#[derive(Debug, Clone)]
+pub struct EchoRequest {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub seq: u16,
+    pub payload: Vec<u8>,
+}
+
+// 返回 icmp message 在 packet 中的范围，packet 可能比 ip total length 长
+fn icmp_range(packet: &[u8]) -> Option<(usize, usize)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let ihl = ((packet[0] & 0x0f) as usize) * 4;
+            let total = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+            if packet.len() < total || total < ihl + 8 || packet[9] != PROTO_ICMPV4 {
+                return None;
+            }
+            Some((ihl, total))
+        }
+        6 => {
+            let payload_len = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize;
+            let total = IPV6_HEADER_LEN + payload_len;
+            // 不处理扩展头
+            if packet.len() < total || payload_len < 8 || packet[6] != PROTO_ICMPV6 {
+                return None;
+            }
+            Some((IPV6_HEADER_LEN, total))
+        }
+        _ => None,
+    }
+}
+
+fn addresses(packet: &[u8]) -> (IpAddr, IpAddr) {
+    if packet[0] >> 4 == 4 {
+        let mut src = [0u8; 4];
+        let mut dst = [0u8; 4];
+        src.copy_from_slice(&packet[12..16]);
+        dst.copy_from_slice(&packet[16..20]);
+        (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into())
+    } else {
+        let mut src = [0u8; 16];
+        let mut dst = [0u8; 16];
+        src.copy_from_slice(&packet[8..24]);
+        dst.copy_from_slice(&packet[24..40]);
+        (Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into())
+    }
+}
+
+pub fn parse_echo_request(packet: &[u8]) -> Option<EchoRequest> {
+    let (start, end) = icmp_range(packet)?;
+    let icmp = &packet[start..end];
+    let expected = if packet[0] >> 4 == 4 { ECHO_REQUEST_V4 } else { ECHO_REQUEST_V6 };
+    if icmp[0] != expected {
+        return None;
+    }
+    let (src, dst) = addresses(packet);
+    Some(EchoRequest {
+        src,
+        dst,
+        seq: u16::from_be_bytes([icmp[6], icmp[7]]),
+        payload: icmp[8..].to_vec(),
+    })
+}
+
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// turn an echo request ip packet into the matching echo reply
+pub fn build_echo_reply(request: &[u8]) -> Option<Vec<u8>> {
+    let (start, end) = icmp_range(request)?;
+    let mut packet = request[..end].to_vec();
+    if packet[0] >> 4 == 4 {
+        let (src, dst) = (request[12..16].to_vec(), request[16..20].to_vec());
+        packet[12..16].copy_from_slice(&dst);
+        packet[16..20].copy_from_slice(&src);
+        packet[8] = 64;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let ip_sum = checksum(&packet[..start], 0);
+        packet[10..12].copy_from_slice(&ip_sum.to_be_bytes());
+        packet[start] = ECHO_REPLY_V4;
+        packet[start + 2..start + 4].copy_from_slice(&[0, 0]);
+        let icmp_sum = checksum(&packet[start..end], 0);
+        packet[start + 2..start + 4].copy_from_slice(&icmp_sum.to_be_bytes());
+    } else {
+        let (src, dst) = (request[8..24].to_vec(), request[24..40].to_vec());
+        packet[8..24].copy_from_slice(&dst);
+        packet[24..40].copy_from_slice(&src);
+        packet[7] = 64;
+        packet[start] = ECHO_REPLY_V6;
+        packet[start + 2..start + 4].copy_from_slice(&[0, 0]);
+        // pseudo header: src, dst, upper layer length, next header
+        let mut pseudo = Vec::with_capacity(40);
+        pseudo.extend_from_slice(&packet[8..40]);
+        pseudo.extend_from_slice(&((end - start) as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, PROTO_ICMPV6]);
+        let initial = pseudo
+            .chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]) as u32)
+            .sum::<u32>();
+        let icmp_sum = checksum(&packet[start..end], initial);
+        packet[start + 2..start + 4].copy_from_slice(&icmp_sum.to_be_bytes());
+    }
+    Some(packet)
+}
+
+pub struct IcmpHandler {
+    mode: IcmpMode,
+    // 写回 tun 的 packet
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl IcmpHandler {
+    /// relay falls back to synthesize when ping sockets are not permitted
+    pub fn new(mode: IcmpMode, tx: mpsc::UnboundedSender<Vec<u8>>) -> IcmpHandler {
+        let mode = match mode {
+            IcmpMode::Relay if ping_socket(Domain::IPV4, Protocol::ICMPV4).is_err() => {
+                debug!("ping socket not permitted, icmp echo replies are synthesized");
+                IcmpMode::Synthesize
+            }
+            m => m,
+        };
+        IcmpHandler { mode, tx }
+    }
+
+    pub fn handle(&self, packet: &[u8], request: EchoRequest) {
+        let reply = match build_echo_reply(packet) {
+            Some(x) => x,
+            None => return,
+        };
+        match self.mode {
+            IcmpMode::Synthesize => {
+                let _ = self.tx.send(reply);
+            }
+            IcmpMode::Relay => {
+                let tx = self.tx.clone();
+                tokio::spawn(async move {
+                    match timeout(PING_TIMEOUT, ping(&request)).await {
+                        Ok(Ok(())) => {
+                            let _ = tx.send(reply);
+                        }
+                        Ok(Err(err)) => debug!("ping {} failed {}", request.dst, err),
+                        Err(_) => trace!("ping {} seq {} timeout", request.dst, request.seq),
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn ping_socket(domain: Domain, protocol: Protocol) -> io::Result<UdpSocket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// 内核会替换 identifier，所以只比较 seq 与 payload
+async fn ping(request: &EchoRequest) -> io::Result<()> {
+    let (socket, ty, reply_ty) = match request.dst {
+        IpAddr::V4(..) => (ping_socket(Domain::IPV4, Protocol::ICMPV4)?, ECHO_REQUEST_V4, ECHO_REPLY_V4),
+        IpAddr::V6(..) => (ping_socket(Domain::IPV6, Protocol::ICMPV6)?, ECHO_REQUEST_V6, ECHO_REPLY_V6),
+    };
+    let mut message = vec![ty, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&request.seq.to_be_bytes());
+    message.extend_from_slice(&request.payload);
+    let sum = checksum(&message, 0);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    socket.send_to(&message, SocketAddr::new(request.dst, 0)).await?;
+    let mut buf = buffer::get(buffer::LARGE);
+    loop {
+        let (n, _) = socket.recv_from(&mut buf).await?;
+        let mut reply = &buf[..n];
+        // macos 的 icmpv4 dgram socket 返回的数据包含 ip header
+        if cfg!(target_os = "macos") && request.dst.is_ipv4() && n >= 20 && reply[0] >> 4 == 4 {
+            reply = &reply[((reply[0] & 0x0f) as usize) * 4..];
+        }
+        if reply.len() >= 8
+            && reply[0] == reply_ty
+            && reply[6..8] == request.seq.to_be_bytes()
+            && reply[8..] == request.payload[..]
+        {
+            return Ok(());
+        }
+    }
+}
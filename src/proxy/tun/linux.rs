@@ -0,0 +1,243 @@
+// linux tun 设备的多队列与 offload，tun crate 不支持这些 flag，直接通过 ioctl 创建
+//
+// multi queue: IFF_MULTI_QUEUE，同一个设备打开多个 fd，内核按流把 packet 分散到各个队列
+//
+// offload: IFF_VNET_HDR + TUNSETOFFLOAD
+// 内核把同一条流的多个 tcp segment 合并为一个最大 64KB 的 packet 交给我们 (GRO)，
+// 写入的大 packet 由内核切分 (TSO)，一次系统调用处理几十个 segment
+// 每个 packet 前面是 virtio_net_hdr，nat 只改写地址、端口与 checksum，header 长度不变，所以大 packet 原样写回即可
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::IpAddr,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    process::Command,
+};
+
+use tokio::io::unix::AsyncFd;
+
+pub const VNET_HDR_LEN: usize = 10;
+// 64KB ip packet + virtio_net_hdr
+pub const MAX_FRAME: usize = 65535 + VNET_HDR_LEN;
+
+// linux/if_tun.h
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
+const TUNSETVNETHDRSZ: libc::c_ulong = 0x4004_54d8;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_MULTI_QUEUE: libc::c_short = 0x0100;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+const TUN_F_CSUM: libc::c_uint = 0x01;
+const TUN_F_TSO4: libc::c_uint = 0x02;
+const TUN_F_TSO6: libc::c_uint = 0x04;
+
+// linux/virtio_net.h
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+#[repr(C)]
+struct Ifreq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// struct virtio_net_hdr, native endian
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    pub fn parse(data: &[u8]) -> Option<VirtioNetHdr> {
+        let data = data.get(..VNET_HDR_LEN)?;
+        let u16_at = |i: usize| u16::from_ne_bytes([data[i], data[i + 1]]);
+        Some(VirtioNetHdr {
+            flags: data[0],
+            gso_type: data[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+    }
+
+    // checksum 只包含 pseudo header，由内核或网卡补全
+    pub fn needs_csum(&self) -> bool {
+        self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0
+    }
+}
+
+fn sum16(sum: &mut u32, bytes: &[u8]) {
+    for chunk in bytes.chunks(2) {
+        *sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+    }
+}
+
+/// tcp pseudo header checksum, not complemented, for NEEDS_CSUM packets
+pub fn pseudo_header_checksum(src: IpAddr, dst: IpAddr, tcp_len: usize) -> u16 {
+    let mut sum = 0u32;
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            sum16(&mut sum, &src.octets());
+            sum16(&mut sum, &dst.octets());
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            sum16(&mut sum, &src.octets());
+            sum16(&mut sum, &dst.octets());
+        }
+        _ => {}
+    }
+    sum += libc::IPPROTO_TCP as u32;
+    sum16(&mut sum, &(tcp_len as u32).to_be_bytes());
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn ioctl_error(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ip(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("ip").args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("ip {:?} exit status {}", args, status)));
+    }
+    Ok(())
+}
+
+/// one queue of a tun device, optionally with virtio_net_hdr and tcp segmentation offloads
+pub struct TunQueue {
+    fd: AsyncFd<File>,
+    name: String,
+    offload: bool,
+}
+
+impl TunQueue {
+    /// open a queue of the device name, creating it if needed
+    /// all queues of a multi queue device must be opened with the same flags
+    pub fn open(name: &str, offload: bool, multi_queue: bool) -> io::Result<TunQueue> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "tun name too long"));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+        let mut flags = IFF_TUN | IFF_NO_PI;
+        if offload {
+            flags |= IFF_VNET_HDR;
+        }
+        if multi_queue {
+            flags |= IFF_MULTI_QUEUE;
+        }
+        let mut req = Ifreq {
+            name: [0; libc::IFNAMSIZ],
+            flags,
+            _pad: [0; 22],
+        };
+        req.name[..name.len()].copy_from_slice(name.as_bytes());
+        let hdr_len = VNET_HDR_LEN as libc::c_int;
+        let fd = file.as_raw_fd();
+        unsafe {
+            ioctl_error(libc::ioctl(fd, TUNSETIFF, &mut req as *mut Ifreq))?;
+            if offload {
+                ioctl_error(libc::ioctl(fd, TUNSETVNETHDRSZ, &hdr_len as *const libc::c_int))?;
+                ioctl_error(libc::ioctl(fd, TUNSETOFFLOAD, TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6))?;
+            }
+        }
+        let len = req.name.iter().position(|x| *x == 0).unwrap_or(req.name.len());
+        let name = String::from_utf8_lossy(&req.name[..len]).to_string();
+        Ok(TunQueue {
+            fd: AsyncFd::new(file)?,
+            name,
+            offload,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// length of the virtio_net_hdr before each packet
+    pub fn header_len(&self) -> usize {
+        if self.offload {
+            VNET_HDR_LEN
+        } else {
+            0
+        }
+    }
+
+    pub fn frame_size(&self, mtu: u16) -> usize {
+        if self.offload {
+            MAX_FRAME
+        } else {
+            mtu as usize
+        }
+    }
+
+    // tun crate 只能配置自己创建的设备，这里通过 ip 命令配置
+    pub fn configure(&self, address: &str, mtu: u16) -> io::Result<()> {
+        ip(&["addr", "add", address, "dev", &self.name])?;
+        ip(&["link", "set", "dev", &self.name, "mtu", &mtu.to_string(), "up"])
+    }
+
+    /// read one frame, with offload a virtio_net_hdr followed by an ip packet of up to 64KB
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            if let Ok(res) = guard.try_io(|fd| (&*fd.get_ref()).read(buf)) {
+                return res;
+            }
+        }
+    }
+
+    pub async fn send(&self, frame: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            if let Ok(res) = guard.try_io(|fd| (&*fd.get_ref()).write(frame)) {
+                return res;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_virtio_net_hdr() {
+    let hdr = VirtioNetHdr {
+        flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+        gso_type: 1,
+        hdr_len: 52,
+        gso_size: 1448,
+        csum_start: 20,
+        csum_offset: 16,
+    };
+    let mut buf = [0u8; VNET_HDR_LEN];
+    hdr.write(&mut buf);
+    assert_eq!(VirtioNetHdr::parse(&buf), Some(hdr));
+    assert!(hdr.needs_csum());
+    // 10.0.0.1 => 10.0.0.2, tcp length 20
+    let sum = pseudo_header_checksum([10, 0, 0, 1].into(), [10, 0, 0, 2].into(), 20);
+    assert_eq!(sum, 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 6 + 20);
+}
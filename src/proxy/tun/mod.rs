@@ -1,59 +1,321 @@
 use etherparse::{
     IpHeader, PacketHeaders, ReadError, TransportHeader,
 };
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use log::error;
 use std::{
     error::Error,
+    future::pending,
     io::{self, Cursor, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    task::AbortHandle,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tun::{AsyncDevice, Device, Layer};
 
+use crate::{
+    app::{Dispatcher, Limiter},
+    common::buffer,
+    config::TunInboundSettings,
+};
+
+use dns::DnsHijack;
+use icmp::{IcmpHandler, IcmpMode};
+pub use tcp::{TcpTimeouts, TcpTuning};
 use tcp::TcpTun;
+use udp::UdpTun;
+mod dns;
+mod icmp;
+#[cfg(target_os = "linux")]
+mod linux;
 mod tcp;
-pub struct Tun {
-    device: AsyncDevice,
+mod udp;
+const DEFAULT_MTU: u16 = 1500;
+// ULA, 与 10.0.0.1/24 对应
+const TUN_IPV6: &str = "fd00:7475:6e::1/64";
+
+// 添加地址时内核同时添加该 /64 的路由
+fn add_ipv6_address(name: &str, network: &Ipv6Net) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    let status = std::process::Command::new("ip")
+        .args(&["-6", "addr", "add", &network.to_string(), "dev", name])
+        .status()?;
+    #[cfg(not(target_os = "linux"))]
+    let status = std::process::Command::new("ifconfig")
+        .args(&[name, "inet6", &network.addr().to_string(), "prefixlen", &network.prefix_len().to_string()])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::new(ErrorKind::Other, format!("exit status {}", status)));
+    }
+    Ok(())
+}
+
+const TUN_ADDRESS: &str = "10.0.0.1";
+#[cfg(target_os = "linux")]
+const LINUX_TUN_NAME: &str = "tunnel0";
+
+enum TunDevice {
+    Plain(AsyncDevice),
+    // 自己通过 ioctl 打开的队列，支持 offload 与多队列
+    #[cfg(target_os = "linux")]
+    Queue(linux::TunQueue, u16),
+}
+
+impl TunDevice {
+    fn name(&self) -> String {
+        match self {
+            TunDevice::Plain(device) => device.get_ref().name().to_string(),
+            #[cfg(target_os = "linux")]
+            TunDevice::Queue(queue, _) => queue.name().to_string(),
+        }
+    }
+
+    fn frame_size(&self) -> usize {
+        match self {
+            TunDevice::Plain(device) => device.get_ref().mtu().expect("mtu") as usize,
+            #[cfg(target_os = "linux")]
+            TunDevice::Queue(queue, mtu) => queue.frame_size(*mtu),
+        }
+    }
+
+    fn header_len(&self) -> usize {
+        match self {
+            TunDevice::Plain(_) => 0,
+            #[cfg(target_os = "linux")]
+            TunDevice::Queue(queue, _) => queue.header_len(),
+        }
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TunDevice::Plain(device) => device.read(buf).await,
+            #[cfg(target_os = "linux")]
+            TunDevice::Queue(queue, _) => queue.recv(buf).await,
+        }
+    }
+
+    // frame 包含 header_len 长度的 header
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            TunDevice::Plain(device) => device.write_all(frame).await,
+            #[cfg(target_os = "linux")]
+            TunDevice::Queue(queue, _) => queue.send(frame).await.map(|_| ()),
+        }
+    }
+
+    // 本地构造的 ip packet，checksum 已经完整，不需要 offload
+    async fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            TunDevice::Plain(device) => device.write_all(packet).await,
+            #[cfg(target_os = "linux")]
+            TunDevice::Queue(queue, _) => {
+                let mut frame = vec![0u8; queue.header_len()];
+                frame.extend_from_slice(packet);
+                queue.send(&frame).await.map(|_| ())
+            }
+        }
+    }
+
+    // NEEDS_CSUM 的 packet 写回时 tcp checksum 也只能包含 pseudo header，由内核补全
+    #[cfg(target_os = "linux")]
+    fn partial_checksum(&self, header: &[u8]) -> bool {
+        linux::VirtioNetHdr::parse(header).map_or(false, |x| x.needs_csum())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn partial_checksum(&self, _header: &[u8]) -> bool {
+        false
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn partial_tcp_checksum(src: IpAddr, dst: IpAddr, tcp_len: usize) -> u16 {
+    linux::pseudo_header_checksum(src, dst, tcp_len)
+}
+
+// 只有 offload 设备会产生 NEEDS_CSUM 的 packet
+#[cfg(not(target_os = "linux"))]
+fn partial_tcp_checksum(_src: IpAddr, _dst: IpAddr, _tcp_len: usize) -> u16 {
+    unreachable!("partial checksum without tun offload")
+}
+
+// 所有队列共享的 nat 与 icmp 处理
+struct Stack {
     tcp_tun: TcpTun,
+    icmp: IcmpHandler,
+    // 开启 dns_hijack 时处理全部发往 53 端口的 udp
+    dns: Option<DnsHijack>,
+    udp: UdpTun,
+}
+
+pub struct Tun {
+    // 多队列时每个队列一个 fd
+    devices: Vec<TunDevice>,
+    stack: Arc<Stack>,
+    // icmp 等异步产生的回复
+    replies: mpsc::UnboundedReceiver<Vec<u8>>,
 }
 
 impl Tun {
-    pub async fn new() -> io::Result<Tun> {
+    /// tcp accepted from the device is routed by dispatcher as connections of inbound tag,
+    /// tcp connections and udp flows count against limiter
+    pub async fn new(
+        settings: &TunInboundSettings,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Arc<Limiter>,
+        tag: String,
+    ) -> io::Result<Tun> {
         let mut config = tun::Configuration::default();
         let netmask = 24;
-        config.address("10.0.0.1").netmask(24).layer(Layer::L3).up();
-        let device = match tun::create_as_async(&config) {
-            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
-            Ok(x) => x,
-        };
-        let tun_address = match device.get_ref().address() {
-            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
-            Ok(x) => x,
+        let mtu = settings.mtu.unwrap_or(DEFAULT_MTU);
+        let queues = settings.queues.unwrap_or(1).max(1);
+        let tun_address: Ipv4Addr = TUN_ADDRESS.parse().expect("tun address");
+        let devices = if settings.offload || queues > 1 {
+            Tun::open_queues(tun_address, netmask, mtu, settings.offload, queues)?
+        } else {
+            config
+                .address(tun_address)
+                .netmask(24)
+                .mtu(mtu as i32)
+                .layer(Layer::L3)
+                .up();
+            match tun::create_as_async(&config) {
+                Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+                Ok(x) => vec![TunDevice::Plain(x)],
+            }
         };
         let tun_network = Ipv4Net::new(tun_address, netmask).expect("ipv4 net new");
-        let tcp_tun = TcpTun::new(tun_network.into())
-            .await
-            .expect("tcp tun error");
-        Ok(Tun { device, tcp_tun })
-    }
-    pub async fn run(mut self) -> io::Result<()> {
-        let mtu = self.device.get_mut().mtu().expect("mtu");
-        let mut packet = vec![0u8; mtu as usize].into_boxed_slice();
-        // self.device.read(buf)
+        let mut networks: Vec<IpNet> = vec![tun_network.into()];
+        // tun crate 只支持配置 ipv4 地址，ipv6 通过系统命令添加
+        let tun_network6: Ipv6Net = TUN_IPV6.parse().expect("ipv6 net");
+        match add_ipv6_address(&devices[0].name(), &tun_network6) {
+            Ok(()) => networks.push(tun_network6.into()),
+            Err(err) => error!("add ipv6 address to tun failed {}, ipv6 disabled", err),
+        }
+        let hijack = if settings.dns_hijack { Some(dispatcher.dns_client()) } else { None };
+        let tcp_tun = TcpTun::new(
+            networks,
+            TcpTimeouts::new(&settings.tcp),
+            TcpTuning::new(&settings.tcp, mtu),
+            devices.len(),
+            hijack.clone(),
+            dispatcher.clone(),
+            limiter.clone(),
+            tag.clone(),
+        )
+        .await?;
+        let (tx, replies) = mpsc::unbounded_channel();
+        let dns = hijack.map(|x| DnsHijack::new(x, tx.clone()));
+        let udp = UdpTun::new(dispatcher, limiter, tag, tx.clone());
+        let icmp = IcmpHandler::new(IcmpMode::Relay, tx);
+        Ok(Tun {
+            devices,
+            stack: Arc::new(Stack { tcp_tun, icmp, dns, udp }),
+            replies,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_queues(address: Ipv4Addr, netmask: u8, mtu: u16, offload: bool, queues: usize) -> io::Result<Vec<TunDevice>> {
+        let mut devices = Vec::with_capacity(queues);
+        for _ in 0..queues {
+            let queue = linux::TunQueue::open(LINUX_TUN_NAME, offload, queues > 1)?;
+            if devices.is_empty() {
+                queue.configure(&format!("{}/{}", address, netmask), mtu)?;
+            }
+            devices.push(TunDevice::Queue(queue, mtu));
+        }
+        Ok(devices)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open_queues(_address: Ipv4Addr, _netmask: u8, _mtu: u16, _offload: bool, _queues: usize) -> io::Result<Vec<TunDevice>> {
+        Err(io::Error::new(ErrorKind::Other, "tun offload and multiple queues are only supported on linux"))
+    }
+
+    /// one reader/writer task per queue, returns when any of them fails and the others have stopped
+    pub async fn run(self) -> io::Result<()> {
+        let Tun { devices, stack, replies } = self;
+        let mut replies = Some(replies);
+        let tasks: Vec<_> = devices
+            .into_iter()
+            // icmp 等异步产生的回复只由第一个队列写回
+            .map(|device| tokio::spawn(Tun::run_queue(device, stack.clone(), replies.take())))
+            .collect();
+        // inbound 停止时 run 被 abort，队列 task 随之结束并释放设备
+        let guard = AbortTasks(tasks.iter().map(|x| x.abort_handle()).collect());
+        let (res, _, remaining) = futures::future::select_all(tasks).await;
+        // 一个队列结束后设备已经不完整，等其余队列真正退出之后再返回
+        drop(guard);
+        futures::future::join_all(remaining).await;
+        res.map_err(|err| io::Error::new(ErrorKind::Other, err))?
+    }
+
+    async fn run_queue(
+        mut device: TunDevice,
+        stack: Arc<Stack>,
+        mut replies: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+    ) -> io::Result<()> {
+        let mut frame = buffer::get(device.frame_size());
+        let header_len = device.header_len();
         loop {
             tokio::select! {
-                n = self.device.read(&mut packet) => {
+                n = device.read(&mut frame) => {
                     let n = n?;
-                    if self.handle_ip_packet(&mut packet).await? {
-                        self.device.write_all(&packet).await?;
+                    if n <= header_len {
+                        continue;
+                    }
+                    let partial_checksum = device.partial_checksum(&frame[..header_len]);
+                    if stack.handle_ip_packet(&mut frame[header_len..n], partial_checksum).await? {
+                        device.write_frame(&frame[..n]).await?;
                     };
-                    println!("{} bytes read", n);
+                }
+                Some(reply) = next_reply(&mut replies) => {
+                    device.write_packet(&reply).await?;
                 }
             }
         }
     }
-    async fn handle_ip_packet(&self, packet: &mut [u8]) -> io::Result<bool> {
+}
+
+struct AbortTasks(Vec<AbortHandle>);
+
+impl Drop for AbortTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+async fn next_reply(replies: &mut Option<mpsc::UnboundedReceiver<Vec<u8>>>) -> Option<Vec<u8>> {
+    match replies {
+        Some(replies) => replies.recv().await,
+        None => pending().await,
+    }
+}
+
+impl Stack {
+    async fn handle_ip_packet(&self, packet: &mut [u8], partial_checksum: bool) -> io::Result<bool> {
+        // etherparse 不解析 icmp，先单独处理 echo request
+        if let Some(request) = icmp::parse_echo_request(packet) {
+            self.icmp.handle(packet, request);
+            return Ok(false);
+        }
+        if let Some(dns) = &self.dns {
+            if let Some(query) = dns::parse_dns_query(packet) {
+                dns.handle(query);
+                return Ok(false);
+            }
+        }
+        if let Some(datagram) = udp::parse_udp(packet) {
+            self.udp.handle(datagram);
+            return Ok(false);
+        }
         let mut ip_packet = match PacketHeaders::from_ip_slice(packet) {
             Ok(ip) => ip,
             Err(ReadError::IoError(err)) => return Err(err),
@@ -114,16 +376,21 @@ impl Tun {
                     _ => unreachable!("dest ip replace unreachable!"),
                 }
                 // calculate tcp checksum
-                match ip_header {
-                    IpHeader::Version4(v4_ip_header) => {
-                        tcp_header.checksum = tcp_header
-                            .calc_checksum_ipv4(&v4_ip_header, ip_packet.payload)
-                            .expect("tcp calculate check sum error")
-                    }
-                    IpHeader::Version6(v6_ip_header) => {
-                        tcp_header.checksum = tcp_header
-                            .calc_checksum_ipv6(&v6_ip_header, ip_packet.payload)
-                            .expect("tcp calculate check sum error")
+                if partial_checksum {
+                    let tcp_len = tcp_header.header_len() as usize + payload_len;
+                    tcp_header.checksum = partial_tcp_checksum(final_src_addr.ip(), final_dest_addr.ip(), tcp_len);
+                } else {
+                    match ip_header {
+                        IpHeader::Version4(v4_ip_header) => {
+                            tcp_header.checksum = tcp_header
+                                .calc_checksum_ipv4(&v4_ip_header, ip_packet.payload)
+                                .expect("tcp calculate check sum error")
+                        }
+                        IpHeader::Version6(v6_ip_header) => {
+                            tcp_header.checksum = tcp_header
+                                .calc_checksum_ipv6(&v6_ip_header, ip_packet.payload)
+                                .expect("tcp calculate check sum error")
+                        }
                     }
                 }
                 let (headers, ..) = packet.split_at_mut(packet.len() - payload_len);
@@ -136,8 +403,8 @@ impl Tun {
                     .write(&mut cursor)
                     .expect("tcp header write failed!");
             }
-            Some(TransportHeader::Udp(ref mut _udp_header)) => {}
-            None => {}
+            // 带扩展头等不支持的 udp packet，不能原样写回
+            Some(TransportHeader::Udp(..)) | None => return Ok(false),
         };
         Ok(true)
     }
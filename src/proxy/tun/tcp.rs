@@ -1,17 +1,31 @@
 use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
     io,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use etherparse::TcpHeader;
 use ipnet::{IpNet};
-use log::error;
+use log::{debug, error};
 use lru_time_cache::LruCache;
-use tokio::{net::TcpStream, sync::Mutex};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
 
-use crate::net::{ProxyStream, ProxyTcpListener};
+use crate::{
+    app::{supervisor, trace, Admission, Dispatcher, DnsClient, DnsServer, Limiter},
+    config::TunTcpSettings,
+    net::ProxyTcpListener,
+    proxy::{Address, Network, Session},
+};
 
 pub struct Nat {
     // fake ip to real_src_ip
@@ -29,12 +43,212 @@ impl Nat {
         }
     }
 }
-pub struct TcpTun {
+    // 分配 fake 地址并记录新连接，port 生成候选端口
+    fn insert(
+        &mut self,
+        src_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        free_address: &[IpAddr],
+        mut port: impl FnMut() -> u16,
+    ) -> SocketAddr {
+        loop {
+            let addr_index = rand::random::<usize>() % free_address.len();
+            let fake_addr = SocketAddr::new(free_address[addr_index], port());
+            if !self.connections.contains_key(&fake_addr) {
+                // mapping record will be created at first time to establish tcp connection.
+                // so key will always be (original_src_ip, original_dest_ip)
+                self.mapping.insert((src_addr, dest_addr), fake_addr);
+                self.connections.insert(
+                    fake_addr,
+                    TcpConnection {
+                        src_addr,
+                        dest_addr,
+                        fake_addr,
+                        state: State::Established,
+                        since: Instant::now(),
+                        last_seen: Instant::now(),
+                    },
+                );
+                return fake_addr;
+            }
+        }
+    }
+}
+
+// 多队列时 nat 按 fake 端口分片，减少队列之间的锁竞争
+// 新连接分配 fake 端口时保证 port % shards == hash(src, dest) % shards，
+// 同一连接两个方向的 packet 无论从哪个队列读到都落在同一个分片
+struct NatTable {
+    shards: Vec<Mutex<Nat>>,
+}
+
+impl NatTable {
+    fn new(shards: usize) -> NatTable {
+        NatTable {
+            shards: (0..shards.max(1)).map(|_| Mutex::new(Nat::new())).collect(),
+        }
+    }
+
+    fn flow_shard(&self, src_addr: &SocketAddr, dest_addr: &SocketAddr) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (src_addr, dest_addr).hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    fn fake_shard(&self, fake_addr: &SocketAddr) -> usize {
+        fake_addr.port() as usize % self.shards.len()
+    }
+
+    // 属于 shard 的随机端口，1024 below are privilege ports
+    fn fake_port(&self, shard: usize) -> u16 {
+        let n = self.shards.len();
+        let low = (1024 + n - 1) / n;
+        let high = 65536 / n;
+        let base = low + rand::random::<usize>() % (high - low);
+        (base * n + shard) as u16
+    }
+}
+
+// 每个地址族一个 listener 与 fake ip 池，ip header 改写后必须与原来的地址族一致
+struct Pool {
     free_address: Vec<IpAddr>,
-    nat: Arc<Mutex<Nat>>,
     listener_addr: SocketAddr,
 }
 
+// 半关闭与无流量连接的超时，app 异常退出时 nat 记录不会一直占用端口与内存
+#[derive(Debug, Clone, Copy)]
+pub struct TcpTimeouts {
+    // 一端发送 FIN 后（另一端处于 CLOSE_WAIT）等待另一端 FIN 的时间
+    pub fin_wait: Duration,
+    // 双方都发送 FIN 后等待最后一个 ACK 的时间
+    pub last_ack: Duration,
+    // established 但长时间没有任何 packet
+    pub orphan: Duration,
+}
+
+impl Default for TcpTimeouts {
+    fn default() -> Self {
+        TcpTimeouts {
+            fin_wait: Duration::from_secs(60),
+            last_ack: Duration::from_secs(30),
+            orphan: Duration::from_secs(2 * 60 * 60),
+        }
+    }
+}
+
+impl TcpTimeouts {
+    pub fn new(settings: &TunTcpSettings) -> TcpTimeouts {
+        let default = TcpTimeouts::default();
+        TcpTimeouts {
+            fin_wait: settings.fin_wait.map_or(default.fin_wait, Duration::from_secs),
+            last_ack: settings.last_ack.map_or(default.last_ack, Duration::from_secs),
+            orphan: settings.orphan.map_or(default.orphan, Duration::from_secs),
+        }
+    }
+}
+
+// tun 后面是内核的 tcp 栈（nat 到本地 listener），窗口、window scale、SACK 都由内核与 app 协商
+// 这里通过 listener 的 socket 选项以及改写经过 tun 的 SYN 中的 option 来调整
+#[derive(Debug, Clone, Copy)]
+pub struct TcpTuning {
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+    pub sack: bool,
+    // SYN 中 MSS 的上限由 mtu 计算，None 不改写
+    pub mtu: Option<u16>,
+    pub delayed_ack: bool,
+    pub nodelay: bool,
+}
+
+impl TcpTuning {
+    pub fn new(settings: &TunTcpSettings, mtu: u16) -> TcpTuning {
+        TcpTuning {
+            recv_buffer: settings.recv_window,
+            send_buffer: settings.send_buffer,
+            sack: settings.sack.unwrap_or(true),
+            mtu: if settings.mss_clamp.unwrap_or(true) { Some(mtu) } else { None },
+            delayed_ack: settings.delayed_ack.unwrap_or(true),
+            nodelay: settings.nodelay,
+        }
+    }
+
+    // ip header 20/40 bytes, tcp header 20 bytes
+    fn mss(&self, addr: &SocketAddr) -> Option<u16> {
+        let overhead = if addr.is_ipv6() { 60 } else { 40 };
+        self.mtu.map(|x| x.saturating_sub(overhead))
+    }
+}
+
+// 改写 SYN 中的 option，header 原地写回，所以长度不能变
+// MSS 不超过 mss，关闭 sack 时 SACK permitted 替换为两个 NOP
+fn rewrite_syn_options(options: &mut [u8], mss: Option<u16>, sack: bool) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            // end of option list
+            0 => break,
+            // nop
+            1 => {
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let len = match options.get(i + 1) {
+            Some(&x) if x >= 2 && i + x as usize <= options.len() => x as usize,
+            _ => break,
+        };
+        match (options[i], len) {
+            (2, 4) => {
+                let value = u16::from_be_bytes([options[i + 2], options[i + 3]]);
+                if let Some(mss) = mss.filter(|x| *x < value) {
+                    options[i + 2..i + 4].copy_from_slice(&mss.to_be_bytes());
+                    changed = true;
+                }
+            }
+            (4, 2) if !sack => {
+                options[i..i + 2].copy_from_slice(&[1, 1]);
+                changed = true;
+            }
+            _ => {}
+        }
+        i += len;
+    }
+    changed
+}
+
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+pub struct ReapStats {
+    pub fin_wait: AtomicU64,
+    pub last_ack: AtomicU64,
+    pub orphan: AtomicU64,
+}
+
+impl ReapStats {
+    fn total(&self) -> u64 {
+        self.fin_wait.load(Ordering::Relaxed) + self.last_ack.load(Ordering::Relaxed) + self.orphan.load(Ordering::Relaxed)
+    }
+}
+
+pub struct TcpTun {
+    pools: Vec<Pool>,
+    nat: Arc<NatTable>,
+    tuning: TcpTuning,
+    // reap 与各个 listener 的 accept 循环，inbound 停止时随 TcpTun 一起结束
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for TcpTun {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq)]
 enum State {
     Established,
@@ -46,91 +260,164 @@ struct TcpConnection {
     dest_addr: SocketAddr,
     fake_addr: SocketAddr,
     state: State,
+    // 进入当前 state 的时间
+    since: Instant,
+    last_seen: Instant,
+}
+
+impl TcpConnection {
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.since = Instant::now();
+    }
 }
 impl TcpTun {
-    pub async fn new(tun_network: IpNet) -> io::Result<TcpTun> {
-        let mut hosts = tun_network.hosts();
-        let listener_addr = match hosts.next() {
-            Some(addr) => addr,
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "unexpected listener address allocate failed",
-                ))
+    pub async fn new(
+        tun_networks: Vec<IpNet>,
+        timeouts: TcpTimeouts,
+        tuning: TcpTuning,
+        shards: usize,
+        dns_hijack: Option<Arc<RwLock<DnsClient>>>,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Arc<Limiter>,
+        tag: String,
+    ) -> io::Result<TcpTun> {
+        let nat = Arc::new(NatTable::new(shards));
+        let reaped = Arc::new(ReapStats::default());
+        let mut tasks = vec![tokio::spawn(TcpTun::reap(nat.clone(), timeouts, reaped))];
+        let mut pools = Vec::new();
+        for tun_network in tun_networks {
+            let mut hosts = tun_network.hosts();
+            let listener_addr = match hosts.next() {
+                Some(addr) => addr,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "unexpected listener address allocate failed",
+                    ))
+                }
+            };
+            let listener =
+                ProxyTcpListener::with_buffers(listener_addr, 0, tuning.recv_buffer, tuning.send_buffer)?;
+            let local_addr = listener.local_addr()?;
+            let free_src_address = hosts.take(10).collect::<Vec<IpAddr>>();
+            tasks.push(tokio::spawn(TcpTun::tunnel(
+                listener,
+                nat.clone(),
+                tuning,
+                dns_hijack.clone(),
+                dispatcher.clone(),
+                limiter.clone(),
+                tag.clone(),
+            )));
+            pools.push(Pool {
+                free_address: free_src_address,
+                listener_addr: local_addr,
+            });
+        }
+        Ok(TcpTun { pools, nat, tuning, tasks })
+    }
+
+    // 定期清理超时的连接
+    async fn reap(nat: Arc<NatTable>, timeouts: TcpTimeouts, stats: Arc<ReapStats>) {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let before = stats.total();
+            for shard in &nat.shards {
+                TcpTun::reap_shard(&mut *shard.lock().await, &timeouts, &stats);
             }
-        };
-        let listener = ProxyTcpListener::new(listener_addr, 0).await?;
-        let local_addr = listener.local_addr()?;
-        let free_src_address = hosts.take(10).collect::<Vec<IpAddr>>();
-        let nat = Arc::new(Mutex::new(Nat::new()));
-        tokio::spawn(TcpTun::tunnel(listener, nat.clone()));
-        Ok(TcpTun {
-            free_address: free_src_address,
-            nat: nat,
-            listener_addr: local_addr,
-        })
+            if stats.total() != before {
+                debug!(
+                    "reaped tun tcp connections, fin_wait: {} last_ack: {} orphan: {}",
+                    stats.fin_wait.load(Ordering::Relaxed),
+                    stats.last_ack.load(Ordering::Relaxed),
+                    stats.orphan.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    fn reap_shard(nat: &mut Nat, timeouts: &TcpTimeouts, stats: &ReapStats) {
+        let now = Instant::now();
+        let Nat {
+            ref mut connections,
+            ref mut mapping,
+        } = *nat;
+        let expired: Vec<(SocketAddr, &AtomicU64)> = connections
+            .peek_iter()
+            .filter_map(|(fake, conn)| {
+                let counter = match conn.state {
+                    State::FinWait if now - conn.since > timeouts.fin_wait => &stats.fin_wait,
+                    State::LastAck if now - conn.since > timeouts.last_ack => &stats.last_ack,
+                    _ if now - conn.last_seen > timeouts.orphan => &stats.orphan,
+                    _ => return None,
+                };
+                Some((*fake, counter))
+            })
+            .collect();
+        for (fake, counter) in expired {
+            if let Some(conn) = connections.remove(&fake) {
+                debug!("reap tun tcp connection {} => {}", conn.src_addr, conn.dest_addr);
+                mapping.remove(&(conn.src_addr, conn.dest_addr));
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn pool(&self, addr: &SocketAddr) -> Option<&Pool> {
+        self.pools
+            .iter()
+            .find(|x| x.listener_addr.is_ipv6() == addr.is_ipv6())
     }
     pub async fn handle_packet(
         &self,
         src_addr: SocketAddr,
         dest_addr: SocketAddr,
-        tcp_header: &TcpHeader,
+        tcp_header: &mut TcpHeader,
     ) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+        // app 的 SYN 与本地 listener 回复的 SYN-ACK 都需要改写
+        if tcp_header.syn {
+            let mut options = tcp_header.options().to_vec();
+            if rewrite_syn_options(&mut options, self.tuning.mss(&src_addr), self.tuning.sack) {
+                if let Err(err) = tcp_header.set_options_raw(&options) {
+                    debug!("rewrite syn options failed {:?}", err);
+                }
+            }
+        }
+        let pool = match self.pool(&src_addr) {
+            Some(x) => x,
+            None => {
+                error!("no tun address for {} packets", if src_addr.is_ipv6() { "ipv6" } else { "ipv4" });
+                return Ok(None);
+            }
+        };
+        let flow = self.nat.flow_shard(&src_addr, &dest_addr);
+        let fake = {
+            let mut nat = self.nat.shards[flow].lock().await;
+            if tcp_header.syn && !tcp_header.ack {
+                // new tcp connection
+                Some(nat.insert(src_addr, dest_addr, &pool.free_address, || self.nat.fake_port(flow)))
+            } else {
+                // existing connections
+                nat.mapping.get(&(src_addr, dest_addr)).copied()
+            }
+        };
+        // 没有 mapping 的是 reply，目的地址就是 fake 地址
+        let (fake, is_reply) = match fake {
+            Some(fake) => (fake, false),
+            None => (dest_addr, true),
+        };
+        let mut nat = self.nat.shards[self.nat.fake_shard(&fake)].lock().await;
         let Nat {
             ref mut connections,
             ref mut mapping,
-        } = *self.nat.lock().await;
-        let (connection, is_reply) = if tcp_header.syn && !tcp_header.ack {
-            // new tcp connection
-            let fake_ip = loop {
-                let addr_index = rand::random::<usize>() % self.free_address.len();
-                // 1024 below are privilege ports
-                let port = rand::random::<u16>() % (65535 - 1024) + 1024;
-                let fake_addr = SocketAddr::new(
-                    self.free_address
-                        .get(addr_index)
-                        .expect("should works")
-                        .clone(),
-                    port,
-                );
-                if !connections.contains_key(&fake_addr) {
-                    // mapping record will be created at first time to establish tcp connection.
-                    // so key will always be (original_src_ip, original_dest_ip)
-                    mapping.insert((src_addr, dest_addr), fake_addr);
-                    connections.insert(
-                        fake_addr,
-                        TcpConnection {
-                            src_addr,
-                            dest_addr,
-                            fake_addr,
-                            state: State::Established,
-                        },
-                    );
-                    break fake_addr;
-                }
-            };
-            // TcpConnection::get(&*connections, &fake_ip)
-            (connections.get_mut(&fake_ip).unwrap(), false)
-        } else {
-            // existing connections
-            match mapping.get(&(src_addr, dest_addr)) {
-                Some(fake) => {
-                    // isn't reply
-                    (connections.get_mut(&fake).unwrap(), false)
-                }
-                None => {
-                    // Does it's a reply packet?
-                    match connections.get_mut(&dest_addr) {
-                        Some(..) => {
-                            // is reply
-                            (connections.get_mut(&dest_addr).unwrap(), true)
-                        }
-                        None => {
-                            error!("unknown connection from {} -> {}", src_addr, dest_addr);
-                            return Ok(None);
-                        }
-                    }
-                }
+        } = *nat;
+        let connection = match connections.get_mut(&fake) {
+            Some(x) => x,
+            None => {
+                error!("unknown connection from {} -> {}", src_addr, dest_addr);
+                return Ok(None);
             }
         };
         //          nat (fake_ip, listener ip)              SO_BINDTODEVICE
@@ -140,8 +427,9 @@ impl TcpTun {
         let (final_src_ip, final_dest_ip) = if is_reply {
             (dest_addr, src_addr)
         } else {
-            (connection.fake_addr, self.listener_addr)
+            (connection.fake_addr, pool.listener_addr)
         };
+        connection.last_seen = Instant::now();
         // clean up old connections
         if tcp_header.rst || (tcp_header.ack && connection.state == State::LastAck) {
             mapping.remove(&(connection.src_addr, connection.dest_addr));
@@ -152,14 +440,22 @@ impl TcpTun {
             // https://users.cs.northwestern.edu/~agupta/cs340/project2/TCPIP_State_Transition_Diagram.pdf
             match connection.state {
                 // tcp connection state machine
-                State::Established => connection.state = State::FinWait,
-                State::FinWait => connection.state = State::LastAck,
+                State::Established => connection.set_state(State::FinWait),
+                State::FinWait => connection.set_state(State::LastAck),
                 _ => {}
             }
         }
         Ok(Some((final_src_ip, final_dest_ip)))
     }
-    async fn tunnel(listener: ProxyTcpListener, translator: Arc<Mutex<Nat>>) {
+    async fn tunnel(
+        listener: ProxyTcpListener,
+        translator: Arc<NatTable>,
+        tuning: TcpTuning,
+        dns_hijack: Option<Arc<RwLock<DnsClient>>>,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Arc<Limiter>,
+        tag: String,
+    ) {
         loop {
             // remote_addr is fake ip
             let (stream, remote_addr) = match listener.accept().await {
@@ -170,7 +466,8 @@ impl TcpTun {
                 }
             };
             let (src_addr, dest_addr) = {
-                let nat = &mut *translator.lock().await;
+                let shard = translator.fake_shard(&remote_addr);
+                let nat = &mut *translator.shards[shard].lock().await;
                 match nat.connections.get(&remote_addr) {
                     Some(conn) => (conn.src_addr, conn.dest_addr),
                     None => {
@@ -179,20 +476,92 @@ impl TcpTun {
                     }
                 }
             };
-            tokio::spawn(TcpTun::handle_redir(stream, src_addr, dest_addr));
+            if tuning.nodelay {
+                if let Err(err) = stream.set_nodelay(true) {
+                    debug!("set nodelay failed {}", err);
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if !tuning.delayed_ack {
+                if let Err(err) = crate::net::sys::linux::set_quick_ack(&stream) {
+                    debug!("set quick ack failed {}", err);
+                }
+            }
+            if let (Some(dns_client), super::dns::DNS_PORT) = (&dns_hijack, dest_addr.port()) {
+                debug!("hijack dns over tcp from {} to {}", src_addr, dest_addr);
+                tokio::spawn(DnsServer::handle_tcp(dns_client.clone(), stream));
+                continue;
+            }
+            let admission = limiter.admit();
+            let id = Session::next_id();
+            let sess = redir_session(id, &tag, src_addr, dest_addr);
+            let dispatcher = dispatcher.clone();
+            supervisor::spawn(&tag, src_addr, trace::scope(id, TcpTun::handle_redir(dispatcher, admission, stream, sess)));
         }
     }
     // REDIRECT
-    // transparent proxy
-    async fn handle_redir(_stream: TcpStream, _src_addr: SocketAddr, dest_addr: SocketAddr) {
-        // stream is local stream
-        //
-        let _stream = match ProxyStream::connect(dest_addr).await {
-            Ok(s) => s,
-            Err(err) => {
-                error!("connect to {} failed because of {}", dest_addr, err);
+    // transparent proxy, stream 是 app 与本地 listener 之间的连接
+    async fn handle_redir(dispatcher: Arc<Dispatcher>, admission: Admission, stream: TcpStream, mut sess: Session) {
+        // 连接结束时释放
+        let _permit = match admission {
+            Admission::Ready(x) => x,
+            Admission::Queued(ticket) => match ticket.wait().await {
+                Some(x) => x,
+                None => {
+                    debug!("sid={} too many connections, {} closed", sess.id, sess.peer_address);
+                    trace::event("closed, too many connections");
+                    return;
+                }
+            },
+            Admission::Rejected => {
+                debug!("sid={} too many connections, {} closed", sess.id, sess.peer_address);
+                trace::event("closed, too many connections");
                 return;
             }
         };
+        trace::event(format_args!(
+            "accepted by inbound {} from {} to {}",
+            sess.inbound_tag.as_deref().unwrap_or_default(),
+            sess.peer_address,
+            sess.destination
+        ));
+        dispatcher.dispatch_tcp(stream, &mut sess).await;
     }
 }
+
+// nat 之前的原始地址，app 看到的对端就是 dest_addr
+fn redir_session(id: u64, tag: &str, src_addr: SocketAddr, dest_addr: SocketAddr) -> Session {
+    Session {
+        id,
+        destination: Address::Ip(dest_addr),
+        local_peer: dest_addr,
+        peer_address: src_addr,
+        network: Network::TCP,
+        user: None,
+        inbound_tag: Some(tag.to_string()),
+        app_protocol: None,
+        process: None,
+        uid: None,
+    }
+}
+
+#[test]
+fn test_rewrite_syn_options() {
+    // MSS 1460, NOP, window scale 7, SACK permitted
+    let mut options = [2, 4, 0x05, 0xb4, 1, 3, 3, 7, 4, 2, 0, 0];
+    assert!(rewrite_syn_options(&mut options, Some(1360), false));
+    assert_eq!(options, [2, 4, 0x05, 0x50, 1, 3, 3, 7, 1, 1, 0, 0]);
+    // 已经小于上限，不改写
+    assert!(!rewrite_syn_options(&mut options, Some(1400), false));
+}
+
+#[test]
+fn test_redir_session() {
+    let src: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+    let dest: SocketAddr = "93.184.216.34:443".parse().unwrap();
+    let sess = redir_session(7, "tun-in", src, dest);
+    assert_eq!(sess.port(), 443);
+    assert_eq!(sess.peer_address, src);
+    assert_eq!(sess.inbound_tag.as_deref(), Some("tun-in"));
+    assert!(matches!(sess.destination, Address::Ip(x) if x == dest));
+}
@@ -0,0 +1,327 @@
+// tun 中的 udp
+// 每个 (src, dst) 是一个 flow，交给 dispatcher 路由与转发，回复构造成 ip/udp packet 写回 tun
+// udp 没有连接状态，flow 由 dispatcher 的 idle timeout 结束
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use log::trace;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    app::{supervisor, trace, Admission, Dispatcher, Limiter},
+    proxy::{Address, Network, Session, UdpFlow},
+};
+
+const PROTO_ICMPV4: u8 = 1;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+// 每个 flow 等待转发的 datagram，超过时丢弃
+const FLOW_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Datagram {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// ip/udp packet, ip options and ipv6 extension headers are not supported
+pub fn parse_udp(packet: &[u8]) -> Option<Datagram> {
+    let (src, dst, start) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = ((packet[0] & 0x0f) as usize) * 4;
+            if packet.len() < ihl + UDP_HEADER_LEN || packet[9] != PROTO_UDP {
+                return None;
+            }
+            let mut src = [0u8; 4];
+            let mut dst = [0u8; 4];
+            src.copy_from_slice(&packet[12..16]);
+            dst.copy_from_slice(&packet[16..20]);
+            (IpAddr::from(Ipv4Addr::from(src)), IpAddr::from(Ipv4Addr::from(dst)), ihl)
+        }
+        6 => {
+            if packet.len() < IPV6_HEADER_LEN + UDP_HEADER_LEN || packet[6] != PROTO_UDP {
+                return None;
+            }
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&packet[8..24]);
+            dst.copy_from_slice(&packet[24..40]);
+            (IpAddr::from(Ipv6Addr::from(src)), IpAddr::from(Ipv6Addr::from(dst)), IPV6_HEADER_LEN)
+        }
+        _ => return None,
+    };
+    let udp = &packet[start..];
+    let len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
+    if len < UDP_HEADER_LEN || len > udp.len() {
+        return None;
+    }
+    Some(Datagram {
+        src: SocketAddr::new(src, u16::from_be_bytes([udp[0], udp[1]])),
+        dst: SocketAddr::new(dst, u16::from_be_bytes([udp[2], udp[3]])),
+        payload: udp[UDP_HEADER_LEN..len].to_vec(),
+    })
+}
+
+fn sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// ip header + payload, the checksum is written at payload[sum_at..sum_at + 2]
+// udp 与 icmpv6 的 checksum 包括 pseudo header，icmpv4 不包括
+fn build_packet(src: IpAddr, dst: IpAddr, proto: u8, mut payload: Vec<u8>, sum_at: usize) -> Vec<u8> {
+    let len = payload.len();
+    let (mut packet, pseudo) = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, proto, 0, 0];
+            header[2..4].copy_from_slice(&((20 + len) as u16).to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let ip_sum = fold(sum(&header));
+            header[10..12].copy_from_slice(&ip_sum.to_be_bytes());
+            let mut pseudo = Vec::with_capacity(12);
+            if proto == PROTO_UDP {
+                pseudo.extend_from_slice(&src.octets());
+                pseudo.extend_from_slice(&dst.octets());
+                pseudo.extend_from_slice(&[0, proto]);
+                pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            (header, pseudo)
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V6(x) => x,
+                IpAddr::V4(x) => x.to_ipv6_mapped(),
+            };
+            let (src, dst) = (to_v6(src), to_v6(dst));
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+            header.extend_from_slice(&[proto, 64]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, proto]);
+            (header, pseudo)
+        }
+    };
+    let mut payload_sum = fold(sum(&pseudo) + sum(&payload));
+    // udp checksum 0 表示没有 checksum
+    if payload_sum == 0 && proto == PROTO_UDP {
+        payload_sum = 0xffff;
+    }
+    payload[sum_at..sum_at + 2].copy_from_slice(&payload_sum.to_be_bytes());
+    packet.extend_from_slice(&payload);
+    packet
+}
+
+/// ip/udp packet from the destination of request back to its source
+pub fn build_reply(request: &Datagram, payload: &[u8]) -> Vec<u8> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&request.dst.port().to_be_bytes());
+    udp.extend_from_slice(&request.src.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    build_packet(request.dst.ip(), request.src.ip(), PROTO_UDP, udp, 6)
+}
+
+/// icmp "fragmentation needed" / icmpv6 "packet too big" for a datagram of request that exceeds max_payload,
+/// so the application lowers its datagram size
+pub fn build_too_big(request: &Datagram, max_payload: usize) -> Vec<u8> {
+    // 引用原始 packet 的 ip 头与 udp 头，client 据此找到对应的 socket
+    let original = Datagram {
+        src: request.dst,
+        dst: request.src,
+        payload: Vec::new(),
+    };
+    let quote = build_reply(&original, &[]);
+    let mut icmp;
+    let proto = match (request.src.ip(), request.dst.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => {
+            let mtu = max_payload + 20 + UDP_HEADER_LEN;
+            icmp = vec![ICMP_DEST_UNREACHABLE, ICMP_FRAG_NEEDED, 0, 0, 0, 0];
+            icmp.extend_from_slice(&(mtu as u16).to_be_bytes());
+            PROTO_ICMPV4
+        }
+        _ => {
+            let mtu = max_payload + IPV6_HEADER_LEN + UDP_HEADER_LEN;
+            icmp = vec![ICMPV6_PACKET_TOO_BIG, 0, 0, 0];
+            icmp.extend_from_slice(&(mtu as u32).to_be_bytes());
+            PROTO_ICMPV6
+        }
+    };
+    icmp.extend_from_slice(&quote);
+    build_packet(request.dst.ip(), request.src.ip(), proto, icmp, 2)
+}
+
+type Flows = Arc<Mutex<HashMap<(SocketAddr, SocketAddr), mpsc::Sender<Vec<u8>>>>>;
+
+pub struct UdpTun {
+    flows: Flows,
+    dispatcher: Arc<Dispatcher>,
+    // 每个 flow 占用一个连接许可
+    limiter: Arc<Limiter>,
+    tag: String,
+    // 写回 tun 的 packet
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl UdpTun {
+    pub fn new(
+        dispatcher: Arc<Dispatcher>,
+        limiter: Arc<Limiter>,
+        tag: String,
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+    ) -> UdpTun {
+        UdpTun {
+            flows: Arc::new(Mutex::new(HashMap::new())),
+            dispatcher,
+            limiter,
+            tag,
+            tx,
+        }
+    }
+
+    pub fn handle(&self, datagram: Datagram) {
+        let key = (datagram.src, datagram.dst);
+        let mut flows = self.flows.lock().unwrap();
+        let payload = match flows.get(&key) {
+            Some(flow) => match flow.try_send(datagram.payload) {
+                Ok(()) => return,
+                Err(TrySendError::Full(_)) => {
+                    trace!("udp flow {} => {} is full, datagram dropped", datagram.src, datagram.dst);
+                    return;
+                }
+                // flow 已经结束，作为新的 flow 重新路由
+                Err(TrySendError::Closed(payload)) => payload,
+            },
+            None => datagram.payload,
+        };
+        // 队列已满时丢弃，之后的 datagram 再次尝试创建 flow
+        let admission = match self.limiter.admit() {
+            Admission::Rejected => {
+                trace!("too many connections, udp {} => {} dropped", datagram.src, datagram.dst);
+                return;
+            }
+            x => x,
+        };
+        let (mut flow, mut inbound) = UdpFlow::pair(FLOW_CAPACITY);
+        let (too_big, mut too_big_rx) = mpsc::unbounded_channel();
+        flow.too_big = Some(too_big);
+        // 刚创建的 channel 一定有空间
+        let _ = inbound.tx.try_send(payload);
+        flows.insert(key, inbound.tx);
+        let id = Session::next_id();
+        let sess = Session {
+            id,
+            destination: Address::Ip(datagram.dst),
+            local_peer: datagram.dst,
+            peer_address: datagram.src,
+            network: Network::UDP,
+            user: None,
+            inbound_tag: Some(self.tag.clone()),
+            app_protocol: None,
+            process: None,
+            uid: None,
+        };
+        let dispatcher = self.dispatcher.clone();
+        supervisor::spawn(&self.tag, datagram.src, trace::scope(id, async move {
+            // flow 结束时释放
+            let _permit = match admission {
+                Admission::Ready(x) => x,
+                Admission::Queued(ticket) => match ticket.wait().await {
+                    Some(x) => x,
+                    None => {
+                        trace::event("closed, too many connections");
+                        return;
+                    }
+                },
+                Admission::Rejected => return,
+            };
+            trace::event(format_args!("udp from {} to {}", sess.peer_address, sess.destination));
+            dispatcher.dispatch_udp(flow, sess).await;
+        }));
+        let request = Datagram { payload: Vec::new(), ..datagram };
+        let flows = self.flows.clone();
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let packet = tokio::select! {
+                    reply = inbound.rx.recv() => match reply {
+                        Some(reply) => build_reply(&request, &reply),
+                        None => break,
+                    },
+                    Some(max_payload) = too_big_rx.recv() => build_too_big(&request, max_payload),
+                };
+                if tx.send(packet).is_err() {
+                    break;
+                }
+            }
+            // dispatcher 结束了这个 flow，之后的 datagram 重新路由
+            let mut flows = flows.lock().unwrap();
+            if flows.get(&key).map_or(false, |x| x.is_closed()) {
+                flows.remove(&key);
+            }
+        });
+    }
+}
+
+#[test]
+fn test_udp_packet() {
+    let request = Datagram {
+        src: "10.0.0.2:40000".parse().unwrap(),
+        dst: "1.1.1.1:443".parse().unwrap(),
+        payload: Vec::new(),
+    };
+    let reply = build_reply(&request, &[4, 5, 6, 7]);
+    assert_eq!(reply.len(), 20 + 8 + 4);
+    assert_eq!(fold(sum(&reply[..20])), 0);
+    let parsed = parse_udp(&reply).unwrap();
+    assert_eq!(parsed.src, request.dst);
+    assert_eq!(parsed.dst, request.src);
+    assert_eq!(parsed.payload, vec![4, 5, 6, 7]);
+    let v6 = Datagram {
+        src: "[fd00:7475:6e::2]:40000".parse().unwrap(),
+        dst: "[2606:4700::1111]:443".parse().unwrap(),
+        payload: Vec::new(),
+    };
+    let parsed = parse_udp(&build_reply(&v6, b"quic")).unwrap();
+    assert_eq!((parsed.src, parsed.dst), (v6.dst, v6.src));
+    assert_eq!(parse_udp(&reply[..24]), None);
+
+    let too_big = build_too_big(&request, 1200);
+    assert_eq!(fold(sum(&too_big[..20])), 0);
+    assert_eq!(too_big[9], PROTO_ICMPV4);
+    assert_eq!(fold(sum(&too_big[20..])), 0);
+    assert_eq!(&too_big[20..22], &[ICMP_DEST_UNREACHABLE, ICMP_FRAG_NEEDED]);
+    assert_eq!(u16::from_be_bytes([too_big[26], too_big[27]]), 1228);
+    // 引用的是 client 发出的 packet
+    let quoted = parse_udp(&too_big[28..]).unwrap();
+    assert_eq!((quoted.src, quoted.dst), (request.src, request.dst));
+    let too_big = build_too_big(&v6, 1400);
+    assert_eq!(too_big[6], PROTO_ICMPV6);
+    assert_eq!(too_big[40], ICMPV6_PACKET_TOO_BIG);
+    assert_eq!(u32::from_be_bytes([too_big[44], too_big[45], too_big[46], too_big[47]]), 1448);
+}
@@ -0,0 +1,110 @@
+// udp over tcp，用于本身不能转发 udp 的 outbound
+// 通过 outbound 的 tcp handler 连接到特殊域名 sp.udp-over-tcp.arpa，服务器看到这个目标时把连接当作 udp 隧道
+// 与 shadowsocks 的 udp-over-tcp v1 兼容，之后每个 datagram 的格式与 relay 协议 cmd UDP 相同
+// |<-atyp 1 byte->|<-addr->|<-port 2 bytes->|<-length 2 bytes->|<-payload->|
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::io::{split, ReadHalf, WriteHalf};
+
+use crate::Context;
+
+use super::{
+    relay::{read_datagram, write_datagram},
+    Address, AnyStream, AnyTcpOutboundHandler, Network, Session,
+};
+
+pub const UOT_MAGIC_ADDRESS: &str = "sp.udp-over-tcp.arpa";
+
+pub struct UdpOverTcp {
+    tcp: AnyTcpOutboundHandler,
+}
+
+impl UdpOverTcp {
+    pub fn new(tcp: AnyTcpOutboundHandler) -> UdpOverTcp {
+        UdpOverTcp { tcp }
+    }
+
+    /// open a tunnel for the udp session, datagrams to any destination can be sent over it
+    pub async fn connect(&self, ctx: Arc<Context>, sess: &Session) -> Result<UotStream> {
+        let mut tunnel = sess.clone();
+        tunnel.destination = Address::Domain(UOT_MAGIC_ADDRESS.to_string(), 0);
+        tunnel.network = Network::TCP;
+        let stream = self.tcp.handle(ctx, &tunnel).await?;
+        Ok(UotStream::new(stream))
+    }
+}
+
+pub struct UotStream {
+    stream: AnyStream,
+}
+
+impl UotStream {
+    pub fn new(stream: AnyStream) -> UotStream {
+        UotStream { stream }
+    }
+
+    pub async fn send_to(&mut self, payload: &[u8], destination: &Address) -> Result<()> {
+        write_datagram(&mut self.stream, destination, payload).await
+    }
+
+    pub async fn recv_from(&mut self) -> Result<(Vec<u8>, Address)> {
+        let (address, payload) = read_datagram(&mut self.stream).await?;
+        Ok((payload, address))
+    }
+
+    /// halves for sending and receiving at the same time
+    pub fn split(self) -> (UotReader, UotWriter) {
+        let (reader, writer) = split(self.stream);
+        (UotReader { stream: reader }, UotWriter { stream: writer })
+    }
+}
+
+pub struct UotReader {
+    stream: ReadHalf<AnyStream>,
+}
+
+impl UotReader {
+    pub async fn recv_from(&mut self) -> Result<(Vec<u8>, Address)> {
+        let (address, payload) = read_datagram(&mut self.stream).await?;
+        Ok((payload, address))
+    }
+}
+
+pub struct UotWriter {
+    stream: WriteHalf<AnyStream>,
+}
+
+impl UotWriter {
+    pub async fn send_to(&mut self, payload: &[u8], destination: &Address) -> Result<()> {
+        write_datagram(&mut self.stream, destination, payload).await
+    }
+}
+
+#[tokio::test]
+async fn test_udp_over_tcp() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = UotStream::new(Box::new(client));
+    let mut server = UotStream::new(Box::new(server));
+    let destination: Address = "1.1.1.1:53".parse().unwrap();
+    client.send_to(b"query", &destination).await.unwrap();
+    let (payload, address) = server.recv_from().await.unwrap();
+    assert_eq!(payload, b"query");
+    assert_eq!(address.to_string(), "1.1.1.1:53");
+    server.send_to(b"answer", &address).await.unwrap();
+    assert_eq!(client.recv_from().await.unwrap().0, b"answer");
+}
+
+#[tokio::test]
+async fn test_udp_over_tcp_split() {
+    let (client, server) = tokio::io::duplex(1024);
+    let (mut reader, mut writer) = UotStream::new(Box::new(client)).split();
+    let mut server = UotStream::new(Box::new(server));
+    let destination: Address = "1.1.1.1:443".parse().unwrap();
+    writer.send_to(b"initial", &destination).await.unwrap();
+    let (payload, address) = server.recv_from().await.unwrap();
+    assert_eq!(payload, b"initial");
+    server.send_to(b"handshake", &address).await.unwrap();
+    assert_eq!(reader.recv_from().await.unwrap().0, b"handshake");
+}
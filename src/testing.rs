@@ -0,0 +1,177 @@
+// 内存中的端到端测试工具，inbound => router => outbound 不需要真实的 socket、tun 设备或者 root
+//
+// let harness = Harness::new(config);
+// let mut stream = harness.connect(Address::Domain("example.com".to_string(), 443));
+// stream.write_all(b"hello").await?;
+// harness.outbound("proxy").sessions();
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    sync::RwLock,
+};
+
+use crate::{
+    app::{trace, Dispatcher, DnsClient, OutboundManager, Router, RuleProviders},
+    config::Config,
+    proxy::{Address, AnyStream, Network, OutboundHandler, Session, TcpOutboundHandlerTrait},
+    Context,
+};
+
+const MEMORY_BUFFER: usize = 64 * 1024;
+pub const MEMORY_INBOUND_TAG: &str = "memory";
+
+/// one end of an in-memory connection
+pub struct MemoryStream {
+    inner: DuplexStream,
+}
+
+/// two connected streams, bytes written to one are read from the other
+pub fn memory_pair() -> (MemoryStream, MemoryStream) {
+    let (a, b) = duplex(MEMORY_BUFFER);
+    (MemoryStream { inner: a }, MemoryStream { inner: b })
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// outbound that echoes everything back and records the sessions it handled
+#[derive(Default)]
+pub struct EchoOutbound {
+    sessions: Mutex<Vec<Session>>,
+}
+
+impl EchoOutbound {
+    pub fn sessions(&self) -> Vec<Session> {
+        self.sessions.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for EchoOutbound {
+    async fn handle(&self, _ctx: Arc<Context>, sess: &Session) -> anyhow::Result<AnyStream> {
+        self.sessions.lock().unwrap().push(sess.clone());
+        let (local, mut remote) = memory_pair();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                match remote.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if remote.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = remote.shutdown().await;
+        });
+        Ok(Box::new(local))
+    }
+}
+
+/// a dispatcher built from config with every outbound replaced by an EchoOutbound
+pub struct Harness {
+    dispatcher: Arc<Dispatcher>,
+    outbounds: HashMap<String, Arc<EchoOutbound>>,
+}
+
+impl Harness {
+    pub fn new(config: Config) -> Harness {
+        let mut handlers = HashMap::new();
+        let mut outbounds = HashMap::new();
+        for outbound in &config.outbounds {
+            let echo = Arc::new(EchoOutbound::default());
+            let handler = OutboundHandler::new(outbound.tag.clone(), Some(echo.clone()), None);
+            handlers.insert(outbound.tag.clone(), Arc::new(handler));
+            outbounds.insert(outbound.tag.clone(), echo);
+        }
+        let rule_providers = RuleProviders::new(&None);
+        let router = Arc::new(Router::new(config.routes.clone(), &rule_providers));
+        let dns_client = Arc::new(RwLock::new(DnsClient::new(config.clone())));
+        let context = Arc::new(Context::new(dns_client.clone()));
+        let dispatcher = Arc::new(Dispatcher::new(
+            context,
+            router,
+            dns_client,
+            Arc::new(OutboundManager {
+                handlers,
+                circuits: HashMap::new(),
+                servers: Default::default(),
+                default_interface: None,
+            }),
+            config,
+        ));
+        Harness {
+            dispatcher,
+            outbounds,
+        }
+    }
+
+    /// the echo outbound standing in for tag, panics if the config has no such outbound
+    pub fn outbound(&self, tag: &str) -> Arc<EchoOutbound> {
+        self.outbounds
+            .get(tag)
+            .unwrap_or_else(|| panic!("no outbound {}", tag))
+            .clone()
+    }
+
+    pub fn dispatcher(&self) -> Arc<Dispatcher> {
+        self.dispatcher.clone()
+    }
+
+    /// open a connection through a mock inbound, returns the app side of it
+    /// must be called within a tokio runtime
+    pub fn connect(&self, destination: Address) -> MemoryStream {
+        let (app, inbound) = memory_pair();
+        let mut sess = Session {
+            id: Session::next_id(),
+            destination,
+            network: Network::TCP,
+            local_peer: SocketAddr::from(([127, 0, 0, 1], 1080)),
+            peer_address: SocketAddr::from(([127, 0, 0, 1], 50000)),
+            user: None,
+            inbound_tag: Some(MEMORY_INBOUND_TAG.to_string()),
+            app_protocol: None,
+            process: None,
+            uid: None,
+        };
+        let dispatcher = self.dispatcher.clone();
+        tokio::spawn(trace::scope(sess.id, async move {
+            dispatcher.dispatch_stream(Box::new(inbound), &mut sess, || {}).await;
+        }));
+        app
+    }
+}
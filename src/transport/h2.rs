@@ -0,0 +1,909 @@
+// http/2 与 grpc transport（client），用于只转发 http/2 或 grpc 的 CDN
+// 一条 h2 连接承载多个 stream，每个 stream 是一条双向字节流，连接在 session 之间复用
+// h2: POST <path>，DATA frame 直接承载数据
+// grpc: POST /<service_name>/Tun，content-type application/grpc，与 v2ray/xray 的 gun 兼容
+//   每个 grpc message 是 protobuf Hunk { bytes data = 1; }，前面是 1 byte 压缩标记 + 4 bytes 长度
+//
+// hpack 只实现需要的部分: 请求头不使用 huffman 与动态表，SETTINGS_HEADER_TABLE_SIZE 为 0
+// 响应只解析第一个字段 :status，不是 200 或者无法解析（huffman）时拒绝这个 stream
+// 流量控制: 接收到的 DATA 在应用读取之后才通过 WINDOW_UPDATE 归还，发送在没有窗口时等待
+// 等待写入连接的 frame 不超过 MAX_QUEUED，对端不读取时 writer 与 read loop 都会等待
+// keepalive: 定时发送 PING，任何 frame 都算对端存活
+
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::Future;
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    sync::Notify,
+};
+
+use crate::{config::TransportSettings, proxy::AnyStream};
+
+use super::keepalive::{Keepalive, Liveness};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+const CANCEL: u32 = 0x8;
+const DEFAULT_WINDOW: i64 = 65535;
+const DEFAULT_MAX_FRAME: usize = 16384;
+// 对端可以发送的最大 frame
+const MAX_RECV_FRAME: usize = 1 << 20;
+// 接收窗口，应用读取之后归还
+const RECV_WINDOW: u32 = 1 << 20;
+// 等待写入连接的数据上限
+const MAX_QUEUED: usize = 256 * 1024;
+// stream id 用完之前换新连接
+const MAX_STREAM_ID: u32 = (1 << 31) - 2;
+
+const GRPC_HEADER_LEN: usize = 5;
+// grpc message 头与 Hunk 的 tag、长度，数据不超过 DEFAULT_MAX_FRAME
+const GRPC_OVERHEAD: usize = GRPC_HEADER_LEN + 4;
+
+pub fn encode_frame(ty: u8, flags: u8, sid: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.push(ty);
+    buf.push(flags);
+    buf.extend_from_slice(&(sid & 0x7fff_ffff).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+// hpack 整数，prefix 为第一个 byte 中可用的 bit 数
+fn encode_int(buf: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        buf.push(flags | value as u8);
+        return;
+    }
+    buf.push(flags | max as u8);
+    let mut value = value - max;
+    while value >= 128 {
+        buf.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    buf.push(value as u8);
+}
+
+fn decode_int(data: &[u8], prefix: u8) -> Option<(usize, usize)> {
+    let max = (1usize << prefix) - 1;
+    let mut value = (*data.first()? as usize) & max;
+    if value < max {
+        return Some((value, 1));
+    }
+    let mut shift = 0;
+    for (i, b) in data[1..].iter().enumerate() {
+        value += ((*b & 0x7f) as usize) << shift;
+        if *b & 0x80 == 0 {
+            return Some((value, i + 2));
+        }
+        shift += 7;
+        if shift > 28 {
+            return None;
+        }
+    }
+    None
+}
+
+fn encode_string(buf: &mut Vec<u8>, value: &str) {
+    encode_int(buf, 0, 7, value.len());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// request header block, literal fields without indexing and huffman
+pub fn request_headers(authority: &str, path: &str, extra: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    // 静态表 3 :method POST，7 :scheme https
+    encode_int(&mut block, 0x80, 7, 3);
+    encode_int(&mut block, 0x80, 7, 7);
+    // 静态表 1 :authority，4 :path 作为名字
+    encode_int(&mut block, 0x00, 4, 1);
+    encode_string(&mut block, authority);
+    encode_int(&mut block, 0x00, 4, 4);
+    encode_string(&mut block, path);
+    for (name, value) in extra {
+        block.push(0x00);
+        encode_string(&mut block, name);
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+/// :status of a response header block, None if it can not be decoded without huffman
+pub fn response_status(block: &[u8]) -> Option<u16> {
+    // 静态表 8-14
+    const STATUS: [u16; 7] = [200, 204, 206, 304, 400, 404, 500];
+    let mut pos = 0;
+    // 跳过开头的动态表大小更新
+    while *block.get(pos)? & 0xe0 == 0x20 {
+        pos += decode_int(&block[pos..], 5)?.1;
+    }
+    let first = block[pos];
+    if first & 0x80 != 0 {
+        let (index, _) = decode_int(&block[pos..], 7)?;
+        return STATUS.get(index.checked_sub(8)?).copied();
+    }
+    let prefix = if first & 0x40 != 0 { 6 } else { 4 };
+    let (index, n) = decode_int(&block[pos..], prefix)?;
+    if index != 8 {
+        return None;
+    }
+    pos += n;
+    if *block.get(pos)? & 0x80 != 0 {
+        return None;
+    }
+    let (len, n) = decode_int(&block[pos..], 7)?;
+    let value = block.get(pos + n..pos + n + len)?;
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// a grpc message carrying a Hunk
+pub fn encode_grpc(data: &[u8]) -> Vec<u8> {
+    let mut hunk = vec![0x0a];
+    let mut len = data.len();
+    while len >= 0x80 {
+        hunk.push((len as u8 & 0x7f) | 0x80);
+        len >>= 7;
+    }
+    hunk.push(len as u8);
+    hunk.extend_from_slice(data);
+    let mut buf = Vec::with_capacity(GRPC_HEADER_LEN + hunk.len());
+    buf.push(0);
+    buf.extend_from_slice(&(hunk.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&hunk);
+    buf
+}
+
+/// take one complete grpc message from buf, returns the data of the Hunk
+pub fn decode_grpc(buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    if buf.len() < GRPC_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = BigEndian::read_u32(&buf[1..5]) as usize;
+    if buf.len() < GRPC_HEADER_LEN + len {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed grpc message"));
+    }
+    buf.advance(GRPC_HEADER_LEN);
+    let mut message = buf.split_to(len).freeze();
+    // 只有 field 1 (bytes)，其他 field 跳过
+    let mut data = BytesMut::new();
+    while message.has_remaining() {
+        let key = message.get_u8();
+        let mut size = 0usize;
+        let mut shift = 0;
+        loop {
+            if !message.has_remaining() || shift > 28 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad grpc hunk"));
+            }
+            let b = message.get_u8();
+            size |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        if key & 0x07 != 2 || size > message.remaining() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad grpc hunk"));
+        }
+        let value = message.split_to(size);
+        if key >> 3 == 1 {
+            data.extend_from_slice(&value);
+        }
+    }
+    Ok(Some(data.freeze()))
+}
+
+// 一个 stream 两个方向的状态
+struct StreamState {
+    // 已经收到但应用还没有读取的数据，总量受接收窗口限制
+    recv: VecDeque<Bytes>,
+    // 对端还可以发送的数据量
+    recv_window: i64,
+    // 应用已经读取、还没有通过 WINDOW_UPDATE 归还的数据量
+    unacked: i64,
+    // 对端 END_STREAM
+    eof: bool,
+    reader: Option<Waker>,
+    // 发送窗口，对端 WINDOW_UPDATE 增加
+    send_window: i64,
+    // 等待窗口或者写入队列的 writer
+    writer: Option<Waker>,
+}
+
+impl StreamState {
+    fn new(send_window: i64) -> StreamState {
+        StreamState {
+            recv: VecDeque::new(),
+            recv_window: RECV_WINDOW as i64,
+            unacked: 0,
+            eof: false,
+            reader: None,
+            send_window,
+            writer: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct State {
+    // 等待 write loop 写入连接的 frame，DATA 超过 MAX_QUEUED 时 writer 等待
+    out: Vec<u8>,
+    streams: HashMap<u32, StreamState>,
+    // 发送方向的连接窗口与对端 SETTINGS
+    send_connection: i64,
+    initial: i64,
+    max_frame: usize,
+    // 接收方向的连接窗口
+    recv_connection: i64,
+    unacked: i64,
+}
+
+impl State {
+    // 应用读取（或者丢弃）n bytes 之后归还窗口，攒够一半再发送 WINDOW_UPDATE
+    fn refund(&mut self, sid: u32, n: usize) {
+        self.unacked += n as i64;
+        if self.unacked >= RECV_WINDOW as i64 / 2 {
+            self.out.extend_from_slice(&encode_frame(WINDOW_UPDATE, 0, 0, &(self.unacked as u32).to_be_bytes()));
+            self.recv_connection += self.unacked;
+            self.unacked = 0;
+        }
+        if let Some(stream) = self.streams.get_mut(&sid) {
+            // 对端已经结束发送，不需要再归还
+            if stream.eof {
+                return;
+            }
+            stream.unacked += n as i64;
+            if stream.unacked >= RECV_WINDOW as i64 / 2 {
+                self.out.extend_from_slice(&encode_frame(WINDOW_UPDATE, 0, sid, &(stream.unacked as u32).to_be_bytes()));
+                stream.recv_window += stream.unacked;
+                stream.unacked = 0;
+            }
+        }
+    }
+
+    // RST_STREAM、被拒绝或者应用 drop，没有读取的数据归还连接窗口
+    fn remove(&mut self, sid: u32) -> Option<StreamState> {
+        let mut stream = self.streams.remove(&sid)?;
+        let unread: usize = stream.recv.iter().map(|x| x.len()).sum();
+        self.refund(sid, unread);
+        stream.wake();
+        Some(stream)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // out 中有新的 frame
+    pending: Notify,
+    // write loop 写出了 out，等待队列空间的 read loop 与 writer 继续
+    drained: Notify,
+    drained_wakers: Mutex<Vec<Waker>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+    liveness: Liveness,
+}
+
+impl Shared {
+    /// queue a control frame, they are small and not flow controlled
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "h2 connection closed"));
+        }
+        self.state.lock().unwrap().out.extend_from_slice(frame);
+        self.pending.notify_one();
+        Ok(())
+    }
+
+    // read loop 在写入队列满时等待，对端不读取时也不再读取它的 frame
+    async fn wait_queue(&self) {
+        loop {
+            let drained = self.drained.notified();
+            if self.closed.load(Ordering::SeqCst) || self.state.lock().unwrap().out.len() < MAX_QUEUED {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // stream 端读到 EOF，等待窗口的 writer 返回错误
+        let mut state = self.state.lock().unwrap();
+        for (_, mut stream) in state.streams.drain() {
+            stream.wake();
+        }
+        drop(state);
+        self.drained_wakers.lock().unwrap().drain(..).for_each(Waker::wake);
+        self.pending.notify_one();
+        self.drained.notify_waiters();
+    }
+}
+
+pub struct H2Connection {
+    shared: Arc<Shared>,
+}
+
+impl H2Connection {
+    pub async fn new(mut stream: AnyStream, keepalive: Option<Keepalive>) -> Result<H2Connection> {
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_HEADER_TABLE_SIZE, 0),
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_INITIAL_WINDOW_SIZE, RECV_WINDOW),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut buf = PREFACE.to_vec();
+        buf.extend_from_slice(&encode_frame(SETTINGS, 0, 0, &settings));
+        // 连接窗口默认 65535，调整为与 stream 相同
+        let increment = RECV_WINDOW - DEFAULT_WINDOW as u32;
+        buf.extend_from_slice(&encode_frame(WINDOW_UPDATE, 0, 0, &increment.to_be_bytes()));
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                out: Vec::new(),
+                streams: HashMap::new(),
+                send_connection: DEFAULT_WINDOW,
+                initial: DEFAULT_WINDOW,
+                max_frame: DEFAULT_MAX_FRAME,
+                recv_connection: RECV_WINDOW as i64,
+                unacked: 0,
+            }),
+            pending: Notify::new(),
+            drained: Notify::new(),
+            drained_wakers: Mutex::new(Vec::new()),
+            next_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+            liveness: Liveness::default(),
+        });
+        let (read_half, write_half) = tokio::io::split(stream);
+        let reader = tokio::spawn(H2Connection::read_loop(read_half, shared.clone()));
+        let writer_shared = shared.clone();
+        tokio::spawn(async move {
+            let write = H2Connection::write_loop(write_half, &writer_shared);
+            match keepalive {
+                Some(keepalive) => tokio::select! {
+                    _ = write => {}
+                    err = keepalive.run(&writer_shared.liveness, || writer_shared.send_frame(&encode_frame(PING, 0, 0, &[0u8; 8]))) => {
+                        debug!("close h2 connection {}", err);
+                    }
+                },
+                None => write.await,
+            }
+            writer_shared.close();
+            reader.abort();
+        });
+        Ok(H2Connection { shared })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst) || self.shared.next_id.load(Ordering::SeqCst) > MAX_STREAM_ID
+    }
+
+    /// send the request headers and return the stream of its body
+    pub fn open_stream(&self, headers: &[u8], grpc: bool) -> io::Result<H2Stream> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "h2 connection closed"));
+        }
+        let sid = self.shared.next_id.fetch_add(2, Ordering::SeqCst);
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let initial = state.initial;
+            state.streams.insert(sid, StreamState::new(initial));
+        }
+        self.shared.send_frame(&encode_frame(HEADERS, END_HEADERS, sid, headers))?;
+        Ok(H2Stream {
+            sid,
+            shared: self.shared.clone(),
+            read_buf: Bytes::new(),
+            grpc: grpc.then(BytesMut::new),
+            fin_sent: false,
+        })
+    }
+
+    async fn read_loop(mut reader: ReadHalf<AnyStream>, shared: Arc<Shared>) {
+        if let Err(err) = H2Connection::read_frames(&mut reader, &shared).await {
+            debug!("h2 connection read failed {}", err);
+        }
+        shared.close();
+    }
+
+    async fn read_frames(reader: &mut ReadHalf<AnyStream>, shared: &Shared) -> io::Result<()> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        // HEADERS 后面跟着 CONTINUATION
+        let mut block: Option<(u32, u8, Vec<u8>)> = None;
+        // 已经收到响应头的 stream
+        let mut responded = HashSet::new();
+        loop {
+            shared.wait_queue().await;
+            reader.read_exact(&mut header).await?;
+            let len = BigEndian::read_u24(&header[..3]) as usize;
+            let (ty, flags) = (header[3], header[4]);
+            let sid = BigEndian::read_u32(&header[5..9]) & 0x7fff_ffff;
+            if len > MAX_RECV_FRAME {
+                return Err(invalid("h2 frame too large"));
+            }
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload).await?;
+            shared.liveness.touch();
+            match ty {
+                DATA => {
+                    let data = strip_padding(flags, &payload)?;
+                    let mut state = shared.state.lock().unwrap();
+                    state.recv_connection -= len as i64;
+                    if state.recv_connection < 0 {
+                        return Err(invalid("h2 connection flow control error"));
+                    }
+                    match state.streams.get_mut(&sid) {
+                        Some(stream) if !stream.eof => {
+                            stream.recv_window -= len as i64;
+                            if stream.recv_window < 0 {
+                                return Err(invalid("h2 stream flow control error"));
+                            }
+                            if !data.is_empty() {
+                                stream.recv.push_back(Bytes::copy_from_slice(data));
+                            }
+                            if flags & END_STREAM != 0 {
+                                stream.eof = true;
+                            }
+                            if let Some(waker) = stream.reader.take() {
+                                waker.wake();
+                            }
+                            // padding 不会被读取，直接归还
+                            state.refund(sid, len - data.len());
+                        }
+                        // 已经关闭的 stream，数据丢弃
+                        _ => state.refund(sid, len),
+                    }
+                    drop(state);
+                    shared.pending.notify_one();
+                }
+                HEADERS | CONTINUATION => {
+                    let fragment = match ty {
+                        HEADERS => {
+                            let mut data = strip_padding(flags, &payload)?;
+                            if flags & PRIORITY != 0 {
+                                data = data.get(5..).ok_or_else(|| invalid("bad h2 priority"))?;
+                            }
+                            block = Some((sid, flags, Vec::new()));
+                            data
+                        }
+                        _ => &payload[..],
+                    };
+                    let (stream, first_flags, mut buf) = block.take().ok_or_else(|| invalid("unexpected continuation"))?;
+                    buf.extend_from_slice(fragment);
+                    if flags & END_HEADERS == 0 {
+                        block = Some((stream, first_flags, buf));
+                        continue;
+                    }
+                    let mut state = shared.state.lock().unwrap();
+                    // 第一个 HEADERS 是响应头，之后的是 trailers
+                    if responded.insert(stream) {
+                        match response_status(&buf) {
+                            Some(200) => trace!("h2 stream {} accepted", stream),
+                            // 无法解析的状态码同样拒绝，CDN 的 403/502 不能当作隧道
+                            status => {
+                                debug!("h2 stream {} rejected with status {:?}", stream, status);
+                                state.remove(stream);
+                                responded.remove(&stream);
+                                state.out.extend_from_slice(&encode_frame(RST_STREAM, 0, stream, &CANCEL.to_be_bytes()));
+                            }
+                        }
+                    }
+                    if first_flags & END_STREAM != 0 {
+                        if let Some(x) = state.streams.get_mut(&stream) {
+                            x.eof = true;
+                            x.wake();
+                        }
+                        responded.remove(&stream);
+                    }
+                    drop(state);
+                    shared.pending.notify_one();
+                }
+                RST_STREAM => {
+                    shared.state.lock().unwrap().remove(sid);
+                    responded.remove(&sid);
+                    shared.pending.notify_one();
+                }
+                SETTINGS if flags & ACK == 0 => {
+                    let mut state = shared.state.lock().unwrap();
+                    for setting in payload.chunks_exact(6) {
+                        let value = BigEndian::read_u32(&setting[2..]);
+                        match BigEndian::read_u16(&setting[..2]) {
+                            SETTINGS_INITIAL_WINDOW_SIZE => {
+                                // 已有 stream 的窗口按差值调整
+                                let delta = value as i64 - state.initial;
+                                state.initial = value as i64;
+                                for stream in state.streams.values_mut() {
+                                    stream.send_window += delta;
+                                    stream.wake();
+                                }
+                            }
+                            SETTINGS_MAX_FRAME_SIZE => state.max_frame = value as usize,
+                            _ => {}
+                        }
+                    }
+                    state.out.extend_from_slice(&encode_frame(SETTINGS, ACK, 0, &[]));
+                    drop(state);
+                    shared.pending.notify_one();
+                }
+                PING if flags & ACK == 0 => {
+                    shared.send_frame(&encode_frame(PING, ACK, 0, &payload))?;
+                }
+                WINDOW_UPDATE if len == 4 => {
+                    let increment = (BigEndian::read_u32(&payload) & 0x7fff_ffff) as i64;
+                    let mut state = shared.state.lock().unwrap();
+                    if sid == 0 {
+                        state.send_connection += increment;
+                        state.streams.values_mut().for_each(StreamState::wake);
+                    } else if let Some(stream) = state.streams.get_mut(&sid) {
+                        stream.send_window += increment;
+                        stream.wake();
+                    }
+                }
+                GOAWAY => {
+                    // 已有的 stream 继续，新的 stream 使用新连接
+                    debug!("h2 connection goaway");
+                    shared.next_id.store(MAX_STREAM_ID + 1, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn write_loop(mut writer: WriteHalf<AnyStream>, shared: &Shared) {
+        loop {
+            let pending = shared.pending.notified();
+            // 同一时间可能有多个 frame 在排队，合并写入
+            let buf = std::mem::take(&mut shared.state.lock().unwrap().out);
+            if buf.is_empty() {
+                // read loop 结束
+                if shared.closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                pending.await;
+                continue;
+            }
+            shared.drained.notify_waiters();
+            shared.drained_wakers.lock().unwrap().drain(..).for_each(Waker::wake);
+            if let Err(err) = writer.write_all(&buf).await {
+                debug!("h2 connection write failed {}", err);
+                break;
+            }
+            if let Err(err) = writer.flush().await {
+                debug!("h2 connection flush failed {}", err);
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn strip_padding(flags: u8, payload: &[u8]) -> io::Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let pad = *payload.first().ok_or_else(|| invalid("bad h2 padding"))? as usize;
+    if pad + 1 > payload.len() {
+        return Err(invalid("bad h2 padding"));
+    }
+    Ok(&payload[1..payload.len() - pad])
+}
+
+pub struct H2Stream {
+    sid: u32,
+    shared: Arc<Shared>,
+    read_buf: Bytes,
+    // grpc 时未解析完整的 message
+    grpc: Option<BytesMut>,
+    fin_sent: bool,
+}
+
+fn closed_stream() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "h2 stream closed")
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            let me = &mut *self;
+            if let Some(pending) = &mut me.grpc {
+                if let Some(data) = decode_grpc(pending)? {
+                    me.read_buf = data;
+                    continue;
+                }
+            }
+            let mut state = me.shared.state.lock().unwrap();
+            let stream = match state.streams.get_mut(&me.sid) {
+                Some(x) => x,
+                // RST_STREAM 或者连接关闭，keepalive 判定断开时返回错误
+                None => return Poll::Ready(me.shared.liveness.check()),
+            };
+            let data = match stream.recv.pop_front() {
+                Some(x) => x,
+                None if stream.eof => return Poll::Ready(me.shared.liveness.check()),
+                None => {
+                    stream.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            };
+            // 应用读取之后才归还窗口，读得慢时对端随之停止发送
+            state.refund(me.sid, data.len());
+            drop(state);
+            me.shared.pending.notify_one();
+            match &mut me.grpc {
+                Some(pending) => pending.extend_from_slice(&data),
+                None => me.read_buf = data,
+            }
+        }
+        let n = min(self.read_buf.len(), buf.remaining());
+        let data = self.read_buf.split_to(n);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    // 没有发送窗口或者写入队列已满时返回 Pending，窗口更新或者队列写出后唤醒
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.fin_sent || self.shared.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(closed_stream()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        if state.out.len() >= MAX_QUEUED {
+            self.shared.drained_wakers.lock().unwrap().push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let limit = min(state.send_connection, state.max_frame as i64);
+        let stream = match state.streams.get_mut(&self.sid) {
+            Some(x) => x,
+            None => return Poll::Ready(Err(closed_stream())),
+        };
+        let overhead = if self.grpc.is_some() { GRPC_OVERHEAD } else { 0 };
+        let available = min(limit, stream.send_window) - overhead as i64;
+        if available <= 0 {
+            stream.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = min(min(buf.len(), DEFAULT_MAX_FRAME), available as usize);
+        let data = match self.grpc {
+            Some(_) => encode_grpc(&buf[..n]),
+            None => buf[..n].to_vec(),
+        };
+        stream.send_window -= data.len() as i64;
+        state.send_connection -= data.len() as i64;
+        state.out.extend_from_slice(&encode_frame(DATA, 0, self.sid, &data));
+        drop(state);
+        self.shared.pending.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.fin_sent {
+            self.fin_sent = true;
+            let _ = self.shared.send_frame(&encode_frame(DATA, END_STREAM, self.sid, &[]));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for H2Stream {
+    fn drop(&mut self) {
+        let removed = self.shared.state.lock().unwrap().remove(self.sid);
+        // 对端还没有结束或者自己还没有结束时取消 stream
+        if let Some(stream) = removed {
+            if !stream.eof || !self.fin_sent {
+                let _ = self.shared.send_frame(&encode_frame(RST_STREAM, 0, self.sid, &CANCEL.to_be_bytes()));
+            }
+        }
+        self.shared.pending.notify_one();
+    }
+}
+
+// 到同一个 server 的 h2 连接，全部 stream 共享，连接关闭或者 GOAWAY 之后重新建立
+pub struct H2Client {
+    authority: String,
+    path: String,
+    grpc: bool,
+    keepalive: Option<Keepalive>,
+    connection: tokio::sync::Mutex<Option<H2Connection>>,
+}
+
+impl H2Client {
+    /// authority is used when transport.host is not set
+    pub fn new(settings: &TransportSettings, authority: &str) -> Result<H2Client> {
+        let (path, grpc) = match settings.network.as_str() {
+            "h2" => (settings.path.clone().unwrap_or_else(|| "/".to_string()), false),
+            "grpc" => {
+                let service = settings.service_name.as_deref().unwrap_or("GunService");
+                (format!("/{}/Tun", service.trim_matches('/')), true)
+            }
+            x => return Err(anyhow!("unknown transport {}, expect h2 or grpc", x)),
+        };
+        Ok(H2Client {
+            authority: settings.host.clone().unwrap_or_else(|| authority.to_string()),
+            path,
+            grpc,
+            keepalive: settings.keepalive.as_ref().map(Keepalive::new),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    fn headers(&self) -> Vec<u8> {
+        let mut extra = vec![("user-agent", "Mozilla/5.0")];
+        if self.grpc {
+            extra = vec![("content-type", "application/grpc"), ("te", "trailers"), ("user-agent", "grpc-go/1.48.0")];
+        }
+        request_headers(&self.authority, &self.path, &extra)
+    }
+
+    /// open a stream, dial a new connection (tls with alpn h2) if there is none
+    pub async fn open<F, Fut>(&self, dial: F) -> Result<H2Stream>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<AnyStream>>,
+    {
+        let mut connection = self.connection.lock().await;
+        if let Some(conn) = connection.as_ref().filter(|x| !x.is_closed()) {
+            if let Ok(stream) = conn.open_stream(&self.headers(), self.grpc) {
+                return Ok(stream);
+            }
+        }
+        let conn = H2Connection::new(dial().await?, self.keepalive).await?;
+        trace!("new h2 connection to {}", self.authority);
+        let stream = conn
+            .open_stream(&self.headers(), self.grpc)
+            .map_err(|err| anyhow!("open h2 stream failed {}", err))?;
+        connection.replace(conn);
+        Ok(stream)
+    }
+}
+
+#[test]
+fn test_h2_codec() {
+    let block = request_headers("example.com", "/GunService/Tun", &[("te", "trailers")]);
+    assert_eq!(&block[..3], &[0x83, 0x87, 0x01]);
+    assert_eq!(block[3] as usize, "example.com".len());
+    // 静态表 :status 200，字面值 :status 502，huffman 无法解析
+    assert_eq!(response_status(&[0x88]), Some(200));
+    assert_eq!(response_status(&[0x20, 0x8d]), Some(404));
+    assert_eq!(response_status(&[0x48, 0x03, b'5', b'0', b'2']), Some(502));
+    assert_eq!(response_status(&[0x48, 0x82, 0x64, 0x02]), None);
+    let mut value = Vec::new();
+    encode_int(&mut value, 0, 5, 1337);
+    assert_eq!(value, vec![0x1f, 0x9a, 0x0a]);
+    assert_eq!(decode_int(&value, 5), Some((1337, 3)));
+
+    let data = vec![7u8; 300];
+    let mut buf = BytesMut::from(&encode_grpc(&data)[..]);
+    buf.extend_from_slice(&encode_grpc(b"next")[..3]);
+    assert_eq!(decode_grpc(&mut buf).unwrap().unwrap(), data);
+    assert!(decode_grpc(&mut buf).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_h2_flow_control() {
+    use std::time::Duration;
+    use tokio::{io::DuplexStream, time::timeout};
+
+    async fn read_frame(server: &mut DuplexStream) -> (u8, u8, u32, Vec<u8>) {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        server.read_exact(&mut header).await.unwrap();
+        let mut payload = vec![0u8; BigEndian::read_u24(&header[..3]) as usize];
+        server.read_exact(&mut payload).await.unwrap();
+        (header[3], header[4], BigEndian::read_u32(&header[5..9]), payload)
+    }
+
+    let (client, mut server) = tokio::io::duplex(1 << 16);
+    let conn = H2Connection::new(Box::new(client), None).await.unwrap();
+    let mut preface = [0u8; PREFACE.len()];
+    server.read_exact(&mut preface).await.unwrap();
+    // stream 窗口只有 100
+    let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+    settings.extend_from_slice(&100u32.to_be_bytes());
+    server.write_all(&encode_frame(SETTINGS, 0, 0, &settings)).await.unwrap();
+    loop {
+        let (ty, flags, _, _) = read_frame(&mut server).await;
+        if ty == SETTINGS && flags & ACK != 0 {
+            break;
+        }
+    }
+
+    let mut stream = conn.open_stream(&request_headers("example.com", "/", &[]), false).unwrap();
+    assert_eq!(stream.write(&[1u8; 150]).await.unwrap(), 100);
+    // 没有窗口时等待
+    assert!(timeout(Duration::from_millis(50), stream.write(&[1u8; 50])).await.is_err());
+    assert_eq!(read_frame(&mut server).await.0, HEADERS);
+    let (ty, _, sid, payload) = read_frame(&mut server).await;
+    assert_eq!((ty, sid, payload.len()), (DATA, 1, 100));
+    server.write_all(&encode_frame(WINDOW_UPDATE, 0, 1, &50u32.to_be_bytes())).await.unwrap();
+    assert_eq!(stream.write(&[1u8; 50]).await.unwrap(), 50);
+    assert_eq!(read_frame(&mut server).await.3.len(), 50);
+
+    // 接收到的数据在读取之前不归还窗口
+    server.write_all(&encode_frame(HEADERS, END_HEADERS, 1, &[0x88])).await.unwrap();
+    let half = vec![2u8; RECV_WINDOW as usize / 2];
+    for chunk in half.chunks(DEFAULT_MAX_FRAME) {
+        server.write_all(&encode_frame(DATA, 0, 1, chunk)).await.unwrap();
+    }
+    assert!(timeout(Duration::from_millis(50), read_frame(&mut server)).await.is_err());
+    let mut buf = vec![0u8; half.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, half);
+    let mut updates = Vec::new();
+    for _ in 0..2 {
+        let (ty, _, sid, payload) = read_frame(&mut server).await;
+        assert_eq!(ty, WINDOW_UPDATE);
+        updates.push((sid, BigEndian::read_u32(&payload)));
+    }
+    updates.sort();
+    assert_eq!(updates, vec![(0, RECV_WINDOW / 2), (1, RECV_WINDOW / 2)]);
+
+    // 无法解析的 :status 同样拒绝
+    let mut rejected = conn.open_stream(&request_headers("example.com", "/", &[]), false).unwrap();
+    server.write_all(&encode_frame(HEADERS, END_HEADERS, 3, &[0x48, 0x82, 0x64, 0x02])).await.unwrap();
+    let mut buf = [0u8; 1];
+    assert_eq!(rejected.read(&mut buf).await.unwrap(), 0);
+    assert!(rejected.write(&buf).await.is_err());
+}
@@ -0,0 +1,170 @@
+// http/3 的最小实现，供 doh3 与 hysteria2 认证使用
+// 只有请求方向的编码与 :status 的识别，qpack 只使用 static table，不使用 huffman
+
+// http/3 frame 与 stream 类型
+pub const FRAME_DATA: u64 = 0x00;
+pub const FRAME_HEADERS: u64 = 0x01;
+pub const FRAME_SETTINGS: u64 = 0x04;
+pub const STREAM_CONTROL: u64 = 0x00;
+
+// qpack static table
+pub const QPACK_AUTHORITY: u8 = 0;
+pub const QPACK_PATH: u8 = 1;
+pub const QPACK_CONTENT_LENGTH: u8 = 4;
+pub const QPACK_METHOD_POST: u8 = 20;
+pub const QPACK_SCHEME_HTTPS: u8 = 23;
+const QPACK_STATUS: [(u8, u16); 5] = [(24, 103), (25, 200), (26, 304), (27, 404), (28, 503)];
+pub const QPACK_ACCEPT_DNS_MESSAGE: u8 = 30;
+pub const QPACK_CONTENT_TYPE_DNS_MESSAGE: u8 = 44;
+
+// quic variable-length integer
+pub fn encode_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+pub fn decode_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = 1 << (first >> 6);
+    if data.len() < len {
+        return None;
+    }
+    let mut value = (first & 0x3f) as u64;
+    for b in &data[1..len] {
+        value = (value << 8) | *b as u64;
+    }
+    Some((value, len))
+}
+
+pub fn encode_frame(buf: &mut Vec<u8>, ty: u64, payload: &[u8]) {
+    encode_varint(buf, ty);
+    encode_varint(buf, payload.len() as u64);
+    buf.extend_from_slice(payload);
+}
+
+/// split a buffer into (type, payload) frames
+pub fn decode_frames(data: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    let mut frames = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (ty, n) = decode_varint(rest)?;
+        rest = &rest[n..];
+        let (len, n) = decode_varint(rest)?;
+        rest = &rest[n..];
+        if rest.len() < len as usize {
+            return None;
+        }
+        let (payload, next) = rest.split_at(len as usize);
+        frames.push((ty, payload));
+        rest = next;
+    }
+    Some(frames)
+}
+
+// qpack prefixed integer, first 是 prefix 之外的高位
+fn encode_prefixed(buf: &mut Vec<u8>, first: u8, prefix: u8, value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        buf.push(first | value as u8);
+        return;
+    }
+    buf.push(first | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        buf.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    buf.push(rest as u8);
+}
+
+/// qpack encoded header block, only the static table
+pub struct HeaderBlock {
+    buf: Vec<u8>,
+}
+
+impl HeaderBlock {
+    pub fn new() -> HeaderBlock {
+        // required insert count 0, delta base 0
+        HeaderBlock { buf: vec![0, 0] }
+    }
+
+    /// static table entry with its value
+    pub fn indexed(mut self, index: u8) -> HeaderBlock {
+        encode_prefixed(&mut self.buf, 0xc0, 6, index as usize);
+        self
+    }
+
+    /// static table name with a literal value
+    pub fn literal(mut self, index: u8, value: &str) -> HeaderBlock {
+        encode_prefixed(&mut self.buf, 0x50, 4, index as usize);
+        encode_prefixed(&mut self.buf, 0x00, 7, value.len());
+        self.buf.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    /// literal name and value, the name must be lowercase
+    pub fn field(mut self, name: &str, value: &str) -> HeaderBlock {
+        encode_prefixed(&mut self.buf, 0x20, 3, name.len());
+        self.buf.extend_from_slice(name.as_bytes());
+        encode_prefixed(&mut self.buf, 0x00, 7, value.len());
+        self.buf.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for HeaderBlock {
+    fn default() -> Self {
+        HeaderBlock::new()
+    }
+}
+
+/// :status of a response header block
+/// only the static table name is recognized, None for other encodings (huffman etc.)
+pub fn response_status(headers: &[u8]) -> Option<u16> {
+    // 跳过 required insert count 与 delta base，都是单字节时才继续
+    let line = *headers.get(2)?;
+    if headers[0] == 0xff || headers[1] & 0x7f == 0x7f {
+        return None;
+    }
+    if line & 0xc0 == 0xc0 {
+        let index = line & 0x3f;
+        return QPACK_STATUS.iter().find(|(i, _)| *i == index).map(|(_, s)| *s);
+    }
+    if line & 0xf0 == 0x50 && QPACK_STATUS.iter().any(|(i, _)| *i == line & 0x0f) {
+        let len = *headers.get(3)?;
+        if len & 0x80 != 0 {
+            return None;
+        }
+        let value = headers.get(4..4 + len as usize)?;
+        return std::str::from_utf8(value).ok()?.parse().ok();
+    }
+    None
+}
+
+#[test]
+fn test_qpack_header_block() {
+    let block = HeaderBlock::new()
+        .indexed(QPACK_METHOD_POST)
+        .literal(QPACK_PATH, "/auth")
+        .field("hysteria-auth", "secret")
+        .finish();
+    assert_eq!(&block[..3], &[0, 0, 0xc0 | QPACK_METHOD_POST]);
+    assert_eq!(&block[3..5], &[0x50 | QPACK_PATH, 5]);
+    // 名字长度 13 超过 3 bit prefix
+    assert_eq!(&block[10..12], &[0x27, 13 - 7]);
+    assert_eq!(&block[12..25], b"hysteria-auth");
+    let mut frames = Vec::new();
+    encode_frame(&mut frames, FRAME_HEADERS, &[0, 0, 0x50 | 24, 3, b'2', b'3', b'3']);
+    let decoded = decode_frames(&frames).unwrap();
+    assert_eq!(decoded[0].0, FRAME_HEADERS);
+    assert_eq!(response_status(decoded[0].1), Some(233));
+    assert!(decode_frames(&frames[..frames.len() - 1]).is_none());
+}
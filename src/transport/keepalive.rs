@@ -0,0 +1,122 @@
+// 多路复用连接（mux、h2）上的应用层 keepalive
+// 每个 interval 发送一次 ping，对端在 interval * tolerance 内没有任何数据时认为路径已经断开
+// 移动网络切换之后旧连接往往既不报错也不再有数据，不探测的话上面的 session 会一直挂着
+// 判定断开后关闭连接，stream 读取返回 TimedOut，pool 下次 open 时重新建立连接
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::config::KeepaliveSettings;
+
+const DEFAULT_INTERVAL: u64 = 15;
+const DEFAULT_TOLERANCE: u32 = 3;
+
+/// when the peer was last heard from, shared by the read loop and the keepalive task
+pub struct Liveness {
+    last_read: Mutex<Instant>,
+    dead: AtomicBool,
+}
+
+impl Default for Liveness {
+    fn default() -> Liveness {
+        Liveness {
+            last_read: Mutex::new(Instant::now()),
+            dead: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Liveness {
+    /// any frame from the peer
+    pub fn touch(&self) {
+        *self.last_read.lock().unwrap() = Instant::now();
+    }
+
+    pub fn silent(&self) -> Duration {
+        self.last_read.lock().unwrap().elapsed()
+    }
+
+    /// Err once keepalive declared the peer dead, streams report it instead of a clean EOF
+    pub fn check(&self) -> io::Result<()> {
+        if self.dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "peer not responding to keepalive"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    interval: Duration,
+    tolerance: u32,
+}
+
+impl Keepalive {
+    pub fn new(settings: &KeepaliveSettings) -> Keepalive {
+        Keepalive {
+            interval: Duration::from_secs(settings.interval.unwrap_or(DEFAULT_INTERVAL).max(1)),
+            tolerance: settings.tolerance.unwrap_or(DEFAULT_TOLERANCE).max(1),
+        }
+    }
+
+    /// ping every interval until the peer is silent for too long or ping fails, returns the reason
+    pub async fn run<P>(&self, liveness: &Liveness, mut ping: P) -> io::Error
+    where
+        P: FnMut() -> io::Result<()>,
+    {
+        let timeout = self.interval * self.tolerance;
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let silent = liveness.silent();
+            if silent >= timeout {
+                liveness.dead.store(true, Ordering::SeqCst);
+                return io::Error::new(io::ErrorKind::TimedOut, format!("no data from peer for {:?}", silent));
+            }
+            if let Err(err) = ping() {
+                return err;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_keepalive() {
+    let keepalive = Keepalive {
+        interval: Duration::from_millis(20),
+        tolerance: 2,
+    };
+    let liveness = Liveness::default();
+    let mut pings = 0;
+    // 对端不回复
+    let err = keepalive
+        .run(&liveness, || {
+            pings += 1;
+            Ok(())
+        })
+        .await;
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(pings >= 1);
+    assert_eq!(liveness.check().unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+    // 每次 ping 都收到回复
+    let liveness = Liveness::default();
+    let mut pings = 0;
+    let err = keepalive
+        .run(&liveness, || {
+            liveness.touch();
+            pings += 1;
+            if pings == 5 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+            }
+            Ok(())
+        })
+        .await;
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert!(liveness.check().is_ok());
+}
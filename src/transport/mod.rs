@@ -0,0 +1,9 @@
+// transport 层在 tcp stream 之上做一层包装（tls 等），供各个 outbound/inbound 复用
+// 而不是每个协议各自处理
+
+pub mod h2;
+pub mod h3;
+pub mod keepalive;
+pub mod mux;
+pub mod quic;
+pub mod tls;
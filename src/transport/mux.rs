@@ -0,0 +1,506 @@
+// smux 风格的多路复用
+// 多个 session 共享一条到 proxy server 的长连接，省掉每次握手（tcp + tls + 协议）的延迟
+//
+// frame 格式
+// |<-ver 1 byte->|<-cmd 1 byte->|<-length 2 bytes->|<-stream id 4 bytes->|<-payload->|
+// client 使用奇数 stream id，server 使用偶数 stream id
+// keepalive: client 发送 payload 为 PING 的 NOP，server 回复 payload 为 PONG 的 NOP，空 payload 的 NOP 仍然忽略
+//
+// 协议没有流量控制，两个方向都用有限的缓冲实现背压
+// 接收: 每个 stream 最多缓冲 STREAM_BUFFER 个 frame，满了之后 read loop 等待，对端随之被 tcp 阻塞
+// 发送: 等待写入的 PSH 超过 MAX_QUEUED bytes 时 poll_write 返回 Pending
+
+use std::{
+    cmp::min,
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use futures::ready;
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    sync::mpsc,
+};
+
+use crate::{config::MuxSettings, proxy::AnyStream};
+
+use super::keepalive::{Keepalive, Liveness};
+
+const VERSION: u8 = 1;
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const HEADER_LEN: usize = 8;
+const MAX_FRAME_PAYLOAD: usize = 0xffff;
+const PING: u8 = 0;
+const PONG: u8 = 1;
+
+// 每个 stream 接收方向缓冲的 frame
+const STREAM_BUFFER: usize = 16;
+// 等待写入 carrier 的 PSH 数据
+const MAX_QUEUED: usize = 256 * 1024;
+
+const DEFAULT_MAX_STREAMS: usize = 8;
+const DEFAULT_IDLE_TIMEOUT: u64 = 60;
+
+struct Frame {
+    cmd: u8,
+    sid: u32,
+    data: Bytes,
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, mpsc::Sender<Bytes>>>,
+    writer: mpsc::UnboundedSender<Frame>,
+    // writer 中 PSH 的数据量，超过 MAX_QUEUED 时 stream 在 blocked 中等待
+    queued: AtomicUsize,
+    blocked: Mutex<Vec<Waker>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+    last_active: Mutex<Instant>,
+    liveness: Liveness,
+}
+
+impl Shared {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // drop 全部 sender，stream 端读到 EOF
+        self.streams.lock().unwrap().clear();
+        self.wake_blocked();
+    }
+    fn wake_blocked(&self) {
+        self.blocked.lock().unwrap().drain(..).for_each(Waker::wake);
+    }
+}
+
+pub struct MuxSession {
+    shared: Arc<Shared>,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<MuxStream>>,
+}
+
+impl MuxSession {
+    pub fn client(stream: AnyStream, idle_timeout: Duration, keepalive: Option<Keepalive>) -> MuxSession {
+        MuxSession::new(stream, 1, idle_timeout, keepalive)
+    }
+
+    /// server only answers pings
+    pub fn server(stream: AnyStream, idle_timeout: Duration) -> MuxSession {
+        MuxSession::new(stream, 2, idle_timeout, None)
+    }
+
+    fn new(stream: AnyStream, first_id: u32, idle_timeout: Duration, keepalive: Option<Keepalive>) -> MuxSession {
+        let (writer_tx, writer_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            streams: Mutex::new(HashMap::new()),
+            writer: writer_tx,
+            queued: AtomicUsize::new(0),
+            blocked: Mutex::new(Vec::new()),
+            next_id: AtomicU32::new(first_id),
+            closed: AtomicBool::new(false),
+            last_active: Mutex::new(Instant::now()),
+            liveness: Liveness::default(),
+        });
+        let (read_half, write_half) = tokio::io::split(stream);
+        let reader = tokio::spawn(MuxSession::read_loop(read_half, shared.clone(), incoming_tx));
+        let writer_shared = shared.clone();
+        tokio::spawn(async move {
+            let write = MuxSession::write_loop(write_half, writer_rx, writer_shared.clone(), idle_timeout);
+            match keepalive {
+                // 对端没有响应时 write loop 可能阻塞在写入上，直接丢弃
+                Some(keepalive) => tokio::select! {
+                    _ = write => {}
+                    err = keepalive.run(&writer_shared.liveness, || send_frame(&writer_shared, CMD_NOP, 0, Bytes::from_static(&[PING]))) => {
+                        debug!("close mux session {}", err);
+                    }
+                },
+                None => write.await,
+            }
+            writer_shared.close();
+            reader.abort();
+        });
+        MuxSession {
+            shared,
+            incoming: tokio::sync::Mutex::new(incoming_rx),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    pub fn open_stream(&self) -> io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"));
+        }
+        let sid = self.shared.next_id.fetch_add(2, Ordering::SeqCst);
+        let stream = MuxStream::new(sid, self.shared.clone());
+        send_frame(&self.shared, CMD_SYN, sid, Bytes::new())?;
+        Ok(stream)
+    }
+
+    /// server side, wait for the next stream opened by peer
+    pub async fn accept(&self) -> Option<MuxStream> {
+        self.incoming.lock().await.recv().await
+    }
+
+    async fn read_loop(
+        mut reader: ReadHalf<AnyStream>,
+        shared: Arc<Shared>,
+        incoming: mpsc::UnboundedSender<MuxStream>,
+    ) {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            if let Err(err) = reader.read_exact(&mut header).await {
+                debug!("mux session read header failed {}", err);
+                break;
+            }
+            if header[0] != VERSION {
+                debug!("unknown mux version {}", header[0]);
+                break;
+            }
+            let cmd = header[1];
+            let len = BigEndian::read_u16(&header[2..4]) as usize;
+            let sid = BigEndian::read_u32(&header[4..8]);
+            let mut data = vec![0u8; len];
+            if let Err(err) = reader.read_exact(&mut data).await {
+                debug!("mux session read payload failed {}", err);
+                break;
+            }
+            shared.liveness.touch();
+            // keepalive 不算活跃，不影响 idle 回收
+            if cmd != CMD_NOP {
+                shared.touch();
+            }
+            match cmd {
+                CMD_SYN => {
+                    let stream = MuxStream::new(sid, shared.clone());
+                    if incoming.send(stream).is_err() {
+                        trace!("mux stream {} opened by peer but nobody accepts", sid);
+                    }
+                }
+                // 空的 PSH 不交给 stream，否则会被当作 EOF
+                CMD_PSH if data.is_empty() => {}
+                CMD_PSH => {
+                    let tx = shared.streams.lock().unwrap().get(&sid).cloned();
+                    if let Some(tx) = tx {
+                        // stream 缓冲已满时等待，stream 关闭时丢弃
+                        let _ = tx.send(Bytes::from(data)).await;
+                    }
+                }
+                CMD_FIN => {
+                    shared.streams.lock().unwrap().remove(&sid);
+                }
+                CMD_NOP => {
+                    if data == [PING] && send_frame(&shared, CMD_NOP, sid, Bytes::from_static(&[PONG])).is_err() {
+                        break;
+                    }
+                }
+                _ => {
+                    debug!("unknown mux cmd {}", cmd);
+                    break;
+                }
+            }
+        }
+        shared.close();
+    }
+
+    async fn write_loop(
+        mut writer: WriteHalf<AnyStream>,
+        mut frames: mpsc::UnboundedReceiver<Frame>,
+        shared: Arc<Shared>,
+        idle_timeout: Duration,
+    ) {
+        let mut buf = Vec::with_capacity(HEADER_LEN + MAX_FRAME_PAYLOAD);
+        loop {
+            let frame = tokio::select! {
+                frame = frames.recv() => frame,
+                _ = tokio::time::sleep(idle_timeout) => {
+                    let idle = shared.last_active.lock().unwrap().elapsed();
+                    if shared.streams.lock().unwrap().is_empty() && idle >= idle_timeout {
+                        trace!("reap idle mux session, idle {:?}", idle);
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let frame = match frame {
+                Some(f) => f,
+                None => break,
+            };
+            if shared.closed.load(Ordering::SeqCst) {
+                break;
+            }
+            buf.clear();
+            buf.extend_from_slice(&[VERSION, frame.cmd, 0, 0, 0, 0, 0, 0]);
+            BigEndian::write_u16(&mut buf[2..4], frame.data.len() as u16);
+            BigEndian::write_u32(&mut buf[4..8], frame.sid);
+            buf.extend_from_slice(&frame.data);
+            // carrier 可能缓存数据（tls、noise），每个 frame 之后 flush
+            if let Err(err) = writer.write_all(&buf).await {
+                debug!("mux session write failed {}", err);
+                break;
+            }
+            if let Err(err) = writer.flush().await {
+                debug!("mux session flush failed {}", err);
+                break;
+            }
+            if frame.cmd == CMD_PSH {
+                shared.queued.fetch_sub(frame.data.len(), Ordering::SeqCst);
+                shared.wake_blocked();
+            }
+            if frame.cmd != CMD_NOP {
+                shared.touch();
+            }
+        }
+        let _ = writer.shutdown().await;
+    }
+}
+
+fn send_frame(shared: &Shared, cmd: u8, sid: u32, data: Bytes) -> io::Result<()> {
+    shared
+        .writer
+        .send(Frame { cmd, sid, data })
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"))
+}
+
+pub struct MuxStream {
+    sid: u32,
+    shared: Arc<Shared>,
+    rx: mpsc::Receiver<Bytes>,
+    read_buf: Bytes,
+    fin_sent: bool,
+}
+
+impl MuxStream {
+    fn new(sid: u32, shared: Arc<Shared>) -> MuxStream {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        shared.streams.lock().unwrap().insert(sid, tx);
+        MuxStream {
+            sid,
+            shared,
+            rx,
+            read_buf: Bytes::new(),
+            fin_sent: false,
+        }
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_buf.is_empty() {
+            match ready!(self.rx.poll_recv(cx)) {
+                Some(data) => self.read_buf = data,
+                // FIN 或者 session 关闭，keepalive 判定断开时返回错误
+                None => return Poll::Ready(self.shared.liveness.check()),
+            }
+        }
+        let n = min(self.read_buf.len(), buf.remaining());
+        let data = self.read_buf.split_to(n);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        if self.fin_sent || self.shared.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "mux stream closed",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        {
+            // 持有锁检查，write loop 减少 queued 之后一定能看到这里注册的 waker
+            let mut blocked = self.shared.blocked.lock().unwrap();
+            if self.shared.queued.load(Ordering::SeqCst) >= MAX_QUEUED {
+                blocked.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        let n = min(buf.len(), MAX_FRAME_PAYLOAD);
+        self.shared.queued.fetch_add(n, Ordering::SeqCst);
+        send_frame(&self.shared, CMD_PSH, self.sid, Bytes::copy_from_slice(&buf[..n]))?;
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        if !self.fin_sent {
+            self.fin_sent = true;
+            let _ = send_frame(&self.shared, CMD_FIN, self.sid, Bytes::new());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        if !self.fin_sent {
+            let _ = send_frame(&self.shared, CMD_FIN, self.sid, Bytes::new());
+        }
+        self.shared.streams.lock().unwrap().remove(&self.sid);
+    }
+}
+
+// 管理到同一个 server 的多条 mux session
+// 每条 session 最多 max_streams 个 stream，满了再新建 session
+pub struct MuxPool {
+    max_streams: usize,
+    idle_timeout: Duration,
+    keepalive: Option<Keepalive>,
+    sessions: tokio::sync::Mutex<Vec<MuxSession>>,
+}
+
+impl MuxPool {
+    pub fn new(settings: &MuxSettings) -> MuxPool {
+        MuxPool {
+            max_streams: settings.max_streams.unwrap_or(DEFAULT_MAX_STREAMS),
+            idle_timeout: Duration::from_secs(settings.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT)),
+            keepalive: settings.keepalive.as_ref().map(Keepalive::new),
+            sessions: tokio::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// open a stream on an existing session, dial a new carrier connection if all sessions are full
+    pub async fn open<F, Fut>(&self, dial: F) -> Result<MuxStream>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<AnyStream>>,
+    {
+        let mut sessions = self.sessions.lock().await;
+        sessions.retain(|s| !s.is_closed());
+        for session in sessions.iter() {
+            if session.num_streams() < self.max_streams {
+                if let Ok(stream) = session.open_stream() {
+                    return Ok(stream);
+                }
+            }
+        }
+        let carrier = dial().await?;
+        let session = MuxSession::client(carrier, self.idle_timeout, self.keepalive);
+        let stream = session
+            .open_stream()
+            .map_err(|err| anyhow!("open mux stream failed {}", err))?;
+        trace!("new mux session, total {}", sessions.len() + 1);
+        sessions.push(session);
+        Ok(stream)
+    }
+}
+
+#[tokio::test]
+async fn test_mux_round_trip() {
+    let (client, server) = tokio::io::duplex(1 << 16);
+    let client = MuxSession::client(Box::new(client), Duration::from_secs(60), None);
+    let server = MuxSession::server(Box::new(server), Duration::from_secs(60));
+    let mut stream = client.open_stream().unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut accepted = server.accept().await.unwrap();
+    let mut buf = [0u8; 5];
+    accepted.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    // 超过一个 frame 的数据
+    let data = vec![7u8; MAX_FRAME_PAYLOAD * 3];
+    accepted.write_all(&data).await.unwrap();
+    let mut buf = vec![0u8; data.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, data);
+}
+
+#[tokio::test]
+async fn test_mux_multi_stream() {
+    let (client, server) = tokio::io::duplex(1 << 16);
+    let client = MuxSession::client(Box::new(client), Duration::from_secs(60), None);
+    let server = MuxSession::server(Box::new(server), Duration::from_secs(60));
+    let mut streams: Vec<MuxStream> = (0..3).map(|_| client.open_stream().unwrap()).collect();
+    assert_eq!(client.num_streams(), 3);
+    for (i, stream) in streams.iter_mut().enumerate().rev() {
+        stream.write_all(&[i as u8; 4]).await.unwrap();
+    }
+    for i in 0..3u8 {
+        let mut accepted = server.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        // stream 按 SYN 的顺序 accept，数据不会混到其他 stream
+        assert_eq!(buf, [i; 4]);
+        accepted.write_all(&[i + 10]).await.unwrap();
+    }
+    for (i, stream) in streams.iter_mut().enumerate() {
+        assert_eq!(stream.read_u8().await.unwrap(), i as u8 + 10);
+    }
+}
+
+#[tokio::test]
+async fn test_mux_close() {
+    let (client, server) = tokio::io::duplex(1 << 16);
+    let client = MuxSession::client(Box::new(client), Duration::from_secs(60), None);
+    let server = MuxSession::server(Box::new(server), Duration::from_secs(60));
+    let mut stream = client.open_stream().unwrap();
+    stream.write_all(b"bye").await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert!(stream.write_all(b"more").await.is_err());
+    let mut accepted = server.accept().await.unwrap();
+    let mut buf = Vec::new();
+    accepted.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"bye");
+    // drop 同样发送 FIN
+    let stream = client.open_stream().unwrap();
+    let mut accepted = server.accept().await.unwrap();
+    drop(stream);
+    assert_eq!(accepted.read(&mut [0u8; 1]).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_mux_empty_push() {
+    let (mut raw, server) = tokio::io::duplex(1 << 16);
+    let server = MuxSession::server(Box::new(server), Duration::from_secs(60));
+    let frame = |cmd: u8, data: &[u8]| {
+        let mut buf = vec![VERSION, cmd, 0, 0, 0, 0, 0, 1];
+        BigEndian::write_u16(&mut buf[2..4], data.len() as u16);
+        buf.extend_from_slice(data);
+        buf
+    };
+    raw.write_all(&frame(CMD_SYN, &[])).await.unwrap();
+    raw.write_all(&frame(CMD_PSH, &[])).await.unwrap();
+    raw.write_all(&frame(CMD_PSH, b"data")).await.unwrap();
+    let mut accepted = server.accept().await.unwrap();
+    let mut buf = [0u8; 4];
+    // 空的 PSH 不是 EOF
+    assert_eq!(accepted.read(&mut buf).await.unwrap(), 4);
+    assert_eq!(&buf, b"data");
+}
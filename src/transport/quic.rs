@@ -0,0 +1,270 @@
+// quic transport
+// 对 outbound 提供双向 stream 与 datagram，hysteria 风格或者 vmess over quic 等协议可以构建在其上
+// 到同一个 server 只保持一条 quic connection，stream 之间共享
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::StreamExt;
+use log::{debug, trace};
+use quinn::{
+    Connection, Datagrams, Endpoint, EndpointConfig, NewConnection, RecvStream, SendStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Mutex,
+};
+
+use crate::config::QuicSettings;
+
+use super::tls::build_client_config;
+
+// quinn 的 SendStream, RecvStream 合并为一个 stream，方便作为 StreamWrapperTrait 使用
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
+
+struct QuicConnection {
+    connection: Connection,
+    datagrams: Mutex<Datagrams>,
+}
+
+pub struct QuicConnector {
+    config: quinn::ClientConfig,
+    server: SocketAddr,
+    server_name: String,
+    zero_rtt: bool,
+    // endpoint 需要在 tokio runtime 中创建，所以第一次连接时才初始化
+    // 同一地址族的多个 connector 可以共享一个 endpoint（一个 udp socket）
+    endpoint: Arc<Mutex<Option<Endpoint>>>,
+    connection: Mutex<Option<Arc<QuicConnection>>>,
+}
+
+fn unspecified_addr(server: &SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+impl QuicConnector {
+    /// server_name is used for sni when tls.sni not configured
+    pub fn new(server: SocketAddr, server_name: &str, settings: &QuicSettings) -> Result<QuicConnector> {
+        let mut crypto = build_client_config(&settings.tls)?;
+        // 0-RTT 需要 tls session resumption 与 early data
+        crypto.enable_early_data = settings.zero_rtt;
+        let config = quinn::ClientConfig::new(Arc::new(crypto));
+        Ok(QuicConnector {
+            config,
+            server,
+            server_name: settings
+                .tls
+                .sni
+                .clone()
+                .unwrap_or_else(|| server_name.to_string()),
+            zero_rtt: settings.zero_rtt,
+            endpoint: Arc::new(Mutex::new(None)),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// transport parameters of new connections, e.g. a custom congestion controller
+    pub fn set_transport(&mut self, transport: quinn::TransportConfig) {
+        self.config.transport = Arc::new(transport);
+    }
+
+    /// use the endpoint of other, both servers must be of the same address family
+    pub fn share_endpoint(&mut self, other: &QuicConnector) {
+        self.endpoint = other.endpoint.clone();
+    }
+
+    async fn endpoint(&self) -> io::Result<Endpoint> {
+        let mut endpoint = self.endpoint.lock().await;
+        if let Some(e) = &*endpoint {
+            return Ok(e.clone());
+        }
+        let socket = QuicConnector::bind_socket(&self.server)?;
+        let (e, _incoming) = Endpoint::new(EndpointConfig::default(), None, socket)?;
+        endpoint.replace(e.clone());
+        Ok(e)
+    }
+
+    // quinn 在内核支持时使用 UDP_SEGMENT 批量发送，这里只做检测，方便排查吞吐问题
+    fn bind_socket(server: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+        let socket = std::net::UdpSocket::bind(unspecified_addr(server))?;
+        #[cfg(target_os = "linux")]
+        {
+            if crate::net::sys::linux::udp_gso_supported(&socket) {
+                debug!("udp gso enabled for quic endpoint to {}", server);
+            } else {
+                debug!("udp gso not supported, quic to {} sends one datagram per syscall", server);
+            }
+        }
+        Ok(socket)
+    }
+
+    async fn connection(&self) -> Result<Arc<QuicConnection>> {
+        let mut cached = self.connection.lock().await;
+        if let Some(conn) = &*cached {
+            if conn.connection.close_reason().is_none() {
+                return Ok(conn.clone());
+            }
+            debug!("quic connection to {} closed, reconnecting", self.server);
+        }
+        let endpoint = self.endpoint().await?;
+        let connecting = endpoint
+            .connect_with(self.config.clone(), self.server, &self.server_name)
+            .map_err(|err| anyhow!("quic connect to {} failed {}", self.server, err))?;
+        let new_connection = if self.zero_rtt {
+            match connecting.into_0rtt() {
+                Ok((conn, _accepted)) => {
+                    trace!("quic 0-RTT to {}", self.server);
+                    conn
+                }
+                // 没有可恢复的 session，走完整握手
+                Err(connecting) => connecting.await?,
+            }
+        } else {
+            connecting.await?
+        };
+        let NewConnection {
+            connection,
+            datagrams,
+            ..
+        } = new_connection;
+        trace!("quic connection established to {}", self.server);
+        let conn = Arc::new(QuicConnection {
+            connection,
+            datagrams: Mutex::new(datagrams),
+        });
+        cached.replace(conn.clone());
+        Ok(conn)
+    }
+
+    /// open a new bidirectional stream on the shared connection
+    pub async fn open_stream(&self) -> Result<QuicStream> {
+        let conn = self.connection().await?;
+        let (send, recv) = conn.connection.open_bi().await?;
+        Ok(QuicStream { send, recv })
+    }
+
+    /// open a unidirectional stream, with the stable id of the connection it belongs to
+    /// so callers can tell when the connection was replaced, e.g. to reopen http/3 control streams
+    pub async fn open_uni(&self) -> Result<(usize, SendStream)> {
+        let conn = self.connection().await?;
+        let send = conn.connection.open_uni().await?;
+        Ok((conn.connection.stable_id(), send))
+    }
+
+    /// stable id of the current connection, connects if needed
+    pub async fn connection_id(&self) -> Result<usize> {
+        Ok(self.connection().await?.connection.stable_id())
+    }
+
+    pub async fn send_datagram(&self, data: Bytes) -> Result<()> {
+        let conn = self.connection().await?;
+        conn.connection
+            .send_datagram(data)
+            .map_err(|err| anyhow!("quic send datagram failed {}", err))
+    }
+
+    pub async fn recv_datagram(&self) -> Result<Bytes> {
+        let conn = self.connection().await?;
+        let mut datagrams = conn.datagrams.lock().await;
+        match datagrams.next().await {
+            Some(Ok(data)) => Ok(data),
+            Some(Err(err)) => Err(err.into()),
+            None => Err(anyhow!("quic connection to {} closed", self.server)),
+        }
+    }
+
+    /// connection migration, move the connection onto a fresh local udp socket
+    /// e.g. after the default interface changed
+    pub async fn rebind(&self) -> io::Result<()> {
+        let endpoint = self.endpoint().await?;
+        let socket = QuicConnector::bind_socket(&self.server)?;
+        endpoint.rebind(socket)
+    }
+}
+
+#[tokio::test]
+async fn test_quic_stream() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let certs = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs");
+    let mut reader = io::BufReader::new(std::fs::File::open(certs.join("a.crt")).unwrap());
+    let chain = rustls_pemfile::certs(&mut reader).unwrap().into_iter().map(rustls::Certificate).collect();
+    let mut reader = io::BufReader::new(std::fs::File::open(certs.join("a.key")).unwrap());
+    let key = rustls::PrivateKey(rustls_pemfile::pkcs8_private_keys(&mut reader).unwrap().remove(0));
+    let config = quinn::ServerConfig::with_single_cert(chain, key).unwrap();
+    let (endpoint, mut incoming) = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+    let server = endpoint.local_addr().unwrap();
+    // echo server，每个 stream 读到结束后原样写回
+    tokio::spawn(async move {
+        let NewConnection { mut bi_streams, .. } = incoming.next().await.unwrap().await.unwrap();
+        while let Some(Ok((mut send, recv))) = bi_streams.next().await {
+            tokio::spawn(async move {
+                let data = recv.read_to_end(1024).await.unwrap();
+                send.write_all(&data).await.unwrap();
+                send.finish().await.unwrap();
+            });
+        }
+    });
+
+    let settings = QuicSettings {
+        tls: crate::config::TlsSettings {
+            insecure: true,
+            ..Default::default()
+        },
+        zero_rtt: false,
+    };
+    let connector = QuicConnector::new(server, "localhost", &settings).unwrap();
+    let id = connector.connection_id().await.unwrap();
+    for message in vec![b"hello".to_vec(), b"world".to_vec()] {
+        let mut stream = connector.open_stream().await.unwrap();
+        stream.write_all(&message).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, message);
+    }
+    // stream 共享同一条 connection
+    assert_eq!(connector.connection_id().await.unwrap(), id);
+}
@@ -0,0 +1,401 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::BufReader,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, trace, warn};
+use ring::digest::{digest, SHA256};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, ProtocolVersion, RootCertStore,
+    ServerConfig, ServerName,
+};
+use tokio_rustls::client::TlsStream;
+
+use crate::{
+    config::{TlsServerSettings, TlsSettings},
+    proxy::StreamWrapperTrait,
+};
+
+// 校验 server 证书
+// 1. insecure: 不做任何校验
+// 2. pins: 证书 sha256 命中其中之一即可，不再校验证书链（用于自签证书）
+// 3. 其他情况走 webpki 证书链校验
+struct CertVerifier {
+    webpki: WebPkiVerifier,
+    pins: Vec<Vec<u8>>,
+    insecure: bool,
+}
+
+impl ServerCertVerifier for CertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.insecure {
+            return Ok(ServerCertVerified::assertion());
+        }
+        if !self.pins.is_empty() {
+            let fingerprint = digest(&SHA256, &end_entity.0);
+            if self.pins.iter().any(|pin| pin.as_slice() == fingerprint.as_ref()) {
+                return Ok(ServerCertVerified::assertion());
+            }
+            return Err(rustls::Error::General(
+                "server certificate does not match any pin".to_string(),
+            ));
+        }
+        self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    let s = s.replace(':', "");
+    if s.len() % 2 != 0 {
+        return Err(anyhow!("bad hex string {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|err| anyhow!("bad hex {} {}", s, err)))
+        .collect()
+}
+
+fn parse_version(version: &str) -> Result<ProtocolVersion> {
+    match version {
+        "1.2" => Ok(ProtocolVersion::TLSv1_2),
+        "1.3" => Ok(ProtocolVersion::TLSv1_3),
+        _ => Err(anyhow!("unsupported tls version {}", version)),
+    }
+}
+
+fn version_rank(version: ProtocolVersion) -> u8 {
+    match version {
+        ProtocolVersion::TLSv1_3 => 3,
+        ProtocolVersion::TLSv1_2 => 2,
+        _ => 0,
+    }
+}
+
+fn load_root_store(ca: &Option<String>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            let mut reader = BufReader::new(File::open(path)?);
+            let certs = rustls_pemfile::certs(&mut reader)?;
+            if certs.is_empty() {
+                return Err(anyhow!("no certificate found in {}", path));
+            }
+            for cert in certs {
+                roots
+                    .add(&Certificate(cert))
+                    .map_err(|err| anyhow!("bad ca certificate in {} {}", path, err))?;
+            }
+        }
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+    }
+    Ok(roots)
+}
+
+// 构造 rustls client config，tls 与 quic transport 共用
+pub(crate) fn build_client_config(settings: &TlsSettings) -> Result<ClientConfig> {
+    let roots = load_root_store(&settings.ca)?;
+    let mut pins = Vec::new();
+    if let Some(values) = &settings.pins {
+        for value in values {
+            pins.push(decode_hex(value)?);
+        }
+    }
+    let verifier = CertVerifier {
+        webpki: WebPkiVerifier::new(roots.clone(), None),
+        pins,
+        insecure: settings.insecure,
+    };
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    if let Some(alpn) = &settings.alpn {
+        config.alpn_protocols = alpn.iter().map(|x| x.as_bytes().to_vec()).collect();
+    }
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(verifier));
+    Ok(config)
+}
+
+// tls client，可被 trojan/vmess/http 等 outbound 复用
+pub struct TlsConnector {
+    inner: tokio_rustls::TlsConnector,
+    sni: Option<String>,
+    alpn: Vec<Vec<u8>>,
+    min_version: Option<ProtocolVersion>,
+    fail_on_downgrade: bool,
+}
+
+impl TlsConnector {
+    pub fn new(settings: &TlsSettings) -> Result<TlsConnector> {
+        let config = build_client_config(settings)?;
+        let alpn = config.alpn_protocols.clone();
+        let min_version = match &settings.min_version {
+            Some(v) => Some(parse_version(v)?),
+            None => None,
+        };
+        Ok(TlsConnector {
+            inner: tokio_rustls::TlsConnector::from(Arc::new(config)),
+            sni: settings.sni.clone(),
+            alpn,
+            min_version,
+            fail_on_downgrade: settings.fail_on_downgrade,
+        })
+    }
+
+    /// host is the server address, used as sni when sni not configured
+    pub async fn connect<T>(&self, host: &str, stream: T) -> Result<TlsStream<T>>
+    where
+        T: StreamWrapperTrait,
+    {
+        let name = self.sni.as_deref().unwrap_or(host);
+        let server_name =
+            ServerName::try_from(name).map_err(|err| anyhow!("invalid sni {} {}", name, err))?;
+        trace!("tls handshake with {}", name);
+        let stream = self.inner.connect(server_name, stream).await.map_err(|err| {
+            debug!("tls handshake with {} failed {}", name, err);
+            err
+        })?;
+        if let Err(err) = self.check_downgrade(name, &stream) {
+            warn!("security warning: {}", err);
+            if self.fail_on_downgrade {
+                return Err(err);
+            }
+        }
+        Ok(stream)
+    }
+
+    // 握手完成后，检查协商结果是否满足配置
+    // 配置错误（或者中间人）可能悄悄降低安全性，例如 server 不支持配置的 ALPN
+    fn check_downgrade<T>(&self, name: &str, stream: &TlsStream<T>) -> Result<()> {
+        let (_, conn) = stream.get_ref();
+        if !self.alpn.is_empty() {
+            match conn.alpn_protocol() {
+                Some(proto) if self.alpn.iter().any(|x| x.as_slice() == proto) => {}
+                Some(proto) => {
+                    return Err(anyhow!(
+                        "tls to {} negotiated unexpected alpn {}",
+                        name,
+                        String::from_utf8_lossy(proto)
+                    ))
+                }
+                None => return Err(anyhow!("tls to {} completed without alpn", name)),
+            }
+        }
+        if let Some(min) = self.min_version {
+            match conn.protocol_version() {
+                Some(version) if version_rank(version) >= version_rank(min) => {}
+                version => {
+                    return Err(anyhow!(
+                        "tls to {} downgraded to {:?}, expect at least {:?}",
+                        name,
+                        version,
+                        min
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey> {
+    let mut reader = BufReader::new(File::open(cert_path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {}", cert_path));
+    }
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(anyhow!("no private key found in {}", key_path)),
+        }
+    };
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| anyhow!("unsupported private key in {}", key_path))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+// 握手时才读取当前证书，替换证书不影响已经建立的连接
+struct ReloadableCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// tls server，供 trojan 等需要 tls termination 的 inbound 复用
+pub struct TlsAcceptor {
+    inner: tokio_rustls::TlsAcceptor,
+    cert: Arc<ReloadableCert>,
+    settings: TlsServerSettings,
+}
+
+impl TlsAcceptor {
+    pub fn new(settings: &TlsServerSettings) -> Result<TlsAcceptor> {
+        let key = load_certified_key(&settings.cert, &settings.key)?;
+        let cert = Arc::new(ReloadableCert {
+            current: RwLock::new(Arc::new(key)),
+        });
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(cert.clone());
+        if let Some(alpn) = &settings.alpn {
+            config.alpn_protocols = alpn.iter().map(|x| x.as_bytes().to_vec()).collect();
+        }
+        Ok(TlsAcceptor {
+            inner: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+            cert,
+            settings: settings.clone(),
+        })
+    }
+
+    pub async fn accept<T>(&self, stream: T) -> Result<tokio_rustls::server::TlsStream<T>>
+    where
+        T: StreamWrapperTrait,
+    {
+        Ok(self.inner.accept(stream).await?)
+    }
+
+    /// reload cert and key from disk, the old cert is kept if loading fails
+    pub fn reload(&self) -> Result<()> {
+        let key = load_certified_key(&self.settings.cert, &self.settings.key)?;
+        *self.cert.current.write().unwrap() = Arc::new(key);
+        info!("tls certificate {} reloaded", self.settings.cert);
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = fs::metadata(&self.settings.cert).and_then(|x| x.modified()).ok()?;
+        let key = fs::metadata(&self.settings.key).and_then(|x| x.modified()).ok()?;
+        Some((cert, key))
+    }
+
+    /// poll cert/key modification time and reload on change
+    /// 证书由外部（例如 Let's Encrypt 的 certbot）续期
+    pub async fn watch(self: Arc<Self>) {
+        let interval = match self.settings.reload_interval {
+            Some(0) => return,
+            Some(x) => Duration::from_secs(x),
+            None => Duration::from_secs(60),
+        };
+        let mut last = self.modified();
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = self.modified();
+            if current.is_none() || current == last {
+                continue;
+            }
+            match self.reload() {
+                Ok(_) => last = current,
+                Err(err) => warn!("reload tls certificate {} failed {}", self.settings.cert, err),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_decode_hex() {
+    assert_eq!(decode_hex("0a:ff").unwrap(), vec![0x0a, 0xff]);
+    assert_eq!(decode_hex("0aff10").unwrap(), vec![0x0a, 0xff, 0x10]);
+    assert!(decode_hex("0af").is_err());
+    assert!(decode_hex("zz").is_err());
+}
+
+#[tokio::test]
+async fn test_tls_acceptor_reload() {
+    // 只接受 pin 命中的证书，据此判断 server 使用的是哪一个证书
+    async fn handshake(acceptor: &TlsAcceptor, pin: &str) -> bool {
+        let connector = TlsConnector::new(&TlsSettings {
+            pins: Some(vec![pin.to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+        let (client, server) = tokio::io::duplex(16384);
+        let (client, _) = tokio::join!(connector.connect("localhost", client), acceptor.accept(server));
+        client.is_ok()
+    }
+    let certs = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs");
+    let pin = |name: &str| -> String {
+        let mut reader = BufReader::new(File::open(certs.join(name)).unwrap());
+        let der = rustls_pemfile::certs(&mut reader).unwrap().remove(0);
+        digest(&SHA256, &der).as_ref().iter().map(|x| format!("{:02x}", x)).collect()
+    };
+    let (pin_a, pin_b) = (pin("a.crt"), pin("b.crt"));
+    let dir = std::env::temp_dir().join(format!("tunnel-tls-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("server.crt"), dir.join("server.key"));
+    fs::copy(certs.join("a.crt"), &cert).unwrap();
+    fs::copy(certs.join("a.key"), &key).unwrap();
+    let acceptor = Arc::new(
+        TlsAcceptor::new(&TlsServerSettings {
+            cert: cert.display().to_string(),
+            key: key.display().to_string(),
+            alpn: None,
+            reload_interval: Some(1),
+        })
+        .unwrap(),
+    );
+    let watch = tokio::spawn(acceptor.clone().watch());
+    assert!(handshake(&acceptor, &pin_a).await);
+    assert!(!handshake(&acceptor, &pin_b).await);
+
+    // 替换证书文件，watch 发现修改时间变化后重新加载，之后的握手使用新证书
+    fs::copy(certs.join("b.key"), &key).unwrap();
+    fs::copy(certs.join("b.crt"), &cert).unwrap();
+    let mut reloaded = false;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if handshake(&acceptor, &pin_b).await {
+            reloaded = true;
+            break;
+        }
+    }
+    assert!(reloaded);
+    assert!(!handshake(&acceptor, &pin_a).await);
+    watch.abort();
+    fs::remove_dir_all(&dir).unwrap();
+}
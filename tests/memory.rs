@@ -0,0 +1,55 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tunnel::{parse_from_str, proxy::Address, testing::Harness};
+
+#[tokio::test]
+async fn route_in_memory() {
+    let config = parse_from_str(
+        r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct"
+            },
+            {
+                "protocol": "socks",
+                "tag": "proxy"
+            }
+        ],
+        "routes": [
+            {
+                "domain": ["domain:example.com"],
+                "target": "proxy"
+            },
+            {
+                "ip": ["0.0.0.0/0"],
+                "target": "direct"
+            }
+        ]
+    }
+    "#,
+    )
+    .unwrap();
+    let harness = Harness::new(config);
+
+    let mut stream = harness.connect(Address::Domain("www.example.com".to_string(), 80));
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let mut stream = harness.connect("1.1.1.1:80".parse().unwrap());
+    stream.write_all(b"world").await.unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"world");
+
+    let proxied = harness.outbound("proxy").sessions();
+    assert_eq!(proxied.len(), 1);
+    assert_eq!(proxied[0].destination.to_string(), "www.example.com:80");
+    assert_eq!(harness.outbound("direct").sessions().len(), 1);
+}
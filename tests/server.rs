@@ -117,10 +117,16 @@ async fn send_data_socks5_tcp(
     let mut stream = TcpStream::connect(addr).await.unwrap();
     debug!("{} {}", proxy_server, remote_server);
     let session = Session {
+        id: Session::next_id(),
         destination: Address::try_from(addr_to_tuple(remote_server)).unwrap(),
         local_peer: stream.local_addr().unwrap(),
         network: tunnel::proxy::Network::TCP,
-        peer_address: stream.peer_addr().unwrap()
+        peer_address: stream.peer_addr().unwrap(),
+        user: None,
+        inbound_tag: None,
+        app_protocol: None,
+        process: None,
+        uid: None,
     };
     tunnel::proxy::socks::handshake_as_client(&mut stream, &session).await?;
     stream.write_all(&buf).await?;